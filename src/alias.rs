@@ -0,0 +1,161 @@
+use std::{collections::HashSet, process::Command};
+
+use anyhow::{anyhow, Result};
+
+use crate::repository::Repository;
+
+/// The command line once aliases are expanded.
+pub enum Expansion {
+    /// Arguments to parse as an `mg` command
+    Args(Vec<String>),
+    /// A shell command (an alias starting with `!`) and its arguments
+    Shell(String, Vec<String>),
+}
+
+/// Split an alias into words, honoring single and double quotes and
+/// backslash escapes as a shell would.
+fn split_words(value: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (Some(q), c) if c == q => quote = None,
+            (None | Some('"'), '\\') => {
+                let escaped = chars
+                    .next()
+                    .ok_or_else(|| anyhow!("unfinished escape in alias '{}'", value))?;
+                word.push(escaped);
+                in_word = true;
+            }
+            (_, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err(anyhow!("unclosed quote in alias '{}'", value));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// The position of the subcommand in `args`, after the program name and the
+/// global options.
+fn command_position(args: &[String]) -> Option<usize> {
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            // global options taking a separate value
+            "--memory-budget" | "-C" | "--git-dir" => i += 2,
+            arg if arg.starts_with('-') => i += 1,
+            _ => return Some(i),
+        }
+    }
+    None
+}
+
+impl Repository {
+    /// Expand the subcommand of `args` if it is an `alias.<name>` entry
+    /// rather than a command, recursively. Commands cannot be redefined.
+    pub fn expand_alias<F>(&self, mut args: Vec<String>, is_command: F) -> Result<Expansion>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let Some(position) = command_position(&args) else {
+            return Ok(Expansion::Args(args));
+        };
+        let config = self.config()?;
+
+        let mut seen = HashSet::new();
+        loop {
+            let name = args[position].clone();
+            if is_command(&name) {
+                return Ok(Expansion::Args(args));
+            }
+            let Some(value) = config.get(&format!("alias.{}", name)) else {
+                return Ok(Expansion::Args(args));
+            };
+            if !seen.insert(name.clone()) {
+                return Err(anyhow!(
+                    "alias loop detected: expansion of '{}' does not terminate",
+                    name
+                ));
+            }
+
+            if let Some(command) = value.strip_prefix('!') {
+                return Ok(Expansion::Shell(
+                    command.to_string(),
+                    args[position + 1..].to_vec(),
+                ));
+            }
+
+            let words = split_words(&value)?;
+            if words.is_empty() {
+                return Err(anyhow!("empty alias for {}", name));
+            }
+            args.splice(position..position + 1, words);
+        }
+    }
+
+    /// Run a shell alias from the top of the worktree, the arguments
+    /// following the command, and return its exit code.
+    pub fn run_shell_alias(&self, command: &str, args: &[String]) -> Result<i32> {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", command))
+            .arg(command)
+            .args(args)
+            .current_dir(&self.path)
+            .status()?;
+
+        Ok(status.code().unwrap_or(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            split_words("log --decorate  main").unwrap(),
+            vec!["log", "--decorate", "main"]
+        );
+        assert_eq!(
+            split_words(r#"commit -m "two words" 'it''s' a\ b"#).unwrap(),
+            vec!["commit", "-m", "two words", "its", "a b"]
+        );
+        assert_eq!(split_words("grep ''").unwrap(), vec!["grep", ""]);
+        assert!(split_words("log \"main").is_err());
+    }
+
+    #[test]
+    fn test_command_position() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(command_position(&args(&["mg", "co", "main"])), Some(1));
+        assert_eq!(
+            command_position(&args(&["mg", "--memory-budget", "1m", "co"])),
+            Some(3)
+        );
+        assert_eq!(command_position(&args(&["mg", "--help"])), None);
+    }
+}
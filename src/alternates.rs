@@ -0,0 +1,57 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::Result;
+
+use crate::repository::Repository;
+
+/// How deep alternates of alternates are followed.
+const MAX_ALTERNATE_DEPTH: usize = 5;
+
+impl Repository {
+    /// The object directories of the repository: its own, then those it
+    /// borrows objects from, listed one per line in `objects/info/alternates`
+    /// (relative paths being relative to the objects directory).
+    pub fn object_dirs(&self) -> Result<Vec<PathBuf>> {
+        let mut dirs = vec![self.git_dir.join("objects")];
+
+        let mut next = 0;
+        let mut depth = vec![0];
+        while next < dirs.len() {
+            let dir = dirs[next].clone();
+            let level = depth[next];
+            next += 1;
+
+            let alternates = dir.join("info").join("alternates");
+            if level >= MAX_ALTERNATE_DEPTH || !alternates.is_file() {
+                continue;
+            }
+            for line in fs::read_to_string(&alternates)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let alternate = dir.join(line);
+                let alternate = alternate.canonicalize().unwrap_or(alternate);
+                if !dirs.contains(&alternate) {
+                    dirs.push(alternate);
+                    depth.push(level + 1);
+                }
+            }
+        }
+
+        Ok(dirs)
+    }
+
+    /// The path of the loose object `hash`, in the first object directory
+    /// holding it, or in the repository's own when none does.
+    pub fn loose_object_path(&self, hash: &str) -> Result<PathBuf> {
+        let dirs = self.object_dirs()?;
+        let path_in = |dir: &PathBuf| dir.join(&hash[..2]).join(&hash[2..]);
+
+        Ok(dirs
+            .iter()
+            .map(path_in)
+            .find(|path| path.exists())
+            .unwrap_or_else(|| path_in(&dirs[0])))
+    }
+}
@@ -0,0 +1,427 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::repository::Repository;
+
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    pub header: String,
+    pub old_start: usize,
+    pub old_count: usize,
+    pub new_count: usize,
+    /// `(' ' | '-' | '+', line)` without the trailing newline, and
+    /// `('\\', text)` for the "\\ No newline at end of file" marker which
+    /// follows the last line of a side lacking it
+    pub lines: Vec<(char, String)>,
+}
+
+impl Hunk {
+    /// Whether the file ends with a newline after this hunk, if the hunk
+    /// says: it does when the marker only follows a removed line.
+    fn trailing_newline(&self) -> Option<bool> {
+        let mut old_missing = false;
+        for pair in self.lines.windows(2) {
+            if pair[1].0 == '\\' {
+                match pair[0].0 {
+                    '+' | ' ' => return Some(false),
+                    _ => old_missing = true,
+                }
+            }
+        }
+        old_missing.then_some(true)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FilePatch {
+    /// the `diff --git`, `---` and `+++` lines, replayed in `.rej` files
+    pub header: Vec<String>,
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    fn target(&self) -> Option<&str> {
+        self.new_path.as_deref().or(self.old_path.as_deref())
+    }
+}
+
+fn parse_path(line: &str) -> Option<String> {
+    let path = line.split('\t').next().unwrap_or(line).trim_end();
+    if path == "/dev/null" {
+        return None;
+    }
+
+    // strip the `a/` and `b/` prefixes used by git
+    let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path);
+    Some(path.to_string())
+}
+
+fn parse_range(range: &str) -> Result<(usize, usize)> {
+    match range.split_once(',') {
+        Some((start, count)) => Ok((start.parse()?, count.parse()?)),
+        None => Ok((range.parse()?, 1)),
+    }
+}
+
+fn parse_hunk_header(line: &str) -> Result<Hunk> {
+    // @@ -old_start,old_count +new_start,new_count @@ section
+    let mut parts = line.split_whitespace().skip(1);
+    let old = parts
+        .next()
+        .and_then(|p| p.strip_prefix('-'))
+        .ok_or_else(|| anyhow!("invalid hunk header: {}", line))?;
+    let new = parts
+        .next()
+        .and_then(|p| p.strip_prefix('+'))
+        .ok_or_else(|| anyhow!("invalid hunk header: {}", line))?;
+
+    let (old_start, old_count) = parse_range(old)?;
+    let (_, new_count) = parse_range(new)?;
+
+    Ok(Hunk {
+        header: line.to_string(),
+        old_start,
+        old_count,
+        new_count,
+        lines: Vec::new(),
+    })
+}
+
+/// Parse a unified diff, as produced by `git diff` or `diff -u`.
+pub fn parse_patch(content: &str) -> Result<Vec<FilePatch>> {
+    let mut patches: Vec<FilePatch> = Vec::new();
+    let mut current: Option<FilePatch> = None;
+    let mut lines = content.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        if line.starts_with("diff ") {
+            if let Some(patch) = current.take() {
+                patches.push(patch);
+            }
+            current = Some(FilePatch {
+                header: vec![line.to_string()],
+                ..Default::default()
+            });
+        } else if let Some(old) = line.strip_prefix("--- ") {
+            if current.as_ref().is_some_and(|p| !p.hunks.is_empty()) {
+                patches.extend(current.take());
+            }
+            let patch = current.get_or_insert_with(FilePatch::default);
+            patch.header.push(line.to_string());
+            patch.old_path = parse_path(old);
+        } else if let Some(new) = line.strip_prefix("+++ ") {
+            let patch = current
+                .as_mut()
+                .ok_or_else(|| anyhow!("unexpected line: {}", line))?;
+            patch.header.push(line.to_string());
+            patch.new_path = parse_path(new);
+        } else if line.starts_with("@@ ") {
+            let patch = current
+                .as_mut()
+                .ok_or_else(|| anyhow!("hunk without file header: {}", line))?;
+            let mut hunk = parse_hunk_header(line)?;
+
+            let (mut old_seen, mut new_seen) = (0, 0);
+            while old_seen < hunk.old_count || new_seen < hunk.new_count {
+                let Some(line) = lines.next() else {
+                    return Err(anyhow!("truncated hunk: {}", hunk.header));
+                };
+                let (tag, text) = match line.chars().next() {
+                    Some(tag @ (' ' | '-' | '+')) => (tag, &line[1..]),
+                    Some('\\') if hunk.lines.last().is_some_and(|(tag, _)| *tag != '\\') => {
+                        hunk.lines.push(('\\', line[1..].to_string()));
+                        continue;
+                    }
+                    // some tools drop the space of empty context lines
+                    None => (' ', ""),
+                    Some(_) => return Err(anyhow!("invalid hunk line: {}", line)),
+                };
+                match tag {
+                    ' ' => {
+                        old_seen += 1;
+                        new_seen += 1;
+                    }
+                    '-' => old_seen += 1,
+                    _ => new_seen += 1,
+                }
+                hunk.lines.push((tag, text.to_string()));
+            }
+
+            // the marker of the last line
+            if let Some(line) = lines.next_if(|l| l.starts_with('\\')) {
+                hunk.lines.push(('\\', line[1..].to_string()));
+            }
+
+            patch.hunks.push(hunk);
+        } else if let Some(patch) = current.as_mut() {
+            // extended git headers (index, mode, new file, ...)
+            if patch.hunks.is_empty() {
+                patch.header.push(line.to_string());
+            }
+        }
+    }
+
+    patches.extend(current);
+
+    Ok(patches)
+}
+
+/// Apply hunks to `lines`, returning the new content and the hunks which
+/// could not be applied.
+pub fn apply_hunks<'a>(lines: &[String], hunks: &'a [Hunk]) -> (Vec<String>, Vec<&'a Hunk>) {
+    let mut result = lines.to_vec();
+    let mut rejected = Vec::new();
+    // shift between the line numbers of the patch and of `result`
+    let mut offset: isize = 0;
+
+    for hunk in hunks {
+        let old: Vec<&str> = hunk
+            .lines
+            .iter()
+            .filter(|(tag, _)| matches!(tag, ' ' | '-'))
+            .map(|(_, line)| line.as_str())
+            .collect();
+        let new: Vec<String> = hunk
+            .lines
+            .iter()
+            .filter(|(tag, _)| matches!(tag, ' ' | '+'))
+            .map(|(_, line)| line.clone())
+            .collect();
+
+        // for an empty old side, the start line is the line after which to insert
+        let expected = if hunk.old_count == 0 {
+            hunk.old_start as isize + offset
+        } else {
+            hunk.old_start as isize - 1 + offset
+        };
+
+        let Some(position) = find_hunk(&result, &old, expected.max(0) as usize) else {
+            rejected.push(hunk);
+            continue;
+        };
+
+        result.splice(position..position + old.len(), new.iter().cloned());
+        offset += new.len() as isize - old.len() as isize + (position as isize - expected);
+    }
+
+    (result, rejected)
+}
+
+/// Find where `old` occurs in `lines`, trying the closest positions to
+/// `expected` first.
+fn find_hunk(lines: &[String], old: &[&str], expected: usize) -> Option<usize> {
+    let matches_at = |pos: usize| {
+        pos + old.len() <= lines.len()
+            && lines[pos..pos + old.len()]
+                .iter()
+                .zip(old)
+                .all(|(a, b)| a == b)
+    };
+
+    let max_distance = lines.len().max(expected);
+    (0..=max_distance).find_map(|distance| {
+        if matches_at(expected + distance) {
+            Some(expected + distance)
+        } else if distance <= expected && matches_at(expected - distance) {
+            Some(expected - distance)
+        } else {
+            None
+        }
+    })
+}
+
+fn split_lines(content: &str) -> (Vec<String>, bool) {
+    let trailing_newline = content.is_empty() || content.ends_with('\n');
+    (
+        content.lines().map(String::from).collect(),
+        trailing_newline,
+    )
+}
+
+fn format_rejects(patch: &FilePatch, rejected: &[&Hunk]) -> String {
+    let mut out = String::new();
+    for line in &patch.header {
+        out.push_str(line);
+        out.push('\n');
+    }
+    for hunk in rejected {
+        out.push_str(&hunk.header);
+        out.push('\n');
+        for (tag, line) in &hunk.lines {
+            out.push(*tag);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+impl Repository {
+    /// Apply a patch file to the worktree. Without `reject`, nothing is written
+    /// unless every hunk applies; with it, failing hunks go to `<file>.rej`.
+    pub fn apply(&self, patch_file: &Path, reject: bool) -> Result<bool> {
+        let content = fs::read_to_string(patch_file).context("could not read patch")?;
+        let patches = parse_patch(&content)?;
+
+        let mut outputs = Vec::new();
+        let mut all_applied = true;
+
+        for patch in &patches {
+            let target = patch
+                .target()
+                .ok_or_else(|| anyhow!("patch without file name"))?;
+            let path = self.path.join(target);
+
+            let (lines, mut trailing_newline) = match &patch.old_path {
+                Some(_) => split_lines(
+                    &fs::read_to_string(&path)
+                        .with_context(|| format!("{}: does not exist", target))?,
+                ),
+                None if fs::symlink_metadata(&path).is_ok() => {
+                    return Err(anyhow!("{}: already exists in working directory", target));
+                }
+                None => (Vec::new(), true),
+            };
+
+            let (new_lines, rejected) = apply_hunks(&lines, &patch.hunks);
+            for hunk in &patch.hunks {
+                if !rejected.iter().any(|r| std::ptr::eq(*r, hunk)) {
+                    trailing_newline = hunk.trailing_newline().unwrap_or(trailing_newline);
+                }
+            }
+
+            if rejected.is_empty() {
+                println!("Applied patch {} cleanly.", target);
+            } else {
+                all_applied = false;
+                println!(
+                    "Applying patch {} with {} reject{}...",
+                    target,
+                    rejected.len(),
+                    if rejected.len() == 1 { "" } else { "s" }
+                );
+                for (idx, hunk) in patch.hunks.iter().enumerate() {
+                    let status = if rejected.iter().any(|r| std::ptr::eq(*r, hunk)) {
+                        "rejected"
+                    } else {
+                        "applied cleanly"
+                    };
+                    println!("Hunk #{} {}.", idx + 1, status);
+                }
+            }
+
+            outputs.push((patch, path, new_lines, trailing_newline, rejected));
+        }
+
+        if !all_applied && !reject {
+            return Err(anyhow!("patch does not apply"));
+        }
+
+        let (mut applied_count, mut rejected_count) = (0, 0);
+        for (patch, path, new_lines, trailing_newline, rejected) in outputs {
+            applied_count += patch.hunks.len() - rejected.len();
+            rejected_count += rejected.len();
+
+            if !rejected.is_empty() {
+                let mut rej_path = path.clone().into_os_string();
+                rej_path.push(".rej");
+                fs::write(rej_path, format_rejects(patch, &rejected))?;
+            }
+
+            if patch.new_path.is_none() && rejected.is_empty() {
+                fs::remove_file(&path)?;
+                continue;
+            }
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut content = new_lines.join("\n");
+            if trailing_newline && !new_lines.is_empty() {
+                content.push('\n');
+            }
+            fs::write(&path, content)?;
+        }
+
+        if reject {
+            println!(
+                "{} hunk{} applied, {} rejected",
+                applied_count,
+                if applied_count == 1 { "" } else { "s" },
+                rejected_count
+            );
+        }
+
+        Ok(all_applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_hunks_with_reject() {
+        let patch = parse_patch(
+            "--- a/file\n\
+             +++ b/file\n\
+             @@ -1,3 +1,3 @@\n \
+             one\n\
+             -two\n\
+             +TWO\n \
+             three\n\
+             @@ -8,2 +8,2 @@\n \
+             missing\n\
+             -context\n\
+             +changed\n",
+        )
+        .unwrap();
+        assert_eq!(patch.len(), 1);
+        assert_eq!(patch[0].new_path.as_deref(), Some("file"));
+
+        let lines: Vec<String> = ["zero", "one", "two", "three"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let (result, rejected) = apply_hunks(&lines, &patch[0].hunks);
+
+        assert_eq!(result, vec!["zero", "one", "TWO", "three"]);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].old_start, 8);
+    }
+
+    #[test]
+    fn test_parse_no_newline_markers() {
+        let patch = parse_patch(
+            "--- a/file\n\
+             +++ b/file\n\
+             @@ -1,2 +1,3 @@\n \
+             one\n\
+             -two\n\
+             \\ No newline at end of file\n\
+             +two\n\
+             +three\n\
+             --- a/other\n\
+             +++ b/other\n\
+             @@ -1 +1 @@\n\
+             -a\n\
+             +b\n\
+             \\ No newline at end of file\n",
+        )
+        .unwrap();
+        assert_eq!(patch.len(), 2);
+        assert_eq!(patch[0].hunks[0].trailing_newline(), Some(true));
+        assert_eq!(patch[1].hunks[0].trailing_newline(), Some(false));
+
+        let lines = vec!["one".to_string(), "two".to_string()];
+        let (result, rejected) = apply_hunks(&lines, &patch[0].hunks);
+        assert!(rejected.is_empty());
+        assert_eq!(result, vec!["one", "two", "three"]);
+    }
+}
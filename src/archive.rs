@@ -0,0 +1,328 @@
+use std::{
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use flate2::GzBuilder;
+
+use crate::{
+    attributes::{AttrState, Attributes},
+    commit::Commit,
+    kind::Kind,
+    repository::Repository,
+};
+
+const BLOCK_SIZE: usize = 512;
+/// Archives are written in records of 20 blocks, as tar does.
+const RECORD_SIZE: usize = 20 * BLOCK_SIZE;
+
+/// A pax extended header record: `<length> <key>=<value>\n`, the length
+/// counting its own digits.
+fn pax_record(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let len = key.len() + value.len() + 3;
+    let mut total = len + 1;
+    while total.to_string().len() + len > total {
+        total += 1;
+    }
+    out.extend_from_slice(format!("{} {}=", total, key).as_bytes());
+    out.extend_from_slice(value);
+    out.push(b'\n');
+}
+
+/// Where to split a long path between the `prefix` and `name` fields.
+fn path_prefix_len(path: &[u8], max: usize) -> usize {
+    let mut i = path.len();
+    if i > 1 && path[i - 1] == b'/' {
+        i -= 1;
+    }
+    i = i.min(max);
+    loop {
+        i -= 1;
+        if i == 0 || path[i] == b'/' {
+            return i;
+        }
+    }
+}
+
+struct TarWriter<W: Write> {
+    out: W,
+    written: usize,
+    mtime: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.out.write_all(data)?;
+        self.written += data.len();
+        Ok(())
+    }
+
+    /// Write data padded to a whole block.
+    fn write_padded(&mut self, data: &[u8]) -> Result<()> {
+        self.write(data)?;
+        let tail = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        self.write(&vec![0; tail])
+    }
+
+    fn header(
+        &mut self,
+        name: &[u8],
+        prefix: &[u8],
+        mode: u32,
+        size: usize,
+        typeflag: u8,
+        link: &[u8],
+    ) -> Result<()> {
+        let mut header = [0u8; BLOCK_SIZE];
+        let mut field = |start: usize, value: &[u8]| {
+            header[start..start + value.len()].copy_from_slice(value);
+        };
+
+        field(0, name);
+        field(100, format!("{:07o}", mode & 0o7777).as_bytes());
+        field(108, b"0000000");
+        field(116, b"0000000");
+        field(124, format!("{:011o}", size).as_bytes());
+        field(136, format!("{:011o}", self.mtime).as_bytes());
+        field(156, &[typeflag]);
+        field(157, link);
+        field(257, b"ustar\0");
+        field(263, b"00");
+        field(265, b"root");
+        field(297, b"root");
+        field(329, b"0000000");
+        field(337, b"0000000");
+        field(345, prefix);
+
+        // the checksum is computed with its own field made of spaces
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..156].copy_from_slice(format!("{:07o}\0", checksum).as_bytes());
+
+        self.write(&header)
+    }
+
+    fn extended_header(&mut self, name: &str, typeflag: u8, records: &[u8]) -> Result<()> {
+        self.header(name.as_bytes(), b"", 0o100666, records.len(), typeflag, b"")?;
+        self.write_padded(records)
+    }
+
+    fn entry(
+        &mut self,
+        hash: &[u8; 20],
+        path: &str,
+        mode: u32,
+        typeflag: u8,
+        content: &[u8],
+    ) -> Result<()> {
+        let path = path.as_bytes();
+        let mut records = Vec::new();
+
+        let (mut name, mut prefix) = (path, &b""[..]);
+        let placeholder = format!("{}.data", hex::encode(hash));
+        if path.len() > 100 {
+            let prefix_len = path_prefix_len(path, 155);
+            if prefix_len > 0 && path.len() - prefix_len - 1 <= 100 {
+                prefix = &path[..prefix_len];
+                name = &path[prefix_len + 1..];
+            } else {
+                name = placeholder.as_bytes();
+                pax_record(&mut records, "path", path);
+            }
+        }
+
+        let link = if typeflag == b'2' {
+            if content.len() > 100 {
+                pax_record(&mut records, "linkpath", content);
+                placeholder.as_bytes()
+            } else {
+                content
+            }
+        } else {
+            b""
+        };
+
+        if !records.is_empty() {
+            self.extended_header(&format!("{}.paxheader", hex::encode(hash)), b'x', &records)?;
+        }
+
+        let size = if typeflag == b'0' { content.len() } else { 0 };
+        self.header(name, prefix, mode, size, typeflag, link)?;
+        if size > 0 {
+            self.write_padded(content)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fill the last record with zeros, keeping at least two zero blocks as
+    /// the end-of-archive marker.
+    fn finish(mut self) -> Result<W> {
+        let tail = RECORD_SIZE - self.written % RECORD_SIZE;
+        self.write(&vec![0; tail])?;
+        if tail < 2 * BLOCK_SIZE {
+            self.write(&vec![0; RECORD_SIZE])?;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl Repository {
+    fn archive_tree<W: Write>(
+        &self,
+        tar: &mut TarWriter<W>,
+        attributes: &mut Attributes,
+        tree: &[u8; 20],
+        path: &str,
+        prefix: &str,
+        umask: u32,
+    ) -> Result<()> {
+        for entry in self.read_tree(tree)? {
+            let entry = entry?;
+            let path = format!("{}{}", path, entry.name);
+            if attributes.value(&path, "export-ignore")? == AttrState::Set {
+                continue;
+            }
+            let full_path = format!("{}{}", prefix, path);
+
+            match entry.kind {
+                // submodules are archived as empty directories
+                Kind::Tree | Kind::Commit => {
+                    let mode = 0o777 & !umask;
+                    tar.entry(&entry.hash, &format!("{}/", full_path), mode, b'5', b"")?;
+                }
+                Kind::Symlink => {
+                    let target = self.read_object(&hex::encode(entry.hash))?.content()?;
+                    tar.entry(&entry.hash, &full_path, 0o777, b'2', &target)?;
+                }
+                Kind::Blob(executable) => {
+                    let content = self.read_object(&hex::encode(entry.hash))?.content()?;
+                    let content = self.convert_to_worktree(attributes, &path, content)?;
+                    let mode = if executable { 0o777 } else { 0o666 } & !umask;
+                    tar.entry(&entry.hash, &full_path, mode, b'0', &content)?;
+                }
+                Kind::Tag => unreachable!("trees do not contain tags"),
+            }
+
+            if matches!(entry.kind, Kind::Tree) {
+                self.archive_tree(
+                    tar,
+                    attributes,
+                    &entry.hash,
+                    &format!("{}/", path),
+                    prefix,
+                    umask,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a tar archive (gzipped with `gzip`) of a tree-ish to `out`,
+    /// with the paths prefixed by `prefix`. Entries are dated with the commit
+    /// date, or the current time for a tree, except with `reproducible`,
+    /// where a tree is dated `SOURCE_DATE_EPOCH` (or the epoch) and the
+    /// configured umask and compression level are not used.
+    pub fn archive<W: Write>(
+        &self,
+        tree_ish: &str,
+        prefix: &str,
+        gzip: bool,
+        reproducible: bool,
+        out: W,
+    ) -> Result<()> {
+        let mut hash = self.resolve_revision(tree_ish)?;
+        while let Some(target) = self.peel_tag(&hash)? {
+            hash = target;
+        }
+
+        let mut object = self.read_object(&hex::encode(hash))?;
+        let (tree, commit_time) = match object.kind() {
+            Kind::Tree => (hash, None),
+            Kind::Commit => {
+                let commit = Commit::parse(&object.string()?)?;
+                (commit.tree, Some(commit.committer.timestamp.max(0) as u64))
+            }
+            kind => return Err(anyhow!("not a tree object: {} is a {}", tree_ish, kind)),
+        };
+
+        let config = self.config()?;
+        let umask = match config.get("tar.umask") {
+            Some(umask) if !reproducible => u32::from_str_radix(&umask, 8)?,
+            _ => 0o002,
+        };
+        let commit = commit_time.is_some();
+        let mtime = match commit_time {
+            Some(time) => time,
+            None if reproducible => std::env::var("SOURCE_DATE_EPOCH")
+                .ok()
+                .and_then(|epoch| epoch.parse().ok())
+                .unwrap_or(0),
+            None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+
+        if gzip {
+            let builder = GzBuilder::new().mtime(if reproducible { 0 } else { mtime as u32 });
+            let out = builder.write(out, self.compression(reproducible)?);
+            self.write_tar(out, &tree, commit.then_some(hash), mtime, prefix, umask)?
+                .finish()?;
+        } else {
+            self.write_tar(out, &tree, commit.then_some(hash), mtime, prefix, umask)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_tar<W: Write>(
+        &self,
+        out: W,
+        tree: &[u8; 20],
+        commit: Option<[u8; 20]>,
+        mtime: u64,
+        prefix: &str,
+        umask: u32,
+    ) -> Result<W> {
+        let mut tar = TarWriter {
+            out,
+            written: 0,
+            mtime,
+        };
+
+        // the commit id is recorded in a global header, as git does
+        if let Some(commit) = commit {
+            let mut records = Vec::new();
+            pax_record(&mut records, "comment", hex::encode(commit).as_bytes());
+            tar.extended_header("pax_global_header", b'g', &records)?;
+        }
+
+        if prefix.ends_with('/') {
+            tar.entry(tree, prefix, 0o777 & !umask, b'5', b"")?;
+        }
+        let mut attributes = self.attributes()?;
+        self.archive_tree(&mut tar, &mut attributes, tree, "", prefix, umask)?;
+
+        tar.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pax_record_length() {
+        let mut out = Vec::new();
+        pax_record(&mut out, "comment", "0".repeat(40).as_bytes());
+        assert_eq!(&out[..11], b"52 comment=");
+        assert_eq!(out.len(), 52);
+
+        let mut out = Vec::new();
+        // 98 bytes without the length, which takes three digits
+        pax_record(&mut out, "path", &[b'a'; 91]);
+        assert_eq!(&out[..4], b"101 ");
+        assert_eq!(out.len(), 101);
+    }
+}
@@ -0,0 +1,475 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{pattern::Pattern, repository::Repository};
+
+/// The state of an attribute for a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttrState {
+    /// `name`
+    Set,
+    /// `-name`
+    Unset,
+    /// `name=value`
+    Value(String),
+    /// `!name`, or no rule mentions the attribute
+    Unspecified,
+}
+
+impl fmt::Display for AttrState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttrState::Set => write!(f, "set"),
+            AttrState::Unset => write!(f, "unset"),
+            AttrState::Value(value) => write!(f, "{}", value),
+            AttrState::Unspecified => write!(f, "unspecified"),
+        }
+    }
+}
+
+type AttrList = Vec<(String, AttrState)>;
+
+/// A line of an attributes file.
+#[derive(Debug, Clone)]
+struct AttrRule {
+    pattern: Pattern,
+    /// directory the pattern is relative to, empty or ending with `/`
+    base: String,
+    attrs: AttrList,
+}
+
+impl AttrRule {
+    fn matches(&self, path: &str) -> bool {
+        // negated and directory patterns are not allowed in attributes files
+        if self.pattern.negated || self.pattern.dir_only {
+            return false;
+        }
+        match path.strip_prefix(self.base.as_str()) {
+            Some(relative) => self.pattern.matches(relative, false),
+            None => false,
+        }
+    }
+}
+
+fn parse_attr(token: &str) -> (String, AttrState) {
+    if let Some(name) = token.strip_prefix('-') {
+        (name.to_string(), AttrState::Unset)
+    } else if let Some(name) = token.strip_prefix('!') {
+        (name.to_string(), AttrState::Unspecified)
+    } else if let Some((name, value)) = token.split_once('=') {
+        (name.to_string(), AttrState::Value(value.to_string()))
+    } else {
+        (token.to_string(), AttrState::Set)
+    }
+}
+
+/// Parse an attributes file, adding the attribute names it mentions to
+/// `names`. `[attr]` macro definitions are only honored when `macros` is
+/// given, i.e. in top-level files.
+fn parse_attr_file(
+    content: &str,
+    base: &str,
+    names: &mut Vec<String>,
+    mut macros: Option<&mut HashMap<String, AttrList>>,
+) -> Vec<AttrRule> {
+    let mut rules = Vec::new();
+
+    for line in content.lines() {
+        let mut tokens = line.split_whitespace();
+        let Some(pattern) = tokens.next() else {
+            continue;
+        };
+        if pattern.starts_with('#') {
+            continue;
+        }
+        let attrs: AttrList = tokens.map(parse_attr).collect();
+
+        let is_macro = pattern.starts_with("[attr]") && macros.is_some();
+        let macro_name = pattern.strip_prefix("[attr]").filter(|_| is_macro);
+        for name in macro_name
+            .into_iter()
+            .chain(attrs.iter().map(|(n, _)| n.as_str()))
+        {
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+
+        if let Some(name) = pattern.strip_prefix("[attr]") {
+            if let Some(macros) = macros.as_deref_mut() {
+                macros.insert(name.to_string(), attrs);
+            }
+            continue;
+        }
+
+        if let Some(pattern) = Pattern::parse(pattern) {
+            rules.push(AttrRule {
+                pattern,
+                base: base.to_string(),
+                attrs,
+            });
+        }
+    }
+
+    rules
+}
+
+/// The attributes of a worktree: `.gitattributes` files of every directory,
+/// `.git/info/attributes` and `core.attributesFile`.
+pub struct Attributes {
+    root: PathBuf,
+    /// `.gitattributes` rules by directory, loaded on demand
+    directories: HashMap<String, Vec<AttrRule>>,
+    /// `core.attributesFile`, the lowest precedence
+    global: Vec<AttrRule>,
+    /// `.git/info/attributes`, the highest precedence
+    info: Vec<AttrRule>,
+    macros: HashMap<String, AttrList>,
+    /// attribute names in the order they were first seen, which is the order
+    /// `check-attr --all` lists them in
+    names: Vec<String>,
+}
+
+impl Attributes {
+    fn directory_rules(&mut self, dir: &str) -> Result<&[AttrRule]> {
+        if !self.directories.contains_key(dir) {
+            let path = self.root.join(format!("{}.gitattributes", dir));
+            let rules = match fs::read_to_string(path) {
+                Ok(content) => parse_attr_file(&content, dir, &mut self.names, None),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e.into()),
+            };
+            self.directories.insert(dir.to_string(), rules);
+        }
+
+        Ok(&self.directories[dir])
+    }
+
+    fn assign(&self, attrs: &mut AttrList, name: &str, state: &AttrState) {
+        // a set macro sets the attributes it stands for as well
+        if *state == AttrState::Set {
+            if let Some(expansion) = self.macros.get(name) {
+                for (name, state) in expansion {
+                    self.assign(attrs, name, state);
+                }
+            }
+        }
+
+        match attrs.iter_mut().find(|(n, _)| n == name) {
+            Some((_, current)) => *current = state.clone(),
+            None => attrs.push((name.to_string(), state.clone())),
+        }
+    }
+
+    /// Every attribute a rule mentions for `path`. Attributes reset with `!`
+    /// are reported unspecified.
+    pub fn get(&mut self, path: &str) -> Result<AttrList> {
+        let mut dirs = vec![String::new()];
+        let mut end = 0;
+        while let Some(pos) = path[end..].find('/') {
+            end += pos + 1;
+            dirs.push(path[..end].to_string());
+        }
+
+        // apply rules from the lowest precedence up, later rules override
+        let mut rules = self.global.clone();
+        for dir in &dirs {
+            rules.extend_from_slice(self.directory_rules(dir)?);
+        }
+        rules.extend_from_slice(&self.info);
+
+        let mut attrs = Vec::new();
+        for rule in rules.iter().filter(|rule| rule.matches(path)) {
+            for (name, state) in &rule.attrs {
+                self.assign(&mut attrs, name, state);
+            }
+        }
+        attrs.sort_by_key(|(name, _)| self.names.iter().position(|n| n == name));
+
+        Ok(attrs)
+    }
+
+    /// The state of the attribute `name` for `path`.
+    pub fn value(&mut self, path: &str, name: &str) -> Result<AttrState> {
+        Ok(self
+            .get(path)?
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, state)| state)
+            .unwrap_or(AttrState::Unspecified))
+    }
+
+    /// Whether `path` is to be diffed as text (`diff`) or as binary
+    /// (`-diff`, or the `binary` macro), or `None` to guess from the content.
+    pub fn diff_as_text(&mut self, path: &str) -> Result<Option<bool>> {
+        Ok(match self.value(path, "diff")? {
+            AttrState::Set => Some(true),
+            AttrState::Unset => Some(false),
+            _ => None,
+        })
+    }
+
+    /// The diff driver of `path`, named by `diff=<driver>`.
+    pub fn diff_driver(&mut self, path: &str) -> Result<Option<String>> {
+        Ok(match self.value(path, "diff")? {
+            AttrState::Value(driver) => Some(driver),
+            _ => None,
+        })
+    }
+
+    /// The merge driver of `path`: `text` for `merge`, `binary` for
+    /// `-merge`, the one named by `merge=<driver>`, or `None` for the default.
+    pub fn merge_driver(&mut self, path: &str) -> Result<Option<String>> {
+        Ok(match self.value(path, "merge")? {
+            AttrState::Set => Some("text".to_string()),
+            AttrState::Unset => Some("binary".to_string()),
+            AttrState::Value(driver) => Some(driver),
+            AttrState::Unspecified => None,
+        })
+    }
+
+    /// Whether line endings of `path`, with the given content, are
+    /// normalized, from the `text` and `eol` attributes.
+    fn is_text(&mut self, path: &str, content: &[u8]) -> Result<bool> {
+        Ok(match self.value(path, "text")? {
+            AttrState::Set => true,
+            AttrState::Value(value) if value == "auto" => !content.contains(&0),
+            AttrState::Unspecified => self.value(path, "eol")? != AttrState::Unspecified,
+            _ => false,
+        })
+    }
+}
+
+/// Run the filter command `cmd` for `path` on `content`.
+fn run_filter(cmd: &str, path: &str, content: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd.replace("%f", &format!("'{}'", path.replace('\'', "'\\''"))))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("could not run filter '{}': {}", cmd, e))?;
+
+    // write from another thread so a filter producing output early cannot
+    // block on a full pipe
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = content.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+
+    let output = child.wait_with_output()?;
+    writer
+        .join()
+        .map_err(|_| anyhow!("could not write to filter '{}'", cmd))??;
+    if !output.status.success() {
+        return Err(anyhow!("filter '{}' failed for {}", cmd, path));
+    }
+
+    Ok(output.stdout)
+}
+
+impl Repository {
+    /// Load the attributes of the worktree.
+    pub fn attributes(&self) -> Result<Attributes> {
+        let mut names = Vec::new();
+        let mut macros = HashMap::new();
+        parse_attr_file(
+            "[attr]binary -diff -merge -text",
+            "",
+            &mut names,
+            Some(&mut macros),
+        );
+
+        let global = match self.config()?.get_path("core.attributesfile")? {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => parse_attr_file(&content, "", &mut names, Some(&mut macros)),
+                Err(_) => Vec::new(),
+            },
+            None => Vec::new(),
+        };
+
+        // macros may be defined in the top-level .gitattributes
+        let mut directories = HashMap::new();
+        let rules = match fs::read_to_string(self.path.join(".gitattributes")) {
+            Ok(content) => parse_attr_file(&content, "", &mut names, Some(&mut macros)),
+            Err(_) => Vec::new(),
+        };
+        directories.insert(String::new(), rules);
+
+        let info = match fs::read_to_string(self.git_dir.join("info").join("attributes")) {
+            Ok(content) => parse_attr_file(&content, "", &mut names, Some(&mut macros)),
+            Err(_) => Vec::new(),
+        };
+
+        Ok(Attributes {
+            root: self.path.clone(),
+            directories,
+            global,
+            info,
+            macros,
+            names,
+        })
+    }
+
+    /// Print the attributes of each path, either `attrs` or every attribute
+    /// which is specified with `all`.
+    pub fn check_attr(&self, attrs: &[String], paths: &[String], all: bool) -> Result<()> {
+        let mut attributes = self.attributes()?;
+
+        for path in paths {
+            let relative = path.trim_start_matches("./");
+            let states = attributes.get(relative)?;
+
+            if all {
+                for (name, state) in states.iter().filter(|(_, s)| *s != AttrState::Unspecified) {
+                    println!("{}: {}: {}", path, name, state);
+                }
+                continue;
+            }
+
+            for name in attrs {
+                let state = states
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, s)| s.clone())
+                    .unwrap_or(AttrState::Unspecified);
+                println!("{}: {}: {}", path, name, state);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `convert_to_git` may change the content of `path`: it has a
+    /// clean filter, or is text whose line endings may be normalized.
+    pub fn converts_to_git(&self, attributes: &mut Attributes, path: &str) -> Result<bool> {
+        if let AttrState::Value(driver) = attributes.value(path, "filter")? {
+            if self
+                .config()?
+                .get(&format!("filter.{}.clean", driver))
+                .is_some()
+            {
+                return Ok(true);
+            }
+        }
+
+        Ok(match attributes.value(path, "text")? {
+            AttrState::Set => true,
+            AttrState::Value(value) => value == "auto",
+            AttrState::Unspecified => attributes.value(path, "eol")? != AttrState::Unspecified,
+            AttrState::Unset => false,
+        })
+    }
+
+    /// Convert worktree content of `path` to what is stored in a blob: run
+    /// the `filter` driver's clean command, then turn CRLF into LF for text.
+    pub fn convert_to_git(
+        &self,
+        attributes: &mut Attributes,
+        path: &str,
+        content: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut content = content;
+
+        if let AttrState::Value(driver) = attributes.value(path, "filter")? {
+            if let Some(cmd) = self.config()?.get(&format!("filter.{}.clean", driver)) {
+                content = run_filter(&cmd, path, &content)?;
+            }
+        }
+
+        if attributes.is_text(path, &content)? && content.contains(&b'\r') {
+            let mut converted = Vec::with_capacity(content.len());
+            for (i, &b) in content.iter().enumerate() {
+                if !(b == b'\r' && content.get(i + 1) == Some(&b'\n')) {
+                    converted.push(b);
+                }
+            }
+            content = converted;
+        }
+
+        Ok(content)
+    }
+
+    /// Convert the content of the blob of `path` for the worktree: turn LF
+    /// into CRLF for `eol=crlf` text, then run the `filter` driver's smudge
+    /// command.
+    pub fn convert_to_worktree(
+        &self,
+        attributes: &mut Attributes,
+        path: &str,
+        content: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut content = content;
+
+        let crlf = attributes.value(path, "eol")? == AttrState::Value("crlf".to_string());
+        if crlf && attributes.is_text(path, &content)? {
+            let mut converted = Vec::with_capacity(content.len());
+            for (i, &b) in content.iter().enumerate() {
+                if b == b'\n' && (i == 0 || content[i - 1] != b'\r') {
+                    converted.push(b'\r');
+                }
+                converted.push(b);
+            }
+            content = converted;
+        }
+
+        if let AttrState::Value(driver) = attributes.value(path, "filter")? {
+            if let Some(cmd) = self.config()?.get(&format!("filter.{}.smudge", driver)) {
+                content = run_filter(&cmd, path, &content)?;
+            }
+        }
+
+        Ok(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribute_precedence() {
+        let mut names = Vec::new();
+        let mut macros = HashMap::new();
+        let content = "[attr]binary -diff -text\n*.txt text eol=lf\n*.png binary\n";
+        let root = parse_attr_file(content, "", &mut names, Some(&mut macros));
+        let sub = parse_attr_file("*.txt !eol\n*.png diff\n", "sub/", &mut names, None);
+
+        let mut directories = HashMap::new();
+        directories.insert(String::new(), root);
+        directories.insert("sub/".to_string(), sub);
+        let mut attributes = Attributes {
+            root: PathBuf::new(),
+            directories,
+            global: Vec::new(),
+            info: Vec::new(),
+            macros,
+            names,
+        };
+
+        assert_eq!(
+            attributes.value("a/b.txt", "eol").unwrap(),
+            AttrState::Value("lf".to_string())
+        );
+        assert_eq!(
+            attributes.value("sub/b.txt", "eol").unwrap(),
+            AttrState::Unspecified
+        );
+        assert_eq!(
+            attributes.value("sub/b.txt", "text").unwrap(),
+            AttrState::Set
+        );
+        assert_eq!(attributes.value("x.png", "diff").unwrap(), AttrState::Unset);
+        assert_eq!(attributes.value("x.png", "binary").unwrap(), AttrState::Set);
+        assert_eq!(
+            attributes.value("sub/x.png", "diff").unwrap(),
+            AttrState::Set
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{kind::Kind, mmap::Mmap, pack_index::PackIndex, repository::Repository};
+
+/// A set of positions in a pack, one bit per object.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    pub fn get(&self, position: u32) -> bool {
+        let position = position as usize;
+        self.words
+            .get(position / 64)
+            .is_some_and(|word| word & (1 << (position % 64)) != 0)
+    }
+
+    pub fn set(&mut self, position: u32) {
+        let position = position as usize;
+        if self.words.len() <= position / 64 {
+            self.words.resize(position / 64 + 1, 0);
+        }
+        self.words[position / 64] |= 1 << (position % 64);
+    }
+
+    pub fn or(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    pub fn and(&mut self, other: &Bitmap) {
+        self.words.truncate(other.words.len());
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+
+    pub fn and_not(&mut self, other: &Bitmap) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= !other;
+        }
+    }
+
+    fn xor(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word ^= other;
+        }
+    }
+
+    /// Append the bitmap EWAH-compressed, as `read_ewah` reads it: runs of
+    /// empty words, each followed by the literal words after it, which is
+    /// how git encodes a bitmap built bit by bit.
+    pub fn write_ewah(&self, out: &mut Vec<u8>) {
+        let used = self
+            .words
+            .iter()
+            .rposition(|&word| word != 0)
+            .map_or(0, |i| i + 1);
+        let bit_size = match used {
+            0 => 0,
+            used => used * 64 - self.words[used - 1].leading_zeros() as usize,
+        };
+
+        // each run-length word counts empty words then literal ones
+        let mut compressed = vec![0u64];
+        let mut marker = 0;
+        for &word in &self.words[..used] {
+            if word == 0 {
+                if compressed[marker] >> 33 != 0 {
+                    marker = compressed.len();
+                    compressed.push(0);
+                }
+                compressed[marker] += 1 << 1;
+            } else {
+                compressed[marker] += 1 << 33;
+                compressed.push(word);
+            }
+        }
+
+        out.extend_from_slice(&(bit_size as u32).to_be_bytes());
+        out.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+        for word in compressed {
+            out.extend_from_slice(&word.to_be_bytes());
+        }
+        out.extend_from_slice(&(marker as u32).to_be_bytes());
+    }
+
+    /// The positions set, in increasing order.
+    pub fn positions(&self) -> impl Iterator<Item = u32> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| (i * 64 + bit) as u32)
+        })
+    }
+}
+
+/// Read the big-endian `N` bytes at `*pos` of `data`, moving past them.
+fn read_be<const N: usize>(data: &[u8], pos: &mut usize) -> Result<[u8; N]> {
+    let bytes = data
+        .get(*pos..*pos + N)
+        .ok_or_else(|| anyhow!("bitmap file is truncated"))?;
+    *pos += N;
+    Ok(bytes.try_into().expect("N bytes"))
+}
+
+/// Decode the EWAH-compressed bitmap at `*pos` of `data`: its size in bits,
+/// its number of words, then the words themselves, each run-length word
+/// giving a run of words all 0 or all 1 followed by a count of literal
+/// words, and last the position of the final run-length word.
+pub fn read_ewah(data: &[u8], pos: &mut usize) -> Result<Bitmap> {
+    let bit_size = u32::from_be_bytes(read_be(data, pos)?) as usize;
+    let word_count = u32::from_be_bytes(read_be(data, pos)?) as usize;
+    let mut compressed = Vec::with_capacity(word_count);
+    for _ in 0..word_count {
+        compressed.push(u64::from_be_bytes(read_be(data, pos)?));
+    }
+    read_be::<4>(data, pos)?;
+
+    let mut words = Vec::with_capacity(bit_size.div_ceil(64));
+    let mut i = 0;
+    while i < compressed.len() {
+        let marker = compressed[i];
+        let running = if marker & 1 != 0 { u64::MAX } else { 0 };
+        let run_length = ((marker >> 1) & 0xffff_ffff) as usize;
+        let literals = (marker >> 33) as usize;
+        let literal_words = compressed
+            .get(i + 1..i + 1 + literals)
+            .ok_or_else(|| anyhow!("bitmap runs past its words"))?;
+        if words.len() + run_length + literals > bit_size.div_ceil(64) {
+            return Err(anyhow!("bitmap is larger than its size"));
+        }
+        words.resize(words.len() + run_length, running);
+        words.extend_from_slice(literal_words);
+        i += 1 + literals;
+    }
+
+    Ok(Bitmap { words })
+}
+
+/// The reachability bitmaps of a pack, from its `.bitmap` file: for some of
+/// its commits, every object of the pack they reach, and the objects of each
+/// type. A bit stands for the object at that position in pack order.
+pub struct PackBitmap {
+    /// The objects of the pack, in pack order
+    objects: Vec<[u8; 20]>,
+    positions: HashMap<[u8; 20], u32>,
+    commits: HashMap<[u8; 20], Bitmap>,
+    /// The commits, trees, blobs and tags
+    types: [Bitmap; 4],
+}
+
+impl PackBitmap {
+    /// Read the `.bitmap` file of the pack `path`. The bitmaps stored as the
+    /// difference with an earlier one are resolved.
+    pub fn open(path: &Path) -> Result<Self> {
+        let index = PackIndex::open(&path.with_extension("idx"))?;
+        let by_id = index.entries()?;
+        let mut by_offset: Vec<(u64, [u8; 20])> = by_id
+            .iter()
+            .map(|(hash, offset)| (*offset, *hash))
+            .collect();
+        by_offset.sort_unstable();
+        let objects: Vec<[u8; 20]> = by_offset.into_iter().map(|(_, hash)| hash).collect();
+        let positions = objects
+            .iter()
+            .enumerate()
+            .map(|(position, hash)| (*hash, position as u32))
+            .collect();
+
+        let bitmap_path = path.with_extension("bitmap");
+        let data = Mmap::open(&bitmap_path)?;
+        let mut pos = 0;
+        let header: [u8; 32] = read_be(&data, &mut pos)?;
+        if header[..4] != *b"BITM" || header[4..6] != 1u16.to_be_bytes() {
+            return Err(anyhow!(
+                "{} is not a version 1 bitmap",
+                bitmap_path.display()
+            ));
+        }
+        if header[12..] != index.pack_checksum()? {
+            return Err(anyhow!("{} is for another pack", bitmap_path.display()));
+        }
+        let count = u32::from_be_bytes(header[8..12].try_into().expect("4 bytes"));
+
+        let types = [
+            read_ewah(&data, &mut pos)?,
+            read_ewah(&data, &mut pos)?,
+            read_ewah(&data, &mut pos)?,
+            read_ewah(&data, &mut pos)?,
+        ];
+
+        let mut resolved: Vec<([u8; 20], Bitmap)> = Vec::with_capacity(count as usize);
+        for n in 0..count as usize {
+            let id_position = u32::from_be_bytes(read_be(&data, &mut pos)?);
+            let [xor_offset, _flags] = read_be(&data, &mut pos)?;
+            let mut bitmap = read_ewah(&data, &mut pos)?;
+            if xor_offset != 0 {
+                let base = n
+                    .checked_sub(xor_offset as usize)
+                    .ok_or_else(|| anyhow!("bitmap {} is based on a missing one", n))?;
+                bitmap.xor(&resolved[base].1);
+            }
+            let (hash, _) = by_id
+                .get(id_position as usize)
+                .ok_or_else(|| anyhow!("bitmap of a commit not in the pack"))?;
+            resolved.push((*hash, bitmap));
+        }
+
+        Ok(PackBitmap {
+            objects,
+            positions,
+            commits: resolved.into_iter().collect(),
+            types,
+        })
+    }
+
+    /// The objects reachable from `roots`: the bitmaps of the commits which
+    /// have one, and what is walked from the others. Returns None when the
+    /// walk leaves the pack, for objects the bitmap cannot stand for.
+    pub fn reachable(&self, repo: &Repository, roots: &[[u8; 20]]) -> Result<Option<Bitmap>> {
+        let mut reachable = Bitmap::default();
+        let mut stack = roots.to_vec();
+        while let Some(hash) = stack.pop() {
+            let Some(&position) = self.positions.get(&hash) else {
+                return Ok(None);
+            };
+            // what a bitmap holds is closed under reachability
+            if reachable.get(position) {
+                continue;
+            }
+            if let Some(bitmap) = self.commits.get(&hash) {
+                reachable.or(bitmap);
+                continue;
+            }
+            reachable.set(position);
+
+            if self.types[0].get(position) {
+                let commit = repo.read_commit(&hash)?;
+                stack.push(commit.tree);
+                stack.extend(commit.parents);
+            } else if self.types[1].get(position) {
+                for entry in repo.read_tree(&hash)? {
+                    let entry = entry?;
+                    // submodule commits live in another repository
+                    if !matches!(entry.kind, Kind::Commit) {
+                        stack.push(entry.hash);
+                    }
+                }
+            } else if self.types[3].get(position) {
+                stack.extend(repo.peel_tag(&hash)?);
+            }
+        }
+
+        Ok(Some(reachable))
+    }
+
+    /// The objects of `kind` in `bitmap`, in pack order.
+    pub fn objects(&self, bitmap: &Bitmap, kind: &Kind) -> Vec<[u8; 20]> {
+        let slot = match kind {
+            Kind::Commit => 0,
+            Kind::Tree => 1,
+            Kind::Blob(_) | Kind::Symlink => 2,
+            Kind::Tag => 3,
+        };
+        let mut of_kind = bitmap.clone();
+        of_kind.and(&self.types[slot]);
+        of_kind
+            .positions()
+            .map(|position| self.objects[position as usize])
+            .collect()
+    }
+
+    /// Every object of `bitmap`: commits, trees, blobs then tags.
+    pub fn all_objects(&self, bitmap: &Bitmap) -> Vec<[u8; 20]> {
+        [Kind::Commit, Kind::Tree, Kind::Blob(false), Kind::Tag]
+            .iter()
+            .flat_map(|kind| self.objects(bitmap, kind))
+            .collect()
+    }
+}
+
+impl Repository {
+    /// The pack with a `.bitmap` file, if any. Bitmaps record the history
+    /// as stored, so they are not used when grafts, replacements or a
+    /// shallow boundary change it.
+    fn bitmapped_pack(&self) -> Result<Option<PathBuf>> {
+        if !self.shallow_commits()?.is_empty() || self.has_replacements()? {
+            return Ok(None);
+        }
+
+        Ok(self
+            .pack_paths()?
+            .into_iter()
+            .find(|pack| pack.with_extension("bitmap").is_file()))
+    }
+
+    /// The objects reachable from `include` but not from `exclude`, found
+    /// through the bitmap of a pack, with that bitmap to list them. Returns
+    /// None without a bitmap, or when it does not cover them all.
+    pub fn bitmap_reachable(
+        &self,
+        include: &[[u8; 20]],
+        exclude: &[[u8; 20]],
+    ) -> Result<Option<(PackBitmap, Bitmap)>> {
+        let Some(pack) = self.bitmapped_pack()? else {
+            return Ok(None);
+        };
+        let bitmap = PackBitmap::open(&pack)?;
+
+        let Some(mut reachable) = bitmap.reachable(self, include)? else {
+            return Ok(None);
+        };
+        if !exclude.is_empty() {
+            let Some(excluded) = bitmap.reachable(self, exclude)? else {
+                return Ok(None);
+            };
+            reachable.and_not(&excluded);
+        }
+
+        Ok(Some((bitmap, reachable)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_ewah() {
+        // a run of two words of ones followed by one literal word, then
+        // the position of that run-length word
+        let marker: u64 = 1 | (2 << 1) | (1 << 33);
+        let mut data = Vec::new();
+        data.extend_from_slice(&130u32.to_be_bytes());
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(&marker.to_be_bytes());
+        data.extend_from_slice(&0b11u64.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+
+        let mut pos = 0;
+        let bitmap = read_ewah(&data, &mut pos).unwrap();
+        assert_eq!(pos, data.len());
+        assert_eq!(bitmap.positions().count(), 130);
+        assert!(bitmap.get(129) && !bitmap.get(130));
+
+        let mut other = Bitmap::default();
+        other.set(3);
+        other.set(200);
+        let mut difference = bitmap.clone();
+        difference.and_not(&other);
+        assert!(!difference.get(3) && difference.get(4));
+        other.and(&bitmap);
+        assert_eq!(other.positions().collect::<Vec<_>>(), [3]);
+
+        let mut sparse = Bitmap::default();
+        for position in [1, 70, 300, 301] {
+            sparse.set(position);
+        }
+        let mut written = Vec::new();
+        sparse.write_ewah(&mut written);
+        let mut pos = 0;
+        assert_eq!(read_ewah(&written, &mut pos).unwrap(), sparse);
+        assert_eq!(u32::from_be_bytes(written[..4].try_into().unwrap()), 302);
+    }
+}
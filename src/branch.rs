@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Result};
+
+use crate::{refs::short_ref_name, repository::Repository};
+
+/// A branch as `list_branches` lists it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchListing {
+    /// The name as `mg branch` shows it: remote-tracking branches listed
+    /// along with the local ones are qualified with `remotes/`
+    pub name: String,
+    pub current: bool,
+    pub remote: bool,
+    /// The branch a symbolic ref such as `origin/HEAD` points to
+    pub target: Option<String>,
+}
+
+impl Repository {
+    /// The local branches, or the remote-tracking ones with `remotes`, or
+    /// both with `all`, sorted by name.
+    pub fn list_branches(&self, remotes: bool, all: bool) -> Result<Vec<BranchListing>> {
+        let mut branches = Vec::new();
+        if !remotes || all {
+            let current_branch = self.current_branch()?;
+
+            for (name, _) in self.list_refs("refs/heads/")? {
+                let name = name.trim_start_matches("refs/heads/");
+                branches.push(BranchListing {
+                    name: name.to_string(),
+                    current: name == current_branch,
+                    remote: false,
+                    target: None,
+                });
+            }
+        }
+
+        if remotes || all {
+            for (name, _) in self.list_refs("refs/remotes/")? {
+                // listed along with the local branches, they are qualified
+                let shown = if all {
+                    name.trim_start_matches("refs/")
+                } else {
+                    short_ref_name(&name)
+                };
+                let target = self.read_symbolic_ref(&name)?;
+                branches.push(BranchListing {
+                    name: shown.to_string(),
+                    current: false,
+                    remote: true,
+                    target: target
+                        .as_deref()
+                        .map(|target| short_ref_name(target).to_string()),
+                });
+            }
+        }
+
+        Ok(branches)
+    }
+
+    pub fn create_branch(&self, name: &str, start_point: Option<&str>) -> Result<()> {
+        let branch_ref = format!("refs/heads/{}", name);
+        if self.read_ref(&branch_ref)?.is_some() {
+            return Err(anyhow!("a branch named '{}' already exists", name));
+        }
+
+        let commit = self.resolve_revision(start_point.unwrap_or("HEAD"))?;
+        self.update_ref(&branch_ref, &commit)
+    }
+
+    /// The commit the branch's upstream points to, if it is configured and
+    /// known locally.
+    fn branch_upstream(&self, name: &str) -> Result<Option<[u8; 20]>> {
+        match self.upstream_ref(name)? {
+            Some(upstream_ref) => self.read_ref(&upstream_ref),
+            None => Ok(None),
+        }
+    }
+
+    /// The ref of the branch's upstream, as `branch.<name>.remote` and
+    /// `branch.<name>.merge` configure it, whether it exists or not.
+    pub fn upstream_ref(&self, name: &str) -> Result<Option<String>> {
+        let config = self.config()?;
+        let remote = config.get(&format!("branch.{}.remote", name));
+        let merge = config.get(&format!("branch.{}.merge", name));
+
+        let (Some(remote), Some(merge)) = (remote, merge) else {
+            return Ok(None);
+        };
+
+        Ok(Some(match remote.as_str() {
+            "." => merge,
+            _ => format!(
+                "refs/remotes/{}/{}",
+                remote,
+                merge.trim_start_matches("refs/heads/")
+            ),
+        }))
+    }
+
+    /// Delete a branch. Unless `force` is set, the branch must be merged into
+    /// its upstream or, without one, into HEAD.
+    pub fn delete_branch(&self, name: &str, force: bool, override_protection: bool) -> Result<()> {
+        let branch_ref = format!("refs/heads/{}", name);
+        let tip = self
+            .read_ref(&branch_ref)?
+            .ok_or_else(|| anyhow!("branch '{}' not found", name))?;
+        self.check_protection(name, "delete", override_protection)?;
+
+        if self.read_head()?.trim() == format!("ref: {}", branch_ref) {
+            return Err(anyhow!(
+                "cannot delete branch '{}' as it is currently checked out",
+                name
+            ));
+        }
+
+        if !force {
+            let target = match self.branch_upstream(name)? {
+                Some(upstream) => upstream,
+                None => self.current_commit()?,
+            };
+
+            if !self.is_ancestor(&tip, &target)? {
+                return Err(anyhow!(
+                    "the branch '{}' is not fully merged, run `mg branch -D {}` to delete it anyway",
+                    name,
+                    name
+                ));
+            }
+        }
+
+        self.delete_ref(&branch_ref)?;
+
+        // the tip is printed in full so the commit can be recovered later
+        println!("Deleted branch {} (was {}).", name, hex::encode(tip));
+
+        Ok(())
+    }
+
+    /// Refuse to `operation` the branch `name` when `branch.<name>.protect`
+    /// is set, unless `override_protection`.
+    pub fn check_protection(
+        &self,
+        name: &str,
+        operation: &str,
+        override_protection: bool,
+    ) -> Result<()> {
+        let key = format!("branch.{}.protect", name);
+        if override_protection || self.config()?.get_bool(&key) != Some(true) {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "refusing to {} the protected branch '{}' ({}), \
+             use --override-protection to do it anyway",
+            operation,
+            name,
+            key
+        ))
+    }
+
+    /// `check_protection` for the checked out branch, if HEAD is not
+    /// detached.
+    pub fn check_current_protection(
+        &self,
+        operation: &str,
+        override_protection: bool,
+    ) -> Result<()> {
+        match self.read_head()?.trim().strip_prefix("ref: refs/heads/") {
+            Some(name) => self.check_protection(name, operation, override_protection),
+            None => Ok(()),
+        }
+    }
+}
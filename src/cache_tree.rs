@@ -0,0 +1,232 @@
+use anyhow::{anyhow, Context, Result};
+
+use crate::{index::IndexEntry, kind::Kind, repository::Repository};
+
+/// The `TREE` index extension: the tree id of each directory of the index,
+/// valid as long as no entry below the directory changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheTree {
+    /// path component of the directory, empty for the root
+    pub name: String,
+    /// number of index entries below the directory, -1 once invalidated
+    pub entry_count: i32,
+    pub hash: [u8; 20],
+    pub children: Vec<CacheTree>,
+}
+
+impl CacheTree {
+    /// An invalid root, for an index without the extension.
+    pub fn new() -> Self {
+        CacheTree {
+            entry_count: -1,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.entry_count >= 0
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let (tree, rest) = Self::parse_node(data)?;
+        if !rest.is_empty() {
+            return Err(anyhow!("trailing data in cache tree"));
+        }
+        Ok(tree)
+    }
+
+    fn parse_node(data: &[u8]) -> Result<(Self, &[u8])> {
+        let invalid = || anyhow!("invalid cache tree");
+
+        // <name> NUL <entry count> SP <subtree count> LF [<hash>]
+        let nul = data.iter().position(|&b| b == 0).ok_or_else(invalid)?;
+        let name = String::from_utf8(data[..nul].to_vec())?;
+        let data = &data[nul + 1..];
+
+        let lf = data.iter().position(|&b| b == b'\n').ok_or_else(invalid)?;
+        let counts = std::str::from_utf8(&data[..lf])?;
+        let (entry_count, subtree_count) = counts.split_once(' ').ok_or_else(invalid)?;
+        let entry_count: i32 = entry_count.parse()?;
+        let subtree_count: usize = subtree_count.parse()?;
+        let mut data = &data[lf + 1..];
+
+        let mut hash = [0; 20];
+        if entry_count >= 0 {
+            hash.copy_from_slice(data.get(..20).ok_or_else(invalid)?);
+            data = &data[20..];
+        }
+
+        let mut children = Vec::with_capacity(subtree_count);
+        for _ in 0..subtree_count {
+            let (child, rest) = Self::parse_node(data)?;
+            children.push(child);
+            data = rest;
+        }
+
+        Ok((
+            CacheTree {
+                name,
+                entry_count,
+                hash,
+                children,
+            },
+            data,
+        ))
+    }
+
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.name.as_bytes());
+        out.push(0);
+        out.extend_from_slice(format!("{} {}\n", self.entry_count, self.children.len()).as_bytes());
+        if self.is_valid() {
+            out.extend_from_slice(&self.hash);
+        }
+        for child in &self.children {
+            child.serialize(out);
+        }
+    }
+
+    /// Invalidate every directory containing `path`.
+    pub fn invalidate(&mut self, path: &str) {
+        self.entry_count = -1;
+        self.hash = [0; 20];
+
+        if let Some((dir, rest)) = path.split_once('/') {
+            if let Some(child) = self.children.iter_mut().find(|c| c.name == dir) {
+                child.invalidate(rest);
+            }
+        }
+    }
+
+    fn child_mut(&mut self, name: &str) -> &mut CacheTree {
+        let position = match self.children.iter().position(|c| c.name == name) {
+            Some(position) => position,
+            None => {
+                self.children.push(CacheTree {
+                    name: name.to_string(),
+                    ..CacheTree::new()
+                });
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[position]
+    }
+}
+
+impl Repository {
+    /// Write the trees of the index, reusing the cached ids of the
+    /// directories which did not change, and store the updated cache tree
+    /// in the index. Returns the root tree id.
+    pub fn write_tree_from_index(&self) -> Result<[u8; 20]> {
+        let mut index = self.load_index()?;
+        if index.entries.iter().any(|e| e.stage() > 0) {
+            return Err(anyhow!("the index has unmerged entries"));
+        }
+
+        let mut cache_tree = index.cache_tree.take().unwrap_or_else(CacheTree::new);
+        let hash = self.write_cached_tree(&index.entries, "", &mut cache_tree)?;
+        index.cache_tree = Some(cache_tree);
+        index.write_to_file(&self.index_path())?;
+
+        Ok(hash)
+    }
+
+    /// Write the tree of the directory `prefix` (empty or ending with `/`),
+    /// whose entries are `entries`.
+    fn write_cached_tree(
+        &self,
+        entries: &[IndexEntry],
+        prefix: &str,
+        cache: &mut CacheTree,
+    ) -> Result<[u8; 20]> {
+        if cache.is_valid() && cache.entry_count as usize == entries.len() {
+            return Ok(cache.hash);
+        }
+
+        let mut out = Vec::new();
+        let mut seen_children = Vec::new();
+        let mut i = 0;
+
+        while i < entries.len() {
+            let rest = &entries[i].file_path[prefix.len()..];
+
+            let (mode, name, hash) = match rest.split_once('/') {
+                None => {
+                    let entry = &entries[i];
+                    i += 1;
+                    (entry.kind().to_mode().to_string(), rest, entry.sha1)
+                }
+                Some((dir, _)) => {
+                    let dir_prefix = format!("{}{}/", prefix, dir);
+                    let child = cache.child_mut(dir);
+
+                    // a valid subtree tells how many entries it spans
+                    let in_dir = |e: &IndexEntry| e.file_path.starts_with(&dir_prefix);
+                    let cached_end = i + child.entry_count.max(0) as usize;
+                    let end = if child.entry_count > 0
+                        && cached_end <= entries.len()
+                        && in_dir(&entries[cached_end - 1])
+                        && entries.get(cached_end).is_none_or(|e| !in_dir(e))
+                    {
+                        cached_end
+                    } else {
+                        i + entries[i..].iter().take_while(|e| in_dir(e)).count()
+                    };
+
+                    let hash = self
+                        .write_cached_tree(&entries[i..end], &dir_prefix, child)
+                        .with_context(|| format!("could not write tree {}", dir_prefix))?;
+                    seen_children.push(dir.to_string());
+                    i = end;
+                    (Kind::Tree.to_mode().to_string(), dir, hash)
+                }
+            };
+
+            out.extend_from_slice(mode.as_bytes());
+            out.push(b' ');
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+            out.extend_from_slice(&hash);
+        }
+
+        // directories which no longer have entries
+        cache.children.retain(|c| seen_children.contains(&c.name));
+
+        cache.hash = self.write_object(Kind::Tree, &out)?;
+        cache.entry_count = entries.len() as i32;
+
+        Ok(cache.hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_tree_roundtrip() {
+        let mut tree = CacheTree {
+            name: String::new(),
+            entry_count: 3,
+            hash: [1; 20],
+            children: vec![CacheTree {
+                name: "src".to_string(),
+                entry_count: 2,
+                hash: [2; 20],
+                children: Vec::new(),
+            }],
+        };
+
+        let mut data = Vec::new();
+        tree.serialize(&mut data);
+        assert_eq!(CacheTree::parse(&data).unwrap(), tree);
+
+        tree.invalidate("src/main.rs");
+        assert!(!tree.is_valid());
+        assert!(!tree.children[0].is_valid());
+
+        let mut data = Vec::new();
+        tree.serialize(&mut data);
+        assert_eq!(CacheTree::parse(&data).unwrap(), tree);
+    }
+}
@@ -0,0 +1,55 @@
+use std::io::{BufRead, Write};
+
+use anyhow::Result;
+
+use crate::{object::Object, repository::Repository};
+
+impl Repository {
+    /// The object the revision `object` names.
+    pub fn cat_file(&self, object: &str) -> Result<Object<impl BufRead>> {
+        let hash = self.resolve_revision(object)?;
+        self.read_object(&hex::encode(hash))
+    }
+
+    /// Read object names from `input`, one per line, and write
+    /// `<oid> <type> <size>` for each to `out`, followed by the raw content
+    /// and a newline with `contents` (`--batch`, else `--batch-check`).
+    /// Unknown names are reported as `<name> missing`.
+    pub fn cat_file_batch<R: BufRead, W: Write>(
+        &self,
+        input: R,
+        mut out: W,
+        contents: bool,
+    ) -> Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            let name = line.trim();
+            if name.is_empty() {
+                continue;
+            }
+
+            let object = self
+                .resolve_revision(name)
+                .and_then(|hash| Ok((hash, self.read_object(&hex::encode(hash))?)));
+            let (hash, mut obj) = match object {
+                Ok(object) => object,
+                Err(_) => {
+                    writeln!(out, "{} missing", name)?;
+                    out.flush()?;
+                    continue;
+                }
+            };
+
+            writeln!(out, "{} {} {}", hex::encode(hash), obj.kind(), obj.size())?;
+            if contents {
+                obj.copy_content(&mut out)?;
+                writeln!(out)?;
+            }
+
+            // flush each object so scripts can drive the command interactively
+            out.flush()?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+use std::{collections::HashSet, fs, path::Path, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    attributes::Attributes, kind::Kind, pathspec::Pathspec, progress::Progress,
+    repository::Repository, trace2, tree::TreeFile,
+};
+
+/// How long a checkout runs before reporting its progress.
+const PROGRESS_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CheckoutAction {
+    Create,
+    Overwrite,
+}
+
+#[derive(Debug)]
+pub struct PlannedFile {
+    pub file: TreeFile,
+    pub size: u64,
+    pub action: CheckoutAction,
+    /// false for paths outside of the sparse-checkout patterns
+    pub materialize: bool,
+}
+
+#[derive(Debug)]
+pub struct CheckoutPlan {
+    pub files: Vec<PlannedFile>,
+    pub removed: Vec<String>,
+    pub total_bytes: u64,
+}
+
+impl Repository {
+    /// Compute what materializing `tree` in the worktree would do, without
+    /// touching it.
+    pub fn plan_checkout(&self, tree: &[u8; 20]) -> Result<CheckoutPlan> {
+        let mut files = Vec::new();
+        let mut total_bytes = 0;
+        let sparse = self.sparse_patterns()?;
+        let tree_files = self.flatten_tree(tree)?;
+
+        // download the blobs a partial clone is missing in one go
+        let needed: Vec<[u8; 20]> = tree_files
+            .iter()
+            .filter(|f| !matches!(f.kind, Kind::Commit))
+            .filter(|f| match &sparse {
+                Some(patterns) => patterns.includes(&f.path, false),
+                None => true,
+            })
+            .map(|f| f.hash)
+            .collect();
+        self.prefetch_objects(&needed)?;
+
+        for file in tree_files {
+            let materialize = match &sparse {
+                Some(patterns) => patterns.includes(&file.path, false),
+                None => true,
+            };
+            if !materialize {
+                files.push(PlannedFile {
+                    file,
+                    size: 0,
+                    action: CheckoutAction::Create,
+                    materialize,
+                });
+                continue;
+            }
+
+            let size = match file.kind {
+                Kind::Commit => 0,
+                _ => self.read_object(&hex::encode(file.hash))?.size() as u64,
+            };
+            let action = if self.path.join(&file.path).exists() {
+                CheckoutAction::Overwrite
+            } else {
+                CheckoutAction::Create
+            };
+
+            total_bytes += size;
+            files.push(PlannedFile {
+                file,
+                size,
+                action,
+                materialize,
+            });
+        }
+
+        // files tracked by the current commit which are not part of the target tree
+        let mut removed = Vec::new();
+        if let Some(current_commit) = self.head_commit()? {
+            let current_tree = self.commit_tree(&current_commit)?;
+            let target: HashSet<&str> = files
+                .iter()
+                .filter(|f| f.materialize)
+                .map(|f| f.file.path.as_str())
+                .collect();
+
+            for file in self.flatten_tree(&current_tree)? {
+                if !target.contains(file.path.as_str()) && self.path.join(&file.path).exists() {
+                    removed.push(file.path);
+                }
+            }
+        }
+
+        Ok(CheckoutPlan {
+            files,
+            removed,
+            total_bytes,
+        })
+    }
+
+    /// The paths of `plan` whose local changes overwriting or removing them
+    /// would lose: tracked files whose index or worktree differs from HEAD,
+    /// and untracked files in the way of those of the target tree.
+    pub fn checkout_conflicts(&self, plan: &CheckoutPlan) -> Result<Vec<String>> {
+        let (changes, untracked) = self.status_changes(&Pathspec::default())?;
+        let dirty: HashSet<&str> = changes.iter().map(|c| c.path.as_str()).collect();
+        // untracked directories are listed with a trailing slash
+        let is_untracked = |path: &str| {
+            untracked
+                .iter()
+                .any(|u| u == path || (u.ends_with('/') && path.starts_with(u.as_str())))
+        };
+
+        let mut conflicts: Vec<String> = plan
+            .files
+            .iter()
+            .filter(|f| f.materialize && f.action == CheckoutAction::Overwrite)
+            .map(|f| &f.file.path)
+            .chain(&plan.removed)
+            .filter(|path| dirty.contains(path.as_str()) || is_untracked(path))
+            .cloned()
+            .collect();
+        conflicts.sort();
+        conflicts.dedup();
+        Ok(conflicts)
+    }
+
+    pub fn checkout(&self, rev: &str, dry_run: bool) -> Result<()> {
+        let commit = self.resolve_revision(rev)?;
+        let tree = self.commit_tree(&commit)?;
+
+        self.materialize_tree(&tree, dry_run)?;
+
+        if !dry_run {
+            self.update_head_for_checkout(rev, &commit)?;
+        }
+
+        Ok(())
+    }
+
+    /// Restore the files `pathspec` matches from the index, or from the tree
+    /// of `rev`, in which case their index entries are updated too. Other
+    /// files are left alone, HEAD included.
+    pub fn checkout_paths(&self, rev: Option<&str>, pathspec: &Pathspec) -> Result<()> {
+        let files: Vec<TreeFile> = match rev {
+            Some(rev) => {
+                let tree = self.commit_tree(&self.resolve_revision(rev)?)?;
+                let mut files = self.flatten_tree(&tree)?;
+                files.retain(|file| pathspec.matches(&file.path));
+                files
+            }
+            None => {
+                let mut files = Vec::new();
+                for entry in self.load_index()?.entries {
+                    if !pathspec.matches(&entry.file_path) || entry.skip_worktree() {
+                        continue;
+                    }
+                    if entry.stage() != 0 {
+                        return Err(anyhow!("path '{}' is unmerged", entry.file_path));
+                    }
+                    files.push(TreeFile {
+                        kind: Kind::from_mode(&format!("{:o}", entry.mode))?,
+                        hash: entry.sha1,
+                        path: entry.file_path,
+                    });
+                }
+                files
+            }
+        };
+        let paths: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        if let Some(spec) = pathspec.unmatched(&paths) {
+            return Err(anyhow!("pathspec '{}' did not match any files", spec));
+        }
+
+        let needed: Vec<[u8; 20]> = files
+            .iter()
+            .filter(|file| !matches!(file.kind, Kind::Commit))
+            .map(|file| file.hash)
+            .collect();
+        self.prefetch_objects(&needed)?;
+        let mut attributes = self.checkout_attributes(&files)?;
+        for file in &files {
+            self.checkout_file(file, &mut attributes)
+                .with_context(|| format!("could not checkout {}", file.path))?;
+        }
+
+        if rev.is_some() {
+            let mut staged: Vec<String> = files
+                .into_iter()
+                .filter(|file| !matches!(file.kind, Kind::Commit))
+                .map(|file| file.path)
+                .collect();
+            staged.sort();
+            self.stage_files(&staged)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the content of `tree` to the worktree and the index.
+    pub fn materialize_tree(&self, tree: &[u8; 20], dry_run: bool) -> Result<()> {
+        let plan = {
+            let _region = trace2::region("checkout", "plan");
+            self.plan_checkout(tree)?
+        };
+
+        let conflicts = self.checkout_conflicts(&plan)?;
+        if !conflicts.is_empty() {
+            return Err(anyhow!(
+                "your local changes to the following files would be overwritten by checkout:\n\t{}\nplease commit your changes or stash them first",
+                conflicts.join("\n\t")
+            ));
+        }
+
+        let available = available_space(&self.path)?;
+
+        if dry_run {
+            for planned in plan.files.iter().filter(|f| f.materialize) {
+                let action = match planned.action {
+                    CheckoutAction::Create => "create",
+                    CheckoutAction::Overwrite => "overwrite",
+                };
+                println!("{} {} ({} bytes)", action, planned.file.path, planned.size);
+            }
+            for path in &plan.removed {
+                println!("remove {}", path);
+            }
+            print!(
+                "{} files, {} bytes to write",
+                plan.files.iter().filter(|f| f.materialize).count(),
+                plan.total_bytes
+            );
+            match available {
+                Some(available) => println!(", {} bytes available", available),
+                None => println!(),
+            }
+            return Ok(());
+        }
+
+        if let Some(available) = available {
+            if available < plan.total_bytes {
+                return Err(anyhow!(
+                    "not enough disk space: {} bytes needed, {} bytes available",
+                    plan.total_bytes,
+                    available
+                ));
+            }
+        }
+
+        let update_worktree = trace2::region("checkout", "update_worktree");
+        for path in &plan.removed {
+            self.remove_worktree_file(path)?;
+        }
+
+        let count = plan.files.iter().filter(|f| f.materialize).count();
+        // as git, only checkouts taking a while report their progress
+        let mut progress =
+            Progress::new("Updating files", Some(count as u64), self.show_progress())
+                .delayed(PROGRESS_DELAY);
+        let materialized = plan.files.iter().filter(|f| f.materialize);
+        let mut attributes = self.checkout_attributes(materialized.clone().map(|f| &f.file))?;
+        for planned in materialized {
+            self.checkout_file(&planned.file, &mut attributes)
+                .with_context(|| format!("could not checkout {}", planned.file.path))?;
+            progress.tick();
+        }
+        progress.finish();
+        drop(update_worktree);
+
+        let materialized: Vec<bool> = plan.files.iter().map(|f| f.materialize).collect();
+        let files: Vec<TreeFile> = plan.files.into_iter().map(|f| f.file).collect();
+        self.write_index_from_tree(&files, &materialized)?;
+
+        Ok(())
+    }
+
+    /// The attributes to check `files` out with, loaded once for all of
+    /// them. A top-level `.gitattributes` among them is checked out first,
+    /// so that it applies to the others.
+    pub fn checkout_attributes<'a>(
+        &self,
+        files: impl IntoIterator<Item = &'a TreeFile>,
+    ) -> Result<Attributes> {
+        let gitattributes = files
+            .into_iter()
+            .find(|file| file.path == ".gitattributes" && matches!(file.kind, Kind::Blob(_)));
+        if let Some(file) = gitattributes {
+            let content = self.read_object(&hex::encode(file.hash))?.content()?;
+            fs::write(self.path.join(&file.path), content)?;
+        }
+
+        self.attributes()
+    }
+
+    /// Remove `path` from the worktree, then the directories it leaves
+    /// empty.
+    pub fn remove_worktree_file(&self, path: &str) -> Result<()> {
+        let target = self.path.join(path);
+        fs::remove_file(&target)?;
+        for dir in target.ancestors().skip(1) {
+            // fails when the directory still holds something
+            if dir == self.path || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn checkout_file(&self, file: &TreeFile, attributes: &mut Attributes) -> Result<()> {
+        let target = self.path.join(&file.path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        match file.kind {
+            Kind::Commit => {
+                // submodules are checked out as empty directories
+                fs::create_dir_all(&target)?;
+            }
+            Kind::Symlink => {
+                let link = self.read_object(&hex::encode(file.hash))?.content()?;
+                if target.symlink_metadata().is_ok() {
+                    fs::remove_file(&target)?;
+                }
+                #[cfg(unix)]
+                std::os::unix::fs::symlink(String::from_utf8(link)?, &target)?;
+                // without symbolic links, the file holds the link's target
+                #[cfg(not(unix))]
+                fs::write(&target, link)?;
+            }
+            Kind::Blob(executable) => {
+                let content = self.read_object(&hex::encode(file.hash))?.content()?;
+                fs::write(
+                    &target,
+                    self.convert_to_worktree(attributes, &file.path, content)?,
+                )?;
+
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mode = if executable { 0o755 } else { 0o644 };
+                    fs::set_permissions(&target, fs::Permissions::from_mode(mode))?;
+                }
+                #[cfg(not(unix))]
+                let _ = executable;
+            }
+            Kind::Tree | Kind::Tag => unreachable!("trees are flattened"),
+        }
+
+        Ok(())
+    }
+
+    fn update_head_for_checkout(&self, rev: &str, commit: &[u8; 20]) -> Result<()> {
+        let branch_ref = format!("refs/heads/{}", rev);
+
+        if self.read_ref(&branch_ref)?.is_some() {
+            self.update_symbolic_ref("HEAD", &branch_ref)
+        } else {
+            self.update_ref("HEAD", commit)
+        }
+    }
+}
+
+/// Free space available to the current user on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
@@ -0,0 +1,132 @@
+use std::{
+    env,
+    io::{self, IsTerminal},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{config::parse_bool, repository::Repository};
+
+pub const RED: &str = "\x1b[31m";
+pub const GREEN: &str = "\x1b[32m";
+pub const YELLOW: &str = "\x1b[33m";
+pub const CYAN: &str = "\x1b[36m";
+pub const BOLD: &str = "\x1b[1m";
+pub const BOLD_RED: &str = "\x1b[1;31m";
+pub const BOLD_GREEN: &str = "\x1b[1;32m";
+pub const BOLD_YELLOW: &str = "\x1b[1;33m";
+pub const BOLD_CYAN: &str = "\x1b[1;36m";
+pub const RESET: &str = "\x1b[m";
+
+/// When output is colored, as `--color` and the `color.*` variables say.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorWhen {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorWhen {
+    /// Parse `auto`, `always` or `never`, or a boolean, true meaning auto
+    /// as in git's configuration.
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "auto" => Ok(ColorWhen::Auto),
+            "always" => Ok(ColorWhen::Always),
+            "never" => Ok(ColorWhen::Never),
+            other => match parse_bool(other) {
+                Some(true) => Ok(ColorWhen::Auto),
+                Some(false) => Ok(ColorWhen::Never),
+                None => Err(anyhow!("unknown color setting '{}'", value)),
+            },
+        }
+    }
+
+    fn enabled(self) -> bool {
+        match self {
+            ColorWhen::Always => true,
+            ColorWhen::Never => false,
+            ColorWhen::Auto => {
+                io::stdout().is_terminal() && env::var("TERM").map_or(true, |term| term != "dumb")
+            }
+        }
+    }
+}
+
+/// `text` in `color`, if there is one.
+pub fn paint(color: Option<&str>, text: &str) -> String {
+    match color {
+        Some(color) if !text.is_empty() => format!("{}{}{}", color, text, RESET),
+        _ => text.to_string(),
+    }
+}
+
+/// A rendered unified diff with git's colors: the headers of each file in
+/// bold, the hunk headers in cyan, and the lines removed and added in red
+/// and green.
+pub fn color_diff(diff: &str) -> String {
+    let mut out = String::with_capacity(diff.len());
+    let mut in_header = true;
+    for line in diff.split_inclusive('\n') {
+        let (text, newline) = match line.strip_suffix('\n') {
+            Some(text) => (text, "\n"),
+            None => (line, ""),
+        };
+        if text.starts_with("diff --git ") {
+            in_header = true;
+        } else if text.starts_with("@@") {
+            in_header = false;
+        }
+        let color = if in_header {
+            Some(BOLD)
+        } else if text.starts_with("@@") {
+            Some(CYAN)
+        } else if text.starts_with('-') {
+            Some(RED)
+        } else if text.starts_with('+') {
+            Some(GREEN)
+        } else {
+            None
+        };
+        out.push_str(&paint(color, text));
+        out.push_str(newline);
+    }
+    out
+}
+
+impl Repository {
+    /// Whether the output of `command` (`status`, `diff`, `branch` or
+    /// `log`) is colored: as `--color` says, else `color.<command>`, else
+    /// `color.ui`, coloring by default only what goes to a terminal.
+    pub fn use_color(&self, command: &str) -> Result<bool> {
+        if let Some(when) = self.color {
+            return Ok(when.enabled());
+        }
+        let config = self.config()?;
+        let value = config
+            .get(&format!("color.{}", command))
+            .or_else(|| config.get("color.ui"));
+        let when = match value {
+            Some(value) => ColorWhen::parse(&value)?,
+            None => ColorWhen::Auto,
+        };
+        Ok(when.enabled())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_diff() {
+        let diff = "diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -1 +1 @@\n-old\n+new\n same\n";
+        assert_eq!(
+            color_diff(diff),
+            "\x1b[1mdiff --git a/f b/f\x1b[m\n\x1b[1m--- a/f\x1b[m\n\x1b[1m+++ b/f\x1b[m\n\
+             \x1b[36m@@ -1 +1 @@\x1b[m\n\x1b[31m-old\x1b[m\n\x1b[32m+new\x1b[m\n same\n"
+        );
+        assert_eq!(ColorWhen::parse("true").unwrap(), ColorWhen::Auto);
+        assert!(ColorWhen::parse("sometimes").is_err());
+    }
+}
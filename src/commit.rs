@@ -1,13 +1,45 @@
-use std::fs::{read_to_string, File};
+use std::fs::read_to_string;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use hex::FromHex;
 
-use crate::{kind::Kind, repository::Repository};
+use crate::{
+    commit_message::cleanup_message,
+    diff::{DiffTarget, FileDiff},
+    ident::{redate, IdentityDate, Role},
+    kind::Kind,
+    repository::Repository,
+    signing::add_signature_header,
+    trace2,
+};
+
+/// How `commit` makes the commit.
+#[derive(Debug, Default, Clone)]
+pub struct CommitOptions {
+    /// Skip the `pre-commit` and `commit-msg` hooks
+    pub no_verify: bool,
+    /// Sign the commit, as `commit.gpgSign` does
+    pub sign: bool,
+    /// Replace the tip commit by one with its parents and author
+    pub amend: bool,
+    /// Stage the changes of the tracked files first
+    pub all: bool,
+    /// Make the commit even if it changes nothing
+    pub allow_empty: bool,
+    /// Without a message, keep the one of the amended commit
+    pub no_edit: bool,
+    /// The author date, over `GIT_AUTHOR_DATE`; `now` is the current time
+    pub date: Option<String>,
+    /// Record the dates in UTC, from a frozen clock unless given, so that
+    /// the same commands make the same commits on any machine
+    pub reproducible: bool,
+    /// Amend even the tip of a protected branch
+    pub override_protection: bool,
+}
 
 impl Repository {
     pub fn read_head(&self) -> Result<String> {
-        let head_path = self.path.join(".git").join("HEAD");
+        let head_path = self.git_dir.join("HEAD");
         read_to_string(head_path).context("reading head")
     }
 
@@ -19,90 +51,502 @@ impl Repository {
             .to_string())
     }
 
-    pub fn current_commit(&self) -> Result<[u8; 20]> {
-        let current_branch = self.current_branch()?;
-        let branch_path = self
-            .path
-            .join(".git")
-            .join("refs")
-            .join("heads")
-            .join(&current_branch);
-
-        let r = read_to_string(branch_path).context("could not read current branch")?;
-        let r = r.trim();
+    /// The commit HEAD points to, or `None` on an unborn branch. The branch
+    /// may be packed.
+    pub fn head_commit(&self) -> Result<Option<[u8; 20]>> {
+        let head = self.read_head()?;
+        match head.trim().strip_prefix("ref: ") {
+            Some(branch) => self
+                .read_ref(branch)
+                .with_context(|| format!("could not read {}", branch)),
+            // detached HEAD
+            None => Ok(Some(<[u8; 20]>::from_hex(head.trim())?)),
+        }
+    }
 
-        Ok(<[u8; 20]>::from_hex(r)?)
+    pub fn current_commit(&self) -> Result<[u8; 20]> {
+        self.head_commit()?.ok_or_else(|| {
+            anyhow!(
+                "current branch '{}' does not have any commits yet",
+                self.current_branch().unwrap_or_default()
+            )
+        })
     }
 
-    pub fn has_current_commit(&self) -> bool {
-        self.current_commit().is_ok()
+    /// Whether HEAD points to a commit, failing when its ref cannot be read
+    /// rather than taking the branch to be unborn.
+    pub fn has_current_commit(&self) -> Result<bool> {
+        Ok(self.head_commit()?.is_some())
     }
 
     pub fn set_current_commit(&self, hash: &[u8; 20]) -> Result<()> {
+        if !self.read_head()?.starts_with("ref: ") {
+            return self.update_ref("HEAD", hash);
+        }
+
         let current_branch = self
             .current_branch()
             .context("could not find current branch")?;
+        self.update_ref(&format!("refs/heads/{}", current_branch), hash)
+    }
 
-        let branch_path = self.path.join(".git").join("refs").join("heads");
+    /// The time of the frozen clock of a reproducible commit of `parents`:
+    /// `SOURCE_DATE_EPOCH` when set, else a second after the latest parent,
+    /// or the epoch for a root commit.
+    fn frozen_clock(&self, parents: &[[u8; 20]]) -> Result<i64> {
+        if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+            return epoch
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("invalid SOURCE_DATE_EPOCH '{}'", epoch));
+        }
 
-        if !branch_path.exists() {
-            std::fs::create_dir_all(&branch_path)?;
+        let mut latest = None;
+        for parent in parents {
+            latest = latest.max(Some(self.read_commit(parent)?.committer.timestamp));
         }
 
-        let branch_path = branch_path.join(&current_branch);
+        Ok(latest.map_or(0, |timestamp| timestamp + 1))
+    }
 
-        // file does not exist
-        if !branch_path.exists() {
-            File::create(&branch_path)?;
+    /// Commit the index with `message`, or one asked for in the editor when
+    /// there is none. The `pre-commit` and `commit-msg`
+    /// hooks can abort the commit, the latter also editing the message,
+    /// unless `no_verify` is set. A commit which changes nothing is refused
+    /// unless `allow_empty` is set.
+    pub fn commit(&self, message: Option<&str>, options: &CommitOptions) -> Result<[u8; 20]> {
+        let CommitOptions {
+            no_verify,
+            sign,
+            amend,
+            all,
+            allow_empty,
+            no_edit,
+            ref date,
+            reproducible,
+            override_protection,
+        } = *options;
+        if amend && !self.has_current_commit()? {
+            return Err(anyhow!("there is no commit to amend"));
+        }
+        if amend {
+            self.check_current_protection("amend the tip of", override_protection)?;
         }
 
-        std::fs::write(branch_path, hex::encode(hash))?;
+        if all {
+            self.add_tracked()?;
+        }
+        if !no_verify {
+            self.run_hook("pre-commit", &[], b"")?;
+        }
+        let message = match message {
+            Some(message) => cleanup_message(message, false),
+            None if amend && no_edit => self.commit_message(&self.current_commit()?)?,
+            None => self.edit_commit_message()?,
+        };
 
-        Ok(())
-    }
+        let message_path = self.git_dir.join("COMMIT_EDITMSG");
+        std::fs::write(&message_path, format!("{}\n", message))?;
+        let message = if no_verify {
+            message
+        } else {
+            self.run_hook("commit-msg", &[&message_path.to_string_lossy()], b"")?;
+            std::fs::read_to_string(&message_path)?
+                .trim_end()
+                .to_string()
+        };
 
-    pub fn commit(&self, message: &str) -> Result<[u8; 20]> {
-        let has_current_commit = self.has_current_commit();
+        let previous = self.head_commit()?;
         let mut out: Vec<u8> = Vec::new();
 
+        let write_tree = trace2::region("commit", "write_tree");
         let tree_hash = self
-            .write_tree(&self.path)
-            .context("could not write_tree")?;
+            .write_tree_from_index()
+            .context("could not write the tree of the index")?;
+        drop(write_tree);
         out.extend_from_slice(b"tree ");
         out.extend_from_slice(hex::encode(tree_hash).as_bytes());
         out.push(b'\n');
 
-        if has_current_commit {
-            let current_commit_id = self.current_commit()?;
+        let (parents, author) = match previous {
+            Some(previous) if amend => {
+                let content = self.read_object(&hex::encode(previous))?.string()?;
+                let headers: Vec<&str> = content
+                    .lines()
+                    .take_while(|line| !line.is_empty())
+                    .collect();
+                let parents = headers
+                    .iter()
+                    .filter_map(|line| line.strip_prefix("parent "))
+                    .map(|parent| Ok(<[u8; 20]>::from_hex(parent)?))
+                    .collect::<Result<Vec<_>>>()?;
+                let author = headers
+                    .iter()
+                    .find_map(|line| line.strip_prefix("author "))
+                    .map(str::to_string);
+                (parents, author)
+            }
+            Some(previous) => (vec![previous], None),
+            None => (Vec::new(), None),
+        };
+        let frozen = match reproducible {
+            true => Some(self.frozen_clock(&parents)?),
+            false => None,
+        };
+        let author_date = IdentityDate {
+            date: date.as_deref(),
+            reproducible: frozen,
+        };
+        let committer_date = IdentityDate {
+            date: None,
+            reproducible: frozen,
+        };
+        // an amended commit keeps its author
+        let author = match author {
+            Some(author) => redate(&author, author_date)?,
+            None => self.identity_at(Role::Author, author_date)?,
+        };
+
+        let parent_tree = match parents.first() {
+            Some(parent) => Some(self.commit_tree(parent)?),
+            None => None,
+        };
+        let empty = match parent_tree {
+            Some(parent_tree) => parent_tree == tree_hash,
+            None => self.load_index()?.entries.is_empty(),
+        };
+        if empty && !allow_empty && !amend {
+            return Err(anyhow!(
+                "nothing to commit, use --allow-empty to commit anyway"
+            ));
+        }
+
+        for parent in &parents {
             out.extend_from_slice(b"parent ");
-            out.extend_from_slice(hex::encode(current_commit_id).as_bytes());
+            out.extend_from_slice(hex::encode(parent).as_bytes());
             out.push(b'\n');
         }
+        out.extend_from_slice(format!("author {}\n", author).as_bytes());
+        out.extend_from_slice(
+            format!(
+                "committer {}\n",
+                self.identity_at(Role::Committer, committer_date)?
+            )
+            .as_bytes(),
+        );
 
         out.push(b'\n');
         out.extend_from_slice(message.as_bytes());
         out.push(b'\n');
 
+        if sign || self.config()?.get_bool("commit.gpgsign").unwrap_or(false) {
+            let signature = self.sign_payload(&out)?;
+            out = add_signature_header(&out, &signature);
+        }
+
+        let write_commit = trace2::region("commit", "write_commit");
         let hash = self.write_object(Kind::Commit, &out).context("Write")?;
 
         // update current branch's commit id
         self.set_current_commit(&hash)?;
+        let action = match previous {
+            _ if amend => "commit (amend)",
+            Some(_) => "commit",
+            None => "commit (initial)",
+        };
+        let reflog_message = format!("{}: {}", action, message);
+        if self.read_head()?.starts_with("ref: ") {
+            let branch = format!("refs/heads/{}", self.current_branch()?);
+            self.append_reflog(&branch, previous.as_ref(), &hash, &reflog_message)?;
+        }
+        self.append_reflog("HEAD", previous.as_ref(), &hash, &reflog_message)?;
+        drop(write_commit);
 
-        self.write_index()?;
+        // the commit is made, whatever the hook says
+        if let Err(e) = self.run_hook("post-commit", &[], b"") {
+            eprintln!("warning: {}", e);
+        }
 
         Ok(hash)
     }
 
-    pub fn show(&self, hash: Option<String>) -> Result<()> {
-        let mut commit = if let Some(hash) = hash {
-            self.read_object(&hash)?
-        } else {
-            let current_commit = self.current_commit()?;
-            self.read_object(&hex::encode(current_commit))?
+    /// Read and parse the commit `hash`, with the parents its graft gives it
+    /// if any.
+    pub fn read_commit(&self, hash: &[u8; 20]) -> Result<Commit> {
+        let mut object = self.read_object(&hex::encode(hash))?;
+        if !matches!(object.kind(), Kind::Commit) {
+            return Err(anyhow!("{} is not a commit", hex::encode(hash)));
+        }
+        let mut commit = Commit::parse(&object.string()?)
+            .with_context(|| format!("invalid commit {}", hex::encode(hash)))?;
+        if let Some(parents) = self.grafted_parents(hash)? {
+            commit.parents = parents;
+        }
+
+        Ok(commit)
+    }
+
+    /// The message of a commit, after its headers.
+    pub fn commit_message(&self, hash: &[u8; 20]) -> Result<String> {
+        Ok(self.read_commit(hash)?.message.trim_end().to_string())
+    }
+
+    /// The parents of a commit, in order.
+    pub fn commit_parents(&self, hash: &[u8; 20]) -> Result<Vec<[u8; 20]>> {
+        Ok(self.read_commit(hash)?.parents)
+    }
+
+    /// Check whether `ancestor` is reachable from `descendant` (a commit is its
+    /// own ancestor).
+    pub fn is_ancestor(&self, ancestor: &[u8; 20], descendant: &[u8; 20]) -> Result<bool> {
+        let shallow = self.shallow_commits()?;
+        let mut seen = std::collections::HashSet::new();
+        let mut queue = vec![*descendant];
+
+        while let Some(commit) = queue.pop() {
+            if commit == *ancestor {
+                return Ok(true);
+            }
+            if !seen.insert(commit) || shallow.contains(&commit) {
+                continue;
+            }
+            queue.extend(self.commit_parents(&commit)?);
+        }
+
+        Ok(false)
+    }
+
+    /// What `mg show` shows of the revision `hash`, HEAD by default.
+    pub fn show(&self, hash: Option<String>) -> Result<Shown> {
+        // files are shown converted by their textconv driver
+        if let Some(spec) = hash.as_deref().filter(|hash| hash.contains(':')) {
+            return Ok(Shown::File(self.textconv_file(spec)?));
+        }
+
+        let hash = match hash {
+            Some(rev) => self.resolve_revision(&rev)?,
+            None => self.current_commit()?,
+        };
+        let mut object = self.read_object(&hex::encode(hash))?;
+        if !matches!(object.kind(), Kind::Commit) {
+            return Ok(Shown::Object(object.string()?));
+        }
+
+        let commit = self.read_commit(&hash)?;
+        let author = &commit.author;
+        let author = self.load_mailmap()?.lookup(&author.name, &author.email);
+
+        let old = match commit.parents.first() {
+            Some(parent) => DiffTarget::Tree(self.commit_tree(parent)?),
+            None => DiffTarget::Empty,
         };
+        let mut diffs = self.diff_targets(old, DiffTarget::Tree(commit.tree))?;
+        self.apply_textconv(&mut diffs)?;
+
+        Ok(Shown::Commit {
+            hash,
+            commit: Box::new(commit),
+            author,
+            diffs,
+        })
+    }
+}
+
+/// What `show` shows.
+#[derive(Debug, Clone)]
+pub enum Shown {
+    /// A commit, with its author's name and email as the mailmap maps
+    /// them, and its changes from its first parent
+    Commit {
+        hash: [u8; 20],
+        commit: Box<Commit>,
+        author: (String, String),
+        diffs: Vec<FileDiff>,
+    },
+    /// A file `<rev>:<path>`, converted by its textconv driver
+    File(Vec<u8>),
+    /// Another object, as `cat-file -p` prints it
+    Object(String),
+}
+
+/// A commit object, parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub tree: [u8; 20],
+    pub parents: Vec<[u8; 20]>,
+    pub author: Signature,
+    pub committer: Signature,
+    /// The other headers, such as `encoding`, `gpgsig` or `mergetag`, in
+    /// order; the continuation lines of a value are joined with newlines
+    pub extra_headers: Vec<(String, String)>,
+    /// Everything after the headers, as written
+    pub message: String,
+}
+
+impl Commit {
+    /// Parse the content of a commit object.
+    pub fn parse(content: &str) -> Result<Self> {
+        let (headers, message) = match content.split_once("\n\n") {
+            Some((headers, message)) => (headers, message),
+            None => (content.trim_end_matches('\n'), ""),
+        };
+
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        let mut extra_headers: Vec<(String, String)> = Vec::new();
+        for line in headers.lines() {
+            if let Some(continuation) = line.strip_prefix(' ') {
+                let (_, value) = extra_headers
+                    .last_mut()
+                    .ok_or_else(|| anyhow!("continuation line without a header"))?;
+                value.push('\n');
+                value.push_str(continuation);
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "tree" => tree = Some(<[u8; 20]>::from_hex(value)?),
+                "parent" => parents.push(<[u8; 20]>::from_hex(value)?),
+                "author" => author = Some(Signature::parse(value)?),
+                "committer" => committer = Some(Signature::parse(value)?),
+                _ => extra_headers.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        Ok(Commit {
+            tree: tree.ok_or_else(|| anyhow!("commit has no tree"))?,
+            parents,
+            author: author.ok_or_else(|| anyhow!("commit has no author"))?,
+            committer: committer.ok_or_else(|| anyhow!("commit has no committer"))?,
+            extra_headers,
+            message: message.to_string(),
+        })
+    }
+
+    /// The first line of the message.
+    pub fn subject(&self) -> &str {
+        self.message.lines().next().unwrap_or_default()
+    }
+
+    /// The value of the extra header `key`, if the commit has it.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.extra_headers
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    pub timestamp: i64,
+    pub timezone: String,
+}
+
+impl Signature {
+    /// Parse an identity of the form `Name <email> epoch tz`.
+    pub fn parse(input: &str) -> Result<Self> {
+        let (name, rest) = input
+            .split_once('<')
+            .ok_or_else(|| anyhow!("invalid identity: {}", input))?;
+        let (email, rest) = rest
+            .split_once('>')
+            .ok_or_else(|| anyhow!("invalid identity: {}", input))?;
+
+        let mut date = rest.split_whitespace();
+        let timestamp = date.next().unwrap_or("0").parse::<i64>()?;
+        let timezone = date.next().unwrap_or("+0000").to_string();
+
+        Ok(Signature {
+            name: name.trim().to_string(),
+            email: email.to_string(),
+            timestamp,
+            timezone,
+        })
+    }
+}
+
+impl std::fmt::Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} <{}> {} {}",
+            self.name, self.email, self.timestamp, self.timezone
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::InitOptions;
+
+    #[test]
+    fn test_parse_commit() {
+        let content = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+                       parent 1cb29c18fbd0d279fc276b0f7a5f8356d0775914\n\
+                       author A U Thor <author@example.com> 1700000000 +0100\n\
+                       committer C O Mitter <committer@example.com> 1700000100 +0000\n\
+                       gpgsig -----BEGIN PGP SIGNATURE-----\n \n abcd\n -----END PGP SIGNATURE-----\n\
+                       \n\
+                       Subject\n\nBody\n";
+        let commit = Commit::parse(content).unwrap();
+        assert_eq!(
+            hex::encode(commit.tree),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+        assert_eq!(commit.parents.len(), 1);
+        assert_eq!(commit.author.name, "A U Thor");
+        assert_eq!(commit.committer.timestamp, 1700000100);
+        assert_eq!(
+            commit.header("gpgsig"),
+            Some("-----BEGIN PGP SIGNATURE-----\n\nabcd\n-----END PGP SIGNATURE-----")
+        );
+        assert_eq!(commit.subject(), "Subject");
+        assert_eq!(commit.message, "Subject\n\nBody\n");
+        assert!(Commit::parse("author A <a> 0 +0000\n\nno tree").is_err());
+    }
+
+    #[test]
+    fn test_commit_on_packed_branch() {
+        let path = std::env::temp_dir().join(format!("mg-packed-commit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        let mut repo = Repository::open(path.clone()).unwrap();
+        repo.init_repository(&path, &InitOptions::default())
+            .unwrap();
+        repo.set_config("user.name", "A U Thor").unwrap();
+        repo.set_config("user.email", "author@example.com").unwrap();
+        assert_eq!(repo.head_commit().unwrap(), None);
+
+        std::fs::write(path.join("a"), "a\n").unwrap();
+        repo.stage_files(&["a".to_string()]).unwrap();
+        let options = CommitOptions::default();
+        let first = repo.commit(Some("first"), &options).unwrap();
+
+        // as `git pack-refs --all` leaves it
+        let branch = format!("refs/heads/{}", repo.current_branch().unwrap());
+        std::fs::write(
+            repo.git_dir.join("packed-refs"),
+            format!(
+                "# pack-refs with: peeled fully-peeled sorted \n{} {}\n",
+                hex::encode(first),
+                branch
+            ),
+        )
+        .unwrap();
+        std::fs::remove_file(repo.git_dir.join(&branch)).unwrap();
+        assert_eq!(repo.current_commit().unwrap(), first);
 
-        println!("{}", commit.string()?);
+        std::fs::write(path.join("a"), "b\n").unwrap();
+        repo.stage_files(&["a".to_string()]).unwrap();
+        let second = repo.commit(Some("second"), &options).unwrap();
+        assert_eq!(repo.read_commit(&second).unwrap().parents, vec![first]);
 
-        Ok(())
+        std::fs::remove_dir_all(&path).unwrap();
     }
 }
@@ -0,0 +1,204 @@
+use std::{io::Read, path::Path, process::Command};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    diff::{DiffTarget, FileDiff},
+    repository::Repository,
+    trace2,
+};
+
+/// Clean up a commit message as git does: trailing whitespace and leading
+/// and trailing blank lines are removed, runs of blank lines are collapsed,
+/// and with `strip_comments`, lines starting with `#` are dropped.
+pub fn cleanup_message(message: &str, strip_comments: bool) -> String {
+    let mut lines: Vec<&str> = Vec::new();
+    for line in message.lines() {
+        if strip_comments && line.starts_with('#') {
+            continue;
+        }
+        let line = line.trim_end();
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+
+    lines.join("\n")
+}
+
+/// Read a commit message from `path`, or from stdin for `-`.
+pub fn read_message_file(path: &Path) -> Result<String> {
+    if path == Path::new("-") {
+        let mut message = String::new();
+        std::io::stdin().read_to_string(&mut message)?;
+        return Ok(message);
+    }
+
+    std::fs::read_to_string(path).map_err(|e| anyhow!("could not read {}: {}", path.display(), e))
+}
+
+/// The `# <label>: <path>` lines of a status section, if it has changes.
+fn status_section(title: &str, diffs: &[FileDiff]) -> String {
+    if diffs.is_empty() {
+        return String::new();
+    }
+
+    let mut section = format!("# {}:\n", title);
+    for file_diff in diffs {
+        let label = match (&file_diff.old, &file_diff.new) {
+            (None, _) => "new file:",
+            (_, None) => "deleted:",
+            _ => "modified:",
+        };
+        section.push_str(&format!("#\t{:<12}{}\n", label, file_diff.path));
+    }
+    section.push_str("#\n");
+    section
+}
+
+impl Repository {
+    /// The editor for messages: `GIT_EDITOR`, `core.editor`, `VISUAL`,
+    /// `EDITOR` or `vi`.
+    fn editor(&self) -> Result<String> {
+        if let Ok(editor) = std::env::var("GIT_EDITOR") {
+            return Ok(editor);
+        }
+        if let Some(editor) = self.config()?.get("core.editor") {
+            return Ok(editor);
+        }
+
+        Ok(std::env::var("VISUAL")
+            .or_else(|_| std::env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_string()))
+    }
+
+    /// Let the user edit `path` with their editor.
+    pub fn edit_file(&self, path: &Path) -> Result<()> {
+        let editor = self.editor()?;
+        let path_arg = path.to_string_lossy();
+        let traced = trace2::child_start("editor", None, true, &[&editor, &path_arg]);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", editor))
+            .arg(&editor)
+            .arg(path)
+            .current_dir(&self.path)
+            .spawn()
+            .map_err(|e| anyhow!("could not run the editor '{}': {}", editor, e))?;
+        let status = child.wait()?;
+        traced.exit(child.id(), &status);
+        if !status.success() {
+            return Err(anyhow!("there was a problem with the editor '{}'", editor));
+        }
+
+        Ok(())
+    }
+
+    /// The content of the `commit.template` file, if one is configured.
+    fn commit_template(&self) -> Result<Option<String>> {
+        let Some(template) = self.config()?.get_path("commit.template")? else {
+            return Ok(None);
+        };
+        let path = self.path.join(template);
+
+        std::fs::read_to_string(&path)
+            .map(Some)
+            .map_err(|e| anyhow!("could not read commit.template {}: {}", path.display(), e))
+    }
+
+    /// The commented summary of what is about to be committed, and of the
+    /// changes left out.
+    fn status_summary(&self) -> Result<String> {
+        let head = match self.head_commit()? {
+            Some(commit) => DiffTarget::Tree(self.commit_tree(&commit)?),
+            None => DiffTarget::Empty,
+        };
+        let staged = self.diff_targets(head, DiffTarget::Index)?;
+        let unstaged = self.diff_targets(DiffTarget::Index, DiffTarget::Worktree)?;
+
+        let mut summary = String::from(
+            "# Please enter the commit message for your changes. Lines starting\n\
+             # with '#' will be ignored, and an empty message aborts the commit.\n#\n",
+        );
+        if self.read_head()?.starts_with("ref: ") {
+            summary.push_str(&format!("# On branch {}\n#\n", self.current_branch()?));
+        }
+        summary.push_str(&status_section("Changes to be committed", &staged));
+        summary.push_str(&status_section("Changes not staged for commit", &unstaged));
+
+        Ok(summary)
+    }
+
+    /// Let the user edit a commit message starting as `content`, in
+    /// `.git/COMMIT_EDITMSG`. Comment lines are stripped, and an empty
+    /// message aborts the commit.
+    pub fn edit_message(&self, content: &str) -> Result<String> {
+        let path = self.git_dir.join("COMMIT_EDITMSG");
+        std::fs::write(&path, content)?;
+
+        self.edit_file(&path)?;
+
+        let message = cleanup_message(&std::fs::read_to_string(&path)?, true);
+        if message.is_empty() {
+            return Err(anyhow!("aborting commit due to empty commit message"));
+        }
+
+        Ok(message)
+    }
+
+    /// Ask for a commit message in the editor, filled with the commit
+    /// template and a status summary. An unedited template aborts the
+    /// commit.
+    pub fn edit_commit_message(&self) -> Result<String> {
+        let template = self.commit_template()?.unwrap_or_default();
+        let mut content = template.clone();
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push('\n');
+        content.push_str(&self.status_summary()?);
+
+        let message = self.edit_message(&content)?;
+        if !template.is_empty() && message == cleanup_message(&template, true) {
+            return Err(anyhow!("aborting commit, the template was not edited"));
+        }
+
+        Ok(message)
+    }
+
+    /// The message of a `--fixup` or `--squash` commit for `rev`, which
+    /// `rebase --autosquash` moves after it. `body` follows the subject.
+    pub fn fixup_message(&self, rev: &str, squash: bool, body: Option<&str>) -> Result<String> {
+        let commit = self.resolve_revision(rev)?;
+        let message = self.commit_message(&commit)?;
+        let subject = message.lines().next().unwrap_or_default();
+
+        let prefix = if squash { "squash!" } else { "fixup!" };
+        let mut message = format!("{} {}", prefix, subject);
+        if let Some(body) = body {
+            message.push_str("\n\n");
+            message.push_str(body);
+        }
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cleanup_message() {
+        let message = "\n\nSubject  \n# a comment\n\n\n\nBody\n#\n\n";
+        assert_eq!(cleanup_message(message, true), "Subject\n\nBody");
+        assert_eq!(
+            cleanup_message(message, false),
+            "Subject\n# a comment\n\nBody\n#"
+        );
+    }
+}
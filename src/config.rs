@@ -0,0 +1,692 @@
+use std::{
+    env, fmt,
+    fs::{read_to_string, write},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{log::parse_date, repository::Repository};
+
+#[derive(Debug, Clone)]
+enum ConfigLine {
+    Section {
+        name: String,
+        subsection: Option<String>,
+    },
+    Entry {
+        key: String,
+        value: String,
+    },
+    Other(String),
+}
+
+/// A git config file. Lines are kept as read so that writing the file back
+/// preserves comments and formatting.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    lines: Vec<ConfigLine>,
+}
+
+/// Split a `section.subsection.key` name into its parts.
+fn split_key(key: &str) -> Result<(String, Option<String>, String)> {
+    let (section, rest) = key
+        .split_once('.')
+        .ok_or_else(|| anyhow!("key does not contain a section: {}", key))?;
+    let (subsection, name) = match rest.rsplit_once('.') {
+        Some((subsection, name)) => (Some(subsection.to_string()), name),
+        None => (None, rest),
+    };
+
+    Ok((section.to_lowercase(), subsection, name.to_lowercase()))
+}
+
+fn parse_value(raw: &str) -> String {
+    let mut value = String::new();
+    let mut in_quotes = false;
+    let mut chars = raw.trim().chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c) => value.push(c),
+                None => {}
+            },
+            '#' | ';' if !in_quotes => break,
+            c => value.push(c),
+        }
+    }
+
+    if in_quotes {
+        value
+    } else {
+        value.trim_end().to_string()
+    }
+}
+
+/// Parse a size with an optional `k`, `m` or `g` suffix, e.g. `512m`.
+pub fn parse_size(value: &str) -> Result<usize> {
+    let value = value.trim().to_lowercase();
+    let (number, unit) = match value.char_indices().last() {
+        Some((idx, 'k')) => (&value[..idx], 1 << 10),
+        Some((idx, 'm')) => (&value[..idx], 1 << 20),
+        Some((idx, 'g')) => (&value[..idx], 1 << 30),
+        _ => (value.as_str(), 1),
+    };
+
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| anyhow!("invalid size: {}", value))
+}
+
+/// Parse a boolean as git does: `true`, `yes`, `on` or a non-zero integer
+/// are true, `false`, `no`, `off`, `0` or the empty string false.
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" => Some(true),
+        "false" | "no" | "off" | "" => Some(false),
+        value => value.parse::<i64>().ok().map(|n| n != 0),
+    }
+}
+
+/// Parse an integer with an optional `k`, `m` or `g` suffix.
+pub fn parse_int(value: &str) -> Result<i64> {
+    let value = value.trim();
+    let lowercase = value.to_lowercase();
+    let (number, unit) = match lowercase.char_indices().last() {
+        Some((idx, 'k')) => (&lowercase[..idx], 1 << 10),
+        Some((idx, 'm')) => (&lowercase[..idx], 1 << 20),
+        Some((idx, 'g')) => (&lowercase[..idx], 1 << 30),
+        _ => (lowercase.as_str(), 1),
+    };
+
+    number
+        .parse::<i64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| anyhow!("bad numeric config value '{}'", value))
+}
+
+/// Expand a leading `~/` or `~user/` in a path to the home directory.
+pub fn expand_path(value: &str) -> Result<PathBuf> {
+    let Some(rest) = value.strip_prefix('~') else {
+        return Ok(PathBuf::from(value));
+    };
+    let (user, rest) = rest.split_once('/').unwrap_or((rest, ""));
+
+    let home = if user.is_empty() {
+        env::var_os("HOME")
+            .map(PathBuf::from)
+            .ok_or_else(|| anyhow!("failed to expand '{}': HOME is not set", value))?
+    } else {
+        let name = std::ffi::CString::new(user)?;
+        let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+        if entry.is_null() {
+            return Err(anyhow!("failed to expand user dir in: '{}'", value));
+        }
+        let dir = unsafe { std::ffi::CStr::from_ptr((*entry).pw_dir) };
+        PathBuf::from(dir.to_string_lossy().into_owned())
+    };
+
+    Ok(if rest.is_empty() {
+        home
+    } else {
+        home.join(rest)
+    })
+}
+
+/// Parse an expiry date: `never` or `false` for none (0), `now` or `all`
+/// for the current time, or any date `--since` accepts.
+pub fn parse_expiry_date(value: &str, now: i64) -> Result<i64> {
+    match value.trim() {
+        "never" | "false" => Ok(0),
+        "now" | "all" => Ok(now),
+        value => parse_date(value, now).map_err(|_| anyhow!("invalid expiry date '{}'", value)),
+    }
+}
+
+/// A type config values are checked against and canonicalized to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigType {
+    Bool,
+    Int,
+    Path,
+    ExpiryDate,
+}
+
+impl ConfigType {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "bool" => Ok(ConfigType::Bool),
+            "int" => Ok(ConfigType::Int),
+            "path" => Ok(ConfigType::Path),
+            "expiry-date" => Ok(ConfigType::ExpiryDate),
+            _ => Err(anyhow!("unrecognized --type argument, {}", name)),
+        }
+    }
+
+    /// The canonical form of `value`: `true` or `false`, an integer with its
+    /// suffix applied, a path with its home directory expanded, or an
+    /// expiry date as a timestamp.
+    pub fn canonicalize(self, value: &str, now: i64) -> Result<String> {
+        match self {
+            ConfigType::Bool => parse_bool(value)
+                .map(|value| value.to_string())
+                .ok_or_else(|| anyhow!("bad boolean config value '{}'", value)),
+            ConfigType::Int => parse_int(value).map(|value| value.to_string()),
+            ConfigType::Path => Ok(expand_path(value)?.to_string_lossy().into_owned()),
+            ConfigType::ExpiryDate => parse_expiry_date(value, now).map(|date| date.to_string()),
+        }
+    }
+}
+
+/// What `mg config` does with a variable.
+pub enum ConfigAction {
+    /// Print the last value
+    Get,
+    /// Print every value
+    GetAll,
+    /// Set the only value
+    Set(String),
+    /// Add a value, keeping the existing ones
+    Add(String),
+    /// Remove the only value
+    Unset,
+    /// Remove every value
+    UnsetAll,
+}
+
+fn format_value(value: &str) -> String {
+    let needs_quotes = value.starts_with(' ')
+        || value.ends_with(' ')
+        || value.contains('#')
+        || value.contains(';');
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+
+    if needs_quotes {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+impl Config {
+    pub fn parse(content: &str) -> Self {
+        let lines = content
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim();
+                if trimmed.starts_with('[') {
+                    if let Some(end) = trimmed.find(']') {
+                        let header = &trimmed[1..end];
+                        return match header.split_once(' ') {
+                            Some((name, subsection)) => ConfigLine::Section {
+                                name: name.to_lowercase(),
+                                subsection: Some(subsection.trim().trim_matches('"').to_string()),
+                            },
+                            None => match header.split_once('.') {
+                                // legacy [section.subsection] syntax
+                                Some((name, subsection)) => ConfigLine::Section {
+                                    name: name.to_lowercase(),
+                                    subsection: Some(subsection.to_string()),
+                                },
+                                None => ConfigLine::Section {
+                                    name: header.to_lowercase(),
+                                    subsection: None,
+                                },
+                            },
+                        };
+                    }
+                }
+
+                if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+                    return ConfigLine::Other(line.to_string());
+                }
+
+                match trimmed.split_once('=') {
+                    Some((key, value)) => ConfigLine::Entry {
+                        key: key.trim().to_lowercase(),
+                        value: parse_value(value),
+                    },
+                    // a key without value is a boolean set to true
+                    None => ConfigLine::Entry {
+                        key: trimmed.to_lowercase(),
+                        value: "true".to_string(),
+                    },
+                }
+            })
+            .collect();
+
+        Config { lines }
+    }
+
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        Ok(Config::parse(&read_to_string(path)?))
+    }
+
+    /// The system and global configuration, which a repository cannot
+    /// override: `/etc/gitconfig` unless `GIT_CONFIG_NOSYSTEM` is set, then
+    /// `GIT_CONFIG_GLOBAL`, or the XDG and home `.gitconfig` files.
+    pub fn load_protected() -> Result<Self> {
+        let mut paths = Vec::new();
+        if env::var_os("GIT_CONFIG_NOSYSTEM").is_none() {
+            paths.push(PathBuf::from("/etc/gitconfig"));
+        }
+        match env::var_os("GIT_CONFIG_GLOBAL") {
+            Some(global) => paths.push(PathBuf::from(global)),
+            None => {
+                let home = env::var_os("HOME").map(PathBuf::from);
+                let xdg = env::var_os("XDG_CONFIG_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| home.as_ref().map(|home| home.join(".config")));
+                paths.extend(xdg.map(|xdg| xdg.join("git").join("config")));
+                paths.extend(home.map(|home| home.join(".gitconfig")));
+            }
+        }
+
+        let mut content = String::new();
+        for path in paths.iter().filter(|path| path.is_file()) {
+            content.push_str(&read_to_string(path)?);
+            content.push('\n');
+        }
+
+        Ok(Config::parse(&content))
+    }
+
+    /// Iterate over `(section, subsection, key, value)` for every entry.
+    fn entries(&self) -> impl Iterator<Item = (&str, Option<&str>, &str, &str)> {
+        let mut section = ("", None);
+        self.lines.iter().filter_map(move |line| match line {
+            ConfigLine::Section { name, subsection } => {
+                section = (name.as_str(), subsection.as_deref());
+                None
+            }
+            ConfigLine::Entry { key, value } => {
+                Some((section.0, section.1, key.as_str(), value.as_str()))
+            }
+            ConfigLine::Other(_) => None,
+        })
+    }
+
+    /// Get the last value of a `section.subsection.key` variable.
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.get_all(key).pop()
+    }
+
+    /// Get every value of a multi-valued variable, in file order.
+    pub fn get_all(&self, key: &str) -> Vec<String> {
+        let Ok((section, subsection, name)) = split_key(key) else {
+            return Vec::new();
+        };
+
+        self.entries()
+            .filter(|(s, sub, k, _)| *s == section && *sub == subsection.as_deref() && *k == name)
+            .map(|(_, _, _, v)| v.to_string())
+            .collect()
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.get(key)
+            .map(|value| parse_bool(&value).unwrap_or(false))
+    }
+
+    pub fn get_int(&self, key: &str) -> Result<Option<i64>> {
+        self.get(key).map(|value| parse_int(&value)).transpose()
+    }
+
+    /// Get a path, its leading `~/` expanded to the home directory.
+    pub fn get_path(&self, key: &str) -> Result<Option<PathBuf>> {
+        self.get(key).map(|value| expand_path(&value)).transpose()
+    }
+
+    /// Every entry as `(section.subsection.key, value)`, in file order.
+    pub fn list(&self) -> Vec<(String, String)> {
+        self.entries()
+            .map(|(section, subsection, key, value)| {
+                let name = match subsection {
+                    Some(subsection) => format!("{}.{}.{}", section, subsection, key),
+                    None => format!("{}.{}", section, key),
+                };
+                (name, value.to_string())
+            })
+            .collect()
+    }
+
+    /// List the subsections of a section, e.g. the remote names for `remote`.
+    pub fn subsections(&self, section: &str) -> Vec<String> {
+        let mut subsections = Vec::new();
+        for line in &self.lines {
+            if let ConfigLine::Section {
+                name,
+                subsection: Some(subsection),
+            } = line
+            {
+                if name == section && !subsections.contains(subsection) {
+                    subsections.push(subsection.clone());
+                }
+            }
+        }
+        subsections
+    }
+
+    /// Set a variable, replacing its last occurrence or appending it to its
+    /// section (creating the section if needed).
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let (section, subsection, name) = split_key(key)?;
+
+        let mut current_matches = false;
+        let mut last_entry = None;
+        let mut section_end = None;
+
+        for (idx, line) in self.lines.iter().enumerate() {
+            match line {
+                ConfigLine::Section {
+                    name: s,
+                    subsection: sub,
+                } => {
+                    current_matches = *s == section && *sub == subsection;
+                    if current_matches {
+                        section_end = Some(idx);
+                    }
+                }
+                ConfigLine::Entry { key: k, .. } if current_matches => {
+                    section_end = Some(idx);
+                    if *k == name {
+                        last_entry = Some(idx);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let entry = ConfigLine::Entry {
+            key: name,
+            value: value.to_string(),
+        };
+
+        match (last_entry, section_end) {
+            (Some(idx), _) => self.lines[idx] = entry,
+            (None, Some(idx)) => self.lines.insert(idx + 1, entry),
+            (None, None) => {
+                self.lines.push(ConfigLine::Section {
+                    name: section,
+                    subsection,
+                });
+                self.lines.push(entry);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a value to a multi-valued variable, after the last entry of its
+    /// section (creating the section if needed).
+    pub fn add(&mut self, key: &str, value: &str) -> Result<()> {
+        let (section, subsection, name) = split_key(key)?;
+
+        let mut current_matches = false;
+        let mut section_end = None;
+        for (idx, line) in self.lines.iter().enumerate() {
+            match line {
+                ConfigLine::Section {
+                    name: s,
+                    subsection: sub,
+                } => {
+                    current_matches = *s == section && *sub == subsection;
+                    if current_matches {
+                        section_end = Some(idx);
+                    }
+                }
+                ConfigLine::Entry { .. } if current_matches => section_end = Some(idx),
+                _ => {}
+            }
+        }
+
+        let entry = ConfigLine::Entry {
+            key: name,
+            value: value.to_string(),
+        };
+        match section_end {
+            Some(idx) => self.lines.insert(idx + 1, entry),
+            None => {
+                self.lines.push(ConfigLine::Section {
+                    name: section,
+                    subsection,
+                });
+                self.lines.push(entry);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove every value of a variable, returning how many there were.
+    pub fn unset_all(&mut self, key: &str) -> Result<usize> {
+        let (section, subsection, name) = split_key(key)?;
+
+        let mut current_matches = false;
+        let before = self.lines.len();
+        self.lines.retain(|line| match line {
+            ConfigLine::Section {
+                name: s,
+                subsection: sub,
+            } => {
+                current_matches = *s == section && *sub == subsection;
+                true
+            }
+            ConfigLine::Entry { key: k, .. } => !(current_matches && *k == name),
+            ConfigLine::Other(_) => true,
+        });
+
+        Ok(before - self.lines.len())
+    }
+
+    pub fn write(&self, path: &std::path::Path) -> Result<()> {
+        write(path, self.to_string())?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        for line in &self.lines {
+            match line {
+                ConfigLine::Section {
+                    name,
+                    subsection: Some(subsection),
+                } => out.push_str(&format!("[{} \"{}\"]\n", name, subsection)),
+                ConfigLine::Section {
+                    name,
+                    subsection: None,
+                } => out.push_str(&format!("[{}]\n", name)),
+                ConfigLine::Entry { key, value } => {
+                    out.push_str(&format!("\t{} = {}\n", key, format_value(value)))
+                }
+                ConfigLine::Other(line) => {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+        }
+        write!(f, "{}", out)
+    }
+}
+
+impl Repository {
+    /// The repository configuration from `.git/config`.
+    pub fn config(&self) -> Result<Config> {
+        Config::load(&self.git_dir.join("config"))
+    }
+
+    pub fn set_config(&self, key: &str, value: &str) -> Result<()> {
+        let config_path = self.git_dir.join("config");
+        let mut config = Config::load(&config_path)?;
+        config.set(key, value)?;
+        config.write(&config_path)
+    }
+
+    /// Get, set or remove the repository variable `key`, its values checked
+    /// and canonicalized as `value_type` if given. Returns false when there
+    /// is nothing to get or remove.
+    pub fn config_command(
+        &self,
+        key: &str,
+        action: ConfigAction,
+        value_type: Option<ConfigType>,
+    ) -> Result<bool> {
+        let config_path = self.git_dir.join("config");
+        let mut config = Config::load(&config_path)?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs() as i64;
+        let canonical = |value: &str| match value_type {
+            Some(value_type) => value_type.canonicalize(value, now),
+            None => Ok(value.to_string()),
+        };
+        // only booleans and integers are stored canonicalized
+        let stored = |value: &str| match value_type {
+            Some(ConfigType::Bool | ConfigType::Int) => canonical(value),
+            Some(_) => canonical(value).map(|_| value.to_string()),
+            None => Ok(value.to_string()),
+        };
+        let values = config.get_all(key);
+
+        match action {
+            ConfigAction::Get | ConfigAction::GetAll if values.is_empty() => return Ok(false),
+            ConfigAction::Get => println!("{}", canonical(values.last().expect("a value"))?),
+            ConfigAction::GetAll => {
+                for value in &values {
+                    println!("{}", canonical(value)?);
+                }
+            }
+            ConfigAction::Set(_) | ConfigAction::Unset if values.len() > 1 => {
+                return Err(anyhow!(
+                    "{} has multiple values, use --add, --unset-all or --get-all",
+                    key
+                ))
+            }
+            ConfigAction::Set(value) => {
+                config.set(key, &stored(&value)?)?;
+                config.write(&config_path)?;
+            }
+            ConfigAction::Add(value) => {
+                config.add(key, &stored(&value)?)?;
+                config.write(&config_path)?;
+            }
+            ConfigAction::Unset | ConfigAction::UnsetAll => {
+                if config.unset_all(key)? == 0 {
+                    return Ok(false);
+                }
+                config.write(&config_path)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Print every variable of the repository configuration as
+    /// `name=value`.
+    pub fn config_list(&self) -> Result<()> {
+        for (name, value) in self.config()?.list() {
+            println!("{}={}", name, value);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_get_set() {
+        let mut config = Config::parse(
+            "# comment\n\
+             [core]\n\
+             \tbare = false\n\
+             [remote \"origin\"]\n\
+             \turl = https://example.com/repo.git ; trailing\n\
+             \tfetch = +refs/heads/*:refs/remotes/origin/*\n\
+             [Alias]\n\
+             \tco = \"switch \\\"x\\\"\"\n",
+        );
+
+        assert_eq!(config.get_bool("core.bare"), Some(false));
+        assert_eq!(
+            config.get("remote.origin.url").as_deref(),
+            Some("https://example.com/repo.git")
+        );
+        assert_eq!(config.get("alias.co").as_deref(), Some("switch \"x\""));
+        assert_eq!(config.subsections("remote"), vec!["origin".to_string()]);
+
+        config.set("remote.origin.promisor", "true").unwrap();
+        config.set("core.bare", "true").unwrap();
+        config.set("user.name", "Jane Doe").unwrap();
+
+        let reparsed = Config::parse(&config.to_string());
+        assert_eq!(reparsed.get_bool("remote.origin.promisor"), Some(true));
+        assert_eq!(reparsed.get_bool("core.bare"), Some(true));
+        assert_eq!(reparsed.get("user.name").as_deref(), Some("Jane Doe"));
+        assert!(config.to_string().starts_with("# comment\n"));
+    }
+
+    #[test]
+    fn test_config_multiple_values_and_types() {
+        let mut config = Config::parse(
+            "[remote \"origin\"]\n\
+             \tfetch = +refs/heads/*:refs/remotes/origin/*\n\
+             [core]\n\
+             \tbare = false\n",
+        );
+
+        config
+            .add("remote.origin.fetch", "+refs/tags/*:refs/tags/*")
+            .unwrap();
+        config
+            .add("remote.upstream.fetch", "refs/heads/main")
+            .unwrap();
+        assert_eq!(
+            config.get_all("remote.origin.fetch"),
+            vec![
+                "+refs/heads/*:refs/remotes/origin/*",
+                "+refs/tags/*:refs/tags/*"
+            ]
+        );
+        assert!(config.to_string().contains("refs/tags/*\n[core]"));
+
+        assert_eq!(config.unset_all("remote.origin.fetch").unwrap(), 2);
+        assert!(config.get_all("remote.origin.fetch").is_empty());
+        assert_eq!(config.get_bool("core.bare"), Some(false));
+
+        assert_eq!(ConfigType::Bool.canonicalize("Yes", 0).unwrap(), "true");
+        assert_eq!(ConfigType::Bool.canonicalize("", 0).unwrap(), "false");
+        assert!(ConfigType::Bool.canonicalize("maybe", 0).is_err());
+        assert_eq!(ConfigType::Int.canonicalize("2k", 0).unwrap(), "2048");
+        assert!(ConfigType::Int.canonicalize("two", 0).is_err());
+        assert_eq!(
+            ConfigType::ExpiryDate.canonicalize("never", 100).unwrap(),
+            "0"
+        );
+        assert_eq!(
+            ConfigType::ExpiryDate
+                .canonicalize("1.day.ago", 100_000)
+                .unwrap(),
+            "13600"
+        );
+    }
+}
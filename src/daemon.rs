@@ -0,0 +1,105 @@
+use std::{
+    io::{BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    pkt_line::{packet_line, read_pkt_line},
+    repository::Repository,
+};
+
+/// The repository directory under `base_path` for a requested path such as
+/// `/project.git`, which may leave out or add the `.git` suffix.
+pub fn find_repository(base_path: &Path, requested: &str) -> Option<PathBuf> {
+    let relative = Path::new(requested.trim_start_matches('/'));
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    let path = base_path.join(relative);
+    let without_suffix = requested
+        .strip_suffix(".git")
+        .map(|stripped| base_path.join(stripped.trim_start_matches('/')));
+
+    [Some(path), without_suffix]
+        .into_iter()
+        .flatten()
+        .find(|path| path.join(".git").is_dir())
+}
+
+fn handle_connection(stream: TcpStream, base_path: &Path, export_all: bool) -> Result<()> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+
+    // git-upload-pack /path NUL host=example.com NUL [extra parameters]
+    let request = read_pkt_line(&mut input)?.ok_or_else(|| anyhow!("empty request"))?;
+    let request = String::from_utf8(request)?;
+    let command = request.split('\0').next().unwrap_or_default();
+    let (service, requested) = command
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("invalid request: {}", command))?;
+
+    if service != "git-upload-pack" {
+        out.write_all(&packet_line(&format!(
+            "ERR service not enabled: {}",
+            service
+        )))?;
+        out.flush()?;
+        return Err(anyhow!("unsupported service {}", service));
+    }
+
+    let repo = find_repository(base_path, requested)
+        .filter(|path| export_all || path.join(".git").join("git-daemon-export-ok").exists());
+    let Some(path) = repo else {
+        let error = format!(
+            "ERR access denied or repository not exported: {}",
+            requested
+        );
+        out.write_all(&packet_line(&error))?;
+        out.flush()?;
+        return Err(anyhow!("refused {}", requested));
+    };
+
+    // the served history is the one stored, whatever is replaced locally
+    let repo = Repository::open(path)?.stored_objects();
+
+    repo.advertise_refs(&mut out)?;
+    repo.upload_pack(&mut input, &mut out, false)
+}
+
+/// Serve the repositories under `base_path` over the git protocol, each
+/// connection in its own thread.
+pub fn daemon(base_path: &Path, listen: &str, port: u16, export_all: bool) -> Result<()> {
+    let listener = TcpListener::bind((listen, port))?;
+    println!("Listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let base_path = base_path.to_path_buf();
+        thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default();
+            if let Err(e) = handle_connection(stream, &base_path, export_all) {
+                eprintln!("[{}] {}", peer, e);
+            }
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,689 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    attributes::Attributes, kind::Kind, metadata::is_worktree_executable, object::hash_blob,
+    pathspec::Pathspec, repository::Repository,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    /// line `old` of the old side equals line `new` of the new side
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Compute a shortest edit script between `a` and `b` with Myers' algorithm.
+pub fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    // common prefix and suffix do not need the quadratic part
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let mut edits: Vec<Edit> = (0..prefix).map(|i| Edit::Equal(i, i)).collect();
+    let middle = myers(&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    edits.extend(middle.into_iter().map(|edit| match edit {
+        Edit::Equal(x, y) => Edit::Equal(x + prefix, y + prefix),
+        Edit::Delete(x) => Edit::Delete(x + prefix),
+        Edit::Insert(y) => Edit::Insert(y + prefix),
+    }));
+    edits.extend((0..suffix).map(|i| Edit::Equal(a.len() - suffix + i, b.len() - suffix + i)));
+
+    edits
+}
+
+/// The furthest x reached on each diagonal k = x - y, for the forward or
+/// the backward search of `middle_snake`.
+struct Frontier {
+    v: Vec<usize>,
+    offset: isize,
+}
+
+impl Frontier {
+    fn new(max_d: usize) -> Self {
+        Frontier {
+            v: vec![0; 2 * max_d + 3],
+            offset: max_d as isize + 1,
+        }
+    }
+
+    fn get(&self, k: isize) -> usize {
+        self.v[(k + self.offset) as usize]
+    }
+
+    fn set(&mut self, k: isize, x: usize) {
+        self.v[(k + self.offset) as usize] = x;
+    }
+}
+
+/// Myers' algorithm in linear space: the middle snake of a shortest edit
+/// script splits it in two, each found the same way, so that memory stays
+/// O(N+M) rather than growing with the number of edits.
+fn myers<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let max_d = (a.len() + b.len()).div_ceil(2) + 1;
+    let mut forward = Frontier::new(max_d);
+    let mut backward = Frontier::new(max_d);
+    let mut edits = Vec::with_capacity(a.len().max(b.len()));
+    conquer(
+        a,
+        0..a.len(),
+        b,
+        0..b.len(),
+        &mut forward,
+        &mut backward,
+        &mut edits,
+    );
+
+    // within a change, the lines removed come before the lines added
+    for change in edits.split_mut(|edit| matches!(edit, Edit::Equal(..))) {
+        change.sort_by_key(|edit| matches!(edit, Edit::Insert(_)));
+    }
+
+    edits
+}
+
+fn conquer<T: PartialEq>(
+    a: &[T],
+    mut a_range: Range<usize>,
+    b: &[T],
+    mut b_range: Range<usize>,
+    forward: &mut Frontier,
+    backward: &mut Frontier,
+    edits: &mut Vec<Edit>,
+) {
+    while !a_range.is_empty() && !b_range.is_empty() && a[a_range.start] == b[b_range.start] {
+        edits.push(Edit::Equal(a_range.start, b_range.start));
+        a_range.start += 1;
+        b_range.start += 1;
+    }
+    let mut suffix = 0;
+    while !a_range.is_empty() && !b_range.is_empty() && a[a_range.end - 1] == b[b_range.end - 1] {
+        a_range.end -= 1;
+        b_range.end -= 1;
+        suffix += 1;
+    }
+
+    if a_range.is_empty() {
+        edits.extend(b_range.clone().map(Edit::Insert));
+    } else if b_range.is_empty() {
+        edits.extend(a_range.clone().map(Edit::Delete));
+    } else {
+        let (x, y) = middle_snake(a, a_range.clone(), b, b_range.clone(), forward, backward);
+        conquer(
+            a,
+            a_range.start..x,
+            b,
+            b_range.start..y,
+            forward,
+            backward,
+            edits,
+        );
+        conquer(
+            a,
+            x..a_range.end,
+            b,
+            y..b_range.end,
+            forward,
+            backward,
+            edits,
+        );
+    }
+
+    edits.extend((0..suffix).map(|i| Edit::Equal(a_range.end + i, b_range.end + i)));
+}
+
+/// A point of a shortest edit script between the non-empty ranges, strictly
+/// between its ends, where the forward and backward searches meet.
+fn middle_snake<T: PartialEq>(
+    a: &[T],
+    a_range: Range<usize>,
+    b: &[T],
+    b_range: Range<usize>,
+    forward: &mut Frontier,
+    backward: &mut Frontier,
+) -> (usize, usize) {
+    let (n, m) = (a_range.len(), b_range.len());
+    let delta = n as isize - m as isize;
+    let odd = delta & 1 == 1;
+    forward.set(1, 0);
+    backward.set(1, 0);
+
+    for d in 0..=((n + m).div_ceil(2) as isize) {
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x = if k == -d || (k != d && forward.get(k - 1) < forward.get(k + 1)) {
+                forward.get(k + 1)
+            } else {
+                forward.get(k - 1) + 1
+            };
+            let start = (x, (x as isize - k) as usize);
+            let mut y = start.1;
+            while x < n && y < m && a[a_range.start + x] == b[b_range.start + y] {
+                x += 1;
+                y += 1;
+            }
+            forward.set(k, x);
+            if odd && (k - delta).abs() < d && x + backward.get(delta - k) >= n {
+                return (a_range.start + start.0, b_range.start + start.1);
+            }
+        }
+
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x = if k == -d || (k != d && backward.get(k - 1) < backward.get(k + 1)) {
+                backward.get(k + 1)
+            } else {
+                backward.get(k - 1) + 1
+            };
+            let mut y = (x as isize - k) as usize;
+            while x < n && y < m && a[a_range.end - x - 1] == b[b_range.end - y - 1] {
+                x += 1;
+                y += 1;
+            }
+            backward.set(k, x);
+            if !odd && (k - delta).abs() <= d && x + forward.get(delta - k) >= n {
+                return (a_range.end - x, b_range.end - y);
+            }
+        }
+    }
+
+    unreachable!("the searches meet within (N+M)/2 edits")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffHunk {
+    pub old_start: usize,
+    pub old_count: usize,
+    pub new_start: usize,
+    pub new_count: usize,
+    pub lines: Vec<DiffLine>,
+}
+
+impl DiffHunk {
+    pub fn header(&self) -> String {
+        format!(
+            "@@ -{} +{} @@",
+            format_range(self.old_start, self.old_count),
+            format_range(self.new_start, self.new_count)
+        )
+    }
+}
+
+fn format_range(start: usize, count: usize) -> String {
+    if count == 1 {
+        format!("{}", start)
+    } else {
+        format!("{},{}", start, count)
+    }
+}
+
+/// Group the edits between two texts into hunks with `context` lines around
+/// each change.
+pub fn make_hunks(old: &[&str], new: &[&str], context: usize) -> Vec<DiffHunk> {
+    let edits = diff(old, new);
+    let changes: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| !matches!(e, Edit::Equal(..)))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut hunks = Vec::new();
+    let mut i = 0;
+    while i < changes.len() {
+        let start = changes[i].saturating_sub(context);
+        let mut end = changes[i];
+        // merge changes whose contexts overlap
+        while i + 1 < changes.len() && changes[i + 1] <= end + 2 * context + 1 {
+            i += 1;
+            end = changes[i];
+        }
+        let end = (end + context + 1).min(edits.len());
+        i += 1;
+
+        let (mut old_start, mut new_start) = (None, None);
+        let (mut old_count, mut new_count) = (0, 0);
+        let mut lines = Vec::new();
+
+        // position of the first line of each side, used for empty sides
+        let (mut old_pos, mut new_pos) = (0, 0);
+        for edit in &edits[..start] {
+            match edit {
+                Edit::Equal(..) => {
+                    old_pos += 1;
+                    new_pos += 1;
+                }
+                Edit::Delete(_) => old_pos += 1,
+                Edit::Insert(_) => new_pos += 1,
+            }
+        }
+
+        for edit in &edits[start..end] {
+            match *edit {
+                Edit::Equal(x, y) => {
+                    old_start.get_or_insert(x + 1);
+                    new_start.get_or_insert(y + 1);
+                    old_count += 1;
+                    new_count += 1;
+                    lines.push(DiffLine::Context(old[x].to_string()));
+                }
+                Edit::Delete(x) => {
+                    old_start.get_or_insert(x + 1);
+                    old_count += 1;
+                    lines.push(DiffLine::Removed(old[x].to_string()));
+                }
+                Edit::Insert(y) => {
+                    new_start.get_or_insert(y + 1);
+                    new_count += 1;
+                    lines.push(DiffLine::Added(new[y].to_string()));
+                }
+            }
+        }
+
+        hunks.push(DiffHunk {
+            old_start: old_start.unwrap_or(old_pos),
+            old_count,
+            new_start: new_start.unwrap_or(new_pos),
+            new_count,
+            lines,
+        });
+    }
+
+    hunks
+}
+
+/// One side of a comparison.
+#[derive(Debug, Clone, Copy)]
+pub enum DiffTarget {
+    Worktree,
+    Index,
+    Tree([u8; 20]),
+    /// No files, the parent side of a root commit
+    Empty,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffEntry {
+    pub mode: String,
+    pub hash: [u8; 20],
+}
+
+#[derive(Debug, Clone)]
+pub struct FileDiff {
+    pub path: String,
+    pub old: Option<DiffEntry>,
+    pub new: Option<DiffEntry>,
+    pub old_content: Vec<u8>,
+    pub new_content: Vec<u8>,
+    /// whether the `diff` attribute forces a text or binary diff
+    pub diff_as_text: Option<bool>,
+}
+
+impl FileDiff {
+    pub fn is_binary(&self) -> bool {
+        match self.diff_as_text {
+            Some(text) => !text,
+            None => self.old_content.contains(&0) || self.new_content.contains(&0),
+        }
+    }
+
+    pub fn hunks(&self, context: usize) -> Vec<DiffHunk> {
+        let old = String::from_utf8_lossy(&self.old_content);
+        let new = String::from_utf8_lossy(&self.new_content);
+        let old_lines: Vec<&str> = old.lines().collect();
+        let new_lines: Vec<&str> = new.lines().collect();
+
+        make_hunks(&old_lines, &new_lines, context)
+    }
+
+    /// Render the change in git's unified diff format.
+    pub fn unified(&self, context: usize) -> String {
+        let mut out = format!("diff --git a/{} b/{}\n", self.path, self.path);
+
+        match (&self.old, &self.new) {
+            (None, Some(new)) => out.push_str(&format!("new file mode {}\n", new.mode)),
+            (Some(old), None) => out.push_str(&format!("deleted file mode {}\n", old.mode)),
+            (Some(old), Some(new)) if old.mode != new.mode => {
+                out.push_str(&format!("old mode {}\nnew mode {}\n", old.mode, new.mode))
+            }
+            _ => {}
+        }
+
+        let old_hash = self.old.as_ref().map(|e| e.hash).unwrap_or_default();
+        let new_hash = self.new.as_ref().map(|e| e.hash).unwrap_or_default();
+        if old_hash == new_hash {
+            return out;
+        }
+        out.push_str(&format!(
+            "index {}..{}",
+            &hex::encode(old_hash)[..7],
+            &hex::encode(new_hash)[..7]
+        ));
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) if old.mode == new.mode => {
+                out.push_str(&format!(" {}\n", old.mode))
+            }
+            _ => out.push('\n'),
+        }
+
+        let old_name = match self.old {
+            Some(_) => format!("a/{}", self.path),
+            None => "/dev/null".to_string(),
+        };
+        let new_name = match self.new {
+            Some(_) => format!("b/{}", self.path),
+            None => "/dev/null".to_string(),
+        };
+
+        if self.is_binary() {
+            out.push_str(&format!(
+                "Binary files {} and {} differ\n",
+                old_name, new_name
+            ));
+            return out;
+        }
+
+        out.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+        for hunk in self.hunks(context) {
+            out.push_str(&hunk.header());
+            out.push('\n');
+            for line in hunk.lines {
+                match line {
+                    DiffLine::Context(l) => out.push_str(&format!(" {}\n", l)),
+                    DiffLine::Removed(l) => out.push_str(&format!("-{}\n", l)),
+                    DiffLine::Added(l) => out.push_str(&format!("+{}\n", l)),
+                }
+            }
+        }
+
+        out
+    }
+}
+
+impl Repository {
+    /// List the files of a diff target with their mode and object id.
+    pub fn diff_snapshot(&self, target: DiffTarget) -> Result<BTreeMap<String, DiffEntry>> {
+        let mut snapshot = BTreeMap::new();
+
+        match target {
+            DiffTarget::Worktree => {
+                let mut attributes = self.attributes()?;
+                let trust_executable_bit = self.trust_executable_bit()?;
+                let index = self.load_index()?;
+                // untracked files are not part of a diff
+                for entry in index.entries {
+                    let path = self.path.join(&entry.file_path);
+                    // the stat data of unchanged files spares hashing them
+                    let up_to_date = path
+                        .metadata()
+                        .is_ok_and(|metadata| entry.is_up_to_date(&metadata, index.timestamp));
+                    let hash = if entry.skip_worktree() || up_to_date {
+                        entry.sha1
+                    } else if path.is_file() {
+                        let content = std::fs::read(&path)?;
+                        hash_blob(&self.convert_to_git(
+                            &mut attributes,
+                            &entry.file_path,
+                            content,
+                        )?)
+                    } else {
+                        continue;
+                    };
+                    let mode = if entry.skip_worktree() {
+                        format!("{:o}", entry.mode)
+                    } else {
+                        let executable =
+                            is_worktree_executable(&path, trust_executable_bit, Some(entry.mode))?;
+                        Kind::Blob(executable).to_mode().to_string()
+                    };
+                    snapshot.insert(entry.file_path, DiffEntry { mode, hash });
+                }
+            }
+            DiffTarget::Index => {
+                for entry in self.load_index()?.entries {
+                    snapshot.insert(
+                        entry.file_path,
+                        DiffEntry {
+                            mode: format!("{:o}", entry.mode),
+                            hash: entry.sha1,
+                        },
+                    );
+                }
+            }
+            DiffTarget::Tree(tree) => {
+                for file in self.flatten_tree(&tree)? {
+                    snapshot.insert(
+                        file.path,
+                        DiffEntry {
+                            mode: file.kind.to_mode().to_string(),
+                            hash: file.hash,
+                        },
+                    );
+                }
+            }
+            DiffTarget::Empty => {}
+        }
+
+        Ok(snapshot)
+    }
+
+    fn diff_content(
+        &self,
+        attributes: &mut Attributes,
+        target: DiffTarget,
+        path: &str,
+        entry: &DiffEntry,
+    ) -> Result<Vec<u8>> {
+        if entry.mode == Kind::Commit.to_mode() {
+            return Ok(format!("Subproject commit {}\n", hex::encode(entry.hash)).into_bytes());
+        }
+
+        match target {
+            DiffTarget::Worktree => {
+                self.convert_to_git(attributes, path, std::fs::read(self.path.join(path))?)
+            }
+            _ => self.read_object(&hex::encode(entry.hash))?.content(),
+        }
+    }
+
+    /// Compare two targets and return the files which differ, sorted by path.
+    pub fn diff_targets(&self, old: DiffTarget, new: DiffTarget) -> Result<Vec<FileDiff>> {
+        self.diff_targets_matching(old, new, &Pathspec::default())
+    }
+
+    /// The differences between `old` and `new` in the paths `pathspec`
+    /// matches.
+    pub fn diff_targets_matching(
+        &self,
+        old: DiffTarget,
+        new: DiffTarget,
+        pathspec: &Pathspec,
+    ) -> Result<Vec<FileDiff>> {
+        let old_snapshot = self.diff_snapshot(old)?;
+        let new_snapshot = self.diff_snapshot(new)?;
+
+        let mut paths: Vec<&String> = old_snapshot
+            .keys()
+            .chain(new_snapshot.keys())
+            .filter(|path| pathspec.matches(path))
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        let mut attributes = self.attributes()?;
+        let mut diffs = Vec::new();
+        for path in paths {
+            let old_entry = old_snapshot.get(path);
+            let new_entry = new_snapshot.get(path);
+
+            if let (Some(o), Some(n)) = (old_entry, new_entry) {
+                if o.hash == n.hash && o.mode == n.mode {
+                    continue;
+                }
+            }
+
+            let old_content = match old_entry {
+                Some(entry) => self.diff_content(&mut attributes, old, path, entry)?,
+                None => Vec::new(),
+            };
+            let new_content = match new_entry {
+                Some(entry) => self.diff_content(&mut attributes, new, path, entry)?,
+                None => Vec::new(),
+            };
+
+            diffs.push(FileDiff {
+                path: path.clone(),
+                old: old_entry.cloned(),
+                new: new_entry.cloned(),
+                old_content,
+                new_content,
+                diff_as_text: attributes.diff_as_text(path)?,
+            });
+        }
+
+        Ok(diffs)
+    }
+
+    /// Resolve the sides compared by `mg diff`: the index and the worktree by
+    /// default, HEAD and the index with `cached`, a commit and the worktree
+    /// (or the index) with one revision, and two commits with two revisions
+    /// or an `A..B` (or `A...B`) range.
+    pub fn diff_sides(
+        &self,
+        revisions: &[String],
+        cached: bool,
+    ) -> Result<(DiffTarget, DiffTarget)> {
+        let tree_of = |rev: &str| -> Result<DiffTarget> {
+            Ok(DiffTarget::Tree(
+                self.commit_tree(&self.resolve_revision(rev)?)?,
+            ))
+        };
+
+        match revisions {
+            [] if cached => Ok((tree_of("HEAD")?, DiffTarget::Index)),
+            [] => Ok((DiffTarget::Index, DiffTarget::Worktree)),
+            [range] if range.contains("..") => {
+                let (from, to) = self.resolve_range(range)?.unwrap();
+                Ok((
+                    DiffTarget::Tree(self.commit_tree(&from)?),
+                    DiffTarget::Tree(self.commit_tree(&to)?),
+                ))
+            }
+            [rev] if cached => Ok((tree_of(rev)?, DiffTarget::Index)),
+            [rev] => Ok((tree_of(rev)?, DiffTarget::Worktree)),
+            [from, to] => Ok((tree_of(from)?, tree_of(to)?)),
+            _ => Err(anyhow!("too many revisions")),
+        }
+    }
+
+    /// The files `mg diff` shows, between the sides `diff_sides` resolves,
+    /// converted by their textconv drivers.
+    pub fn diff(
+        &self,
+        revisions: &[String],
+        cached: bool,
+        pathspec: &Pathspec,
+    ) -> Result<Vec<FileDiff>> {
+        let (old, new) = self.diff_sides(revisions, cached)?;
+        if let DiffTarget::Worktree = new {
+            self.refresh_index()?;
+        }
+
+        let mut diffs = self.diff_targets_matching(old, new, pathspec)?;
+        self.apply_textconv(&mut diffs)?;
+        Ok(diffs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_hunks() {
+        let old = vec!["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+        let new = vec!["a", "B", "c", "d", "e", "f", "g", "h", "i", "j", "k"];
+
+        let hunks = make_hunks(&old, &new, 3);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunks[0].header(), "@@ -1,5 +1,5 @@");
+        assert_eq!(
+            &hunks[0].lines[..3],
+            &[
+                DiffLine::Context("a".to_string()),
+                DiffLine::Removed("b".to_string()),
+                DiffLine::Added("B".to_string()),
+            ]
+        );
+        assert_eq!(hunks[1].header(), "@@ -8,3 +8,4 @@");
+
+        let hunks = make_hunks(&[], &["x", "y"], 3);
+        assert_eq!(hunks[0].header(), "@@ -0,0 +1,2 @@");
+    }
+
+    #[test]
+    fn test_diff_is_shortest() {
+        // small alphabets make many matches, and many ways to align them
+        let mut seed = 42u64;
+        let mut random = |bound: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % bound
+        };
+        for _ in 0..2000 {
+            let alphabet = random(4) + 1;
+            let a: Vec<u64> = (0..random(16)).map(|_| random(alphabet)).collect();
+            let b: Vec<u64> = (0..random(16)).map(|_| random(alphabet)).collect();
+            let edits = diff(&a, &b);
+
+            let (mut x, mut y) = (0, 0);
+            for edit in &edits {
+                match *edit {
+                    Edit::Equal(i, j) => {
+                        assert_eq!((i, j), (x, y));
+                        assert_eq!(a[i], b[j]);
+                        x += 1;
+                        y += 1;
+                    }
+                    Edit::Delete(i) => {
+                        assert_eq!(i, x);
+                        x += 1;
+                    }
+                    Edit::Insert(j) => {
+                        assert_eq!(j, y);
+                        y += 1;
+                    }
+                }
+            }
+            assert_eq!((x, y), (a.len(), b.len()));
+
+            let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+            for i in (0..a.len()).rev() {
+                for j in (0..b.len()).rev() {
+                    lcs[i][j] = if a[i] == b[j] {
+                        lcs[i + 1][j + 1] + 1
+                    } else {
+                        lcs[i + 1][j].max(lcs[i][j + 1])
+                    };
+                }
+            }
+            let equal = edits
+                .iter()
+                .filter(|edit| matches!(edit, Edit::Equal(..)))
+                .count();
+            assert_eq!(equal, lcs[0][0], "{:?} {:?}", a, b);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{config::Config, diff::DiffTarget, repository::Repository};
+
+/// Commands of the tools known without configuration, as `(name, diff, merge,
+/// trust exit code)`. They are run by `sh` with `$LOCAL`, `$REMOTE`, `$BASE`
+/// and `$MERGED` set.
+const PRESETS: &[(&str, &str, &str, bool)] = &[
+    (
+        "vimdiff",
+        r#"vimdiff "$LOCAL" "$REMOTE""#,
+        r#"vimdiff -f -d -c 'wincmd J' "$MERGED" "$LOCAL" "$BASE" "$REMOTE""#,
+        false,
+    ),
+    (
+        "nvimdiff",
+        r#"nvim -d "$LOCAL" "$REMOTE""#,
+        r#"nvim -d -c 'wincmd J' "$MERGED" "$LOCAL" "$BASE" "$REMOTE""#,
+        false,
+    ),
+    (
+        "meld",
+        r#"meld "$LOCAL" "$REMOTE""#,
+        r#"meld "$LOCAL" "$MERGED" "$REMOTE" --output "$MERGED""#,
+        false,
+    ),
+    (
+        "kdiff3",
+        r#"kdiff3 --L1 "$MERGED (A)" --L2 "$MERGED (B)" "$LOCAL" "$REMOTE""#,
+        r#"kdiff3 --auto --L1 "$MERGED (Base)" --L2 "$MERGED (Local)" --L3 "$MERGED (Remote)" -o "$MERGED" "$BASE" "$LOCAL" "$REMOTE""#,
+        true,
+    ),
+    (
+        "vscode",
+        r#"code --wait --diff "$LOCAL" "$REMOTE""#,
+        r#"code --wait --merge "$REMOTE" "$LOCAL" "$BASE" "$MERGED""#,
+        true,
+    ),
+    (
+        "opendiff",
+        r#"opendiff "$LOCAL" "$REMOTE" | cat"#,
+        r#"opendiff "$LOCAL" "$REMOTE" -ancestor "$BASE" -merge "$MERGED" | cat"#,
+        false,
+    ),
+];
+
+/// The command line of a tool and whether its exit status tells if a merge
+/// was resolved.
+struct Tool {
+    name: String,
+    cmd: String,
+    trust_exit_code: bool,
+}
+
+/// Find the tool to use: `tool` if given, else `<kind>.tool` (with `diff.tool`
+/// falling back to `merge.tool`), configured with `<kind>tool.<name>.cmd` or
+/// taken from the presets.
+fn find_tool(config: &Config, kind: &str, tool: Option<&str>) -> Result<Tool> {
+    let name = match tool {
+        Some(name) => name.to_string(),
+        None => config
+            .get(&format!("{}.tool", kind))
+            .or_else(|| config.get("merge.tool"))
+            .ok_or_else(|| anyhow!("no tool configured, set {}.tool or use --tool", kind))?,
+    };
+
+    let preset = PRESETS.iter().find(|(preset, ..)| *preset == name);
+    let cmd = config
+        .get(&format!("{}tool.{}.cmd", kind, name))
+        .or_else(|| {
+            preset.map(|(_, diff, merge, _)| if kind == "diff" { diff } else { merge }.to_string())
+        })
+        .ok_or_else(|| anyhow!("unknown tool '{}', set {}tool.{}.cmd", name, kind, name))?;
+    let trust_exit_code = config
+        .get_bool(&format!("{}tool.{}.trustexitcode", kind, name))
+        .unwrap_or_else(|| preset.is_some_and(|(.., trust)| *trust));
+
+    Ok(Tool {
+        name,
+        cmd,
+        trust_exit_code,
+    })
+}
+
+fn run_tool(tool: &Tool, vars: &[(&str, &Path)]) -> Result<process::ExitStatus> {
+    let mut command = process::Command::new("sh");
+    command.arg("-c").arg(&tool.cmd);
+    for (name, path) in vars {
+        command.env(name, path);
+    }
+
+    command
+        .status()
+        .map_err(|e| anyhow!("could not launch '{}': {}", tool.name, e))
+}
+
+fn prompt(question: &str) -> Result<bool> {
+    print!("{} ", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(!answer.trim().to_lowercase().starts_with('n'))
+}
+
+/// A temporary file named after `path`, e.g. `dir/file_LOCAL_1234.rs`.
+fn temp_path(dir: &Path, path: &str, label: &str) -> PathBuf {
+    let file = Path::new(path);
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match file.extension() {
+        Some(ext) => format!(
+            "{}_{}_{}.{}",
+            stem,
+            label,
+            process::id(),
+            ext.to_string_lossy()
+        ),
+        None => format!("{}_{}_{}", stem, label, process::id()),
+    };
+    dir.join(name)
+}
+
+impl Repository {
+    /// Open each changed file in an external diff tool.
+    pub fn difftool(
+        &self,
+        revisions: &[String],
+        cached: bool,
+        tool: Option<&str>,
+        no_prompt: bool,
+        trust_exit_code: bool,
+    ) -> Result<()> {
+        let config = self.config()?;
+        let tool = find_tool(&config, "diff", tool)?;
+        let no_prompt = no_prompt || config.get_bool("difftool.prompt") == Some(false);
+
+        let (old, new) = self.diff_sides(revisions, cached)?;
+        let temp_dir = std::env::temp_dir().join(format!("mg-difftool-{}", process::id()));
+        fs::create_dir_all(&temp_dir)?;
+
+        let result = (|| -> Result<()> {
+            for file_diff in self.diff_targets(old, new)? {
+                let path = &file_diff.path;
+                if !no_prompt
+                    && !prompt(&format!(
+                        "Viewing: '{}'\nLaunch '{}' [Y/n]?",
+                        path, tool.name
+                    ))?
+                {
+                    continue;
+                }
+
+                let local = temp_path(&temp_dir, path, "LOCAL");
+                fs::write(&local, &file_diff.old_content)?;
+
+                // the worktree file is opened directly so it can be edited
+                let remote = match new {
+                    DiffTarget::Worktree if file_diff.new.is_some() => self.path.join(path),
+                    _ => {
+                        let remote = temp_path(&temp_dir, path, "REMOTE");
+                        fs::write(&remote, &file_diff.new_content)?;
+                        remote
+                    }
+                };
+
+                let status = run_tool(
+                    &tool,
+                    &[
+                        ("LOCAL", &local),
+                        ("REMOTE", &remote),
+                        ("MERGED", Path::new(path)),
+                        ("BASE", Path::new(path)),
+                    ],
+                )?;
+                if trust_exit_code && !status.success() {
+                    return Err(anyhow!("'{}' exited with {}", tool.name, status));
+                }
+            }
+            Ok(())
+        })();
+
+        fs::remove_dir_all(&temp_dir)?;
+        result
+    }
+
+    /// Run an external merge tool on each conflicted path of the index, and
+    /// mark the paths it resolves as such. Returns whether every conflict was
+    /// resolved.
+    pub fn mergetool(&self, paths: &[String], tool: Option<&str>, no_prompt: bool) -> Result<bool> {
+        let config = self.config()?;
+        let tool = find_tool(&config, "merge", tool)?;
+        let no_prompt = no_prompt || config.get_bool("mergetool.prompt") == Some(false);
+        let keep_backup = config.get_bool("mergetool.keepbackup").unwrap_or(true);
+
+        // base, local and remote blobs of each conflicted path
+        let mut conflicts: BTreeMap<String, [Option<[u8; 20]>; 3]> = BTreeMap::new();
+        for entry in self.load_index()?.entries {
+            let stage = entry.stage() as usize;
+            if stage > 0 && (paths.is_empty() || paths.contains(&entry.file_path)) {
+                conflicts.entry(entry.file_path).or_default()[stage - 1] = Some(entry.sha1);
+            }
+        }
+
+        if conflicts.is_empty() {
+            println!("No files need merging");
+            return Ok(true);
+        }
+
+        let mut all_resolved = true;
+        for (path, stages) in &conflicts {
+            if !no_prompt && !prompt(&format!("Normal merge conflict for '{}':\nHit return to start merge resolution tool ({}):", path, tool.name))? {
+                all_resolved = false;
+                continue;
+            }
+
+            let merged = self.path.join(path);
+            let dir = merged.parent().unwrap_or(&self.path).to_path_buf();
+
+            let mut temp_files = Vec::new();
+            for (label, blob) in ["BASE", "LOCAL", "REMOTE"].iter().zip(stages) {
+                let temp = temp_path(&dir, path, label);
+                let content = match blob {
+                    Some(hash) => self.read_object(&hex::encode(hash))?.content()?,
+                    None => Vec::new(),
+                };
+                fs::write(&temp, content)?;
+                temp_files.push(temp);
+            }
+
+            let before = fs::read(&merged).unwrap_or_default();
+            let status = run_tool(
+                &tool,
+                &[
+                    ("BASE", &temp_files[0]),
+                    ("LOCAL", &temp_files[1]),
+                    ("REMOTE", &temp_files[2]),
+                    ("MERGED", &merged),
+                ],
+            );
+            for temp in &temp_files {
+                fs::remove_file(temp)?;
+            }
+            let status = status?;
+
+            // without a trusted exit status, an untouched file is not resolved
+            let resolved = if tool.trust_exit_code {
+                status.success()
+            } else {
+                status.success() && fs::read(&merged).unwrap_or_default() != before
+            };
+
+            if !resolved {
+                println!("merge of {} failed", path);
+                all_resolved = false;
+                continue;
+            }
+
+            if keep_backup {
+                let mut backup = merged.clone().into_os_string();
+                backup.push(".orig");
+                fs::write(backup, &before)?;
+            }
+            self.mark_resolved(path)?;
+        }
+
+        Ok(all_resolved)
+    }
+}
@@ -1,7 +1,65 @@
+use std::io;
+
 use thiserror::Error;
 
+/// The failures callers of the library may want to tell apart. They travel
+/// inside `anyhow::Error`, with context added on the way up, and are found
+/// again with [`MgError::find`].
 #[derive(Error, Debug)]
-pub enum RuntimeError {
-    #[error("Invalid character found")]
-    UnexpectedChar,
+pub enum MgError {
+    #[error("{0}")]
+    NotARepository(String),
+    #[error("object {0} not found")]
+    ObjectNotFound(String),
+    #[error("object {object} is corrupt: {reason}")]
+    CorruptObject { object: String, reason: String },
+    #[error("unknown revision: {0}")]
+    InvalidRef(String),
+    #[error("{0}")]
+    PackFormat(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Options or arguments a command cannot take
+    #[error("{0}")]
+    Usage(String),
+}
+
+impl MgError {
+    /// The typed error `error` was raised with, if any.
+    pub fn find(error: &anyhow::Error) -> Option<&MgError> {
+        error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<MgError>())
+    }
+
+    /// The exit code of a command failing with `error`, as git's: 129 for
+    /// a usage error, else 128. Commands which answer no, such as `grep`
+    /// finding nothing, exit with 1 instead.
+    pub fn exit_code(error: &anyhow::Error) -> i32 {
+        match MgError::find(error) {
+            Some(MgError::Usage(_)) => 129,
+            _ => 128,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_find_through_context() {
+        let error = Err::<(), _>(MgError::InvalidRef("nope".to_string()))
+            .context("reading HEAD")
+            .unwrap_err();
+        assert!(matches!(
+            MgError::find(&error),
+            Some(MgError::InvalidRef(_))
+        ));
+        assert_eq!(MgError::exit_code(&error), 128);
+        assert_eq!(MgError::exit_code(&anyhow::anyhow!("other")), 128);
+        let usage = MgError::Usage("bad option".to_string()).into();
+        assert_eq!(MgError::exit_code(&usage), 129);
+    }
 }
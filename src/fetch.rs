@@ -0,0 +1,313 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use hex::FromHex;
+use reqwest::Url;
+use tokio::{
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    credential::strip_userinfo,
+    http::{fetch_pack, list_remote_refs},
+    repository::Repository,
+    shallow::{ShallowRequest, INFINITE_DEPTH},
+    transport::{IpFamily, TransportOptions},
+};
+
+/// How a fetch moves the shallow boundary of a shallow repository.
+#[derive(Debug, Clone, Copy)]
+pub enum Deepen {
+    /// Fetch this many more commits past the boundary
+    By(u32),
+    /// Fetch the whole history, leaving the repository complete
+    Unshallow,
+}
+
+/// A ref of the remote and the local ref it is fetched into.
+struct RefUpdate {
+    remote: String,
+    local: String,
+    old: Option<[u8; 20]>,
+    new: [u8; 20],
+}
+
+impl RefUpdate {
+    /// The summary line of the update, as `git fetch` prints it.
+    fn summary(&self, forced: bool) -> String {
+        let short = |name: &str| {
+            name.strip_prefix("refs/heads/")
+                .or_else(|| name.strip_prefix("refs/tags/"))
+                .or_else(|| name.strip_prefix("refs/remotes/"))
+                .unwrap_or(name)
+                .to_string()
+        };
+        let (flag, what) = match self.old {
+            None if self.remote.starts_with("refs/tags/") => ('*', "[new tag]".to_string()),
+            None => ('*', "[new branch]".to_string()),
+            Some(old) if forced => (
+                '+',
+                format!(
+                    "{}...{}",
+                    &hex::encode(old)[..7],
+                    &hex::encode(self.new)[..7]
+                ),
+            ),
+            Some(old) => (
+                ' ',
+                format!(
+                    "{}..{}",
+                    &hex::encode(old)[..7],
+                    &hex::encode(self.new)[..7]
+                ),
+            ),
+        };
+
+        let mut line = format!(
+            " {} {:<17} {:<10} -> {}",
+            flag,
+            what,
+            short(&self.remote),
+            short(&self.local)
+        );
+        if forced {
+            line.push_str("  (forced update)");
+        }
+        line
+    }
+}
+
+impl Repository {
+    /// The remotes configured with a url, in configuration order.
+    pub fn remotes(&self) -> Result<Vec<String>> {
+        let config = self.config()?;
+        Ok(config
+            .subsections("remote")
+            .into_iter()
+            .filter(|remote| config.get(&format!("remote.{}.url", remote)).is_some())
+            .collect())
+    }
+
+    /// The local refs to update for the refs advertised by `remote`: its
+    /// branches go under `refs/remotes/<remote>/`, and the tags missing
+    /// locally are added.
+    fn ref_updates(&self, remote: &str, refs: &[(String, String)]) -> Result<Vec<RefUpdate>> {
+        let mut updates = Vec::new();
+        for (name, hash) in refs {
+            let local = if let Some(branch) = name.strip_prefix("refs/heads/") {
+                format!("refs/remotes/{}/{}", remote, branch)
+            } else if name.starts_with("refs/tags/") && !name.ends_with("^{}") {
+                if self.read_ref(name)?.is_some() {
+                    continue;
+                }
+                name.clone()
+            } else {
+                continue;
+            };
+
+            let new = <[u8; 20]>::from_hex(hash)?;
+            let old = self.read_ref(&local)?;
+            if old != Some(new) {
+                updates.push(RefUpdate {
+                    remote: name.clone(),
+                    local,
+                    old,
+                    new,
+                });
+            }
+        }
+        Ok(updates)
+    }
+
+    /// Keep a fetched pack as it is, indexed, rather than as loose objects.
+    fn store_pack(&self, pack_data: &[u8]) -> Result<()> {
+        let (temp, idx) = self.receive_pack(pack_data)?;
+        self.install_pack(&temp, &idx, false)?;
+
+        Ok(())
+    }
+}
+
+/// Fetch one remote and update its refs, returning the lines to report.
+async fn fetch_remote(
+    repository: &Repository,
+    remote: &str,
+    unpack_lock: &Mutex<()>,
+    options: &TransportOptions,
+    deepen: Option<Deepen>,
+) -> Result<Vec<String>> {
+    let url = repository
+        .config()?
+        .get(&format!("remote.{}.url", remote))
+        .ok_or_else(|| anyhow!("no url configured for remote '{}'", remote))?;
+
+    let refs = list_remote_refs(&url, &[], options).await?;
+    let updates = repository.ref_updates(remote, &refs)?;
+    if updates.is_empty() && deepen.is_none() {
+        return Ok(Vec::new());
+    }
+
+    let mut wants: BTreeSet<String> = updates
+        .iter()
+        .filter(|update| !repository.has_object(&update.new))
+        .map(|update| hex::encode(update.new))
+        .collect();
+    // the history behind the branches already fetched grows too
+    if deepen.is_some() {
+        wants.extend(
+            refs.iter()
+                .filter(|(name, _)| name.starts_with("refs/heads/"))
+                .map(|(_, hash)| hash.clone()),
+        );
+    }
+    let shallow = ShallowRequest {
+        shallow: repository
+            .shallow_commits()?
+            .iter()
+            .map(hex::encode)
+            .collect(),
+        depth: match deepen {
+            Some(Deepen::By(depth)) => Some(depth),
+            Some(Deepen::Unshallow) => Some(INFINITE_DEPTH),
+            None => None,
+        },
+        relative: matches!(deepen, Some(Deepen::By(_))),
+    };
+    if !wants.is_empty() {
+        let haves: BTreeSet<String> = repository
+            .list_refs("refs/")?
+            .iter()
+            .map(|(_, hash)| hex::encode(hash))
+            .collect();
+        let wants: Vec<String> = wants.into_iter().collect();
+        let haves: Vec<String> = haves.into_iter().collect();
+        let (_, pack_data, shallow_info) =
+            fetch_pack(&url, &wants, &haves, &shallow, None, options).await?;
+
+        // the remotes sharing objects and the shallow file take turns
+        // storing them
+        let _guard = unpack_lock.lock().await;
+        tokio::task::block_in_place(|| -> Result<()> {
+            if !pack_data.is_empty() {
+                repository.store_pack(&pack_data)?;
+            }
+            // the new parents are only walked once their objects are in
+            repository.update_shallow(&shallow_info)
+        })?;
+    }
+
+    let mut lines = Vec::new();
+    for update in &updates {
+        // another remote may have fetched the same tag meanwhile
+        if repository.read_ref(&update.local)? == Some(update.new) {
+            continue;
+        }
+        let forced = match update.old {
+            Some(old) if repository.has_object(&old) => {
+                !repository.ancestors(&update.new)?.contains(&old)
+            }
+            _ => false,
+        };
+        repository.update_ref(&update.local, &update.new)?;
+        lines.push(update.summary(forced));
+    }
+    if !lines.is_empty() {
+        // credentials in the url are not shown
+        let shown = match Url::parse(&url) {
+            Ok(parsed) => strip_userinfo(&parsed).to_string(),
+            Err(_) => url,
+        };
+        lines.insert(0, format!("From {}", shown));
+    }
+
+    Ok(lines)
+}
+
+/// Fetch `remotes` (`origin` by default, every remote with `all`), up to
+/// `jobs` of them at a time (`fetch.parallel` by default, 0 meaning one per
+/// CPU), over `ip_family` if given, moving the shallow boundary as `deepen`
+/// says. Each remote reports its updates once done, and a failing remote
+/// does not stop the others. Returns whether every fetch succeeded.
+pub async fn fetch(
+    repository: Repository,
+    remotes: Vec<String>,
+    all: bool,
+    jobs: Option<usize>,
+    ip_family: Option<IpFamily>,
+    deepen: Option<Deepen>,
+) -> Result<bool> {
+    if deepen.is_some() && repository.shallow_commits()?.is_empty() {
+        return Err(match deepen {
+            Some(Deepen::Unshallow) => {
+                anyhow!("--unshallow on a complete repository does not make sense")
+            }
+            _ => anyhow!("--deepen on a complete repository does not make sense"),
+        });
+    }
+
+    let remotes = match (all, remotes.is_empty()) {
+        (true, true) => repository.remotes()?,
+        (true, false) => return Err(anyhow!("fetch --all does not take a remote")),
+        (false, true) => vec!["origin".to_string()],
+        (false, false) => remotes,
+    };
+    let jobs = match jobs {
+        Some(jobs) => jobs,
+        None => match repository.config()?.get_int("fetch.parallel")? {
+            Some(jobs) => usize::try_from(jobs)?,
+            None => 1,
+        },
+    };
+    let jobs = match jobs {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        jobs => jobs,
+    };
+
+    let verbose = remotes.len() > 1;
+    let mut options = repository.transport_options(ip_family)?;
+    // the downloads of remotes fetched together would garble each other's
+    // progress
+    if verbose && jobs > 1 {
+        options.progress = false;
+    }
+    let options = Arc::new(options);
+    let repository = Arc::new(repository);
+    let semaphore = Arc::new(Semaphore::new(jobs));
+    let unpack_lock = Arc::new(Mutex::new(()));
+
+    let mut tasks = JoinSet::new();
+    for remote in remotes {
+        let repository = repository.clone();
+        let semaphore = semaphore.clone();
+        let unpack_lock = unpack_lock.clone();
+        let options = options.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire().await?;
+            if verbose {
+                println!("Fetching {}", remote);
+            }
+            fetch_remote(&repository, &remote, &unpack_lock, &options, deepen)
+                .await
+                .map_err(|e| anyhow!("could not fetch {}: {}", remote, e))
+        });
+    }
+
+    let mut success = true;
+    while let Some(result) = tasks.join_next().await {
+        match result? {
+            // the lines of a remote are printed together, whatever the others do
+            Ok(lines) => {
+                if !lines.is_empty() {
+                    println!("{}", lines.join("\n"));
+                }
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                success = false;
+            }
+        }
+    }
+
+    Ok(success)
+}
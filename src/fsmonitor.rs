@@ -0,0 +1,446 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::repository::Repository;
+
+/// What changed in the worktree since a token returned by an earlier query.
+pub struct FsChanges {
+    /// The token to query the changes since now, if the monitor gave one
+    pub token: Option<String>,
+    /// The changed paths, relative to the worktree (directories end with
+    /// `/`), or `None` if anything may have changed
+    pub paths: Option<Vec<String>>,
+}
+
+impl FsChanges {
+    fn everything(token: Option<String>) -> FsChanges {
+        FsChanges { token, paths: None }
+    }
+}
+
+/// A source of worktree changes, sparing commands a scan of the worktree.
+pub trait FsMonitor {
+    fn changes_since(&mut self, token: Option<&str>) -> Result<FsChanges>;
+}
+
+/// An fsmonitor hook (`core.fsmonitor` set to an executable) speaking
+/// version 2 of the hook protocol: called with `2 <token>`, it prints the new
+/// token then the changed paths, NUL-terminated, `/` meaning everything.
+pub struct HookMonitor {
+    hook: PathBuf,
+    worktree: PathBuf,
+}
+
+impl FsMonitor for HookMonitor {
+    fn changes_since(&mut self, token: Option<&str>) -> Result<FsChanges> {
+        let output = Command::new(&self.hook)
+            .args(["2", token.unwrap_or("")])
+            .current_dir(&self.worktree)
+            .output()
+            .map_err(|e| anyhow!("could not run fsmonitor hook: {}", e))?;
+        // a failing hook leaves the worktree to be scanned
+        if !output.status.success() {
+            return Ok(FsChanges::everything(None));
+        }
+
+        let output = String::from_utf8(output.stdout)?;
+        let mut fields = output.split('\0');
+        let new_token = fields.next().filter(|t| !t.is_empty()).map(String::from);
+
+        let paths: Vec<String> = fields
+            .filter(|path| !path.is_empty())
+            .map(String::from)
+            .collect();
+        if token.is_none() || paths.iter().any(|path| path == "/") {
+            return Ok(FsChanges::everything(new_token));
+        }
+
+        Ok(FsChanges {
+            token: new_token,
+            paths: Some(paths),
+        })
+    }
+}
+
+/// The NTFS change journal of the volume holding the worktree, which keeps
+/// the changes of every file without a process watching them. Tokens are
+/// `usn:<journal id>:<next update sequence number>`.
+#[cfg(windows)]
+pub struct UsnJournalMonitor {
+    worktree: PathBuf,
+}
+
+#[cfg(windows)]
+impl FsMonitor for UsnJournalMonitor {
+    fn changes_since(&mut self, token: Option<&str>) -> Result<FsChanges> {
+        let worktree = fs::canonicalize(&self.worktree)?;
+        let journal = match usn::Journal::open(&worktree) {
+            Ok(journal) => journal,
+            // the journal needs NTFS and the rights to read it
+            Err(_) => return Ok(FsChanges::everything(None)),
+        };
+        let new_token = format!("usn:{:x}:{}", journal.id, journal.next_usn);
+
+        let since = token.and_then(|token| {
+            let mut fields = token.strip_prefix("usn:")?.split(':');
+            let id = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let usn = fields.next()?.parse::<i64>().ok()?;
+            // the journal was recreated, or the changes were purged from it
+            (id == journal.id && usn >= journal.first_usn).then_some(usn)
+        });
+        let Some(since) = since else {
+            return Ok(FsChanges::everything(Some(new_token)));
+        };
+
+        let Some(paths) = journal.changed_paths(since, &worktree)? else {
+            return Ok(FsChanges::everything(Some(new_token)));
+        };
+        Ok(FsChanges {
+            token: Some(new_token),
+            paths: Some(paths),
+        })
+    }
+}
+
+#[cfg(windows)]
+mod usn {
+    use std::{
+        collections::HashMap,
+        ffi::{c_void, OsString},
+        os::windows::ffi::{OsStrExt, OsStringExt},
+        path::{Component, Path, Prefix},
+        ptr,
+    };
+
+    use anyhow::{anyhow, Result};
+
+    type Handle = *mut c_void;
+
+    const INVALID_HANDLE_VALUE: Handle = -1isize as Handle;
+    const GENERIC_READ: u32 = 0x8000_0000;
+    const FILE_SHARE_READ_WRITE: u32 = 0x1 | 0x2;
+    const OPEN_EXISTING: u32 = 3;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    const FSCTL_QUERY_USN_JOURNAL: u32 = 0x0009_00f4;
+    const FSCTL_READ_USN_JOURNAL: u32 = 0x0009_00bb;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct UsnJournalData {
+        usn_journal_id: u64,
+        first_usn: i64,
+        next_usn: i64,
+        lowest_valid_usn: i64,
+        max_usn: i64,
+        maximum_size: u64,
+        allocation_delta: u64,
+    }
+
+    #[repr(C)]
+    struct ReadUsnJournalData {
+        start_usn: i64,
+        reason_mask: u32,
+        return_only_on_close: u32,
+        timeout: u64,
+        bytes_to_wait_for: u64,
+        usn_journal_id: u64,
+    }
+
+    #[repr(C)]
+    struct FileIdDescriptor {
+        size: u32,
+        id_type: u32,
+        file_id: u64,
+        // the union with the 128-bit id
+        _padding: u64,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateFileW(
+            name: *const u16,
+            access: u32,
+            share: u32,
+            security: *mut c_void,
+            disposition: u32,
+            flags: u32,
+            template: Handle,
+        ) -> Handle;
+        fn DeviceIoControl(
+            device: Handle,
+            code: u32,
+            input: *const c_void,
+            input_size: u32,
+            output: *mut c_void,
+            output_size: u32,
+            returned: *mut u32,
+            overlapped: *mut c_void,
+        ) -> i32;
+        fn OpenFileById(
+            volume: Handle,
+            id: *const FileIdDescriptor,
+            access: u32,
+            share: u32,
+            security: *mut c_void,
+            flags: u32,
+        ) -> Handle;
+        fn GetFinalPathNameByHandleW(file: Handle, path: *mut u16, size: u32, flags: u32) -> u32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    struct OwnedHandle(Handle);
+
+    impl Drop for OwnedHandle {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+
+    pub struct Journal {
+        volume: OwnedHandle,
+        pub id: u64,
+        pub first_usn: i64,
+        pub next_usn: i64,
+    }
+
+    impl Journal {
+        /// Open the change journal of the volume holding `path`.
+        pub fn open(path: &Path) -> Result<Journal> {
+            let letter = match path.components().next() {
+                Some(Component::Prefix(prefix)) => match prefix.kind() {
+                    Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => letter as char,
+                    _ => return Err(anyhow!("{} is not on a local disk", path.display())),
+                },
+                _ => return Err(anyhow!("{} has no drive", path.display())),
+            };
+
+            let name: Vec<u16> = format!("\\\\.\\{}:", letter)
+                .encode_utf16()
+                .chain(Some(0))
+                .collect();
+            let volume = unsafe {
+                CreateFileW(
+                    name.as_ptr(),
+                    GENERIC_READ,
+                    FILE_SHARE_READ_WRITE,
+                    ptr::null_mut(),
+                    OPEN_EXISTING,
+                    0,
+                    ptr::null_mut(),
+                )
+            };
+            if volume == INVALID_HANDLE_VALUE {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let volume = OwnedHandle(volume);
+
+            let mut data = UsnJournalData::default();
+            let mut returned = 0;
+            let ok = unsafe {
+                DeviceIoControl(
+                    volume.0,
+                    FSCTL_QUERY_USN_JOURNAL,
+                    ptr::null(),
+                    0,
+                    &mut data as *mut _ as *mut c_void,
+                    std::mem::size_of::<UsnJournalData>() as u32,
+                    &mut returned,
+                    ptr::null_mut(),
+                )
+            };
+            if ok == 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            Ok(Journal {
+                volume,
+                id: data.usn_journal_id,
+                first_usn: data.first_usn,
+                next_usn: data.next_usn,
+            })
+        }
+
+        /// The path of a file or directory of the volume by its file
+        /// reference number, or `None` if it no longer exists.
+        fn path_of(&self, file_id: u64) -> Option<OsString> {
+            let descriptor = FileIdDescriptor {
+                size: std::mem::size_of::<FileIdDescriptor>() as u32,
+                id_type: 0,
+                file_id,
+                _padding: 0,
+            };
+            let handle = unsafe {
+                OpenFileById(
+                    self.volume.0,
+                    &descriptor,
+                    0,
+                    FILE_SHARE_READ_WRITE,
+                    ptr::null_mut(),
+                    FILE_FLAG_BACKUP_SEMANTICS,
+                )
+            };
+            if handle == INVALID_HANDLE_VALUE {
+                return None;
+            }
+            let handle = OwnedHandle(handle);
+
+            let mut buffer = vec![0u16; 32768];
+            let len = unsafe {
+                GetFinalPathNameByHandleW(handle.0, buffer.as_mut_ptr(), buffer.len() as u32, 0)
+            } as usize;
+            if len == 0 || len > buffer.len() {
+                return None;
+            }
+            Some(OsString::from_wide(&buffer[..len]))
+        }
+
+        /// The paths under `worktree` changed since `since`, relative to it,
+        /// or `None` if a change cannot be located.
+        pub fn changed_paths(&self, since: i64, worktree: &Path) -> Result<Option<Vec<String>>> {
+            let worktree: Vec<u16> = worktree.as_os_str().encode_wide().collect();
+            let mut directories: HashMap<u64, Option<OsString>> = HashMap::new();
+            let mut paths = Vec::new();
+
+            let mut request = ReadUsnJournalData {
+                start_usn: since,
+                reason_mask: 0xffff_ffff,
+                return_only_on_close: 0,
+                timeout: 0,
+                bytes_to_wait_for: 0,
+                usn_journal_id: self.id,
+            };
+            let mut buffer = vec![0u8; 64 * 1024];
+            while request.start_usn < self.next_usn {
+                let mut returned = 0u32;
+                let ok = unsafe {
+                    DeviceIoControl(
+                        self.volume.0,
+                        FSCTL_READ_USN_JOURNAL,
+                        &request as *const _ as *const c_void,
+                        std::mem::size_of::<ReadUsnJournalData>() as u32,
+                        buffer.as_mut_ptr() as *mut c_void,
+                        buffer.len() as u32,
+                        &mut returned,
+                        ptr::null_mut(),
+                    )
+                };
+                if ok == 0 {
+                    return Err(std::io::Error::last_os_error().into());
+                }
+                let returned = returned as usize;
+                if returned <= 8 {
+                    break;
+                }
+
+                // the next usn to read, followed by USN_RECORD_V2 records
+                let u16_at = |at: usize| u16::from_le_bytes([buffer[at], buffer[at + 1]]);
+                let u64_at = |at: usize| u64::from_le_bytes(buffer[at..at + 8].try_into().unwrap());
+                let mut offset = 8;
+                while offset + 60 <= returned {
+                    let length =
+                        u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap()) as usize;
+                    if length == 0 {
+                        break;
+                    }
+                    let parent = u64_at(offset + 16);
+                    let name_length = u16_at(offset + 56) as usize;
+                    let name_offset = u16_at(offset + 58) as usize;
+                    let name: Vec<u16> = (0..name_length / 2)
+                        .map(|i| u16_at(offset + name_offset + 2 * i))
+                        .collect();
+
+                    let directory = directories
+                        .entry(parent)
+                        .or_insert_with(|| self.path_of(parent));
+                    let Some(directory) = directory else {
+                        // a change in a deleted directory
+                        return Ok(None);
+                    };
+                    let mut path: Vec<u16> = directory.encode_wide().collect();
+                    path.push(b'\\' as u16);
+                    path.extend(name);
+
+                    if path.len() > worktree.len() + 1
+                        && path[..worktree.len()] == worktree[..]
+                        && path[worktree.len()] == b'\\' as u16
+                    {
+                        let relative =
+                            String::from_utf16(&path[worktree.len() + 1..])?.replace('\\', "/");
+                        if !paths.contains(&relative) {
+                            paths.push(relative);
+                        }
+                    }
+
+                    offset += length;
+                }
+                request.start_usn = u64_at(0) as i64;
+            }
+
+            Ok(Some(paths))
+        }
+    }
+}
+
+impl Repository {
+    /// The filesystem monitor of `core.fsmonitor`: `true` for the builtin
+    /// one (the change journal on Windows, none elsewhere yet) or the path of
+    /// a hook.
+    pub fn fsmonitor(&self) -> Result<Option<Box<dyn FsMonitor>>> {
+        let Some(value) = self.config()?.get("core.fsmonitor") else {
+            return Ok(None);
+        };
+
+        match value.to_lowercase().as_str() {
+            "false" | "no" | "off" | "0" | "" => Ok(None),
+            "true" | "yes" | "on" | "1" => Ok(builtin_monitor(&self.path)),
+            _ => Ok(Some(Box::new(HookMonitor {
+                hook: self.path.join(value),
+                worktree: self.path.clone(),
+            }))),
+        }
+    }
+
+    fn fsmonitor_token_path(&self) -> PathBuf {
+        self.git_dir.join("fsmonitor-token")
+    }
+
+    /// The paths changed since the index was last written, or `None` if the
+    /// worktree has to be scanned, with the token to record along with the
+    /// next index.
+    pub fn fsmonitor_changes(&self) -> Result<(Option<Vec<String>>, Option<String>)> {
+        let Some(mut monitor) = self.fsmonitor()? else {
+            return Ok((None, None));
+        };
+
+        let token = fs::read_to_string(self.fsmonitor_token_path()).ok();
+        let changes = monitor.changes_since(token.as_deref().map(str::trim))?;
+        Ok((changes.paths, changes.token))
+    }
+
+    pub fn save_fsmonitor_token(&self, token: Option<&str>) -> Result<()> {
+        let path = self.fsmonitor_token_path();
+        match token {
+            Some(token) => fs::write(path, format!("{}\n", token))?,
+            None if path.exists() => fs::remove_file(path)?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+fn builtin_monitor(worktree: &Path) -> Option<Box<dyn FsMonitor>> {
+    Some(Box::new(UsnJournalMonitor {
+        worktree: worktree.to_path_buf(),
+    }))
+}
+
+#[cfg(not(windows))]
+fn builtin_monitor(_worktree: &Path) -> Option<Box<dyn FsMonitor>> {
+    None
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use hex::FromHex;
+
+use crate::{pack::read_pack_index, repository::Repository};
+
+/// A pack of the repository and the objects it holds.
+pub struct Pack {
+    pub path: PathBuf,
+    pub objects: HashSet<[u8; 20]>,
+    /// Whether a `.keep` file protects the pack from removal
+    pub keep: bool,
+}
+
+/// The files making up the pack stored in `path`, its index first.
+fn pack_files(path: &Path) -> Vec<PathBuf> {
+    ["idx", "pack", "keep", "rev", "bitmap", "promisor"]
+        .iter()
+        .map(|extension| path.with_extension(extension))
+        .filter(|path| path.exists())
+        .collect()
+}
+
+impl Repository {
+    /// The indexed packs of the repository, by name.
+    pub fn packs(&self) -> Result<Vec<Pack>> {
+        let pack_dir = self.git_dir.join("objects").join("pack");
+        if !pack_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&pack_dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with("pack-")
+                && name.ends_with(".pack")
+                && path.with_extension("idx").is_file()
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        paths
+            .into_iter()
+            .map(|path| {
+                Ok(Pack {
+                    objects: read_pack_index(&path.with_extension("idx"))?
+                        .into_iter()
+                        .collect(),
+                    keep: path.with_extension("keep").exists(),
+                    path,
+                })
+            })
+            .collect()
+    }
+
+    /// The loose objects of the repository, with their paths.
+    fn loose_objects(&self) -> Result<Vec<([u8; 20], PathBuf)>> {
+        let objects_dir = self.git_dir.join("objects");
+        let mut objects = Vec::new();
+
+        for dir in fs::read_dir(&objects_dir)? {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().into_owned();
+            if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let file = file?;
+                let name = format!("{}{}", prefix, file.file_name().to_string_lossy());
+                if let Ok(hash) = <[u8; 20]>::from_hex(&name) {
+                    objects.push((hash, file.path()));
+                }
+            }
+        }
+        objects.sort();
+
+        Ok(objects)
+    }
+
+    /// Remove the loose objects which are also in a pack, and the object
+    /// directories left empty. With `dry_run`, print what would be removed
+    /// instead. Returns the number of objects removed.
+    pub fn prune_packed(&self, dry_run: bool) -> Result<usize> {
+        let packed: HashSet<[u8; 20]> = self
+            .packs()?
+            .into_iter()
+            .flat_map(|pack| pack.objects)
+            .collect();
+
+        let mut pruned = 0;
+        let mut dirs = HashSet::new();
+        for (hash, path) in self.loose_objects()? {
+            if !packed.contains(&hash) {
+                continue;
+            }
+            if dry_run {
+                let path = path.strip_prefix(&self.path).unwrap_or(&path);
+                println!("rm -f {}", path.display());
+            } else {
+                fs::remove_file(&path)?;
+                if let Some(dir) = path.parent() {
+                    dirs.insert(dir.to_path_buf());
+                }
+            }
+            pruned += 1;
+        }
+
+        for dir in dirs {
+            // fails when the directory still holds objects
+            let _ = fs::remove_dir(dir);
+        }
+
+        Ok(pruned)
+    }
+
+    /// The packs whose every object is also in the other packs kept, given
+    /// by the path of their `.pack` file. Packs are kept largest first, and
+    /// packs with a `.keep` file are never redundant.
+    pub fn redundant_packs(&self) -> Result<Vec<PathBuf>> {
+        let mut packs = self.packs()?;
+        packs.sort_by_key(|pack| (!pack.keep, std::cmp::Reverse(pack.objects.len())));
+
+        let mut covered = HashSet::new();
+        let mut redundant = Vec::new();
+        for pack in packs {
+            if !pack.keep && pack.objects.is_subset(&covered) {
+                redundant.push(pack.path);
+            } else {
+                covered.extend(pack.objects);
+            }
+        }
+        redundant.sort();
+
+        Ok(redundant)
+    }
+
+    /// Remove the redundant packs, returning how many were removed.
+    pub fn remove_redundant_packs(&self) -> Result<usize> {
+        let redundant = self.redundant_packs()?;
+        for pack in &redundant {
+            // the index goes first, so a pack is never indexed but missing
+            for file in pack_files(pack) {
+                fs::remove_file(file)?;
+            }
+        }
+
+        Ok(redundant.len())
+    }
+
+    /// Tidy the object store: drop the redundant packs, then the loose
+    /// objects which are packed.
+    pub fn gc(&self) -> Result<()> {
+        let packs = self.remove_redundant_packs()?;
+        let objects = self.prune_packed(false)?;
+        println!(
+            "Removed {} redundant pack{} and {} packed loose object{}",
+            packs,
+            if packs == 1 { "" } else { "s" },
+            objects,
+            if objects == 1 { "" } else { "s" }
+        );
+
+        Ok(())
+    }
+}
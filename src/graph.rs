@@ -0,0 +1,101 @@
+/// Draws the history as lanes of `*`, `|`, `/` and `\`, one commit at a
+/// time, as `log --graph` does.
+#[derive(Default)]
+pub struct Graph {
+    /// The commit expected next in each lane
+    columns: Vec<[u8; 20]>,
+}
+
+impl Graph {
+    /// The lines showing `commit`, with `text`, and then the edges from its
+    /// lane to the lanes of its parents.
+    pub fn lines(&mut self, commit: &[u8; 20], parents: &[[u8; 20]], text: &str) -> Vec<String> {
+        let col = match self.columns.iter().position(|c| c == commit) {
+            Some(col) => col,
+            None => {
+                self.columns.push(*commit);
+                self.columns.len() - 1
+            }
+        };
+
+        let old = std::mem::take(&mut self.columns);
+        let mut new: Vec<[u8; 20]> = Vec::new();
+        for (i, lane) in old.iter().enumerate() {
+            if i == col {
+                // a parent waiting in a lane to the right moves to this one,
+                // one waiting to the left stays there
+                for parent in parents {
+                    if !new.contains(parent) {
+                        new.push(*parent);
+                    }
+                }
+            } else if !new.contains(lane) {
+                new.push(*lane);
+            }
+        }
+
+        let mut edges = Vec::new();
+        for (i, lane) in old.iter().enumerate() {
+            let targets: Vec<&[u8; 20]> = if i == col {
+                parents.iter().collect()
+            } else {
+                vec![lane]
+            };
+            for target in targets {
+                if let Some(to) = new.iter().position(|c| c == target) {
+                    edges.push((i, to));
+                }
+            }
+        }
+
+        let width = old.len().max(new.len());
+        let mut row: String = (0..old.len())
+            .map(|i| if i == col { "* " } else { "| " })
+            .collect();
+        row.push_str(&" ".repeat(2 * (width - old.len())));
+        row.push_str(text);
+        let mut lines = vec![row];
+
+        // edges move by at most one lane per line
+        while edges.iter().any(|(from, to)| from != to) {
+            let mut row = vec![' '; 2 * width];
+            for (from, to) in edges.iter_mut() {
+                if *from == *to {
+                    row[2 * *from] = '|';
+                } else if *to > *from {
+                    row[2 * *from + 1] = '\\';
+                    *from += 1;
+                } else {
+                    row[2 * *from - 1] = '/';
+                    *from -= 1;
+                }
+            }
+            lines.push(row.into_iter().collect::<String>().trim_end().to_string());
+        }
+
+        self.columns = new;
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_graph() {
+        let (merge, main, side, base) = ([4; 20], [3; 20], [2; 20], [1; 20]);
+        let mut graph = Graph::default();
+
+        let mut lines = Vec::new();
+        lines.extend(graph.lines(&merge, &[main, side], "merge"));
+        lines.extend(graph.lines(&side, &[base], "side"));
+        lines.extend(graph.lines(&main, &[base], "main"));
+        lines.extend(graph.lines(&base, &[], "base"));
+
+        assert_eq!(
+            lines,
+            vec!["*   merge", "|\\", "| * side", "* | main", "|/", "* base"]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::Result;
+
+use crate::{commit::Signature, error::MgError, repository::Repository, rev_list::RevisionRoots};
+
+/// A commit of the exported graph.
+struct GraphCommit {
+    hash: [u8; 20],
+    parents: Vec<[u8; 20]>,
+    subject: String,
+    author: Signature,
+}
+
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Repository {
+    /// The commits reachable from the included revisions but not from the
+    /// excluded ones, as `revision_roots` gives them.
+    fn graph_commits(&self, revisions: &[String]) -> Result<Vec<GraphCommit>> {
+        let RevisionRoots { include, exclude } = self.revision_roots(revisions)?;
+
+        let mut excluded = HashSet::new();
+        for commit in &exclude {
+            excluded.extend(self.ancestors(commit)?);
+        }
+
+        let shallow = self.shallow_commits()?;
+        let mut seen = excluded;
+        let mut stack = include;
+        let mut commits = Vec::new();
+        while let Some(hash) = stack.pop() {
+            if !seen.insert(hash) {
+                continue;
+            }
+
+            let commit = self.read_commit(&hash)?;
+            let parents = if shallow.contains(&hash) {
+                Vec::new()
+            } else {
+                commit.parents.clone()
+            };
+            stack.extend(&parents);
+
+            commits.push(GraphCommit {
+                hash,
+                parents,
+                subject: commit.subject().to_string(),
+                author: commit.author,
+            });
+        }
+
+        // newest first
+        commits.sort_by_key(|c| std::cmp::Reverse(c.author.timestamp));
+        Ok(commits)
+    }
+
+    /// The refs (and HEAD) pointing at the given commits, by commit.
+    fn graph_refs(&self, commits: &HashSet<[u8; 20]>) -> Result<BTreeMap<String, [u8; 20]>> {
+        let mut refs = BTreeMap::new();
+        if let Some(head) = self.head_commit()? {
+            refs.insert("HEAD".to_string(), head);
+        }
+        for (name, mut hash) in self.list_refs("refs/")? {
+            while let Some(target) = self.peel_tag(&hash)? {
+                hash = target;
+            }
+            refs.insert(name, hash);
+        }
+
+        refs.retain(|_, hash| commits.contains(hash));
+        Ok(refs)
+    }
+
+    /// Print the commit graph of `revisions` with the refs pointing into it,
+    /// in Graphviz `dot` or `json` format.
+    pub fn graph_export(&self, revisions: &[String], format: &str) -> Result<()> {
+        let commits = self.graph_commits(revisions)?;
+        let in_graph: HashSet<[u8; 20]> = commits.iter().map(|c| c.hash).collect();
+        let refs = self.graph_refs(&in_graph)?;
+
+        match format {
+            "dot" => {
+                println!("digraph commits {{");
+                println!("  node [shape=box, fontname=\"monospace\"];");
+                for commit in &commits {
+                    let hash = hex::encode(commit.hash);
+                    let label = format!("{} {}", &hash[..7], commit.subject);
+                    println!("  {} [label={}];", dot_string(&hash), dot_string(&label));
+                }
+                for commit in &commits {
+                    // edges leaving the range are left out
+                    for parent in commit.parents.iter().filter(|p| in_graph.contains(*p)) {
+                        println!(
+                            "  {} -> {};",
+                            dot_string(&hex::encode(commit.hash)),
+                            dot_string(&hex::encode(parent))
+                        );
+                    }
+                }
+                for (name, hash) in &refs {
+                    let short = name
+                        .strip_prefix("refs/heads/")
+                        .or_else(|| name.strip_prefix("refs/"))
+                        .unwrap_or(name);
+                    println!(
+                        "  {} [label={}, shape=ellipse, style=filled, fillcolor=lightyellow];",
+                        dot_string(name),
+                        dot_string(short)
+                    );
+                    println!(
+                        "  {} -> {} [style=dashed];",
+                        dot_string(name),
+                        dot_string(&hex::encode(hash))
+                    );
+                }
+                println!("}}");
+            }
+            "json" => {
+                let commits: Vec<String> = commits
+                    .iter()
+                    .map(|commit| {
+                        let parents: Vec<String> = commit
+                            .parents
+                            .iter()
+                            .map(|p| json_string(&hex::encode(p)))
+                            .collect();
+                        let author = &commit.author;
+                        format!(
+                            "{{\"id\":{},\"parents\":[{}],\"author\":{},\"time\":{},\"subject\":{}}}",
+                            json_string(&hex::encode(commit.hash)),
+                            parents.join(","),
+                            json_string(&format!("{} <{}>", author.name, author.email)),
+                            author.timestamp,
+                            json_string(&commit.subject)
+                        )
+                    })
+                    .collect();
+                let refs: Vec<String> = refs
+                    .iter()
+                    .map(|(name, hash)| {
+                        format!(
+                            "{{\"name\":{},\"target\":{}}}",
+                            json_string(name),
+                            json_string(&hex::encode(hash))
+                        )
+                    })
+                    .collect();
+                println!(
+                    "{{\"commits\":[{}],\"refs\":[{}]}}",
+                    commits.join(","),
+                    refs.join(",")
+                );
+            }
+            _ => return Err(MgError::Usage(format!("unknown graph format '{}'", format)).into()),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use crate::{
+    index::list_all_files,
+    kind::Kind,
+    parallel::{default_jobs, parallel_map},
+    pathspec::Pathspec,
+    regex::Regex,
+    repository::Repository,
+};
+
+pub use crate::regex::Syntax;
+
+#[derive(Debug)]
+enum GrepSource {
+    Worktree(String),
+    Blob([u8; 20]),
+}
+
+#[derive(Debug)]
+struct GrepTarget {
+    /// name printed in front of matches, e.g. `path` or `rev:path`
+    name: String,
+    source: GrepSource,
+}
+
+#[derive(Debug, Default)]
+pub struct GrepOptions {
+    /// Whether the pattern is a basic or extended regular expression, or a
+    /// fixed string
+    pub syntax: Syntax,
+    pub ignore_case: bool,
+    pub line_number: bool,
+    pub files_with_matches: bool,
+    pub jobs: Option<usize>,
+    /// Only search the files this matches
+    pub pathspec: Pathspec,
+    /// In the worktree, search the untracked files too
+    pub untracked: bool,
+}
+
+impl Repository {
+    /// Search each line of the tracked files of the worktree (with the
+    /// untracked ones if asked), of the index (`cached`) or of the trees of
+    /// the given revisions for `pattern`. Returns whether anything matched.
+    pub fn grep(
+        &self,
+        pattern: &str,
+        cached: bool,
+        revisions: &[String],
+        options: &GrepOptions,
+    ) -> Result<bool> {
+        let mut targets = Vec::new();
+
+        if !revisions.is_empty() {
+            for rev in revisions {
+                let tree = self.commit_tree(&self.resolve_revision(rev)?)?;
+                for file in self.flatten_tree(&tree)? {
+                    if !options.pathspec.matches(&file.path) {
+                        continue;
+                    }
+                    if let Kind::Blob(_) = file.kind {
+                        targets.push(GrepTarget {
+                            name: format!("{}:{}", rev, file.path),
+                            source: GrepSource::Blob(file.hash),
+                        });
+                    }
+                }
+            }
+        } else if cached {
+            for entry in self.load_index()?.entries {
+                if !options.pathspec.matches(&entry.file_path) {
+                    continue;
+                }
+                targets.push(GrepTarget {
+                    name: entry.file_path,
+                    source: GrepSource::Blob(entry.sha1),
+                });
+            }
+        } else {
+            let mut files = Vec::new();
+            let mut tracked = HashSet::new();
+            for entry in self.load_index()?.entries {
+                // the stages of a conflict share their worktree file
+                if !tracked.insert(entry.file_path.clone())
+                    || entry.skip_worktree()
+                    || matches!(entry.kind(), Kind::Commit)
+                    || !self.path.join(&entry.file_path).is_file()
+                {
+                    continue;
+                }
+                files.push(entry.file_path);
+            }
+            if options.untracked {
+                for file in list_all_files(&self.path, &mut self.ignore_rules()?)? {
+                    if !tracked.contains(&file) {
+                        files.push(file);
+                    }
+                }
+                files.sort();
+            }
+            for file in files {
+                if !options.pathspec.matches(&file) {
+                    continue;
+                }
+                targets.push(GrepTarget {
+                    name: file.clone(),
+                    source: GrepSource::Worktree(file),
+                });
+            }
+        }
+
+        let regex = Regex::new(pattern, options.syntax, options.ignore_case)?;
+
+        let jobs = options.jobs.unwrap_or_else(default_jobs);
+
+        // the results are printed in the order of the targets
+        let results = parallel_map(&targets, jobs, |target| {
+            self.grep_target(target, &regex, options)
+        });
+
+        let mut matched = false;
+        for result in results {
+            for line in result? {
+                matched = true;
+                println!("{}", line);
+            }
+        }
+
+        Ok(matched)
+    }
+
+    fn grep_target(
+        &self,
+        target: &GrepTarget,
+        regex: &Regex,
+        options: &GrepOptions,
+    ) -> Result<Vec<String>> {
+        let content = match &target.source {
+            GrepSource::Worktree(path) => std::fs::read(self.path.join(path))?,
+            GrepSource::Blob(hash) => self.read_object(&hex::encode(hash))?.content()?,
+        };
+
+        let mut output = Vec::new();
+
+        if content.contains(&0) {
+            let haystack = String::from_utf8_lossy(&content);
+            if haystack.lines().any(|line| regex.is_match(line)) {
+                output.push(format!("Binary file {} matches", target.name));
+            }
+            return Ok(output);
+        }
+
+        let text = String::from_utf8_lossy(&content);
+        for (number, line) in text.lines().enumerate() {
+            if !regex.is_match(line) {
+                continue;
+            }
+
+            if options.files_with_matches {
+                output.push(target.name.clone());
+                break;
+            }
+
+            if options.line_number {
+                output.push(format!("{}:{}:{}", target.name, number + 1, line));
+            } else {
+                output.push(format!("{}:{}", target.name, line));
+            }
+        }
+
+        Ok(output)
+    }
+}
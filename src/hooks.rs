@@ -0,0 +1,100 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{repository::Repository, trace2};
+
+impl Repository {
+    /// The hooks directory, `core.hooksPath` or `.git/hooks`.
+    fn hooks_dir(&self) -> Result<PathBuf> {
+        Ok(match self.config()?.get("core.hookspath") {
+            Some(path) => self.path.join(path),
+            None => self.git_dir.join("hooks"),
+        })
+    }
+
+    /// The hook `name`, if it is installed. A hook which is not executable
+    /// is ignored, with a hint.
+    fn find_hook(&self, name: &str) -> Result<Option<PathBuf>> {
+        let path = self.hooks_dir()?.join(name);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if path.metadata()?.permissions().mode() & 0o111 == 0 {
+                eprintln!(
+                    "hint: The '{}' hook was ignored because it's not set as executable.",
+                    name
+                );
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(path))
+    }
+
+    /// Run the hook `name`, if installed, from the top of the worktree with
+    /// `args` and `input` on its standard input. Fails if the hook exits with
+    /// a non-zero status.
+    pub fn run_hook(&self, name: &str, args: &[&str], input: &[u8]) -> Result<()> {
+        let Some(hook) = self.find_hook(name)? else {
+            return Ok(());
+        };
+
+        let hook_arg = hook.to_string_lossy();
+        let mut argv = vec![hook_arg.as_ref()];
+        argv.extend_from_slice(args);
+        let traced = trace2::child_start("hook", Some(name), false, &argv);
+        let mut child = Command::new(&hook)
+            .args(args)
+            .current_dir(&self.path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("could not run the {} hook: {}", name, e))?;
+
+        // a hook is free not to read its input
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let written = stdin.write_all(input);
+        drop(stdin);
+
+        let status = child.wait()?;
+        traced.exit(child.id(), &status);
+        if !status.success() {
+            return Err(anyhow!("the {} hook declined", name));
+        }
+        match written {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run the `pre-push` hook for a push of `updates`, given as
+    /// `(local ref, local object, remote ref, remote object)`, to `remote`.
+    #[allow(dead_code)]
+    pub fn run_pre_push_hook(
+        &self,
+        remote: &str,
+        url: &str,
+        updates: &[(String, [u8; 20], String, [u8; 20])],
+    ) -> Result<()> {
+        let mut input = String::new();
+        for (local_ref, local, remote_ref, remote_hash) in updates {
+            input.push_str(&format!(
+                "{} {} {} {}\n",
+                local_ref,
+                hex::encode(local),
+                remote_ref,
+                hex::encode(remote_hash)
+            ));
+        }
+
+        self.run_hook("pre-push", &[remote, url], input.as_bytes())
+    }
+}
@@ -0,0 +1,297 @@
+use std::{fmt::Write, fs, path::Path};
+
+use anyhow::Result;
+
+use crate::{
+    diff::{DiffLine, FileDiff},
+    repository::Repository,
+};
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 0; display: flex; }
+nav { width: 18em; padding: 1em; border-right: 1px solid #ccc; height: 100vh; overflow: auto; position: sticky; top: 0; box-sizing: border-box; }
+nav ul { list-style: none; padding: 0; font-size: 0.9em; }
+nav a { text-decoration: none; }
+main { flex: 1; padding: 1em; min-width: 0; }
+.file { margin-bottom: 2em; border: 1px solid #ccc; }
+.file h2 { margin: 0; padding: 0.4em; font-size: 1em; font-family: monospace; background: #f3f3f3; }
+table { border-collapse: collapse; width: 100%; font-family: monospace; font-size: 0.85em; table-layout: fixed; }
+td { padding: 0 0.4em; white-space: pre-wrap; word-break: break-all; vertical-align: top; }
+td.num { width: 3.5em; text-align: right; color: #888; }
+tr.hunk td { background: #eef; color: #558; }
+td.add { background: #dfd; }
+td.del { background: #fdd; }
+td.empty { background: #f6f6f6; }
+.added { color: #2a2; }
+.removed { color: #c22; }
+";
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn number(n: Option<usize>) -> String {
+    n.map(|n| n.to_string()).unwrap_or_default()
+}
+
+/// Count the added and removed lines of a file.
+fn stat(file_diff: &FileDiff) -> (usize, usize) {
+    if file_diff.is_binary() {
+        return (0, 0);
+    }
+
+    file_diff
+        .hunks(0)
+        .iter()
+        .flat_map(|hunk| &hunk.lines)
+        .fold((0, 0), |(added, removed), line| match line {
+            DiffLine::Added(_) => (added + 1, removed),
+            DiffLine::Removed(_) => (added, removed + 1),
+            DiffLine::Context(_) => (added, removed),
+        })
+}
+
+fn render_unified(out: &mut String, file_diff: &FileDiff) {
+    out.push_str("<table>\n");
+    for hunk in file_diff.hunks(3) {
+        let _ = writeln!(
+            out,
+            "<tr class=\"hunk\"><td class=\"num\"></td><td class=\"num\"></td><td>{}</td></tr>",
+            escape(&hunk.header())
+        );
+
+        let (mut old, mut new) = (hunk.old_start, hunk.new_start);
+        for line in &hunk.lines {
+            let (class, old_number, new_number, text) = match line {
+                DiffLine::Context(text) => {
+                    old += 1;
+                    new += 1;
+                    ("", Some(old - 1), Some(new - 1), text)
+                }
+                DiffLine::Removed(text) => {
+                    old += 1;
+                    ("del", Some(old - 1), None, text)
+                }
+                DiffLine::Added(text) => {
+                    new += 1;
+                    ("add", None, Some(new - 1), text)
+                }
+            };
+            let _ = writeln!(
+                out,
+                "<tr><td class=\"num\">{}</td><td class=\"num\">{}</td><td class=\"{}\">{}</td></tr>",
+                number(old_number),
+                number(new_number),
+                class,
+                escape(text)
+            );
+        }
+    }
+    out.push_str("</table>\n");
+}
+
+fn render_side_by_side(out: &mut String, file_diff: &FileDiff) {
+    out.push_str("<table>\n");
+    for hunk in file_diff.hunks(3) {
+        let _ = writeln!(
+            out,
+            "<tr class=\"hunk\"><td class=\"num\"></td><td colspan=\"3\">{}</td></tr>",
+            escape(&hunk.header())
+        );
+
+        let (mut old, mut new) = (hunk.old_start, hunk.new_start);
+        let mut removed: Vec<(usize, &str)> = Vec::new();
+        let mut added: Vec<(usize, &str)> = Vec::new();
+
+        // pair each run of removed lines with the following run of added lines
+        let flush =
+            |out: &mut String, removed: &mut Vec<(usize, &str)>, added: &mut Vec<(usize, &str)>| {
+                for i in 0..removed.len().max(added.len()) {
+                    let left = removed.get(i).map_or(
+                        "<td class=\"num\"></td><td class=\"empty\"></td>".to_string(),
+                        |(n, text)| {
+                            format!(
+                                "<td class=\"num\">{}</td><td class=\"del\">{}</td>",
+                                n,
+                                escape(text)
+                            )
+                        },
+                    );
+                    let right = added.get(i).map_or(
+                        "<td class=\"num\"></td><td class=\"empty\"></td>".to_string(),
+                        |(n, text)| {
+                            format!(
+                                "<td class=\"num\">{}</td><td class=\"add\">{}</td>",
+                                n,
+                                escape(text)
+                            )
+                        },
+                    );
+                    let _ = writeln!(out, "<tr>{}{}</tr>", left, right);
+                }
+                removed.clear();
+                added.clear();
+            };
+
+        for line in &hunk.lines {
+            match line {
+                DiffLine::Context(text) => {
+                    flush(out, &mut removed, &mut added);
+                    let _ = writeln!(
+                        out,
+                        "<tr><td class=\"num\">{}</td><td>{}</td><td class=\"num\">{}</td><td>{}</td></tr>",
+                        old,
+                        escape(text),
+                        new,
+                        escape(text)
+                    );
+                    old += 1;
+                    new += 1;
+                }
+                DiffLine::Removed(text) => {
+                    if !added.is_empty() {
+                        flush(out, &mut removed, &mut added);
+                    }
+                    removed.push((old, text));
+                    old += 1;
+                }
+                DiffLine::Added(text) => {
+                    added.push((new, text));
+                    new += 1;
+                }
+            }
+        }
+        flush(out, &mut removed, &mut added);
+    }
+    out.push_str("</table>\n");
+}
+
+impl Repository {
+    /// The first-parent commits of a `from..to` range, newest first.
+    fn html_range_commits(&self, revisions: &[String]) -> Result<Vec<([u8; 20], String)>> {
+        let (from, to) = match revisions {
+            [range] if range.contains("..") => self.resolve_range(range)?.unwrap(),
+            [from, to] => (self.resolve_revision(from)?, self.resolve_revision(to)?),
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut current = Some(to);
+        let mut commits = Vec::new();
+
+        while let Some(commit) = current {
+            if self.is_ancestor(&commit, &from)? || commits.iter().any(|(c, _)| *c == commit) {
+                break;
+            }
+
+            let content = self.read_object(&hex::encode(commit))?.string()?;
+            let subject = content
+                .split_once("\n\n")
+                .and_then(|(_, message)| message.lines().next())
+                .unwrap_or("")
+                .to_string();
+            commits.push((commit, subject));
+
+            current = self.commit_parents(&commit)?.first().copied();
+        }
+
+        Ok(commits)
+    }
+
+    /// Write a diff as a static HTML report (`index.html` and `style.css`) in
+    /// `dir`, with a navigation index of the changed files.
+    pub fn diff_html(
+        &self,
+        revisions: &[String],
+        cached: bool,
+        dir: &Path,
+        side_by_side: bool,
+    ) -> Result<()> {
+        let (old, new) = self.diff_sides(revisions, cached)?;
+        let diffs = self.diff_targets(old, new)?;
+        let commits = self.html_range_commits(revisions)?;
+
+        let title = match revisions {
+            [] if cached => "HEAD..index".to_string(),
+            [] => "index..worktree".to_string(),
+            _ => revisions.join(" "),
+        };
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>diff {}</title>\n<link rel=\"stylesheet\" href=\"style.css\">\n</head>\n<body>",
+            escape(&title)
+        );
+
+        out.push_str("<nav>\n");
+        let _ = writeln!(out, "<h1>{}</h1>", escape(&title));
+        let _ = writeln!(out, "<p>{} file(s) changed</p>\n<ul>", diffs.len());
+        for (idx, file_diff) in diffs.iter().enumerate() {
+            let (added, removed) = stat(file_diff);
+            let _ = writeln!(
+                out,
+                "<li><a href=\"#file-{}\">{}</a> <span class=\"added\">+{}</span> <span class=\"removed\">-{}</span></li>",
+                idx,
+                escape(&file_diff.path),
+                added,
+                removed
+            );
+        }
+        out.push_str("</ul>\n</nav>\n<main>\n");
+
+        if !commits.is_empty() {
+            out.push_str("<h2>Commits</h2>\n<ul>\n");
+            for (hash, subject) in &commits {
+                let _ = writeln!(
+                    out,
+                    "<li><code>{}</code> {}</li>",
+                    &hex::encode(hash)[..7],
+                    escape(subject)
+                );
+            }
+            out.push_str("</ul>\n");
+        }
+
+        for (idx, file_diff) in diffs.iter().enumerate() {
+            let status = match (&file_diff.old, &file_diff.new) {
+                (None, _) => " (new file)",
+                (_, None) => " (deleted)",
+                _ => "",
+            };
+            let _ = writeln!(
+                out,
+                "<div class=\"file\" id=\"file-{}\">\n<h2>{}{}</h2>",
+                idx,
+                escape(&file_diff.path),
+                status
+            );
+
+            if file_diff.is_binary() {
+                out.push_str("<p>Binary files differ</p>\n");
+            } else if side_by_side {
+                render_side_by_side(&mut out, file_diff);
+            } else {
+                render_unified(&mut out, file_diff);
+            }
+            out.push_str("</div>\n");
+        }
+
+        out.push_str("</main>\n</body>\n</html>\n");
+
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("style.css"), STYLE)?;
+        fs::write(dir.join("index.html"), out)?;
+
+        Ok(())
+    }
+}
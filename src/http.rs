@@ -1,11 +1,43 @@
 use std::io::Write;
 
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
 use nom::AsBytes;
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+
+use crate::{
+    credential::{self, Credential},
+    pkt_line::{packet_line, read_pkt_line},
+    progress::{print_remote_progress, Progress},
+    repository::Repository,
+    shallow::{ShallowInfo, ShallowRequest},
+    transport::TransportOptions,
+};
+
+pub async fn clone(
+    repository: &Repository,
+    repo: &str,
+    depth: Option<u32>,
+    filter: Option<&str>,
+    options: &TransportOptions,
+) -> Result<(), Error> {
+    if (depth.is_some() || filter.is_some()) && !repository.git_dir.is_dir() {
+        return Err(anyhow!(
+            "shallow and partial clones need a repository to record their state, run `mg init` first"
+        ));
+    }
+
+    let (size, refs, shallow_info) = get_refs(repo, depth, filter, options).await?;
 
-pub async fn clone(repo: &str) -> Result<(), Error> {
-    let (size, refs) = get_refs(repo).await?;
+    if depth.is_some() {
+        repository.update_shallow(&shallow_info)?;
+    }
+
+    if let Some(filter) = filter {
+        // objects left out by the filter are fetched on demand from this remote
+        repository.set_config("remote.origin.url", repo)?;
+        repository.set_config("remote.origin.promisor", "true")?;
+        repository.set_config("remote.origin.partialclonefilter", filter)?;
+    }
 
     println!("Refs:");
     for (sha1, name) in refs.iter() {
@@ -60,36 +92,216 @@ pub fn parse_refs(input: &[u8]) -> Result<Vec<(String, String)>> {
     Ok(refs)
 }
 
-pub async fn get_refs(repo_url: &str) -> Result<(usize, Vec<(String, String)>), Error> {
+pub async fn get_refs(
+    repo_url: &str,
+    depth: Option<u32>,
+    filter: Option<&str>,
+    options: &TransportOptions,
+) -> Result<(usize, Vec<(String, String)>, ShallowInfo), Error> {
+    let refs = list_remote_refs(repo_url, &[], options).await?;
+
+    get_packfile(repo_url, refs, depth, filter, options).await
+}
+
+/// The refs advertised by a remote, as (name, object id) pairs, with the
+/// peeled ids of annotated tags as `<tag>^{}`. Servers speaking protocol v2
+/// are asked with `ls-refs` for the refs starting with one of `prefixes`
+/// only, older ones advertise them all and the others are dropped here. No
+/// prefix lists every ref.
+pub async fn list_remote_refs(
+    repo_url: &str,
+    prefixes: &[&str],
+    options: &TransportOptions,
+) -> Result<Vec<(String, String)>, Error> {
     let info_refs_url = format!("{}/info/refs?service=git-upload-pack", repo_url);
 
-    let client = Client::new();
-    let response = client
-        .get(&info_refs_url)
-        .header("User-Agent", "git/2.30.0")
-        .send()
-        .await?;
+    let response = send(&info_refs_url, options, |client, url| {
+        client
+            .get(url)
+            .header("User-Agent", "git/2.30.0")
+            .header("Git-Protocol", "version=2")
+    })
+    .await?;
 
-    response.error_for_status_ref()?;
+    let content = response.bytes().await?;
+    let advertised = &content[4.min(content.len())..];
+    let refs = if advertised.starts_with(b"version 2\n") {
+        ls_refs(repo_url, prefixes, options).await?
+    } else if advertised.starts_with(b"# service=git-upload-pack") {
+        parse_refs(&content)?
+    } else {
+        return Err(anyhow!("{} is not a git repository", repo_url));
+    };
+
+    Ok(refs
+        .into_iter()
+        .filter(|(name, _)| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p)))
+        .collect())
+}
+
+/// Run a protocol v2 `ls-refs` command for the refs starting with one of
+/// `prefixes`.
+async fn ls_refs(
+    repo_url: &str,
+    prefixes: &[&str],
+    options: &TransportOptions,
+) -> Result<Vec<(String, String)>, Error> {
+    let upload_pack_url = format!("{}/git-upload-pack", repo_url);
+
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend(packet_line("command=ls-refs\n").as_slice());
+    payload.extend(packet_line("agent=git/2.30.0\n").as_slice());
+    payload.extend(packet_line("object-format=sha1\n").as_slice());
+    payload.extend("0001".as_bytes());
+    payload.extend(packet_line("peel\n").as_slice());
+    for prefix in prefixes {
+        payload.extend(packet_line(&format!("ref-prefix {}\n", prefix)).as_slice());
+    }
+    payload.extend("0000".as_bytes());
+
+    let response = send(&upload_pack_url, options, |client, url| {
+        client
+            .post(url)
+            .header("User-Agent", "git/2.30.0")
+            .header("Content-Type", "application/x-git-upload-pack-request")
+            .header("Accept", "application/x-git-upload-pack-result")
+            .header("Git-Protocol", "version=2")
+            .body(payload.clone())
+    })
+    .await?;
 
     let content = response.bytes().await?;
-    let refs = parse_refs(&content)?;
+    parse_ls_refs(content.as_bytes())
+}
 
-    get_packfile(repo_url, refs).await
+/// The refs of an `ls-refs` response: `<oid> <name>[ peeled:<oid>]` lines,
+/// up to a flush packet.
+pub fn parse_ls_refs(mut input: &[u8]) -> Result<Vec<(String, String)>, Error> {
+    let mut refs = Vec::new();
+    while let Some(line) = read_pkt_line(&mut input)? {
+        let line = String::from_utf8(line)?;
+        let mut fields = line.trim_end().split(' ');
+        let (Some(oid), Some(name)) = (fields.next(), fields.next()) else {
+            return Err(anyhow!("invalid ls-refs line: {}", line.trim_end()));
+        };
+        refs.push((name.to_string(), oid.to_string()));
+        if let Some(peeled) = fields.find_map(|field| field.strip_prefix("peeled:")) {
+            refs.push((format!("{}^{{}}", name), peeled.to_string()));
+        }
+    }
+
+    Ok(refs)
 }
 
-pub fn packet_line(data: &str) -> Vec<u8> {
-    let length = format!("{:04x}", data.len() + 4);
-    let mut line = Vec::new();
-    line.extend_from_slice(length.as_bytes());
-    line.extend_from_slice(data.as_bytes());
-    line
+/// Print the refs of a remote as `<oid>\t<name>`, only branches with
+/// `heads` and tags with `tags`, and only those matching one of `patterns`
+/// (a full name, or its last components) if any are given.
+pub async fn ls_remote(
+    repo_url: &str,
+    heads: bool,
+    tags: bool,
+    patterns: &[String],
+    options: &TransportOptions,
+) -> Result<(), Error> {
+    let mut prefixes = Vec::new();
+    if heads {
+        prefixes.push("refs/heads/");
+    }
+    if tags {
+        prefixes.push("refs/tags/");
+    }
+
+    for (name, oid) in list_remote_refs(repo_url, &prefixes, options).await? {
+        let matches = patterns.is_empty()
+            || patterns
+                .iter()
+                .any(|pattern| name == *pattern || name.ends_with(&format!("/{}", pattern)));
+        if matches {
+            println!("{}\t{}", oid, name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Send the request built by `build` for `url`, with the credentials of the
+/// url userinfo or of the environment if any. Without them, a request refused
+/// with 401 is retried once with the credentials of `git credential fill`,
+/// which are then approved or rejected.
+async fn send<F>(url: &str, options: &TransportOptions, build: F) -> Result<Response, Error>
+where
+    F: Fn(&Client, Url) -> RequestBuilder,
+{
+    let url = Url::parse(url)?;
+    let client = options.client()?;
+    let given = Credential::from_url(&url).or_else(Credential::from_env);
+    let url = credential::strip_userinfo(&url);
+
+    let mut request = build(&client, url.clone());
+    if let Some(credential) = &given {
+        request = credential.authorize(request);
+    }
+    let response = request.send().await?;
+
+    if response.status() != StatusCode::UNAUTHORIZED || given.is_some() {
+        response.error_for_status_ref()?;
+        return Ok(response);
+    }
+
+    let Some(credential) = credential::fill(&url)? else {
+        return Err(anyhow!("authentication required for {}", url));
+    };
+    let response = credential
+        .authorize(build(&client, url.clone()))
+        .send()
+        .await?;
+
+    let accepted = response.status() != StatusCode::UNAUTHORIZED;
+    credential::report(&url, &credential, accepted)?;
+    if !accepted {
+        return Err(anyhow!("authentication failed for {}", url));
+    }
+    response.error_for_status_ref()?;
+
+    Ok(response)
 }
 
 pub async fn get_packfile(
     repo_url: &str,
     refs: Vec<(String, String)>,
-) -> Result<(usize, Vec<(String, String)>), Error> {
+    depth: Option<u32>,
+    filter: Option<&str>,
+    options: &TransportOptions,
+) -> Result<(usize, Vec<(String, String)>, ShallowInfo), Error> {
+    let wants: Vec<String> = refs.iter().map(|(_, sha1)| sha1.clone()).collect();
+
+    let shallow = ShallowRequest {
+        depth,
+        ..Default::default()
+    };
+    let (size, pack_data, shallow_info) =
+        fetch_pack(repo_url, &wants, &[], &shallow, filter, options).await?;
+
+    if !pack_data.is_empty() {
+        let mut packfile = std::fs::File::create("downloaded.pack")?;
+        packfile.write_all(&pack_data)?;
+        println!("Packfile saved as 'downloaded.pack'");
+    }
+
+    Ok((size, refs, shallow_info))
+}
+
+/// Run a protocol v2 `fetch` command for the given object ids, telling the
+/// server about the objects in `haves` and the shallow boundary, and return
+/// the response size, the raw pack data and the shallow boundary updates.
+pub async fn fetch_pack(
+    repo_url: &str,
+    wants: &[String],
+    haves: &[String],
+    shallow: &ShallowRequest,
+    filter: Option<&str>,
+    options: &TransportOptions,
+) -> Result<(usize, Vec<u8>, ShallowInfo), Error> {
     let upload_pack_url = format!("{}/git-upload-pack", repo_url);
 
     let mut payload: Vec<u8> = Vec::new();
@@ -99,69 +311,208 @@ pub async fn get_packfile(
     payload.extend(packet_line("object-format=sha1").as_slice());
     payload.extend("0001".as_bytes());
     payload.extend(packet_line("ofs-delta").as_slice());
-    payload.extend(packet_line("no-progress").as_slice());
+    if !options.progress {
+        payload.extend(packet_line("no-progress").as_slice());
+    }
 
-    for (_, sha1) in refs.iter() {
+    for sha1 in wants.iter() {
         let want = format!("want {}\n", sha1);
         payload.extend(packet_line(want.as_str()).as_slice());
     }
 
-    payload.extend("0000".as_bytes());
-    payload.extend(packet_line("done").as_slice());
-
-    let client = Client::new();
-    let response = client
-        .post(&upload_pack_url)
-        .header("User-Agent", "git/2.30.0")
-        .header("Content-Type", "application/x-git-upload-pack-request")
-        .header("Accept-Encoding", "deflate")
-        .header("Accept", "application/x-git-upload-pack-result")
-        .header("Git-Protocol", "version=2")
-        .body(payload)
-        .send()
-        .await?;
+    for sha1 in haves.iter() {
+        payload.extend(packet_line(&format!("have {}\n", sha1)).as_slice());
+    }
 
-    response.error_for_status_ref()?;
+    for sha1 in shallow.shallow.iter() {
+        payload.extend(packet_line(&format!("shallow {}\n", sha1)).as_slice());
+    }
 
-    let content = response.bytes().await?;
-    decode_git_response(content.as_bytes())?;
+    if let Some(depth) = shallow.depth {
+        payload.extend(packet_line(&format!("deepen {}\n", depth)).as_slice());
+        if shallow.relative {
+            payload.extend(packet_line("deepen-relative\n").as_slice());
+        }
+    }
+
+    if let Some(filter) = filter {
+        payload.extend(packet_line(&format!("filter {}\n", filter)).as_slice());
+    }
+
+    payload.extend(packet_line("done\n").as_slice());
+    payload.extend("0000".as_bytes());
+
+    let mut response = send(&upload_pack_url, options, |client, url| {
+        client
+            .post(url)
+            .header("User-Agent", "git/2.30.0")
+            .header("Content-Type", "application/x-git-upload-pack-request")
+            .header("Accept-Encoding", "deflate")
+            .header("Accept", "application/x-git-upload-pack-result")
+            .header("Git-Protocol", "version=2")
+            .body(payload.clone())
+    })
+    .await?;
+
+    // the response is decoded as it arrives, so the messages of the server
+    // and the pack received so far are reported as they come
+    let mut decoder = ResponseDecoder::new(options.progress);
+    let mut receiving = Progress::new("Receiving pack", None, options.progress).bytes();
+    let mut size = 0;
+    while let Some(chunk) = response.chunk().await? {
+        size += chunk.len();
+        decoder.feed(&chunk)?;
+        if decoder.pack_len() > 0 {
+            receiving.update(decoder.pack_len() as u64);
+        }
+    }
+    if decoder.pack_len() > 0 {
+        receiving.finish();
+    }
+    let (pack_data, shallow_info) = decoder.finish()?;
 
-    Ok((content.len(), refs))
+    Ok((size, pack_data, shallow_info))
 }
 
-fn decode_git_response(content: &[u8]) -> Result<(), Error> {
-    let mut cursor = 0;
-    let mut pack_data = Vec::new();
+/// A `fetch` response decoded packet by packet as its chunks arrive, into
+/// the pack data and the shallow boundary updates.
+pub struct ResponseDecoder {
+    /// The start of a packet not received whole yet
+    pending: Vec<u8>,
+    /// The offset of `pending` in the response
+    offset: usize,
+    pack_data: Vec<u8>,
+    shallow_info: ShallowInfo,
+    in_packfile: bool,
+    /// Whether the flush packet ending the response was read
+    done: bool,
+    /// Whether the progress messages of the server are shown
+    progress: bool,
+}
 
-    while cursor < content.len() {
-        let length_str = std::str::from_utf8(&content[cursor..cursor + 4])?;
-        cursor += 4;
+impl ResponseDecoder {
+    pub fn new(progress: bool) -> Self {
+        ResponseDecoder {
+            pending: Vec::new(),
+            offset: 0,
+            pack_data: Vec::new(),
+            shallow_info: ShallowInfo::default(),
+            in_packfile: false,
+            done: false,
+            progress,
+        }
+    }
 
-        let length = usize::from_str_radix(length_str, 16)?;
-        if length == 0 {
-            break;
+    /// Decode the packets `data` completes.
+    pub fn feed(&mut self, data: &[u8]) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.pending.extend_from_slice(data);
+
+        let mut cursor = 0;
+        while let Some(length_str) = self.pending.get(cursor..cursor + 4) {
+            let length = usize::from_str_radix(std::str::from_utf8(length_str)?, 16)?;
+            if length == 0 {
+                self.done = true;
+                break;
+            }
+            if length < 4 {
+                // delimiter (0001) or response-end (0002) packets
+                cursor += 4;
+                continue;
+            }
+            let Some(payload) = self.pending.get(cursor + 4..cursor + length) else {
+                break;
+            };
+            let payload = payload.to_vec();
+            cursor += length;
+            self.packet(&payload)?;
         }
 
-        let payload = &content[cursor..cursor + length - 4];
-        cursor += length - 4;
+        self.pending.drain(..cursor);
+        self.offset += cursor;
+        Ok(())
+    }
 
-        let side_band = payload[0];
-        let data = &payload[1..];
+    fn packet(&mut self, payload: &[u8]) -> Result<()> {
+        if !self.in_packfile {
+            // section headers and their lines, until the packfile section starts
+            let line = std::str::from_utf8(payload)?.trim_end();
+            if line == "packfile" {
+                self.in_packfile = true;
+            } else if let Some(hash) = line.strip_prefix("shallow ") {
+                self.shallow_info.shallow.push(hash.to_string());
+            } else if let Some(hash) = line.strip_prefix("unshallow ") {
+                self.shallow_info.unshallow.push(hash.to_string());
+            }
+            return Ok(());
+        }
 
-        if side_band == 1 {
-            pack_data.extend(data);
-        } else if side_band == 2 {
-            println!("Progress: {}", std::str::from_utf8(data)?);
-        } else if side_band == 3 {
-            println!("Error: {}", std::str::from_utf8(data)?);
+        let Some((&side_band, data)) = payload.split_first() else {
+            return Err(anyhow!("empty packet in the packfile section"));
+        };
+        match side_band {
+            1 => self.pack_data.extend(data),
+            2 if self.progress => print_remote_progress(data),
+            3 => {
+                return Err(anyhow!(
+                    "remote error: {}",
+                    String::from_utf8_lossy(data).trim_end()
+                ))
+            }
+            _ => {}
         }
+        Ok(())
     }
 
-    if !pack_data.is_empty() {
-        let mut packfile = std::fs::File::create("downloaded.pack")?;
-        packfile.write_all(&pack_data)?;
-        println!("Packfile saved as 'downloaded.pack'");
+    /// The bytes of pack data received so far.
+    pub fn pack_len(&self) -> usize {
+        self.pack_data.len()
     }
 
-    Ok(())
+    /// The pack data and shallow boundary updates of the whole response.
+    pub fn finish(self) -> Result<(Vec<u8>, ShallowInfo)> {
+        if !self.done && !self.pending.is_empty() {
+            return Err(anyhow!("truncated packet at offset {}", self.offset));
+        }
+        Ok((self.pack_data, self.shallow_info))
+    }
+}
+
+/// The pack data and shallow boundary updates of a whole `fetch` response,
+/// the progress messages of the server shown with `progress`.
+pub fn decode_git_response(content: &[u8], progress: bool) -> Result<(Vec<u8>, ShallowInfo)> {
+    let mut decoder = ResponseDecoder::new(progress);
+    decoder.feed(content)?;
+    decoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_in_chunks() {
+        let mut response = Vec::new();
+        response.extend(packet_line("shallow-info\n"));
+        response.extend(packet_line(&format!("shallow {}\n", "a".repeat(40))));
+        response.extend(b"0001");
+        response.extend(packet_line("packfile\n"));
+        response.extend(packet_line("\x01PACK"));
+        response.extend(packet_line("\x01data"));
+        response.extend(b"0000");
+
+        // packets split across chunks are decoded once whole
+        let mut decoder = ResponseDecoder::new(false);
+        for chunk in response.chunks(3) {
+            decoder.feed(chunk).unwrap();
+        }
+        let (pack, shallow) = decoder.finish().unwrap();
+        assert_eq!(pack, b"PACKdata");
+        assert_eq!(shallow.shallow, vec!["a".repeat(40)]);
+
+        let truncated = decode_git_response(&response[..response.len() - 8], false);
+        assert!(truncated.is_err());
+    }
 }
@@ -0,0 +1,171 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+
+use crate::{commit::Signature, log::parse_date, repository::Repository};
+
+/// Who an identity is recorded for, naming its `GIT_<ROLE>_*` variables.
+#[derive(Debug, Clone, Copy)]
+pub enum Role {
+    Author,
+    Committer,
+}
+
+impl Role {
+    fn variable(&self, field: &str) -> String {
+        match self {
+            Role::Author => format!("GIT_AUTHOR_{}", field),
+            Role::Committer => format!("GIT_COMMITTER_{}", field),
+        }
+    }
+}
+
+/// The offset of the local time zone at `timestamp`, as `+HHMM`.
+fn local_timezone(timestamp: i64) -> String {
+    let time = timestamp as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return "+0000".to_string();
+    }
+
+    let offset = tm.tm_gmtoff / 60;
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!("{}{:02}{:02}", sign, offset.abs() / 60, offset.abs() % 60)
+}
+
+/// Parse the date of a `GIT_*_DATE` variable: `<epoch> <tz>`, `@<epoch>`
+/// or any date `--since` takes, in the local time zone unless given.
+fn parse_ident_date(value: &str, now: i64) -> Result<(i64, String)> {
+    let value = value.trim();
+    let (date, timezone) = match value.rsplit_once(' ') {
+        Some((date, tz))
+            if tz.len() == 5
+                && tz.starts_with(['+', '-'])
+                && tz[1..].bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            (date, Some(tz.to_string()))
+        }
+        _ => (value, None),
+    };
+
+    let timestamp = match date.parse::<i64>() {
+        Ok(epoch) => epoch,
+        Err(_) => parse_date(date, now)?,
+    };
+    let timezone = timezone.unwrap_or_else(|| local_timezone(timestamp));
+
+    Ok((timestamp, timezone))
+}
+
+/// How the date of an identity is chosen, beyond the `GIT_<ROLE>_DATE`
+/// variables.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityDate<'a> {
+    /// A date taking precedence over the variable, `now` included
+    pub date: Option<&'a str>,
+    /// Record the date in UTC, and when no date is given the time of this
+    /// frozen clock rather than the current one
+    pub reproducible: Option<i64>,
+}
+
+/// `identity` dated `date.date` instead when given, and in UTC when
+/// reproducible: the author of an amended commit.
+pub fn redate(identity: &str, date: IdentityDate) -> Result<String> {
+    let mut signature = Signature::parse(identity)?;
+    if let Some(given) = date.date {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        (signature.timestamp, signature.timezone) = parse_ident_date(given, now)?;
+    }
+    if date.reproducible.is_some() {
+        signature.timezone = "+0000".to_string();
+    }
+
+    Ok(signature.to_string())
+}
+
+impl Repository {
+    /// The identity recorded for `role`, as `name <email> epoch tz`: the
+    /// `GIT_<ROLE>_NAME`, `GIT_<ROLE>_EMAIL` and `GIT_<ROLE>_DATE` variables
+    /// when set, `user.name`, `user.email` and the current time otherwise.
+    pub fn identity(&self, role: Role) -> Result<String> {
+        self.identity_at(role, IdentityDate::default())
+    }
+
+    /// The identity recorded for `role`, dated as `date` says.
+    pub fn identity_at(&self, role: Role, date: IdentityDate) -> Result<String> {
+        let config = self.config()?;
+        let name = std::env::var(role.variable("NAME"))
+            .ok()
+            .or_else(|| config.get("user.name"));
+        let email = std::env::var(role.variable("EMAIL"))
+            .ok()
+            .or_else(|| config.get("user.email"));
+        let (Some(name), Some(email)) = (name, email) else {
+            return Err(anyhow!(
+                "please tell me who you are: set user.name and user.email"
+            ));
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let given = date
+            .date
+            .map(str::to_string)
+            .or_else(|| std::env::var(role.variable("DATE")).ok());
+        let (timestamp, timezone) = match given {
+            Some(given) => parse_ident_date(&given, now)?,
+            None => {
+                let timestamp = date.reproducible.unwrap_or(now);
+                (timestamp, local_timezone(timestamp))
+            }
+        };
+        // the local time zone differs between machines
+        let timezone = match date.reproducible {
+            Some(_) => "+0000".to_string(),
+            None => timezone,
+        };
+
+        Ok(format!("{} <{}> {} {}", name, email, timestamp, timezone))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ident_date() {
+        assert_eq!(
+            parse_ident_date("1700000000 +0200", 0).unwrap(),
+            (1_700_000_000, "+0200".to_string())
+        );
+        assert_eq!(
+            parse_ident_date("@1234 -0130", 0).unwrap(),
+            (1234, "-0130".to_string())
+        );
+        assert_eq!(
+            parse_ident_date("2024-03-01 12:30 +0000", 0).unwrap().0,
+            1_709_296_200
+        );
+    }
+
+    #[test]
+    fn test_redate() {
+        let author = "A U Thor <author@example.com> 1700000000 +0200";
+        let frozen = IdentityDate {
+            date: None,
+            reproducible: Some(0),
+        };
+        assert_eq!(
+            redate(author, frozen).unwrap(),
+            "A U Thor <author@example.com> 1700000000 +0000"
+        );
+        let dated = IdentityDate {
+            date: Some("@1234 -0130"),
+            reproducible: None,
+        };
+        assert_eq!(
+            redate(author, dated).unwrap(),
+            "A U Thor <author@example.com> 1234 -0130"
+        );
+    }
+}
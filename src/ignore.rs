@@ -0,0 +1,406 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{pattern::Pattern, repository::Repository};
+
+/// A pattern read from an ignore file, with where it came from.
+#[derive(Debug, Clone)]
+pub struct IgnorePattern {
+    pub pattern: Pattern,
+    /// the line as written in the file, e.g. `!*.log`
+    pub text: String,
+    /// the file the pattern comes from, as displayed by `check-ignore -v`
+    pub source: String,
+    /// 1-based line number in `source`
+    pub line: usize,
+    /// directory the pattern is relative to, empty or ending with `/`
+    pub base: String,
+}
+
+impl IgnorePattern {
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        match path.strip_prefix(self.base.as_str()) {
+            Some(relative) => self.pattern.matches(relative, is_dir),
+            None => false,
+        }
+    }
+}
+
+fn parse_ignore_file(content: &str, source: &str, base: &str) -> Vec<IgnorePattern> {
+    content
+        .lines()
+        .enumerate()
+        .filter_map(|(idx, line)| {
+            Pattern::parse(line).map(|pattern| IgnorePattern {
+                pattern,
+                text: line.trim_end().to_string(),
+                source: source.to_string(),
+                line: idx + 1,
+                base: base.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The ignore rules of a worktree: `.gitignore` files of every directory,
+/// `.git/info/exclude` and `core.excludesFile`, from highest to lowest
+/// precedence.
+pub struct IgnoreRules {
+    root: PathBuf,
+    /// `.gitignore` patterns by directory, loaded on demand
+    directories: HashMap<String, Vec<IgnorePattern>>,
+    /// `info/exclude` then `core.excludesFile`
+    global: Vec<Vec<IgnorePattern>>,
+}
+
+impl IgnoreRules {
+    fn directory_patterns(&mut self, dir: &str) -> Result<&[IgnorePattern]> {
+        if !self.directories.contains_key(dir) {
+            let source = format!("{}.gitignore", dir);
+            let patterns = match fs::read_to_string(self.root.join(&source)) {
+                Ok(content) => parse_ignore_file(&content, &source, dir),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e.into()),
+            };
+            self.directories.insert(dir.to_string(), patterns);
+        }
+
+        Ok(&self.directories[dir])
+    }
+
+    /// The pattern deciding whether `path` is ignored, not looking at its
+    /// parent directories. A negated pattern means the path is not ignored.
+    fn own_match(&mut self, path: &str, is_dir: bool) -> Result<Option<IgnorePattern>> {
+        // the deepest .gitignore has the highest precedence
+        let mut dirs = vec![String::new()];
+        let mut end = 0;
+        while let Some(pos) = path[end..].find('/') {
+            end += pos + 1;
+            dirs.push(path[..end].to_string());
+        }
+
+        for dir in dirs.iter().rev() {
+            let patterns = self.directory_patterns(dir)?;
+            if let Some(pattern) = patterns.iter().rev().find(|p| p.matches(path, is_dir)) {
+                return Ok(Some(pattern.clone()));
+            }
+        }
+
+        for patterns in &self.global {
+            if let Some(pattern) = patterns.iter().rev().find(|p| p.matches(path, is_dir)) {
+                return Ok(Some(pattern.clone()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The pattern deciding whether `path` is ignored, if any. A file inside an
+    /// ignored directory is ignored by the directory's pattern, as no pattern
+    /// can re-include it.
+    pub fn matched(&mut self, path: &str, is_dir: bool) -> Result<Option<IgnorePattern>> {
+        let mut end = 0;
+        while let Some(pos) = path[end..].find('/') {
+            let dir = &path[..end + pos];
+            if let Some(pattern) = self.own_match(dir, true)? {
+                if !pattern.pattern.negated {
+                    return Ok(Some(pattern));
+                }
+            }
+            end += pos + 1;
+        }
+
+        self.own_match(path, is_dir)
+    }
+
+    /// Whether `path` is ignored, by its own pattern or its directory's.
+    pub fn is_ignored(&mut self, path: &str, is_dir: bool) -> Result<bool> {
+        Ok(self
+            .matched(path, is_dir)?
+            .is_some_and(|pattern| !pattern.pattern.negated))
+    }
+}
+
+impl Repository {
+    /// The user's ignore file: `core.excludesFile`, by default `git/ignore`
+    /// in the XDG configuration directory.
+    pub fn excludes_file(&self) -> Result<Option<PathBuf>> {
+        Ok(match self.config()?.get_path("core.excludesfile")? {
+            Some(path) => Some(path),
+            None => std::env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| {
+                    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+                })
+                .map(|config| config.join("git").join("ignore")),
+        })
+    }
+
+    /// Load the ignore rules of the worktree.
+    pub fn ignore_rules(&self) -> Result<IgnoreRules> {
+        let mut global = Vec::new();
+
+        let exclude_path = self.git_dir.join("info").join("exclude");
+        if exclude_path.is_file() {
+            let content = fs::read_to_string(&exclude_path)?;
+            global.push(parse_ignore_file(&content, ".git/info/exclude", ""));
+        }
+
+        if let Some(path) = self.excludes_file()?.filter(|path| path.is_file()) {
+            let content = fs::read_to_string(&path)?;
+            global.push(parse_ignore_file(&content, &path.to_string_lossy(), ""));
+        }
+
+        Ok(IgnoreRules {
+            root: self.path.clone(),
+            directories: HashMap::new(),
+            global,
+        })
+    }
+
+    /// Print the paths which are ignored. With `verbose`, print the matching
+    /// pattern and where it comes from as well; with `non_matching`, print
+    /// paths without a matching pattern too. Returns whether any path is
+    /// ignored.
+    pub fn check_ignore(
+        &self,
+        paths: &[String],
+        verbose: bool,
+        non_matching: bool,
+        no_index: bool,
+    ) -> Result<bool> {
+        let mut rules = self.ignore_rules()?;
+        let tracked: Vec<String> = if no_index {
+            Vec::new()
+        } else {
+            self.load_index()
+                .map(|index| index.entries.into_iter().map(|e| e.file_path).collect())
+                .unwrap_or_default()
+        };
+
+        let mut any_ignored = false;
+        for path in paths {
+            let relative = path.trim_start_matches("./");
+            let is_dir = relative.ends_with('/') || self.path.join(relative).is_dir();
+            let relative = relative.trim_end_matches('/');
+
+            // tracked files are not subject to ignore rules
+            let matched = if tracked.iter().any(|t| t == relative) {
+                None
+            } else {
+                rules.matched(relative, is_dir)?
+            };
+
+            match matched {
+                Some(pattern) if verbose => {
+                    any_ignored |= !pattern.pattern.negated;
+                    println!(
+                        "{}:{}:{}\t{}",
+                        pattern.source, pattern.line, pattern.text, path
+                    );
+                }
+                Some(pattern) if !pattern.pattern.negated => {
+                    any_ignored = true;
+                    println!("{}", path);
+                }
+                _ if non_matching && verbose => println!("::\t{}", path),
+                _ if non_matching => println!("{}", path),
+                _ => {}
+            }
+        }
+
+        Ok(any_ignored)
+    }
+
+    /// Append the patterns missing from the `.gitignore` of `dir` (the top of
+    /// the worktree by default), creating it if needed. The patterns are the
+    /// given ones followed by those of a `template` file such as a language's
+    /// `.gitignore`. With `cached`, the tracked files the patterns now ignore
+    /// are removed from the index, staying in the worktree.
+    pub fn ignore_add(
+        &self,
+        patterns: &[String],
+        template: Option<&Path>,
+        dir: Option<&str>,
+        cached: bool,
+    ) -> Result<()> {
+        let dir = dir.unwrap_or("").trim_matches('/');
+        if dir.split('/').any(|c| c == ".." || c == ".git") {
+            return Err(anyhow!("'{}' is outside the worktree", dir));
+        }
+        let ignore_path = self.path.join(dir).join(".gitignore");
+        let display = Path::new(dir).join(".gitignore");
+
+        let mut wanted: Vec<String> = patterns.iter().map(|p| p.trim_end().to_string()).collect();
+        if let Some(template) = template {
+            // the comments and blank lines of templates are left out
+            wanted.extend(
+                fs::read_to_string(template)?
+                    .lines()
+                    .map(|line| line.trim_end())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from),
+            );
+        }
+
+        let mut content = match fs::read_to_string(&ignore_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut existing: Vec<String> = content.lines().map(|l| l.trim_end().to_string()).collect();
+
+        let mut added = 0;
+        for pattern in wanted {
+            if pattern.is_empty() || existing.contains(&pattern) {
+                continue;
+            }
+            if !content.is_empty() && !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.push_str(&pattern);
+            content.push('\n');
+            println!("Added '{}' to {}", pattern, display.display());
+            existing.push(pattern);
+            added += 1;
+        }
+
+        if added > 0 {
+            fs::create_dir_all(self.path.join(dir))?;
+            fs::write(&ignore_path, content)?;
+        }
+
+        if cached {
+            let mut rules = self.ignore_rules()?;
+            let mut removed = Vec::new();
+            for entry in self.load_index()?.entries {
+                let ignored = rules.is_ignored(&entry.file_path, false)?;
+                if ignored && !removed.contains(&entry.file_path) {
+                    println!("rm '{}'", entry.file_path);
+                    removed.push(entry.file_path);
+                }
+            }
+            if !removed.is_empty() {
+                self.remove_from_index(&removed)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::InitOptions;
+
+    fn test_repo(name: &str, files: &[(&str, &str)]) -> (PathBuf, Repository) {
+        let path = std::env::temp_dir().join(format!("mg-ignore-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        let mut repo = Repository::open(path.clone()).unwrap();
+        repo.init_repository(&path, &InitOptions::default())
+            .unwrap();
+
+        for (file, content) in files {
+            let file = path.join(file);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, content).unwrap();
+        }
+        (path, repo)
+    }
+
+    #[test]
+    fn test_negation_and_directories() {
+        let (path, repo) = test_repo(
+            "patterns",
+            &[(
+                ".gitignore",
+                "*.log\n!keep.log\nbuild/\n!build/kept\n**/cache\ndocs/**/*.md\nout/**\n",
+            )],
+        );
+        let mut rules = repo.ignore_rules().unwrap();
+
+        assert!(rules.is_ignored("a.log", false).unwrap());
+        assert!(rules.is_ignored("src/a.log", false).unwrap());
+        assert!(!rules.is_ignored("keep.log", false).unwrap());
+
+        // a directory-only pattern leaves files of that name alone
+        assert!(rules.is_ignored("build", true).unwrap());
+        assert!(!rules.is_ignored("build", false).unwrap());
+        assert!(rules.is_ignored("src/build", true).unwrap());
+        // nothing inside an ignored directory can be re-included
+        assert!(rules.is_ignored("build/kept", false).unwrap());
+        assert_eq!(
+            rules.matched("build/kept", false).unwrap().unwrap().text,
+            "build/"
+        );
+
+        assert!(rules.is_ignored("cache", true).unwrap());
+        assert!(rules.is_ignored("a/b/cache", false).unwrap());
+        assert!(rules.is_ignored("docs/a.md", false).unwrap());
+        assert!(rules.is_ignored("docs/x/y/z.md", false).unwrap());
+        assert!(!rules.is_ignored("docs/a.txt", false).unwrap());
+        assert!(!rules.is_ignored("src/docs/a.md", false).unwrap());
+        assert!(rules.is_ignored("out/a/b", false).unwrap());
+        assert!(!rules.is_ignored("out", true).unwrap());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_precedence() {
+        let (path, repo) = test_repo(
+            "precedence",
+            &[
+                ("excludes", "*.txt\n*.bak\n*.tmp\n"),
+                (".git/info/exclude", "!notes.txt\n*.o\n"),
+                (".gitignore", "!important.bak\n!main.o\n*.log\n"),
+                ("sub/.gitignore", "!local.log\n"),
+            ],
+        );
+        let mut config = fs::read_to_string(path.join(".git/config")).unwrap();
+        config.push_str(&format!(
+            "[core]\n\texcludesFile = {}\n",
+            path.join("excludes").display()
+        ));
+        fs::write(path.join(".git/config"), config).unwrap();
+        let mut rules = repo.ignore_rules().unwrap();
+
+        let source = |rules: &mut IgnoreRules, file: &str| {
+            rules
+                .matched(file, false)
+                .unwrap()
+                .map(|pattern| (pattern.source, pattern.text))
+        };
+
+        // core.excludesFile applies when nothing else matches
+        assert!(rules.is_ignored("other.txt", false).unwrap());
+        assert!(rules.is_ignored("a.tmp", false).unwrap());
+        // info/exclude comes before core.excludesFile
+        assert!(!rules.is_ignored("notes.txt", false).unwrap());
+        assert_eq!(
+            source(&mut rules, "notes.txt"),
+            Some((".git/info/exclude".to_string(), "!notes.txt".to_string()))
+        );
+        // .gitignore comes before both
+        assert!(!rules.is_ignored("important.bak", false).unwrap());
+        assert!(!rules.is_ignored("main.o", false).unwrap());
+        assert!(rules.is_ignored("other.o", false).unwrap());
+        // and the deepest .gitignore first
+        assert!(rules.is_ignored("local.log", false).unwrap());
+        assert!(!rules.is_ignored("sub/local.log", false).unwrap());
+        assert_eq!(
+            source(&mut rules, "sub/local.log"),
+            Some(("sub/.gitignore".to_string(), "!local.log".to_string()))
+        );
+        assert!(rules.is_ignored("sub/other.log", false).unwrap());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,4 +1,10 @@
-use std::{os::linux::fs::MetadataExt, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{File, Metadata},
+    io::{self, IsTerminal, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use nom::{
     bytes::complete::take,
@@ -10,7 +16,28 @@ use anyhow::{anyhow, Error, Result};
 use sha1::{Digest, Sha1};
 use walkdir::WalkDir;
 
-use crate::repository::Repository;
+use crate::{
+    cache_tree::CacheTree,
+    graph_export::json_string,
+    ignore::IgnoreRules,
+    kind::Kind,
+    lockfile::LockFile,
+    metadata::{stat_data, StatData},
+    mmap::Mmap,
+    object::{hash_blob, hash_object},
+    pack::HashWriter,
+    parallel::{default_jobs, parallel_map},
+    pathspec::Pathspec,
+    progress::Progress,
+    repository::Repository,
+    resolve_undo::ResolveUndo,
+    trace2,
+    tree::TreeFile,
+    untracked_cache::{invalidate_changed, UntrackedCache},
+};
+
+/// Entries from which writing the index reports its progress.
+const PROGRESS_THRESHOLD: usize = 100_000;
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -22,27 +49,41 @@ struct IndexHeader {
 
 #[derive(Debug)]
 #[allow(dead_code)]
-struct IndexEntry {
-    ctime_s: u32,
-    ctime_n: u32,
-    mtime_s: u32,
-    mtime_n: u32,
-    dev: u32,
-    ino: u32,
-    mode: u32,
-    uid: u32,
-    gid: u32,
-    size: u32,
-    sha1: [u8; 20],
-    flags: u16,
-    file_path: String,
+pub struct IndexEntry {
+    pub ctime_s: u32,
+    pub ctime_n: u32,
+    pub mtime_s: u32,
+    pub mtime_n: u32,
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub sha1: [u8; 20],
+    pub flags: u16,
+    pub extended_flags: u16,
+    pub file_path: String,
 }
 
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+/// The length of the path, or this mask itself when it is longer
+const FLAG_NAME_MASK: u16 = 0x0FFF;
+const EXTENDED_FLAG_SKIP_WORKTREE: u16 = 0x4000;
+
 #[derive(Debug)]
 #[allow(dead_code)]
-struct Index {
+pub struct Index {
     header: IndexHeader,
-    entries: Vec<IndexEntry>,
+    pub entries: Vec<IndexEntry>,
+    pub cache_tree: Option<CacheTree>,
+    pub resolve_undo: ResolveUndo,
+    pub untracked_cache: Option<UntrackedCache>,
+    /// The modification time, in seconds, of the index file read: entries
+    /// modified since, or in the same second, are racily clean
+    pub timestamp: Option<u32>,
 }
 
 fn parse_index(input: &[u8]) -> IResult<&[u8], Index> {
@@ -51,12 +92,56 @@ fn parse_index(input: &[u8]) -> IResult<&[u8], Index> {
     let mut entries = Vec::with_capacity(header.entries_count as usize);
 
     for _ in 0..header.entries_count {
-        let (remaining, entry) = parse_entry(input)?;
+        let (remaining, entry) = parse_entry(input, header.version)?;
         entries.push(entry);
         input = remaining;
     }
 
-    Ok((input, Index { header, entries }))
+    Ok((
+        input,
+        Index {
+            header,
+            entries,
+            cache_tree: None,
+            resolve_undo: ResolveUndo::default(),
+            untracked_cache: None,
+            timestamp: None,
+        },
+    ))
+}
+
+/// Read the extensions following the entries: each is a signature, a 32-bit
+/// size and the data. What remains after them is the checksum, if any.
+fn parse_extensions<'a>(index: &mut Index, mut input: &'a [u8]) -> Result<&'a [u8]> {
+    while input.len() >= 8 && input.len() != 20 {
+        let signature = &input[..4];
+        let size = u32::from_be_bytes(input[4..8].try_into().expect("4 bytes")) as usize;
+        if input.len() < 8 + size {
+            return Err(anyhow!(
+                "index extension {} is truncated",
+                String::from_utf8_lossy(signature)
+            ));
+        }
+
+        let data = &input[8..8 + size];
+        match signature {
+            b"TREE" => index.cache_tree = CacheTree::parse(data).ok(),
+            b"REUC" => index.resolve_undo = ResolveUndo::parse(data).unwrap_or_default(),
+            b"UNTR" => index.untracked_cache = UntrackedCache::parse(data).ok(),
+            // extensions starting with a capital letter are optional, and
+            // dropped when unknown
+            _ if signature[0].is_ascii_uppercase() => {}
+            _ => {
+                return Err(anyhow!(
+                    "index uses {} extension, which we do not understand",
+                    String::from_utf8_lossy(signature)
+                ))
+            }
+        }
+        input = &input[8 + size..];
+    }
+
+    Ok(input)
 }
 
 fn parse_header(input: &[u8]) -> IResult<&[u8], IndexHeader> {
@@ -76,7 +161,7 @@ fn parse_header(input: &[u8]) -> IResult<&[u8], IndexHeader> {
     ))
 }
 
-fn parse_entry(input: &[u8]) -> IResult<&[u8], IndexEntry> {
+fn parse_entry(input: &[u8], version: u32) -> IResult<&[u8], IndexEntry> {
     let start_input_len = input.len();
     let (
         input,
@@ -96,14 +181,24 @@ fn parse_entry(input: &[u8]) -> IResult<&[u8], IndexEntry> {
         be_u16,
     )
         .parse(input)?;
+
+    let (input, extended_flags) = if version >= 3 && flags & FLAG_EXTENDED != 0 {
+        be_u16(input)?
+    } else {
+        (input, 0)
+    };
     let current_input_len = input.len();
 
-    let path_len = flags & 0xFFF;
-    let (input, path_bytes) = take(path_len as usize)(input)?;
+    // a path too long for the flags ends at the first NUL of the padding
+    let path_len = match flags & FLAG_NAME_MASK {
+        FLAG_NAME_MASK => input.iter().position(|&b| b == 0).unwrap_or(input.len()),
+        path_len => path_len as usize,
+    };
+    let (input, path_bytes) = take(path_len)(input)?;
     let file_path = String::from_utf8_lossy(path_bytes).into_owned();
 
     //  between 1 and 8 NUL bytes to pad the entry.
-    let padding_len = 8 - ((start_input_len - current_input_len) + path_len as usize) % 8;
+    let padding_len = 8 - ((start_input_len - current_input_len) + path_len) % 8;
     let (input, _) = take(padding_len)(input)?;
 
     let mut sha1 = [0u8; 20];
@@ -124,133 +219,787 @@ fn parse_entry(input: &[u8]) -> IResult<&[u8], IndexEntry> {
             size,
             sha1,
             flags,
+            extended_flags,
             file_path,
         },
     ))
 }
 
+impl IndexEntry {
+    fn from_file(repo_path: &Path, file: &str) -> Result<Self> {
+        let stat = stat_data(&std::fs::metadata(repo_path.join(file))?);
+
+        Ok(IndexEntry {
+            ctime_s: stat.ctime_s,
+            ctime_n: stat.ctime_n,
+            mtime_s: stat.mtime_s,
+            mtime_n: stat.mtime_n,
+            dev: stat.dev,
+            ino: stat.ino,
+            mode: stat.mode,
+            uid: stat.uid,
+            gid: stat.gid,
+            size: stat.size,
+            sha1: hash_object(&repo_path.join(file))?,
+            flags: 0,
+            extended_flags: 0,
+            file_path: file.to_string(),
+        })
+    }
+
+    /// An entry for a path which is not present in the worktree.
+    fn skipped(file: &str, mode: u32, sha1: [u8; 20]) -> Self {
+        IndexEntry {
+            ctime_s: 0,
+            ctime_n: 0,
+            mtime_s: 0,
+            mtime_n: 0,
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            sha1,
+            flags: FLAG_EXTENDED,
+            extended_flags: EXTENDED_FLAG_SKIP_WORKTREE,
+            file_path: file.to_string(),
+        }
+    }
+
+    /// The kind of the entry, from its mode.
+    pub fn kind(&self) -> Kind {
+        match self.mode & 0o170000 {
+            0o120000 => Kind::Symlink,
+            0o160000 => Kind::Commit,
+            _ => Kind::Blob(self.mode & 0o100 != 0),
+        }
+    }
+
+    /// The merge stage: 0 for a regular entry, 1 (base), 2 (ours) or 3
+    /// (theirs) for a conflicted path.
+    pub fn stage(&self) -> u16 {
+        (self.flags & FLAG_STAGE_MASK) >> 12
+    }
+
+    pub fn skip_worktree(&self) -> bool {
+        self.extended_flags & EXTENDED_FLAG_SKIP_WORKTREE != 0
+    }
+
+    /// Whether the stat data of the entry is that of the file `metadata`
+    /// describes. A change of the executable bit changes the change time.
+    pub fn stat_matches(&self, metadata: &Metadata) -> bool {
+        let stat = stat_data(metadata);
+        self.mtime_s == stat.mtime_s
+            && self.mtime_n == stat.mtime_n
+            && self.ctime_s == stat.ctime_s
+            && self.ctime_n == stat.ctime_n
+            && self.ino == stat.ino
+            && self.size == stat.size
+            && self.mode & 0o170000 == stat.mode & 0o170000
+    }
+
+    /// Record `stat` as the stat data of the entry, keeping its mode.
+    fn update_stat(&mut self, stat: StatData) {
+        self.ctime_s = stat.ctime_s;
+        self.ctime_n = stat.ctime_n;
+        self.mtime_s = stat.mtime_s;
+        self.mtime_n = stat.mtime_n;
+        self.dev = stat.dev;
+        self.ino = stat.ino;
+        self.uid = stat.uid;
+        self.gid = stat.gid;
+        self.size = stat.size;
+    }
+
+    /// Whether the file `metadata` describes is known to still have the
+    /// content of the entry, without hashing it. The stat data must match,
+    /// and not be racily clean in the index written at `index_timestamp`:
+    /// modified in the same second, the file may have changed again after
+    /// it was hashed, without its modification time telling.
+    pub fn is_up_to_date(&self, metadata: &Metadata, index_timestamp: Option<u32>) -> bool {
+        self.stat_matches(metadata)
+            && index_timestamp.is_some_and(|timestamp| timestamp > self.mtime_s)
+    }
+
+    /// Whether the file of the entry, racily clean if modified from
+    /// `racy_from` on, differs from it. Its size is then written as 0, so
+    /// that its stat data no longer matches and it is hashed again.
+    fn needs_smudge(&self, repo_path: &Path, racy_from: u32) -> bool {
+        if self.mtime_s < racy_from || self.skip_worktree() || self.stage() > 0 {
+            return false;
+        }
+        hash_object(&repo_path.join(&self.file_path)).map_or(true, |hash| hash != self.sha1)
+    }
+
+    fn serialize(&self, smudged: bool) -> Vec<u8> {
+        let mut entry_content = Vec::new();
+        entry_content.extend_from_slice(&self.ctime_s.to_be_bytes());
+        entry_content.extend_from_slice(&self.ctime_n.to_be_bytes());
+        entry_content.extend_from_slice(&self.mtime_s.to_be_bytes());
+        entry_content.extend_from_slice(&self.mtime_n.to_be_bytes());
+        entry_content.extend_from_slice(&self.dev.to_be_bytes());
+        entry_content.extend_from_slice(&self.ino.to_be_bytes());
+        entry_content.extend_from_slice(&self.mode.to_be_bytes());
+        entry_content.extend_from_slice(&self.uid.to_be_bytes());
+        entry_content.extend_from_slice(&self.gid.to_be_bytes());
+        let size = if smudged { 0 } else { self.size };
+        entry_content.extend_from_slice(&size.to_be_bytes());
+        entry_content.extend_from_slice(&self.sha1);
+
+        let path_bytes = self.file_path.as_bytes();
+        let mut flags = path_bytes.len().min(FLAG_NAME_MASK as usize) as u16
+            | (self.flags & (FLAG_ASSUME_VALID | FLAG_STAGE_MASK));
+        if self.extended_flags != 0 {
+            flags |= FLAG_EXTENDED;
+        }
+        entry_content.extend_from_slice(&flags.to_be_bytes());
+        if self.extended_flags != 0 {
+            entry_content.extend_from_slice(&self.extended_flags.to_be_bytes());
+        }
+        entry_content.extend_from_slice(path_bytes);
+
+        //  between 1 and 8 NUL bytes to pad the entry.
+        let padding_len = 8 - entry_content.len() % 8;
+        entry_content.extend(vec![0u8; padding_len]);
+
+        entry_content
+    }
+}
+
 impl Index {
     pub fn read_from_file(path: &Path) -> Result<Self, Error> {
-        let content = std::fs::read(path)?;
-        let (_remaining, index) =
+        let _region = trace2::region("index", "do_read_index");
+        let file = File::open(path)?;
+        let timestamp = stat_data(&file.metadata()?).mtime_s;
+        // the entries are parsed straight out of the mapping
+        let content = Mmap::map(&file)?;
+        let (remaining, mut index) =
             parse_index(&content).map_err(|e| anyhow!("Failed to parse index: {}", e))?;
+        let checksum = parse_extensions(&mut index, remaining)?;
+        // indexes written without one end after their extensions, and
+        // `index.skipHash` writes a null one
+        if checksum.len() == 20 && checksum != [0; 20] {
+            let content = &content[..content.len() - 20];
+            if Sha1::digest(content).as_slice() != checksum {
+                return Err(anyhow!("index file corrupt: bad checksum"));
+            }
+        }
+        index.timestamp = Some(timestamp);
         Ok(index)
     }
 }
 
 impl Repository {
-    pub fn read_index(&self) -> Result<()> {
-        let index_path = self.path.join(".git").join("index");
+    /// Load the index, or an empty one if it does not exist yet.
+    pub fn load_index(&self) -> Result<Index> {
+        let index_path = self.index_path();
+        if !index_path.exists() {
+            return Ok(Index::new(Vec::new()));
+        }
+
+        Index::read_from_file(&index_path)
+    }
+
+    /// The entries of the index, or with `resolve_undo` the stages of the
+    /// conflicts resolved since, as `ls-index` lists them.
+    pub fn read_index(&self, resolve_undo: bool) -> Result<Vec<IndexListing>> {
+        let index_path = self.index_path();
         let index = Index::read_from_file(&index_path)?;
 
-        for entry in index.entries {
-            println!("{} {}", hex::encode(entry.sha1), entry.file_path);
+        if resolve_undo {
+            let mut listings = Vec::new();
+            for entry in &index.resolve_undo.entries {
+                for (stage, recorded) in entry.stages.iter().enumerate() {
+                    if let Some((mode, hash)) = recorded {
+                        listings.push(IndexListing {
+                            mode: *mode,
+                            hash: *hash,
+                            stage: stage + 1,
+                            path: entry.path.clone(),
+                        });
+                    }
+                }
+            }
+            return Ok(listings);
         }
 
-        Ok(())
+        Ok(index
+            .entries
+            .into_iter()
+            .map(|entry| IndexListing {
+                mode: entry.mode,
+                hash: entry.sha1,
+                stage: entry.stage() as usize,
+                path: entry.file_path,
+            })
+            .collect())
     }
 
     pub fn write_index(&self) -> Result<()> {
-        let index_path = self.path.join(".git").join("index");
+        let index_path = self.index_path();
+
+        let (changed, token) = self.fsmonitor_changes()?;
+        let (files, mut entries) = match &changed {
+            Some(changed) if index_path.exists() => self.monitored_entries(changed)?,
+            _ => {
+                // list all files in the repository
+                let files = list_all_files(&self.path, &mut self.ignore_rules()?)?;
+
+                let entries = parallel_map(&files, default_jobs(), |file| {
+                    IndexEntry::from_file(&self.path, file)
+                })
+                .into_iter()
+                .collect::<Result<Vec<_>>>()?;
+                (files, entries)
+            }
+        };
 
-        // list all files in the repository
-        let files = list_all_files(&self.path, &self.ignore)?;
+        let mut cache_tree = None;
+        let mut resolve_undo = ResolveUndo::default();
+        let mut untracked_cache = None;
+        let mut timestamp = None;
+        if index_path.exists() {
+            let previous = Index::read_from_file(&index_path)?;
+            timestamp = previous.timestamp;
+
+            // the conflicts the worktree files resolve
+            resolve_undo = previous.resolve_undo;
+            resolve_undo.record(
+                previous
+                    .entries
+                    .iter()
+                    .filter(|e| files.binary_search(&e.file_path).is_ok()),
+            );
+
+            // keep the cached trees of the directories in which nothing changed
+            if let Some(mut tree) = previous.cache_tree {
+                let old: HashMap<&str, &IndexEntry> = previous
+                    .entries
+                    .iter()
+                    .map(|e| (e.file_path.as_str(), e))
+                    .collect();
+                for entry in &entries {
+                    match old.get(entry.file_path.as_str()) {
+                        Some(o)
+                            if o.sha1 == entry.sha1
+                                && o.kind().to_mode() == entry.kind().to_mode() => {}
+                        _ => tree.invalidate(&entry.file_path),
+                    }
+                }
+                for path in old.keys() {
+                    if files.binary_search_by(|f| f.as_str().cmp(path)).is_err() {
+                        tree.invalidate(path);
+                    }
+                }
+                cache_tree = Some(tree);
+            }
 
-        let index = Index {
-            header: IndexHeader {
-                signature: *b"DIRC",
-                version: 2,
-                entries_count: files.len() as u32,
-            },
-            entries: Vec::new(),
-        };
+            // the listings of the directories in which files were added or
+            // removed, or in which the monitor saw changes, are read again
+            if let Some(mut cache) = previous.untracked_cache {
+                invalidate_changed(&mut cache, &previous.entries, &entries);
+                for path in changed.iter().flatten() {
+                    cache.invalidate(path);
+                }
+                untracked_cache = Some(cache);
+            }
+
+            // keep entries which are deliberately absent from a sparse worktree
+            entries.extend(
+                previous
+                    .entries
+                    .into_iter()
+                    .filter(|e| e.skip_worktree() && files.binary_search(&e.file_path).is_err()),
+            );
+        }
 
-        let mut content = Vec::new();
-        content.extend_from_slice(&index.header.signature);
-        content.extend_from_slice(&index.header.version.to_be_bytes());
-        content.extend_from_slice(&index.header.entries_count.to_be_bytes());
+        let mut index = Index::new(entries);
+        index.cache_tree = cache_tree;
+        index.resolve_undo = resolve_undo;
+        index.untracked_cache = untracked_cache;
+        index.timestamp = timestamp;
+        index.write_to_file(&index_path)?;
+        self.save_fsmonitor_token(token.as_deref())
+    }
 
-        for file in files {
-            let metadata = std::fs::metadata(self.path.join(file.clone()))?;
-
-            let entry = IndexEntry {
-                ctime_s: metadata.st_ctime() as u32,
-                ctime_n: metadata.st_ctime_nsec() as u32,
-                mtime_s: metadata.st_mtime() as u32,
-                mtime_n: metadata.st_mtime_nsec() as u32,
-                dev: metadata.st_dev() as u32,
-                ino: metadata.st_ino() as u32,
-                mode: metadata.st_mode(),
-                uid: metadata.st_uid(),
-                gid: metadata.st_gid(),
-                size: metadata.st_size() as u32,
-                sha1: hash_file(&self.path.join(file.clone()))?,
-                flags: 0,
-                file_path: file,
+    /// The worktree files and their entries when only the `changed` paths
+    /// reported by the filesystem monitor may differ from the index: the
+    /// other entries are kept as they are, without looking at their files.
+    fn monitored_entries(&self, changed: &[String]) -> Result<(Vec<String>, Vec<IndexEntry>)> {
+        let previous = self.load_index()?;
+        let mut entries: BTreeMap<String, IndexEntry> = previous
+            .entries
+            .into_iter()
+            .filter(|e| !e.skip_worktree() && e.stage() == 0)
+            .map(|e| (e.file_path.clone(), e))
+            .collect();
+
+        // a changed directory is listed again, from a scan made at most once
+        let mut all_files = None;
+        let mut rules = self.ignore_rules()?;
+        for path in changed {
+            let path = path.trim_end_matches('/');
+            let full_path = self.path.join(path);
+            if full_path.is_dir() {
+                let prefix = format!("{}/", path);
+                entries.retain(|name, _| !name.starts_with(&prefix));
+                if all_files.is_none() {
+                    all_files = Some(list_all_files(&self.path, &mut rules)?);
+                }
+                for file in all_files.iter().flatten() {
+                    if file.starts_with(&prefix) {
+                        let entry = IndexEntry::from_file(&self.path, file)?;
+                        entries.insert(file.clone(), entry);
+                    }
+                }
+                continue;
+            }
+
+            if full_path.is_file() && !rules.is_ignored(path, false)? {
+                entries.insert(path.to_string(), IndexEntry::from_file(&self.path, path)?);
+            } else {
+                entries.remove(path);
+            }
+        }
+
+        let files = entries.keys().cloned().collect();
+        Ok((files, entries.into_values().collect()))
+    }
+
+    /// Update the stat data of the entries whose files changed on disk but
+    /// not in content, so that later commands need not hash them again.
+    /// This is optional: nothing is done without `optional_locks`, and the
+    /// index is left as it is if another process holds its lock.
+    pub fn refresh_index(&self) -> Result<()> {
+        if !self.optional_locks {
+            return Ok(());
+        }
+
+        let mut index = self.load_index()?;
+        let mut attributes = self.attributes()?;
+        let mut refreshed = false;
+        for entry in index.entries.iter_mut() {
+            if entry.stage() > 0 || entry.skip_worktree() {
+                continue;
+            }
+            let path = self.path.join(&entry.file_path);
+            let Ok(metadata) = path.metadata() else {
+                continue;
             };
+            if !metadata.is_file() || entry.is_up_to_date(&metadata, index.timestamp) {
+                continue;
+            }
 
-            let mut entry_content = Vec::new();
-            entry_content.extend_from_slice(&entry.ctime_s.to_be_bytes());
-            entry_content.extend_from_slice(&entry.ctime_n.to_be_bytes());
-            entry_content.extend_from_slice(&entry.mtime_s.to_be_bytes());
-            entry_content.extend_from_slice(&entry.mtime_n.to_be_bytes());
-            entry_content.extend_from_slice(&entry.dev.to_be_bytes());
-            entry_content.extend_from_slice(&entry.ino.to_be_bytes());
-            entry_content.extend_from_slice(&entry.mode.to_be_bytes());
-            entry_content.extend_from_slice(&entry.uid.to_be_bytes());
-            entry_content.extend_from_slice(&entry.gid.to_be_bytes());
-            entry_content.extend_from_slice(&entry.size.to_be_bytes());
-            entry_content.extend_from_slice(&entry.sha1);
-            //entry_content.extend_from_slice(&entry.flags.to_be_bytes());
-
-            let path_bytes = entry.file_path.as_bytes();
-            entry_content.extend_from_slice(&(path_bytes.len() as u16).to_be_bytes());
-            entry_content.extend_from_slice(path_bytes);
-
-            //  between 1 and 8 NUL bytes to pad the entry.
-            let padding_len = 8 - entry_content.len() % 8;
-            entry_content.extend(vec![0u8; padding_len]);
-
-            content.extend(entry_content);
+            let content = std::fs::read(&path)?;
+            let content = self.convert_to_git(&mut attributes, &entry.file_path, content)?;
+            if hash_blob(&content) == entry.sha1 {
+                entry.update_stat(stat_data(&metadata));
+                refreshed = true;
+            }
         }
 
-        std::fs::write(index_path, content)?;
+        if refreshed {
+            // a busy index is refreshed by a later command instead
+            let _ = index.write_to_file(&self.index_path());
+        }
 
         Ok(())
     }
-}
 
-pub fn list_all_files(path: &Path, ignore_list: &[String]) -> Result<Vec<String>> {
-    let mut files = Vec::new();
+    /// Replace the conflicted stages of `path` with its worktree content.
+    pub fn mark_resolved(&self, path: &str) -> Result<()> {
+        let index_path = self.index_path();
+        let index = self.load_index()?;
+        let mut entries = index.entries;
+        let mut resolve_undo = index.resolve_undo;
+
+        resolve_undo.record(entries.iter().filter(|e| e.file_path == path));
+        entries.retain(|e| e.file_path != path);
+        self.write_blob(&self.path.join(path))?;
+        entries.push(IndexEntry::from_file(&self.path, path)?);
+
+        let mut resolved = Index::new(entries);
+        resolved.cache_tree = index.cache_tree.map(|mut tree| {
+            tree.invalidate(path);
+            tree
+        });
+        resolved.resolve_undo = resolve_undo;
+        resolved.timestamp = index.timestamp;
+        resolved.write_to_file(&index_path)
+    }
 
-    for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
-        if entry.file_type().is_file() {
-            if ignore_list.iter().any(|i| entry.path().ends_with(i)) {
-                continue;
+    /// Stage the worktree files `pathspec` matches: their content is written
+    /// to the object database and their entries updated, and the entries of
+    /// files which are gone are dropped.
+    pub fn add(&self, pathspec: &Pathspec) -> Result<()> {
+        let index = self.load_index()?;
+        let matches = |file: &str| pathspec.matches(file);
+
+        let mut files: Vec<String> = list_all_files(&self.path, &mut self.ignore_rules()?)?
+            .into_iter()
+            .filter(|file| matches(file))
+            .collect();
+        // tracked files are not subject to ignore rules
+        files.extend(
+            index
+                .entries
+                .iter()
+                .filter(|e| matches(&e.file_path) && !e.skip_worktree())
+                .filter(|e| self.path.join(&e.file_path).is_file())
+                .map(|e| e.file_path.clone()),
+        );
+        files.sort();
+        files.dedup();
+        let known: Vec<&str> = files
+            .iter()
+            .chain(index.entries.iter().map(|e| &e.file_path))
+            .map(String::as_str)
+            .collect();
+        if let Some(spec) = pathspec.unmatched(&known) {
+            return Err(anyhow!("pathspec '{}' did not match any files", spec));
+        }
+
+        self.stage(index, matches, &files)
+    }
+
+    /// Stage the worktree files `files`, sorted, as they are now.
+    pub fn stage_files(&self, files: &[String]) -> Result<()> {
+        let index = self.load_index()?;
+        self.stage(
+            index,
+            |file| files.binary_search_by(|f| f.as_str().cmp(file)).is_ok(),
+            files,
+        )
+    }
+
+    /// Stage the changes of the tracked files, their removal included, as
+    /// `commit -a` does. Untracked files are left alone.
+    pub fn add_tracked(&self) -> Result<()> {
+        let index = self.load_index()?;
+        let tracked: HashSet<String> = index
+            .entries
+            .iter()
+            .filter(|e| !e.skip_worktree())
+            .map(|e| e.file_path.clone())
+            .collect();
+
+        let mut files: Vec<String> = tracked
+            .iter()
+            .filter(|path| self.path.join(path).is_file())
+            .cloned()
+            .collect();
+        files.sort();
+
+        self.stage(index, |file| tracked.contains(file), &files)
+    }
+
+    /// Write the index with the entries of `files`, the sorted worktree
+    /// files matched by `matches`, updated from their content, and without
+    /// the other entries matched.
+    fn stage(&self, index: Index, matches: impl Fn(&str) -> bool, files: &[String]) -> Result<()> {
+        let index_path = self.index_path();
+        let mut cache_tree = index.cache_tree;
+        let mut untracked_cache = index.untracked_cache;
+        let mut resolve_undo = index.resolve_undo;
+        resolve_undo.record(
+            index
+                .entries
+                .iter()
+                .filter(|e| files.binary_search(&e.file_path).is_ok()),
+        );
+
+        let mut entries = Vec::with_capacity(index.entries.len());
+        let mut previous = HashMap::new();
+        for entry in index.entries {
+            // skipped entries have no file to stage
+            if !matches(&entry.file_path) || entry.skip_worktree() {
+                entries.push(entry);
+            } else if entry.stage() == 0 {
+                previous.insert(entry.file_path.clone(), entry);
+            }
+        }
+
+        let trust_executable_bit = self.trust_executable_bit()?;
+        let mut changed: Vec<String> = previous
+            .keys()
+            .filter(|path| files.binary_search(path).is_err())
+            .cloned()
+            .collect();
+        let mut modified = Vec::new();
+        for file in files {
+            if let Some(old) = previous.get(file) {
+                if old.is_up_to_date(&self.path.join(file).metadata()?, index.timestamp) {
+                    entries.push(previous.remove(file).expect("found above"));
+                    continue;
+                }
             }
+            modified.push(file);
+        }
 
-            let s = entry.path().to_path_buf().to_str().unwrap().to_string();
-            let s = s.strip_prefix(path.to_str().unwrap()).unwrap().to_string();
+        // the blobs are hashed and compressed in parallel
+        let written = parallel_map(&modified, default_jobs(), |file| -> Result<_> {
+            let hash = self.write_blob(&self.path.join(file))?;
+            Ok((hash, IndexEntry::from_file(&self.path, file)?))
+        });
+        for (file, written) in modified.into_iter().zip(written) {
+            let (hash, mut entry) = written?;
+            entry.sha1 = hash;
+            // without a trusted executable bit, files keep the one they had
+            if !trust_executable_bit && entry.mode & 0o170000 == 0o100000 {
+                let executable = previous.get(file).is_some_and(|old| old.mode & 0o100 != 0);
+                entry.mode = if executable { 0o100755 } else { 0o100644 };
+            }
 
-            if ignore_list.iter().any(|i| s.starts_with(i)) {
-                continue;
+            match previous.get(file) {
+                Some(old) if old.sha1 == hash && old.kind().to_mode() == entry.kind().to_mode() => {
+                }
+                _ => changed.push(file.clone()),
             }
+            entries.push(entry);
+        }
 
-            files.push(s.strip_prefix("/").unwrap().to_string());
+        if let Some(tree) = &mut cache_tree {
+            for path in &changed {
+                tree.invalidate(path);
+            }
+        }
+        if let Some(cache) = &mut untracked_cache {
+            for path in &changed {
+                cache.invalidate(path);
+            }
         }
+        let mut staged = Index::new(entries);
+        staged.cache_tree = cache_tree;
+        staged.untracked_cache = untracked_cache;
+        staged.resolve_undo = resolve_undo;
+        staged.timestamp = index.timestamp;
+        staged.write_to_file(&index_path)
     }
 
-    files.sort();
+    /// Drop `paths` from the index, leaving the worktree alone.
+    pub fn remove_from_index(&self, paths: &[String]) -> Result<()> {
+        let index_path = self.index_path();
+        let index = self.load_index()?;
+        let mut entries = index.entries;
+        let mut resolve_undo = index.resolve_undo;
+        resolve_undo.record(entries.iter().filter(|e| paths.contains(&e.file_path)));
+        entries.retain(|e| !paths.contains(&e.file_path));
+
+        let mut removed = Index::new(entries);
+        removed.cache_tree = index.cache_tree.map(|mut tree| {
+            for path in paths {
+                tree.invalidate(path);
+            }
+            tree
+        });
+        removed.untracked_cache = index.untracked_cache.map(|mut cache| {
+            for path in paths {
+                cache.invalidate(path);
+            }
+            cache
+        });
+        removed.resolve_undo = resolve_undo;
+        removed.timestamp = index.timestamp;
+        removed.write_to_file(&index_path)
+    }
 
-    Ok(files)
+    /// Write the index for a freshly checked out tree: materialized files get
+    /// their stat data, the others are marked skip-worktree.
+    pub fn write_index_from_tree(&self, files: &[TreeFile], materialized: &[bool]) -> Result<()> {
+        let index_path = self.index_path();
+
+        let mut entries = Vec::with_capacity(files.len());
+        for (file, materialized) in files.iter().zip(materialized) {
+            if *materialized {
+                entries.push(IndexEntry::from_file(&self.path, &file.path)?);
+            } else {
+                let mode = u32::from_str_radix(file.kind.to_mode(), 8)?;
+                entries.push(IndexEntry::skipped(&file.path, mode, file.hash));
+            }
+        }
+
+        Index::new(entries).write_to_file(&index_path)
+    }
+
+    /// Set the skip-worktree bit of the entries of `skip`, whose files were
+    /// removed, and clear it for those of `unskip`, whose files are back in
+    /// the worktree. The other entries are left as they are.
+    pub fn update_skip_worktree(&self, skip: &[String], unskip: &[String]) -> Result<()> {
+        let index = self.load_index()?;
+        let mut entries = Vec::with_capacity(index.entries.len());
+        for entry in index.entries {
+            if entry.stage() != 0 {
+                entries.push(entry);
+            } else if skip.contains(&entry.file_path) {
+                entries.push(IndexEntry::skipped(
+                    &entry.file_path,
+                    entry.mode,
+                    entry.sha1,
+                ));
+            } else if unskip.contains(&entry.file_path) {
+                let mut unskipped = IndexEntry::from_file(&self.path, &entry.file_path)?;
+                unskipped.sha1 = entry.sha1;
+                unskipped.mode = entry.mode;
+                entries.push(unskipped);
+            } else {
+                entries.push(entry);
+            }
+        }
+
+        let mut updated = Index::new(entries);
+        updated.cache_tree = index.cache_tree;
+        updated.untracked_cache = index.untracked_cache.map(|mut cache| {
+            for path in skip.iter().chain(unskip) {
+                cache.invalidate(path);
+            }
+            cache
+        });
+        updated.resolve_undo = index.resolve_undo;
+        updated.timestamp = index.timestamp;
+        updated.write_to_file(&self.index_path())
+    }
+}
+
+impl Index {
+    fn new(mut entries: Vec<IndexEntry>) -> Self {
+        entries.sort_by(|a, b| {
+            a.file_path
+                .cmp(&b.file_path)
+                .then(a.stage().cmp(&b.stage()))
+        });
+
+        let version = if entries.iter().any(|e| e.extended_flags != 0) {
+            3
+        } else {
+            2
+        };
+
+        Index {
+            header: IndexHeader {
+                signature: *b"DIRC",
+                version,
+                entries_count: entries.len() as u32,
+            },
+            entries,
+            cache_tree: None,
+            resolve_undo: ResolveUndo::default(),
+            untracked_cache: None,
+            timestamp: None,
+        }
+    }
+
+    /// Write the index to `path` through `<path>.lock`, entry by entry, so
+    /// that even a very large index is never held serialized in memory and
+    /// `path` stays intact if writing fails or is interrupted. The SHA-1
+    /// of everything written is appended as the checksum.
+    pub fn write_to_file(&self, path: &Path) -> Result<()> {
+        let _region = trace2::region("index", "do_write_index");
+        let mut out = HashWriter {
+            inner: LockFile::acquire(path)?,
+            hasher: Sha1::new(),
+        };
+        out.write_all(&self.header.signature)?;
+        out.write_all(&self.header.version.to_be_bytes())?;
+        out.write_all(&self.header.entries_count.to_be_bytes())?;
+
+        // entries modified in the second the index was read, or from now
+        // on that it is written, are racily clean
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+        let racy_from = self.timestamp.map_or(now, |timestamp| timestamp.min(now));
+        let repo_path = path
+            .parent()
+            .and_then(Path::parent)
+            .ok_or_else(|| anyhow!("invalid index path {}", path.display()))?;
+
+        let total = self.entries.len();
+        let mut progress = Progress::new(
+            "Writing index",
+            Some(total as u64),
+            total >= PROGRESS_THRESHOLD && io::stderr().is_terminal(),
+        );
+        for entry in &self.entries {
+            out.write_all(&entry.serialize(entry.needs_smudge(repo_path, racy_from)))?;
+            progress.tick();
+        }
+        progress.finish();
+
+        if let Some(cache_tree) = &self.cache_tree {
+            let mut data = Vec::new();
+            cache_tree.serialize(&mut data);
+            out.write_all(b"TREE")?;
+            out.write_all(&(data.len() as u32).to_be_bytes())?;
+            out.write_all(&data)?;
+        }
+        if !self.resolve_undo.is_empty() {
+            let mut data = Vec::new();
+            self.resolve_undo.serialize(&mut data);
+            out.write_all(b"REUC")?;
+            out.write_all(&(data.len() as u32).to_be_bytes())?;
+            out.write_all(&data)?;
+        }
+        if let Some(untracked_cache) = &self.untracked_cache {
+            let mut data = Vec::new();
+            untracked_cache.serialize(&mut data);
+            out.write_all(b"UNTR")?;
+            out.write_all(&(data.len() as u32).to_be_bytes())?;
+            out.write_all(&data)?;
+        }
+
+        let checksum = out.hasher.finalize();
+        out.inner.write_all(&checksum)?;
+        out.inner.commit()
+    }
+}
+
+/// An index entry, or a stage resolve-undo recorded, as `read_index`
+/// lists them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexListing {
+    pub mode: u32,
+    pub hash: [u8; 20],
+    pub stage: usize,
+    pub path: String,
 }
 
-fn hash_file(path: &Path) -> Result<[u8; 20]> {
-    let content = std::fs::read(path)?;
+impl IndexListing {
+    pub fn json(&self) -> String {
+        format!(
+            "{{\"type\":\"entry\",\"mode\":\"{:06o}\",\"id\":\"{}\",\"stage\":{},\"path\":{}}}",
+            self.mode,
+            hex::encode(self.hash),
+            self.stage,
+            json_string(&self.path)
+        )
+    }
+}
+
+/// Whether `file` is `spec` or below it, the empty spec matching everything.
+/// The files of the worktree at `path` which `rules` do not ignore, sorted.
+/// Ignored directories are not walked into.
+pub fn list_all_files(path: &Path, rules: &mut IgnoreRules) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+
+    let mut walker = WalkDir::new(path).min_depth(1).into_iter();
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let is_dir = entry.file_type().is_dir();
+        let relative = entry.path().strip_prefix(path)?.to_string_lossy();
+        if (is_dir && relative == ".git") || rules.is_ignored(&relative, is_dir)? {
+            if is_dir {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+
+        if entry.file_type().is_file() {
+            files.push(relative.into_owned());
+        }
+    }
 
-    let mut hasher = Sha1::new();
-    hasher.update(format!("blob {}\0", content.len()).as_bytes());
-    hasher.update(content);
+    files.sort();
 
-    Ok(hasher.finalize().into())
+    Ok(files)
 }
@@ -0,0 +1,114 @@
+use std::{
+    fs::{self, File},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use hex::FromHex;
+
+use crate::{pack_index::PackIndex, repository::Repository};
+
+/// The refs of a snapshot: `<id> <ref>` lines as `ls-remote` and `clone`
+/// print them. Other lines, such as headers, and peeled `^{}` entries are
+/// skipped.
+pub fn parse_ref_snapshot(content: &str) -> Vec<(String, [u8; 20])> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (id, name) = line.split_once([' ', '\t'])?;
+            let id = <[u8; 20]>::from_hex(id).ok()?;
+            let name = name.trim();
+            let valid = name == "HEAD" || name.starts_with("refs/");
+            (valid && !name.ends_with("^{}")).then(|| (name.to_string(), id))
+        })
+        .collect()
+}
+
+impl Repository {
+    /// Move the standalone pack `file` into `.git/objects/pack` once it is
+    /// checked and indexed, then update the refs of `snapshot` if given.
+    /// The pack and its index only take their final names when complete, so
+    /// an interrupted ingest leaves nothing another command would read, and
+    /// refs are only written once their objects are known to be there. When
+    /// HEAD had no commit yet, the branch of the snapshot is checked out.
+    pub fn ingest_pack(&self, file: &Path, snapshot: Option<&Path>) -> Result<String> {
+        let refs = match snapshot {
+            Some(path) => parse_ref_snapshot(&fs::read_to_string(path)?),
+            None => Vec::new(),
+        };
+        if snapshot.is_some() && refs.is_empty() {
+            return Err(anyhow!("no refs in the snapshot"));
+        }
+
+        // a pack of a partial clone comes from its promisor remote
+        let promisor = self.promisor_remote()?.is_some();
+        let (temp, idx) = self
+            .receive_pack(File::open(file)?)
+            .map_err(|e| anyhow!("invalid pack {}: {}", file.display(), e))?;
+
+        let packed = PackIndex::open(&idx)?;
+        for (ref_name, id) in &refs {
+            if packed.find_offset(id)?.is_none() && !self.has_object(id) {
+                let _ = fs::remove_file(&temp);
+                let _ = fs::remove_file(&idx);
+                return Err(anyhow!(
+                    "{} points to {}, which is neither in the pack nor in the repository",
+                    ref_name,
+                    hex::encode(id)
+                ));
+            }
+        }
+
+        let name = self.install_pack(&temp, &idx, promisor)?;
+        fs::remove_file(file)?;
+
+        let unborn = self.head_commit()?.is_none();
+        for (ref_name, id) in refs.iter().filter(|(name, _)| name != "HEAD") {
+            self.update_ref(ref_name, id)?;
+            println!("{} {}", hex::encode(id), ref_name);
+        }
+
+        if unborn {
+            let head = self.read_head()?;
+            let current = head.trim().strip_prefix("ref: ").map(str::to_string);
+            let branch = match current.filter(|name| refs.iter().any(|(n, _)| n == name)) {
+                Some(name) => Some(name),
+                // the branch the snapshot's HEAD is at
+                None => refs
+                    .iter()
+                    .find(|(name, _)| name == "HEAD")
+                    .and_then(|(_, head)| {
+                        refs.iter()
+                            .find(|(name, id)| name.starts_with("refs/heads/") && id == head)
+                    })
+                    .map(|(name, _)| name.clone()),
+            };
+            if let Some(branch) = branch {
+                self.update_symbolic_ref("HEAD", &branch)?;
+                self.checkout(branch.trim_start_matches("refs/heads/"), false)?;
+            }
+        }
+
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ref_snapshot() {
+        let snapshot = "Refs:\n\
+            1cb29c18fbd0d279fc276b0f7a5f8356d0775914 HEAD\n\
+            1cb29c18fbd0d279fc276b0f7a5f8356d0775914 refs/heads/main\n\
+            a5debbc0a5debbc0a5debbc0a5debbc0a5debbc0\trefs/tags/v1\n\
+            1cb29c18fbd0d279fc276b0f7a5f8356d0775914 refs/tags/v1^{}\n\
+            Downloaded file size: 1234\n";
+        let names: Vec<String> = parse_ref_snapshot(snapshot)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["HEAD", "refs/heads/main", "refs/tags/v1"]);
+    }
+}
@@ -2,12 +2,13 @@ use std::fmt;
 
 use anyhow::{anyhow, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Kind {
     Blob(bool), // 100644 or 100755
     Commit,     // 160000
     Tree,       // 040000
     Symlink,    // 120000
+    Tag,
 }
 
 impl Kind {
@@ -23,13 +24,14 @@ impl Kind {
         }
     }
 
-    pub fn to_mode(&self) -> &str {
+    pub fn to_mode(&self) -> &'static str {
         match self {
             Kind::Blob(false) => "100644",
             Kind::Blob(true) => "100755",
             Kind::Commit => "160000",
             Kind::Tree => "40000",
             Kind::Symlink => "120000",
+            Kind::Tag => unreachable!("tags are not tree entries"),
         }
     }
 }
@@ -41,6 +43,7 @@ impl fmt::Display for Kind {
             Kind::Commit => "commit",
             Kind::Tree => "tree",
             Kind::Symlink => "symlink",
+            Kind::Tag => "tag",
         };
         write!(f, "{}", kind)
     }
@@ -0,0 +1,109 @@
+//! mg, a git implementation: repositories, their objects, index, packs
+//! and refs, and the commands working on them.
+//!
+//! Everything goes through a [`Repository`](repository::Repository), opened
+//! with [`Repository::open`](repository::Repository::open). Commands which
+//! produce data, such as [`log`](repository::Repository::log),
+//! [`status_changes`](repository::Repository::status_changes) or
+//! [`list_refs`](repository::Repository::list_refs), return it rather than
+//! print it; the `mg` binary is a thin command line over them.
+//!
+//! Failures are `anyhow` errors. Those a caller may act on, such as a
+//! missing object or an unknown revision, carry an
+//! [`MgError`](error::MgError), found with
+//! [`MgError::find`](error::MgError::find).
+
+pub mod alias;
+pub mod alternates;
+pub mod apply;
+pub mod archive;
+pub mod attributes;
+pub(crate) mod bitmap;
+pub mod branch;
+pub(crate) mod cache_tree;
+pub mod cat_file;
+pub mod checkout;
+pub mod color;
+pub mod commit;
+pub mod commit_message;
+pub mod config;
+#[cfg(feature = "http")]
+pub mod credential;
+#[cfg(feature = "server")]
+pub mod daemon;
+pub mod diff;
+#[cfg(feature = "ui")]
+pub mod difftool;
+pub mod error;
+#[cfg(feature = "http")]
+pub mod fetch;
+pub(crate) mod fsmonitor;
+pub mod gc;
+pub mod graph;
+pub mod graph_export;
+pub mod grep;
+pub(crate) mod hooks;
+pub mod html;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod ident;
+pub mod ignore;
+pub mod index;
+pub mod ingest_pack;
+pub mod kind;
+pub mod line_log;
+pub mod local_clone;
+pub(crate) mod lockfile;
+pub mod log;
+pub mod mailmap;
+pub mod merge_base;
+pub mod merge_driver;
+pub(crate) mod metadata;
+pub(crate) mod mmap;
+pub mod object;
+pub(crate) mod object_cache;
+pub(crate) mod object_header;
+pub mod output;
+pub mod pack;
+pub mod pack_index;
+pub(crate) mod parallel;
+pub(crate) mod path_cache;
+pub mod pathspec;
+pub(crate) mod pattern;
+#[cfg(any(feature = "http", feature = "server"))]
+pub(crate) mod pkt_line;
+pub(crate) mod progress;
+pub mod promisor;
+pub mod rebase;
+pub mod reflog;
+pub mod refs;
+pub(crate) mod regex;
+pub mod release;
+pub mod rename;
+pub mod replace;
+#[cfg(feature = "http")]
+pub mod replay_wire;
+pub mod repository;
+pub mod resolve_undo;
+pub mod rev_list;
+pub mod revwalk;
+pub(crate) mod safe_directory;
+#[cfg(feature = "server")]
+pub mod serve;
+pub mod shallow;
+pub mod shared;
+pub mod signing;
+pub mod sparse;
+pub(crate) mod spill;
+pub mod status;
+pub mod subtree;
+pub mod tag;
+pub mod textconv;
+pub mod trace2;
+#[cfg(feature = "http")]
+pub mod transport;
+pub mod tree;
+pub(crate) mod untracked_cache;
+#[cfg(feature = "server")]
+pub mod upload_pack;
+pub mod verify_index;
@@ -0,0 +1,315 @@
+use anyhow::{anyhow, Result};
+
+use crate::{
+    diff::{diff, Edit},
+    error::MgError,
+    output::OutputFormat,
+    pathspec::Pathspec,
+    repository::Repository,
+};
+
+/// A `<start>,<end>:<file>` range as given to `mg log -L`, with 1-based
+/// inclusive line numbers. `<end>` may also be `+<count>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineRange {
+    pub path: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl LineRange {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid line range: {}", spec);
+
+        let (range, path) = spec.split_once(':').ok_or_else(invalid)?;
+        let (start, end) = range.split_once(',').ok_or_else(invalid)?;
+        let start: usize = start.parse().map_err(|_| invalid())?;
+        let end: usize = match end.strip_prefix('+') {
+            Some(count) => {
+                let count: usize = count.parse().map_err(|_| invalid())?;
+                // a count of zero, or one running past the last line number,
+                // has no last line
+                count
+                    .checked_sub(1)
+                    .and_then(|count| start.checked_add(count))
+                    .ok_or_else(invalid)?
+            }
+            None => end.parse().map_err(|_| invalid())?,
+        };
+
+        if start == 0 || end < start || path.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(LineRange {
+            path: path.to_string(),
+            start,
+            end,
+        })
+    }
+}
+
+/// Where a range of the new side of `edits` comes from.
+#[derive(Debug, PartialEq, Eq)]
+struct TracedRange {
+    /// the edits covering the range
+    edits: std::ops::Range<usize>,
+    /// whether any line of the range was changed
+    touched: bool,
+    /// the 1-based inclusive range on the old side, if any line existed there
+    old: Option<(usize, usize)>,
+}
+
+/// Map the 1-based inclusive range `start..=end` of the new side back to the
+/// old side.
+fn trace_range(edits: &[Edit], start: usize, end: usize) -> TracedRange {
+    let new_line = |edit: &Edit| match *edit {
+        Edit::Equal(_, y) | Edit::Insert(y) => Some(y + 1),
+        Edit::Delete(_) => None,
+    };
+
+    let first = edits
+        .iter()
+        .position(|e| new_line(e) == Some(start))
+        .unwrap_or(edits.len());
+    let last = edits
+        .iter()
+        .position(|e| new_line(e) == Some(end))
+        .map_or(first, |p| p + 1);
+
+    let covered = &edits[first..last];
+    let old_lines: Vec<usize> = covered
+        .iter()
+        .filter_map(|e| match *e {
+            Edit::Equal(x, _) | Edit::Delete(x) => Some(x + 1),
+            Edit::Insert(_) => None,
+        })
+        .collect();
+
+    TracedRange {
+        edits: first..last,
+        touched: covered.iter().any(|e| !matches!(e, Edit::Equal(..))),
+        old: old_lines
+            .first()
+            .zip(old_lines.last())
+            .map(|(a, b)| (*a, *b)),
+    }
+}
+
+fn split_lines(content: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+impl Repository {
+    /// Find where a file missing from `parent_tree` came from: a file with the
+    /// same content or, failing that, the most similar file which disappeared
+    /// in the child.
+    fn find_rename(
+        &self,
+        parent_tree: &[u8; 20],
+        child_tree: &[u8; 20],
+        hash: &[u8; 20],
+        lines: &[String],
+    ) -> Result<Option<(String, [u8; 20])>> {
+        let parent_files = self.flatten_tree(parent_tree)?;
+        if let Some(file) = parent_files.iter().find(|f| f.hash == *hash) {
+            return Ok(Some((file.path.clone(), file.hash)));
+        }
+
+        let mut best = None;
+        let mut best_score = 0;
+        for file in parent_files {
+            if self.tree_lookup(child_tree, &file.path)?.is_some() {
+                continue;
+            }
+
+            let old = split_lines(&self.read_object(&hex::encode(file.hash))?.content()?);
+            let common = diff(&old, lines)
+                .iter()
+                .filter(|e| matches!(e, Edit::Equal(..)))
+                .count();
+            // at least half of the lines must be kept
+            let score = common * 100 / old.len().max(lines.len()).max(1);
+            if score >= 50 && score > best_score {
+                best_score = score;
+                best = Some((file.path, file.hash));
+            }
+        }
+
+        Ok(best)
+    }
+
+    /// Print the commits which changed a range of lines, following the range
+    /// through edits and renames along the first-parent history.
+    pub fn log_line_range(&self, range: &LineRange) -> Result<()> {
+        if self.output_format == OutputFormat::Json {
+            return Err(MgError::Usage("-L has no JSON output".to_string()).into());
+        }
+        let mailmap = self.load_mailmap()?;
+        let shallow = self.shallow_commits()?;
+
+        let head = self.current_commit()?;
+        let file = self
+            .tree_lookup(&self.commit_tree(&head)?, &range.path)?
+            .ok_or_else(|| anyhow!("no such path '{}' in HEAD", range.path))?;
+        let content = self.read_object(&hex::encode(file.hash))?.content()?;
+        if range.end > split_lines(&content).len() {
+            return Err(anyhow!(
+                "file {} has only {} lines",
+                range.path,
+                split_lines(&content).len()
+            ));
+        }
+
+        // the range being tracked, in the coordinates of the current commit
+        let mut tracked = Some((range.path.clone(), file.hash, range.start, range.end));
+
+        let mut cache = self.path_diff_cache()?;
+        let result = self.walk_first_parent(|hash, commit| {
+            let Some((path, blob, start, end)) = tracked.take() else {
+                return Ok(false);
+            };
+
+            // the file is the same in the parent, and so is the range
+            if !self.commit_touches(&mut cache, hash, &Pathspec::literal(&[&path])?)? {
+                tracked = Some((path, blob, start, end));
+                return Ok(true);
+            }
+
+            let lines = split_lines(&self.read_object(&hex::encode(blob))?.content()?);
+            let tree = commit.tree;
+
+            let parent = match commit.parents.first() {
+                Some(parent) if !shallow.contains(hash) => Some(self.commit_tree(parent)?),
+                _ => None,
+            };
+            let parent_file = match parent {
+                Some(parent_tree) => match self.tree_lookup(&parent_tree, &path)? {
+                    Some(file) => Some((path.clone(), file.hash)),
+                    None => self.find_rename(&parent_tree, &tree, &blob, &lines)?,
+                },
+                None => None,
+            };
+
+            let old_lines = match &parent_file {
+                Some((_, old_blob)) if *old_blob == blob => lines.clone(),
+                Some((_, old_blob)) => {
+                    split_lines(&self.read_object(&hex::encode(old_blob))?.content()?)
+                }
+                None => Vec::new(),
+            };
+
+            let edits = diff(&old_lines, &lines);
+            let traced = trace_range(&edits, start, end);
+
+            if let (Some((old_path, old_blob)), Some((old_start, old_end))) =
+                (&parent_file, traced.old)
+            {
+                tracked = Some((old_path.clone(), *old_blob, old_start, old_end));
+            }
+
+            if !traced.touched {
+                return Ok(true);
+            }
+
+            println!("commit {}", hex::encode(hash));
+            let (name, email) = mailmap.lookup(&commit.author.name, &commit.author.email);
+            println!("Author: {} <{}>", name, email);
+            println!();
+            for line in commit.message.lines() {
+                println!("    {}", line);
+            }
+            println!();
+
+            let old_name = match &parent_file {
+                Some((old_path, _)) if traced.old.is_some() => format!("a/{}", old_path),
+                _ => "/dev/null".to_string(),
+            };
+            println!(
+                "diff --git a/{} b/{}",
+                parent_file.as_ref().map_or(&path, |(p, _)| p),
+                path
+            );
+            println!("--- {}", old_name);
+            println!("+++ b/{}", path);
+            let (old_start, old_count) = match traced.old {
+                Some((s, e)) => (s, e - s + 1),
+                None => (0, 0),
+            };
+            println!(
+                "@@ -{},{} +{},{} @@",
+                old_start,
+                old_count,
+                start,
+                end - start + 1
+            );
+            for edit in &edits[traced.edits] {
+                match *edit {
+                    Edit::Equal(_, y) => println!(" {}", lines[y]),
+                    Edit::Delete(x) => println!("-{}", old_lines[x]),
+                    Edit::Insert(y) => println!("+{}", lines[y]),
+                }
+            }
+            println!();
+
+            Ok(true)
+        });
+        cache.save()?;
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_range_parse() {
+        let range = LineRange::parse("3,+2:src/main.rs").unwrap();
+        assert_eq!((range.start, range.end), (3, 4));
+        let range = LineRange::parse("3,7:a:b").unwrap();
+        assert_eq!((range.path.as_str(), range.start, range.end), ("a:b", 3, 7));
+
+        for spec in [
+            "0,+0:f",
+            "1,+0:f",
+            "0,3:f",
+            "3,2:f",
+            "1,2:",
+            "1:f",
+            "2,+18446744073709551615:f",
+        ] {
+            assert!(LineRange::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_trace_range() {
+        let old = ["a", "b", "c", "d", "e"];
+        let new = ["a", "B", "c", "x", "d", "e"];
+        let edits = diff(&old, &new);
+
+        // "c" to "d" contains an inserted line
+        let traced = trace_range(&edits, 3, 5);
+        assert!(traced.touched);
+        assert_eq!(traced.old, Some((3, 4)));
+
+        // "d" and "e" only moved
+        let traced = trace_range(&edits, 5, 6);
+        assert!(!traced.touched);
+        assert_eq!(traced.old, Some((4, 5)));
+
+        assert_eq!(
+            LineRange::parse("2,+3:src/main.rs").unwrap(),
+            LineRange {
+                path: "src/main.rs".to_string(),
+                start: 2,
+                end: 4
+            }
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use walkdir::WalkDir;
+
+use crate::repository::{InitOptions, Repository};
+
+/// The worktree of the repository at `source`, given as its worktree or its
+/// `.git` directory.
+fn source_worktree(source: &Path) -> Result<PathBuf> {
+    let source = source
+        .canonicalize()
+        .map_err(|e| anyhow!("could not open {}: {}", source.display(), e))?;
+    if source.join(".git").is_dir() {
+        return Ok(source);
+    }
+    match source.parent() {
+        Some(parent) if source.file_name().is_some_and(|name| name == ".git") => {
+            Ok(parent.to_path_buf())
+        }
+        _ => Err(anyhow!("{} is not a git repository", source.display())),
+    }
+}
+
+impl Repository {
+    /// Clone the repository at `source`, a local path, into this one. Its
+    /// objects are hardlinked, or copied with `no_hardlinks` or across
+    /// filesystems; with `shared` they are not copied at all but borrowed
+    /// through an alternates entry, so they must not be pruned from
+    /// `source`. Its branches become `refs/remotes/origin/*` and its current
+    /// branch is checked out.
+    pub fn clone_local(&mut self, source: &Path, shared: bool, no_hardlinks: bool) -> Result<()> {
+        let source = source_worktree(source)?;
+        let origin = Repository::open(source.clone())?;
+        origin.ensure_safe_directory()?;
+        let source_objects = source.join(".git").join("objects");
+
+        if !self.git_dir.is_dir() {
+            let path = self.path.clone();
+            self.init_repository(&path, &InitOptions::default())?;
+        }
+        let objects = self.git_dir.join("objects");
+
+        if shared {
+            fs::create_dir_all(objects.join("info"))?;
+            fs::write(
+                objects.join("info").join("alternates"),
+                format!("{}\n", source_objects.display()),
+            )?;
+        } else {
+            let mut linked = 0;
+            let mut copied = 0;
+            for entry in WalkDir::new(&source_objects) {
+                let entry = entry?;
+                let relative = entry.path().strip_prefix(&source_objects)?;
+                let target = objects.join(relative);
+                if entry.file_type().is_dir() {
+                    fs::create_dir_all(&target)?;
+                    continue;
+                }
+                if target.exists() {
+                    continue;
+                }
+                // hardlinks fail across filesystems
+                if !no_hardlinks && fs::hard_link(entry.path(), &target).is_ok() {
+                    linked += 1;
+                } else {
+                    fs::copy(entry.path(), &target)?;
+                    copied += 1;
+                }
+            }
+            println!("Linked {} and copied {} object files", linked, copied);
+        }
+
+        let url = source.to_string_lossy();
+        self.set_config("remote.origin.url", &url)?;
+        self.set_config("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?;
+
+        for (name, hash) in origin.list_refs("refs/heads/")? {
+            let branch = name.trim_start_matches("refs/heads/");
+            self.update_ref(&format!("refs/remotes/origin/{}", branch), &hash)?;
+        }
+        for (name, hash) in origin.list_refs("refs/tags/")? {
+            self.update_ref(&name, &hash)?;
+        }
+
+        let Some(head) = origin.read_symbolic_ref("HEAD")? else {
+            return Ok(());
+        };
+        let Some(commit) = origin.read_ref(&head)? else {
+            // the source has no commit yet
+            return Ok(());
+        };
+        let branch = head.trim_start_matches("refs/heads/");
+        self.update_ref(&format!("refs/heads/{}", branch), &commit)?;
+        self.checkout(branch, false)
+    }
+}
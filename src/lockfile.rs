@@ -0,0 +1,216 @@
+#[cfg(unix)]
+use std::{
+    ffi::CString,
+    os::unix::ffi::OsStrExt,
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Once,
+    },
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+/// The lock files to remove if the process is interrupted while writing
+/// them. The signal handler walks the list without taking a lock, so slots
+/// are never freed: a slot is emptied when its lock is done, and reused by
+/// a later lock.
+#[cfg(unix)]
+static PENDING_LOCKS: AtomicPtr<PendingLock> = AtomicPtr::new(ptr::null_mut());
+
+#[cfg(unix)]
+struct PendingLock {
+    path: AtomicPtr<libc::c_char>,
+    next: AtomicPtr<PendingLock>,
+}
+
+#[cfg(unix)]
+extern "C" fn remove_pending_locks(signal: libc::c_int) {
+    let mut slot = PENDING_LOCKS.load(Ordering::SeqCst);
+    unsafe {
+        while let Some(pending) = slot.as_ref() {
+            let path = pending.path.swap(ptr::null_mut(), Ordering::SeqCst);
+            if !path.is_null() {
+                libc::unlink(path);
+            }
+            slot = pending.next.load(Ordering::SeqCst);
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+/// Remove `lock_path` if the process is interrupted, until `forget_pending`.
+#[cfg(unix)]
+fn add_pending(lock_path: &Path) -> Result<&'static PendingLock> {
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| {
+        let handler = remove_pending_locks as *const () as libc::sighandler_t;
+        unsafe {
+            libc::signal(libc::SIGINT, handler);
+            libc::signal(libc::SIGTERM, handler);
+        }
+    });
+
+    let path = CString::new(lock_path.as_os_str().as_bytes())?.into_raw();
+    let mut slot = PENDING_LOCKS.load(Ordering::SeqCst);
+    while let Some(pending) = unsafe { slot.as_ref() } {
+        let reused = pending.path.compare_exchange(
+            ptr::null_mut(),
+            path,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        if reused.is_ok() {
+            return Ok(pending);
+        }
+        slot = pending.next.load(Ordering::SeqCst);
+    }
+
+    let head = PENDING_LOCKS.load(Ordering::SeqCst);
+    let pending = Box::leak(Box::new(PendingLock {
+        path: AtomicPtr::new(path),
+        next: AtomicPtr::new(head),
+    }));
+    while let Err(head) = PENDING_LOCKS.compare_exchange(
+        pending.next.load(Ordering::SeqCst),
+        pending,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+    ) {
+        pending.next.store(head, Ordering::SeqCst);
+    }
+
+    Ok(pending)
+}
+
+/// Stop removing the lock file of `pending` on interrupts.
+#[cfg(unix)]
+fn forget_pending(pending: &PendingLock) {
+    let path = pending.path.swap(ptr::null_mut(), Ordering::SeqCst);
+    if !path.is_null() {
+        drop(unsafe { CString::from_raw(path) });
+    }
+}
+
+/// A `<path>.lock` file written to replace `<path>`. It is created
+/// exclusively, so that a concurrent writer fails instead of interleaving
+/// with this one, and renamed over `<path>` on commit. Until then `<path>`
+/// is untouched: the lock file is removed if it is dropped, on errors, or
+/// if the process is interrupted.
+pub struct LockFile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    out: Option<BufWriter<File>>,
+    #[cfg(unix)]
+    pending: &'static PendingLock,
+}
+
+impl LockFile {
+    pub fn acquire(path: &Path) -> Result<Self> {
+        let mut lock_name = path.file_name().unwrap_or_default().to_os_string();
+        lock_name.push(".lock");
+        let lock_path = path.with_file_name(lock_name);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => anyhow!(
+                    "unable to create '{}': File exists.\n\
+                     Another mg process seems to be running in this repository. If it \
+                     is not, remove the file and try again.",
+                    lock_path.display()
+                ),
+                _ => anyhow!("unable to create '{}': {}", lock_path.display(), e),
+            })?;
+
+        Ok(LockFile {
+            path: path.to_path_buf(),
+            #[cfg(unix)]
+            pending: add_pending(&lock_path)?,
+            lock_path,
+            out: Some(BufWriter::new(file)),
+        })
+    }
+
+    /// Flush the lock file to disk and move it over the locked path.
+    pub fn commit(mut self) -> Result<()> {
+        let out = self.out.take().expect("a lock file is committed once");
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&self.lock_path, &self.path)?;
+        #[cfg(unix)]
+        forget_pending(self.pending);
+
+        Ok(())
+    }
+}
+
+impl Write for LockFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.as_mut().expect("the lock file is open").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.as_mut().expect("the lock file is open").flush()
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if self.out.take().is_some() {
+            let _ = fs::remove_file(&self.lock_path);
+            #[cfg(unix)]
+            forget_pending(self.pending);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn pending_paths() -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        let mut slot = PENDING_LOCKS.load(Ordering::SeqCst);
+        while let Some(pending) = unsafe { slot.as_ref() } {
+            let path = pending.path.load(Ordering::SeqCst);
+            if !path.is_null() {
+                let path = unsafe { std::ffi::CStr::from_ptr(path) };
+                paths.push(PathBuf::from(std::ffi::OsStr::from_bytes(path.to_bytes())));
+            }
+            slot = pending.next.load(Ordering::SeqCst);
+        }
+        paths
+    }
+
+    #[test]
+    fn test_every_held_lock_is_pending() {
+        let dir = std::env::temp_dir().join(format!("mg-lockfile-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let first = LockFile::acquire(&dir.join("first")).unwrap();
+        let second = LockFile::acquire(&dir.join("second")).unwrap();
+        let pending = pending_paths();
+        assert!(pending.contains(&dir.join("first.lock")));
+        assert!(pending.contains(&dir.join("second.lock")));
+
+        drop(first);
+        second.commit().unwrap();
+        let pending = pending_paths();
+        assert!(!pending.contains(&dir.join("first.lock")));
+        assert!(!pending.contains(&dir.join("second.lock")));
+        assert!(!dir.join("first").exists());
+        assert!(dir.join("second").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,36 +1,566 @@
-use crate::repository::Repository;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use anyhow::Result;
-use hex::FromHex;
+use crate::{
+    color::{paint, BOLD_CYAN, BOLD_GREEN, BOLD_YELLOW, YELLOW},
+    commit::Commit,
+    error::MgError,
+    graph::Graph,
+    graph_export::json_string,
+    mailmap::Mailmap,
+    output::{signature_json, OutputFormat},
+    path_cache::PathDiffCache,
+    pathspec::Pathspec,
+    repository::Repository,
+    rev_list::RevisionRoots,
+    revwalk::{RevWalk, RevWalkOptions, Sort},
+    trace2::json_array,
+};
+
+use anyhow::{anyhow, Result};
+
+/// What `log` shows, and how.
+#[derive(Default)]
+pub struct LogOptions {
+    /// Show at most this many commits
+    pub max_count: Option<usize>,
+    /// Show each commit on a single line, with an abbreviated id
+    pub oneline: bool,
+    /// Show the refs pointing at each commit
+    pub decorate: bool,
+    /// Only commits made at or after this time
+    pub since: Option<i64>,
+    /// Only commits made at or before this time
+    pub until: Option<i64>,
+    /// Only commits whose author contains this text
+    pub author: Option<String>,
+    /// Only commits changing the paths this matches
+    pub pathspec: Pathspec,
+    /// Show every parent of merges, drawing the history as a graph
+    pub graph: bool,
+    /// Follow only the first parent of merges
+    pub first_parent: bool,
+    /// Show no commit before its children, instead of by date
+    pub topo_order: bool,
+}
+
+/// The line showing a commit: its id, decorations, subject and author,
+/// in git's colors with `color`.
+pub fn commit_line(
+    hash: &[u8; 20],
+    commit: &Commit,
+    options: &LogOptions,
+    decorations: &HashMap<[u8; 20], Vec<String>>,
+    mailmap: &Mailmap,
+    color: bool,
+) -> String {
+    let mut id = hex::encode(hash);
+    if options.oneline {
+        id.truncate(7);
+    }
+    let yellow = color.then_some(YELLOW);
+    let mut id = paint(yellow, &id);
+    if let Some(names) = decorations.get(hash) {
+        let names: Vec<String> = names.iter().map(|name| decoration(name, color)).collect();
+        id.push_str(&format!(
+            "{}{}{}",
+            paint(yellow, " ("),
+            names.join(&paint(yellow, ", ")),
+            paint(yellow, ")")
+        ));
+    }
+
+    if options.oneline {
+        return format!("{} {}", id, commit.subject());
+    }
+    let (name, email) = mailmap.lookup(&commit.author.name, &commit.author.email);
+    format!("{} {} ({} <{}>)", id, commit.subject(), name, email)
+}
+
+/// A ref decorating a commit, HEAD in cyan, tags in yellow and branches in
+/// green.
+fn decoration(name: &str, color: bool) -> String {
+    if !color {
+        return name.to_string();
+    }
+    if let Some(branch) = name.strip_prefix("HEAD -> ") {
+        return format!(
+            "{}{}",
+            paint(Some(BOLD_CYAN), "HEAD -> "),
+            paint(Some(BOLD_GREEN), branch)
+        );
+    }
+    let color = if name == "HEAD" {
+        BOLD_CYAN
+    } else if name.starts_with("tag: ") {
+        BOLD_YELLOW
+    } else {
+        BOLD_GREEN
+    };
+    paint(Some(color), name)
+}
+
+/// `commit` as a JSON object, for `--format json`.
+pub fn commit_json(
+    hash: &[u8; 20],
+    commit: &Commit,
+    decorations: &HashMap<[u8; 20], Vec<String>>,
+    mailmap: &Mailmap,
+) -> String {
+    let parents: Vec<String> = commit.parents.iter().map(hex::encode).collect();
+    let parents: Vec<&str> = parents.iter().map(String::as_str).collect();
+    let refs: Vec<&str> = decorations.get(hash).map_or(Vec::new(), |names| {
+        names.iter().map(String::as_str).collect()
+    });
+    let (name, email) = mailmap.lookup(&commit.author.name, &commit.author.email);
+    let (committer_name, committer_email) =
+        mailmap.lookup(&commit.committer.name, &commit.committer.email);
+    format!(
+        "{{\"type\":\"commit\",\"id\":{},\"tree\":{},\"parents\":{},\"author\":{},\"committer\":{},\"refs\":{},\"subject\":{},\"message\":{}}}",
+        json_string(&hex::encode(hash)),
+        json_string(&hex::encode(commit.tree)),
+        json_array(&parents),
+        signature_json(&name, &email, &commit.author),
+        signature_json(&committer_name, &committer_email, &commit.committer),
+        json_array(&refs),
+        json_string(commit.subject()),
+        json_string(&commit.message)
+    )
+}
+
+/// The commits `log` selects, as an iterator. The paths each commit walked
+/// changes are cached along the way, and saved by `finish`.
+pub struct Log<'a> {
+    repo: &'a Repository,
+    walk: RevWalk<'a>,
+    options: &'a LogOptions,
+    cache: PathDiffCache,
+    shown: usize,
+}
+
+impl Log<'_> {
+    fn next_selected(&mut self) -> Result<Option<([u8; 20], Commit)>> {
+        let options = self.options;
+        let pathspec = &options.pathspec;
+        if options.max_count.is_some_and(|max| self.shown >= max) {
+            return Ok(None);
+        }
+
+        for entry in self.walk.by_ref() {
+            let (hash, commit) = entry?;
+            let author = &commit.author;
+            let time = commit.committer.timestamp;
+            if options.until.is_some_and(|until| time > until) {
+                continue;
+            }
+            if options.since.is_some_and(|since| time < since) {
+                // by date, the commits left are older still
+                match options.topo_order {
+                    true => continue,
+                    false => return Ok(None),
+                }
+            }
+            if let Some(pattern) = &options.author {
+                let matches =
+                    format!("{} <{}>", author.name, author.email).contains(pattern.as_str());
+                if !matches {
+                    continue;
+                }
+            }
+            if !pathspec.is_empty()
+                && !self.repo.commit_touches(&mut self.cache, &hash, pathspec)?
+            {
+                continue;
+            }
+            if !pathspec.is_empty()
+                && !options.first_parent
+                && self.repo.merge_keeps_a_parent(&hash, &commit, pathspec)?
+            {
+                continue;
+            }
+
+            self.shown += 1;
+            return Ok(Some((hash, commit)));
+        }
+
+        Ok(None)
+    }
+
+    /// Save what was learnt of the paths the commits change.
+    pub fn finish(mut self) -> Result<()> {
+        self.cache.save()
+    }
+}
+
+impl Iterator for Log<'_> {
+    type Item = Result<([u8; 20], Commit)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_selected().transpose()
+    }
+}
 
 impl Repository {
-    pub fn log(&self) -> Result<()> {
-        let mut current_commit = self.current_commit()?;
+    /// The walk of the history of `revisions` (HEAD by default) in the
+    /// order `options` ask for. Ranges leave out the history of their
+    /// excluded side.
+    fn log_revwalk(&self, revisions: &[String], options: &LogOptions) -> Result<RevWalk<'_>> {
+        let RevisionRoots { include, exclude } = self.revision_roots(revisions)?;
+        let mut walk = self.rev_walk(RevWalkOptions {
+            first_parent: options.first_parent,
+            sort: match options.topo_order || options.graph {
+                true => Sort::Topological,
+                false => Sort::Date,
+            },
+        })?;
+        for commit in &exclude {
+            walk.hide(commit)?;
+        }
+        for commit in &include {
+            walk.push(commit)?;
+        }
+        Ok(walk)
+    }
 
-        loop {
-            let mut commit = self.read_object(&hex::encode(current_commit))?;
+    /// The commits of the history of `revisions` (HEAD by default) which
+    /// `options` select, with their ids, newest first.
+    pub fn log<'a>(&'a self, revisions: &[String], options: &'a LogOptions) -> Result<Log<'a>> {
+        Ok(Log {
+            repo: self,
+            walk: self.log_revwalk(revisions, options)?,
+            options,
+            cache: self.path_diff_cache()?,
+            shown: 0,
+        })
+    }
 
-            let commit_desc = commit.string()?;
-            let lines = commit_desc.lines().collect::<Vec<&str>>();
+    /// Show the history `log` selects, a line for each commit, or as a
+    /// graph.
+    pub fn print_log(&self, revisions: &[String], options: &LogOptions) -> Result<()> {
+        let mailmap = self.load_mailmap()?;
+        let decorations = if options.decorate {
+            self.decorations()?
+        } else {
+            HashMap::new()
+        };
+        let json = self.output_format == OutputFormat::Json;
+        let color = !json && self.use_color("log")?;
+        if options.graph {
+            if json {
+                return Err(MgError::Usage("--graph has no JSON output".to_string()).into());
+            }
+            let walk = self.log_revwalk(revisions, options)?;
+            return self.log_graph(walk, options, &decorations, &mailmap, color);
+        }
 
-            // find the first empty line
-            let first_empty_line = lines.iter().position(|line| line.is_empty());
+        let mut log = self.log(revisions, options)?;
+        let result = log.by_ref().try_for_each(|entry| {
+            let (hash, commit) = entry?;
+            if json {
+                println!("{}", commit_json(&hash, &commit, &decorations, &mailmap));
+            } else {
+                println!(
+                    "{}",
+                    commit_line(&hash, &commit, options, &decorations, &mailmap, color)
+                );
+            }
+            Ok(())
+        });
+        log.finish()?;
 
-            println!(
-                "{} {}",
-                hex::encode(current_commit),
-                lines[first_empty_line.unwrap() + 1]
-            );
+        result
+    }
 
-            let parent_commit_id = lines.iter().find(|line| line.starts_with("parent "));
-            if parent_commit_id.is_none() {
+    /// Whether the merge `commit` has what `pathspec` matches as one of its
+    /// parents has it, which makes it uninteresting to a log of these paths.
+    fn merge_keeps_a_parent(
+        &self,
+        hash: &[u8; 20],
+        commit: &Commit,
+        pathspec: &Pathspec,
+    ) -> Result<bool> {
+        if commit.parents.len() < 2 || self.shallow_commits()?.contains(hash) {
+            return Ok(false);
+        }
+        for parent in &commit.parents {
+            if !self.trees_differ(Some(&self.commit_tree(parent)?), &commit.tree, pathspec)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Show the commits of `walk`, merged branches included, as a graph.
+    /// Children are shown before their parents, and the commits of a
+    /// branch are kept together.
+    fn log_graph(
+        &self,
+        walk: RevWalk,
+        options: &LogOptions,
+        decorations: &HashMap<[u8; 20], Vec<String>>,
+        mailmap: &Mailmap,
+        color: bool,
+    ) -> Result<()> {
+        let commits = walk.collect::<Result<Vec<_>>>()?;
+        // lines only go to the parents shown, which leaves out those of
+        // shallow commits and those a range excludes
+        let shown: HashSet<[u8; 20]> = commits.iter().map(|(hash, _)| *hash).collect();
+
+        let mut graph = Graph::default();
+        let count = options.max_count.unwrap_or(usize::MAX);
+        for (hash, commit) in commits.iter().take(count) {
+            let parents: Vec<[u8; 20]> = commit
+                .parents
+                .iter()
+                .filter(|parent| shown.contains(*parent))
+                .copied()
+                .collect();
+            let text = commit_line(hash, commit, options, decorations, mailmap, color);
+            for line in graph.lines(hash, &parents, &text) {
+                println!("{}", line);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn shortlog(&self) -> Result<()> {
+        let mailmap = self.load_mailmap()?;
+        let mut authors: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        self.walk_first_parent(|_, commit| {
+            let name = mailmap.lookup(&commit.author.name, &commit.author.email).0;
+            authors
+                .entry(name)
+                .or_default()
+                .push(commit.subject().to_string());
+
+            Ok(true)
+        })?;
+
+        for (name, subjects) in authors {
+            println!("{} ({}):", name, subjects.len());
+            for subject in subjects.iter().rev() {
+                println!("      {}", subject);
+            }
+            println!();
+        }
+
+        Ok(())
+    }
+
+    /// Walk the first-parent history of HEAD, calling `f` with each commit
+    /// for as long as it returns true.
+    pub fn walk_first_parent<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(&[u8; 20], &Commit) -> Result<bool>,
+    {
+        self.walk_first_parent_from(self.current_commit()?, f)
+    }
+
+    pub fn walk_first_parent_from<F>(&self, start: [u8; 20], mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8; 20], &Commit) -> Result<bool>,
+    {
+        let mut current_commit = start;
+        let shallow = self.shallow_commits()?;
+        let mut seen = HashSet::new();
+
+        // replacements and grafts can introduce cycles
+        while seen.insert(current_commit) {
+            let commit = self.read_commit(&current_commit)?;
+            if !f(&current_commit, &commit)? {
                 break;
             }
 
-            let parent_commit_id = parent_commit_id.unwrap();
-            current_commit = <[u8; 20]>::from_hex(parent_commit_id.split_once(' ').unwrap().1)?;
+            // the parents of a shallow commit are not available locally
+            if shallow.contains(&current_commit) {
+                break;
+            }
+
+            match commit.parents.first() {
+                Some(parent) => current_commit = *parent,
+                None => break,
+            }
         }
 
         Ok(())
     }
 }
+
+/// Parse a date given to `--since` or `--until`: `@<epoch>`, an ISO date
+/// (`2024-01-31`, optionally followed by a `12:00[:00]` UTC time), `now`,
+/// `yesterday` or a relative date such as `2 weeks ago` or `3.days`.
+pub fn parse_date(value: &str, now: i64) -> Result<i64> {
+    let value = value.trim();
+    if let Some(epoch) = value.strip_prefix('@') {
+        return Ok(epoch.parse()?);
+    }
+    match value {
+        "now" => return Ok(now),
+        "yesterday" => return Ok(now - 86400),
+        _ => {}
+    }
+
+    if let Some((date, time)) = parse_iso_date(value) {
+        let (year, month, day) = date;
+        return Ok(days_from_civil(year, month, day) * 86400 + time);
+    }
+
+    let words: Vec<&str> = value
+        .split(|c: char| c.is_whitespace() || c == '.')
+        .filter(|word| !word.is_empty())
+        .collect();
+    if let [count, unit, rest @ ..] = words.as_slice() {
+        if rest.is_empty() || rest == ["ago"] {
+            let count: i64 = count
+                .parse()
+                .map_err(|_| anyhow!("invalid date '{}'", value))?;
+            let unit = match unit.trim_end_matches('s') {
+                "second" | "sec" => 1,
+                "minute" | "min" => 60,
+                "hour" => 3600,
+                "day" => 86400,
+                "week" => 7 * 86400,
+                "month" => 30 * 86400,
+                "year" => 365 * 86400,
+                _ => return Err(anyhow!("invalid date '{}'", value)),
+            };
+            return Ok(now - count * unit);
+        }
+    }
+
+    Err(anyhow!("invalid date '{}'", value))
+}
+
+/// Split `YYYY-MM-DD[ HH:MM[:SS]]` into the date and the seconds into the day.
+fn parse_iso_date(value: &str) -> Option<((i64, i64, i64), i64)> {
+    let (date, time) = match value.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (Some(Some(year)), Some(Some(month)), Some(Some(day))) =
+        (date.next(), date.next(), date.next())
+    else {
+        return None;
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let seconds = match time {
+        Some(time) => {
+            let parts = time
+                .split(':')
+                .map(|part| part.parse::<i64>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            match parts.as_slice() {
+                [hours, minutes] => hours * 3600 + minutes * 60,
+                [hours, minutes, seconds] => hours * 3600 + minutes * 60 + seconds,
+                _ => return None,
+            }
+        }
+        None => 0,
+    };
+
+    Some(((year, month, day), seconds))
+}
+
+/// The number of days from 1970-01-01 to a date of the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date of 1970-01-01 plus `days` days in the proleptic Gregorian
+/// calendar, as (year, month, day).
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Format a date in git's default format, `Tue Mar 5 14:07:12 2024 +0100`,
+/// in the time zone it was recorded in.
+pub fn format_date(timestamp: i64, timezone: &str) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let offset = match timezone.split_at_checked(1) {
+        Some((sign, digits)) if digits.len() == 4 => {
+            let hours: i64 = digits[..2].parse().unwrap_or(0);
+            let minutes: i64 = digits[2..].parse().unwrap_or(0);
+            let offset = hours * 3600 + minutes * 60;
+            if sign == "-" {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => 0,
+    };
+
+    let local = timestamp + offset;
+    let days = local.div_euclid(86400);
+    let seconds = local.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{} {} {} {:02}:{:02}:{:02} {} {}",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        MONTHS[month as usize - 1],
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        year,
+        timezone
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        let now = 1_700_000_000;
+        assert_eq!(parse_date("@1234", now).unwrap(), 1234);
+        assert_eq!(parse_date("1970-01-02", now).unwrap(), 86400);
+        assert_eq!(parse_date("2024-03-01 12:30", now).unwrap(), 1_709_296_200);
+        assert_eq!(parse_date("2 weeks ago", now).unwrap(), now - 14 * 86400);
+        assert_eq!(parse_date("3.days", now).unwrap(), now - 3 * 86400);
+        assert_eq!(parse_date("1 hour ago", now).unwrap(), now - 3600);
+        assert!(parse_date("someday", now).is_err());
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(
+            format_date(1_709_644_032, "+0100"),
+            "Tue Mar 5 14:07:12 2024 +0100"
+        );
+        assert_eq!(format_date(0, "-0500"), "Wed Dec 31 19:00:00 1969 -0500");
+    }
+}
@@ -0,0 +1,157 @@
+use std::fs::read_to_string;
+
+use anyhow::Result;
+
+use crate::repository::Repository;
+
+#[derive(Debug, PartialEq, Eq)]
+struct MailmapEntry {
+    proper_name: Option<String>,
+    proper_email: Option<String>,
+    commit_name: Option<String>,
+    commit_email: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Mailmap {
+    entries: Vec<MailmapEntry>,
+}
+
+impl Mailmap {
+    pub fn parse(content: &str) -> Self {
+        let entries = content.lines().filter_map(parse_line).collect();
+
+        Mailmap { entries }
+    }
+
+    /// Map a commit identity to its canonical name and email.
+    pub fn lookup(&self, name: &str, email: &str) -> (String, String) {
+        // entries with a commit name are more specific and win over email-only ones
+        let entry = self
+            .entries
+            .iter()
+            .filter(|e| e.commit_email.eq_ignore_ascii_case(email))
+            // names match regardless of ASCII case, as emails do
+            .filter(|e| match &e.commit_name {
+                Some(commit_name) => commit_name.eq_ignore_ascii_case(name),
+                None => true,
+            })
+            .max_by_key(|e| e.commit_name.is_some());
+
+        match entry {
+            Some(entry) => (
+                entry
+                    .proper_name
+                    .clone()
+                    .unwrap_or_else(|| name.to_string()),
+                entry
+                    .proper_email
+                    .clone()
+                    .unwrap_or_else(|| email.to_string()),
+            ),
+            None => (name.to_string(), email.to_string()),
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<MailmapEntry> {
+    let line = match line.find('#') {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+
+    // split the line into (name, email) pairs
+    let mut pairs = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find('<') {
+        let end = rest[start..].find('>')? + start;
+        let name = rest[..start].trim();
+        let name = (!name.is_empty()).then(|| name.to_string());
+        pairs.push((name, rest[start + 1..end].to_string()));
+        rest = &rest[end + 1..];
+    }
+
+    match pairs.len() {
+        1 => {
+            let (name, email) = pairs.pop()?;
+            Some(MailmapEntry {
+                proper_name: name,
+                proper_email: None,
+                commit_name: None,
+                commit_email: email,
+            })
+        }
+        2 => {
+            let (commit_name, commit_email) = pairs.pop()?;
+            let (proper_name, proper_email) = pairs.pop()?;
+            Some(MailmapEntry {
+                proper_name,
+                proper_email: Some(proper_email),
+                commit_name,
+                commit_email,
+            })
+        }
+        _ => None,
+    }
+}
+
+impl Repository {
+    pub fn load_mailmap(&self) -> Result<Mailmap> {
+        let mailmap_path = self.path.join(".mailmap");
+        if !mailmap_path.exists() {
+            return Ok(Mailmap::default());
+        }
+
+        Ok(Mailmap::parse(&read_to_string(mailmap_path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mailmap_lookup() {
+        let mailmap = Mailmap::parse(
+            "# comment\n\
+             Proper Name <commit@example.com>\n\
+             <proper@example.com> <old@example.com>\n\
+             Jane Doe <jane@example.com> jdoe <JDOE@laptop>\n",
+        );
+
+        assert_eq!(
+            mailmap.lookup("whatever", "commit@example.com"),
+            ("Proper Name".to_string(), "commit@example.com".to_string())
+        );
+        assert_eq!(
+            mailmap.lookup("Old", "old@example.com"),
+            ("Old".to_string(), "proper@example.com".to_string())
+        );
+        assert_eq!(
+            mailmap.lookup("jdoe", "jdoe@laptop"),
+            ("Jane Doe".to_string(), "jane@example.com".to_string())
+        );
+        assert_eq!(
+            mailmap.lookup("someone", "jdoe@laptop"),
+            ("someone".to_string(), "jdoe@laptop".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mailmap_lookup_ignores_name_case() {
+        let mailmap = Mailmap::parse("Jane Doe <jane@example.com> JDoe <jdoe@laptop>\n");
+
+        assert_eq!(
+            mailmap.lookup("jdoe", "JDOE@laptop"),
+            ("Jane Doe".to_string(), "jane@example.com".to_string())
+        );
+        assert_eq!(
+            mailmap.lookup("JDOE", "jdoe@laptop"),
+            ("Jane Doe".to_string(), "jane@example.com".to_string())
+        );
+        assert_eq!(
+            mailmap.lookup("J Doe", "jdoe@laptop"),
+            ("J Doe".to_string(), "jdoe@laptop".to_string())
+        );
+    }
+}
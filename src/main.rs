@@ -1,28 +1,74 @@
-use anyhow::{Error, Result};
-use object::hash_object;
-use repository::default_init_path;
-use std::path::PathBuf;
+use anyhow::{anyhow, Context, Error, Result};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
 
-use clap::Parser;
 use clap::Subcommand;
+use clap::{Arg, ArgAction, CommandFactory, FromArgMatches, Parser};
 
-mod commit;
-mod error;
-mod http;
-mod index;
-mod kind;
-mod log;
-mod object;
-mod pack;
-mod repository;
-mod tree;
-
-use crate::http::clone;
-use crate::repository::Repository;
+use mg::alias::Expansion;
+use mg::branch::BranchListing;
+use mg::color::{color_diff, paint, ColorWhen, GREEN, RED};
+use mg::commit::{CommitOptions, Shown};
+use mg::config::{ConfigAction, ConfigType};
+use mg::diff::FileDiff;
+use mg::error::MgError;
+#[cfg(feature = "http")]
+use mg::fetch::{fetch, Deepen};
+use mg::grep::{GrepOptions, Syntax};
+#[cfg(feature = "http")]
+use mg::http::{clone, ls_remote};
+use mg::kind::Kind;
+use mg::line_log::LineRange;
+use mg::log::{format_date, parse_date, LogOptions};
+use mg::output::OutputFormat;
+use mg::release::ReleaseOptions;
+#[cfg(feature = "http")]
+use mg::replay_wire;
+use mg::repository::{InitOptions, Repository};
+use mg::shared::SharedMode;
+#[cfg(feature = "http")]
+use mg::transport::IpFamily;
+use mg::{commit_message, config, object::hash_object, repository::default_init_path, trace2};
+#[cfg(feature = "server")]
+use mg::{daemon, serve};
 
 #[derive(Parser)]
 #[command(name = "mg", about = "A simple git clone")]
 struct Cli {
+    /// Ignore replacement refs and grafts, operating on the true history
+    #[arg(long, global = true)]
+    no_replace_objects: bool,
+
+    /// Memory large intermediate tables may use before spilling to disk, e.g. `256m`
+    #[arg(long, global = true, value_parser = config::parse_size)]
+    memory_budget: Option<usize>,
+
+    /// Never take the index lock in read-only commands such as status and diff
+    #[arg(long, global = true)]
+    no_optional_locks: bool,
+
+    /// Print what log, status, ls-index and the pack dumps produce as
+    /// `text` (the default), or as `json`, one object per line. For
+    /// graph-export and archive, the format of the graph or archive
+    #[arg(long, global = true, value_name = "FORMAT")]
+    format: Option<String>,
+
+    /// Color status, diff, log and branch output: `auto` (on a terminal),
+    /// `always` or `never`, overriding `color.ui`
+    #[arg(long, global = true, value_name = "WHEN", num_args = 0..=1, require_equals = true, default_missing_value = "always", value_parser = ColorWhen::parse)]
+    color: Option<ColorWhen>,
+
+    /// Report the progress of clone, fetch and checkout even when stderr is
+    /// not a terminal
+    #[arg(long, global = true)]
+    progress: bool,
+
+    /// Report no progress
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
     #[clap(subcommand)]
     command: Command,
 }
@@ -34,11 +80,40 @@ enum Command {
         /// The path where to create the repository. Defaults to current directory
         #[arg(default_value=default_init_path().into_os_string())]
         path: PathBuf,
+        /// Create a bare repository, without a working directory
+        #[arg(long)]
+        bare: bool,
+        /// Share the repository with the group (`group`, the default), with
+        /// everybody (`all`) or with the given octal permissions
+        #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "group")]
+        shared: Option<String>,
+        /// The branch HEAD points to, `init.defaultBranch` or `main` by default
+        #[arg(short = 'b', long, value_name = "NAME")]
+        initial_branch: Option<String>,
     },
     /// Display a Git object
     CatFile {
+        /// Show the object type
+        #[arg(short = 't', group = "mode")]
+        show_type: bool,
+        /// Show the object size
+        #[arg(short = 's', group = "mode")]
+        size: bool,
+        /// Pretty-print the object content
+        #[arg(short = 'p', group = "mode")]
+        pretty: bool,
+        /// Print the file `<rev>:<path>` converted by its textconv driver
+        #[arg(long, group = "mode")]
+        textconv: bool,
+        /// Read object names from stdin and print their info and content
+        #[arg(long, group = "mode")]
+        batch: bool,
+        /// Read object names from stdin and print their info
+        #[arg(long, group = "mode")]
+        batch_check: bool,
         /// The object to display
-        hash: String,
+        #[arg(required_unless_present_any = ["batch", "batch_check"])]
+        hash: Option<String>,
     },
     /// Write a blob object
     WriteBlob {
@@ -47,25 +122,191 @@ enum Command {
     },
     /// Write a tree object
     WriteTree {
-        /// The path to write
-        path: PathBuf,
+        /// The directory to write, instead of the trees of the index
+        path: Option<PathBuf>,
     },
-    /// Commit current changes
+    /// Stage the content of files for the next commit
+    Add {
+        /// The files or directories to stage
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Commit the staged changes
     Commit {
-        /// The commit message
-        message: String,
+        /// The commit message, as with --message
+        #[arg(value_name = "MESSAGE", conflicts_with_all = ["message", "file"])]
+        positional_message: Option<String>,
+        /// The commit message. Without it or --file, the editor is opened
+        #[arg(short, long, conflicts_with = "file")]
+        message: Option<String>,
+        /// Read the commit message from a file, or stdin for `-`
+        #[arg(short = 'F', long)]
+        file: Option<PathBuf>,
+        /// Make a commit to meld into COMMIT by `rebase --autosquash`
+        #[arg(long, value_name = "COMMIT", conflicts_with = "squash")]
+        fixup: Option<String>,
+        /// Like --fixup, but keep the message when melding
+        #[arg(long, value_name = "COMMIT")]
+        squash: Option<String>,
+        /// With --amend, keep the message of the amended commit
+        #[arg(long)]
+        no_edit: bool,
+        /// Skip the pre-commit and commit-msg hooks
+        #[arg(short = 'n', long)]
+        no_verify: bool,
+        /// Sign the commit with `user.signingKey`
+        #[arg(short = 'S', long)]
+        gpg_sign: bool,
+        /// Replace the tip commit instead of adding a new one
+        #[arg(long)]
+        amend: bool,
+        /// Stage the changes of all tracked files first
+        #[arg(short, long)]
+        all: bool,
+        /// Commit even if nothing changed
+        #[arg(long)]
+        allow_empty: bool,
+        /// The author date, overriding GIT_AUTHOR_DATE; `now` for the current time
+        #[arg(long)]
+        date: Option<String>,
+        /// Date in UTC from a frozen clock, for commits identical on any machine
+        #[arg(long)]
+        reproducible: bool,
+        /// Amend even on a protected branch
+        #[arg(long)]
+        override_protection: bool,
+    },
+    /// Create a tag, annotated with a message and optionally signed
+    Tag {
+        /// The name of the tag
+        name: String,
+        /// The object to tag, HEAD by default
+        target: Option<String>,
+        /// The message of an annotated tag
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Make a signed annotated tag
+        #[arg(short, long)]
+        sign: bool,
+    },
+    /// Tag a release, with the commits since the previous tag as its changelog
+    Release {
+        /// The version, naming the tag
+        version: String,
+        /// Sign the tag
+        #[arg(short, long)]
+        sign: bool,
+        /// Add the changelog at the top of this file and commit it first
+        #[arg(long, value_name = "FILE")]
+        changelog: Option<PathBuf>,
+    },
+    /// Check the index for corruption
+    VerifyIndex,
+    /// Check the signature of commits
+    VerifyCommit {
+        /// The commits to check
+        #[arg(required = true)]
+        commits: Vec<String>,
+        /// Print the contents of the commit
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Check the signature of tags
+    VerifyTag {
+        /// The tags to check
+        #[arg(required = true)]
+        tags: Vec<String>,
+        /// Print the contents of the tag
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Get the current branch, or list, create and delete branches
+    Branch {
+        /// The branch to create or delete
+        name: Option<String>,
+        /// The commit the new branch points to. Defaults to HEAD
+        start_point: Option<String>,
+        /// List all branches
+        #[arg(short, long)]
+        list: bool,
+        /// List the remote-tracking branches
+        #[arg(short, long)]
+        remotes: bool,
+        /// List both local and remote-tracking branches
+        #[arg(short, long)]
+        all: bool,
+        /// Delete a branch merged into its upstream or HEAD
+        #[arg(short, long)]
+        delete: bool,
+        /// Delete a branch even if it is not merged
+        #[arg(short = 'D')]
+        force_delete: bool,
+        /// With --delete, skip the merge check
+        #[arg(short, long)]
+        force: bool,
+        /// Delete even a protected branch
+        #[arg(long)]
+        override_protection: bool,
     },
-    /// Get the current branch
-    Branch,
     /// Get the latest commit
     Show {
-        /// The commit to show
+        /// The commit to show, or a file as `<rev>:<path>`
         hash: Option<String>,
     },
     /// Show the commit log
-    Log,
+    Log {
+        /// Trace the history of a line range, given as `<start>,<end>:<file>`
+        #[arg(short = 'L')]
+        line_range: Option<String>,
+        /// Show the refs pointing at each commit
+        #[arg(long)]
+        decorate: bool,
+        /// Show at most this many commits
+        #[arg(short = 'n', long)]
+        max_count: Option<usize>,
+        /// Show each commit on one line, with an abbreviated id
+        #[arg(long)]
+        oneline: bool,
+        /// Only show commits more recent than a date
+        #[arg(long, alias = "after")]
+        since: Option<String>,
+        /// Only show commits older than a date
+        #[arg(long, alias = "before")]
+        until: Option<String>,
+        /// Only show commits whose author contains this text
+        #[arg(long)]
+        author: Option<String>,
+        /// Draw the history, merged branches included, as a graph
+        #[arg(long, conflicts_with_all = ["since", "until", "author", "paths"])]
+        graph: bool,
+        /// Follow only the first parent of merge commits
+        #[arg(long, conflicts_with = "graph")]
+        first_parent: bool,
+        /// Show no commit before all its children, instead of by date
+        #[arg(long, conflicts_with = "graph")]
+        topo_order: bool,
+        /// The revisions, with `A..B`, `A...B` or `^A` excluding commits.
+        /// Defaults to HEAD
+        revisions: Vec<String>,
+        /// Only show commits changing these paths, given after `--`
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+    /// Export the commit graph for Graphviz or other tools, as `dot` or,
+    /// with `--format json`, as JSON
+    GraphExport {
+        /// Commits to include, `^<commit>` to exclude, or `<from>..<to>`
+        /// ranges; HEAD by default
+        revisions: Vec<String>,
+    },
+    /// Summarize the commit log by author
+    Shortlog,
     /// List the index entries
-    LsIndex,
+    LsIndex {
+        /// List the conflicted stages of the paths resolved since instead
+        #[arg(long)]
+        resolve_undo: bool,
+    },
     /// Write the index file
     WriteIndex,
     /// Dump a Pack File
@@ -80,85 +321,1458 @@ enum Command {
         /// The pack index file to dump
         pack_id: String,
     },
+    /// Write every object of a pack file as a loose object
+    UnpackObjects {
+        /// The pack file to unpack
+        file: PathBuf,
+    },
+    /// Remove the loose objects which are already packed
+    PrunePacked {
+        /// Print the objects which would be removed
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// List the packs whose objects are all in other packs
+    PackRedundant,
+    /// Remove redundant packs and the loose objects already packed
+    Gc,
+    /// Build the index file of a pack file
+    IndexPack {
+        /// The pack file to index
+        file: PathBuf,
+    },
+    /// Move a standalone pack file, such as the one `clone` downloads, into
+    /// the repository once checked and indexed
+    IngestPack {
+        /// The pack file to ingest
+        file: PathBuf,
+        /// A file of `<id> <ref>` lines, as `clone` prints them, giving the
+        /// refs to update once the pack is in
+        #[arg(long)]
+        refs: Option<PathBuf>,
+    },
+    /// Write a pack of the objects listed on stdin
+    PackObjects {
+        /// Write the pack to stdout instead of `<base-name>-<checksum>.pack`
+        #[arg(long, conflicts_with = "base_name")]
+        stdout: bool,
+        /// Order objects by id and ignore the configured compression level,
+        /// so the same objects always give the same pack
+        #[arg(long)]
+        reproducible: bool,
+        #[arg(required_unless_present = "stdout")]
+        base_name: Option<PathBuf>,
+    },
     /// Hash an object
     HashObject {
         /// The object to hash
         file: PathBuf,
     },
+    /// Clone a repository over HTTP or from a local path
     Clone {
         /// The repository to clone
         repo: String,
+        /// Clone from a local path, hardlinking its objects
+        #[arg(short, long)]
+        local: bool,
+        /// Borrow the objects of the local source through an alternates entry
+        /// instead of copying them
+        #[arg(short, long)]
+        shared: bool,
+        /// Copy the objects of the local source instead of hardlinking them
+        #[arg(long)]
+        no_hardlinks: bool,
+        /// Create a shallow clone truncated to the given number of commits
+        #[arg(long)]
+        depth: Option<u32>,
+        /// Omit objects matching the filter (e.g. `blob:none`), fetching them on demand
+        #[arg(long)]
+        filter: Option<String>,
+        /// Connect over IPv4 only
+        #[arg(short = '4', long, conflicts_with = "ipv6")]
+        ipv4: bool,
+        /// Connect over IPv6 only
+        #[arg(short = '6', long)]
+        ipv6: bool,
+    },
+    #[cfg(feature = "http")]
+    /// Download objects and refs from other repositories
+    Fetch {
+        /// The remotes to fetch, `origin` by default
+        remotes: Vec<String>,
+        /// Fetch every configured remote
+        #[arg(long)]
+        all: bool,
+        /// The number of remotes fetched at the same time, `fetch.parallel`
+        /// by default (0 for one per CPU)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Connect over IPv4 only
+        #[arg(short = '4', long, conflicts_with = "ipv6")]
+        ipv4: bool,
+        /// Connect over IPv6 only
+        #[arg(short = '6', long)]
+        ipv6: bool,
+        /// Fetch this many more commits of the history of a shallow repository
+        #[arg(long, value_name = "DEPTH", conflicts_with = "unshallow")]
+        deepen: Option<u32>,
+        /// Fetch the whole history of a shallow repository
+        #[arg(long)]
+        unshallow: bool,
+    },
+    #[cfg(feature = "http")]
+    /// List the refs of a remote repository
+    LsRemote {
+        /// The url of the repository
+        url: String,
+        /// Only list the refs matching these names, or their last components
+        patterns: Vec<String>,
+        /// Only list branches
+        #[arg(long)]
+        heads: bool,
+        /// Only list tags
+        #[arg(short, long)]
+        tags: bool,
+        /// Connect over IPv4 only
+        #[arg(short = '4', long, conflicts_with = "ipv6")]
+        ipv4: bool,
+        /// Connect over IPv6 only
+        #[arg(short = '6', long)]
+        ipv6: bool,
+    },
+    /// Materialize a commit in the working directory
+    Checkout {
+        /// The branch or commit to check out, or to restore paths from
+        #[arg(required_unless_present = "paths")]
+        rev: Option<String>,
+        /// List what would be created or overwritten without touching the worktree
+        #[arg(long, conflicts_with = "paths")]
+        dry_run: bool,
+        /// Only restore these paths, from the index without a revision, given
+        /// after `--`
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+    /// Search tracked files for a string
+    Grep {
+        /// The pattern to search for, a basic regular expression by default
+        pattern: String,
+        /// Take the pattern as an extended regular expression
+        #[arg(short = 'E', long, conflicts_with = "fixed_strings")]
+        extended_regexp: bool,
+        /// Take the pattern as a fixed string
+        #[arg(short = 'F', long)]
+        fixed_strings: bool,
+        /// Search the blobs registered in the index instead of the worktree
+        #[arg(long)]
+        cached: bool,
+        /// Ignore case differences
+        #[arg(short, long)]
+        ignore_case: bool,
+        /// Prefix matching lines with their line number
+        #[arg(short = 'n', long)]
+        line_number: bool,
+        /// Only print the names of matching files
+        #[arg(short = 'l', long)]
+        files_with_matches: bool,
+        /// Number of worker threads
+        #[arg(long)]
+        threads: Option<usize>,
+        /// Search the untracked files of the worktree too
+        #[arg(long, conflicts_with = "cached")]
+        untracked: bool,
+        /// Search the trees of these revisions instead of the worktree
+        revisions: Vec<String>,
+        /// Only search these paths, given after `--`
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+    /// Apply a patch to the working directory
+    Apply {
+        /// The patch file
+        patch: PathBuf,
+        /// Apply the hunks that can be applied and write the others to `.rej` files
+        #[arg(long)]
+        reject: bool,
+    },
+    /// Manage ignore patterns
+    Ignore {
+        #[clap(subcommand)]
+        command: IgnoreCommand,
+    },
+    /// Show which ignore pattern matches paths
+    CheckIgnore {
+        /// Show the pattern, its file and line for each path
+        #[arg(short, long)]
+        verbose: bool,
+        /// Also show paths not matching any pattern
+        #[arg(short, long)]
+        non_matching: bool,
+        /// Do not skip paths tracked in the index
+        #[arg(long)]
+        no_index: bool,
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Show the gitattributes of paths
+    CheckAttr {
+        /// Show every attribute set on the paths
+        #[arg(short, long)]
+        all: bool,
+        /// The attributes to show, followed by the paths unless `--` is used
+        attrs: Vec<String>,
+        /// The paths, after `--`
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+    /// Replay the commits of the current branch on top of another commit
+    Rebase {
+        /// The commit to replay onto
+        upstream: String,
+        /// Edit the list of commits to replay first
+        #[arg(short, long)]
+        interactive: bool,
+        /// Meld the `fixup!` and `squash!` commits into the commits they amend
+        #[arg(long)]
+        autosquash: bool,
+        /// Rebase even a protected branch
+        #[arg(long)]
+        override_protection: bool,
+    },
+    /// Show the current branch and the unmerged paths
+    Status {
+        /// Give the output in the stable format of VERSION, v1 or v2
+        #[arg(long, value_name = "VERSION", num_args = 0..=1, require_equals = true, default_missing_value = "v1")]
+        porcelain: Option<String>,
+        /// Show the branch and how it compares with its upstream
+        #[arg(short, long)]
+        branch: bool,
+        /// Only show these paths
+        paths: Vec<String>,
+    },
+    /// Find the best common ancestors of two commits
+    MergeBase {
+        a: String,
+        b: String,
+        /// Print every best common ancestor instead of one
+        #[arg(long)]
+        all: bool,
+        /// Exit with status 0 if A is an ancestor of B and 1 otherwise
+        #[arg(long)]
+        is_ancestor: bool,
+    },
+    /// Show changes between the index, the worktree and commits
+    Diff {
+        /// Compare against the index instead of the worktree
+        #[arg(long)]
+        cached: bool,
+        /// Write an HTML report to this directory instead of printing a patch
+        #[arg(long)]
+        html: Option<PathBuf>,
+        /// Render the HTML report side by side instead of unified
+        #[arg(long, requires = "html")]
+        side_by_side: bool,
+        /// A commit, two commits or a `<from>..<to>` range
+        revisions: Vec<String>,
+        /// Only compare these paths, given after `--`
+        #[arg(last = true)]
+        paths: Vec<String>,
+    },
+    #[cfg(feature = "ui")]
+    /// Show changes with an external diff tool
+    Difftool {
+        /// Compare against the index instead of the worktree
+        #[arg(long)]
+        cached: bool,
+        /// The tool to use instead of `diff.tool`
+        #[arg(short, long)]
+        tool: Option<String>,
+        /// Do not ask before launching the tool
+        #[arg(short = 'y', long)]
+        no_prompt: bool,
+        /// Stop at the first file for which the tool fails
+        #[arg(long)]
+        trust_exit_code: bool,
+        /// A commit, two commits or a `<from>..<to>` range
+        revisions: Vec<String>,
+    },
+    #[cfg(feature = "ui")]
+    /// Resolve merge conflicts with an external merge tool
+    Mergetool {
+        /// The tool to use instead of `merge.tool`
+        #[arg(short, long)]
+        tool: Option<String>,
+        /// Do not ask before launching the tool
+        #[arg(short = 'y', long)]
+        no_prompt: bool,
+        /// Only resolve these paths
+        paths: Vec<String>,
+    },
+    /// Create a tar archive of a tree, or with `--format tar.gz` (`tgz`)
+    /// a compressed one; the format is guessed from the output file name
+    Archive {
+        /// Prepend this to every path in the archive
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Write the archive to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Make the archive independent of the time and the local
+        /// configuration
+        #[arg(long)]
+        reproducible: bool,
+        tree_ish: String,
+    },
+    #[cfg(feature = "server")]
+    /// Serve repositories over the git:// protocol
+    Daemon {
+        /// The directory containing the served repositories
+        #[arg(long)]
+        base_path: PathBuf,
+        /// The address to listen on
+        #[arg(long, default_value = "0.0.0.0")]
+        listen: String,
+        #[arg(long, default_value_t = 9418)]
+        port: u16,
+        /// Serve every repository, not only those with a
+        /// `git-daemon-export-ok` file
+        #[arg(long)]
+        export_all: bool,
+    },
+    #[cfg(feature = "server")]
+    /// Serve repositories to smart HTTP clients
+    Serve {
+        /// The address to listen on, e.g. `127.0.0.1:8080`
+        #[arg(long)]
+        http: String,
+        /// The directory containing the served repositories
+        #[arg(long, default_value = ".")]
+        base_path: PathBuf,
+        /// Serve every repository, not only those with a
+        /// `git-daemon-export-ok` file
+        #[arg(long)]
+        export_all: bool,
+    },
+    #[cfg(feature = "http")]
+    /// Tools to investigate mg itself
+    Debug {
+        #[clap(subcommand)]
+        command: DebugCommand,
+    },
+    /// Restrict the working directory to a subset of paths
+    SparseCheckout {
+        #[clap(subcommand)]
+        command: SparseCheckoutCommand,
+    },
+    /// Merge the history of another project into a directory, or extract the
+    /// history of a directory into a project of its own
+    Subtree {
+        #[clap(subcommand)]
+        command: SubtreeCommand,
+    },
+    /// List the commits of a range, newest first
+    RevList {
+        /// The revisions, with `A..B`, `A...B` or `^A` excluding commits.
+        /// Defaults to HEAD
+        revisions: Vec<String>,
+        /// Also list the trees and blobs the commits add, with their paths
+        #[arg(long)]
+        objects: bool,
+        /// Print the size the listed objects take on disk instead, in bytes
+        /// or with `=human` in a readable unit
+        #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "bytes", value_parser = ["bytes", "human"])]
+        disk_usage: Option<String>,
+        /// Find the objects through the reachability bitmap of a pack,
+        /// listing them without paths
+        #[arg(long)]
+        use_bitmap_index: bool,
+    },
+    /// Get and set repository options
+    Config {
+        /// The variable, as `section[.subsection].key`
+        #[arg(required_unless_present = "list")]
+        name: Option<String>,
+        /// The value to set, or to add with `--add`
+        value: Option<String>,
+        /// Check and canonicalize the values as `bool`, `int`, `path` or
+        /// `expiry-date`
+        #[arg(long = "type", value_parser = ConfigType::parse)]
+        value_type: Option<ConfigType>,
+        /// Print the last value of the variable
+        #[arg(long, group = "action", conflicts_with = "value")]
+        get: bool,
+        /// Print every value of a multi-valued variable
+        #[arg(long, group = "action", conflicts_with = "value")]
+        get_all: bool,
+        /// Add a value, keeping the existing ones
+        #[arg(long, group = "action", requires = "value")]
+        add: bool,
+        /// Remove the variable, which must have a single value
+        #[arg(long, group = "action", conflicts_with = "value")]
+        unset: bool,
+        /// Remove every value of the variable
+        #[arg(long, group = "action", conflicts_with = "value")]
+        unset_all: bool,
+        /// List every variable
+        #[arg(short, long, group = "action", conflicts_with_all = ["name", "value"])]
+        list: bool,
     },
 }
 
+impl Command {
+    /// Whether the command works in the repository around the current
+    /// directory, rather than creating one or not needing one at all.
+    fn needs_repository(&self) -> bool {
+        match self {
+            Command::Init { .. } | Command::Clone { .. } | Command::HashObject { .. } => false,
+            #[cfg(feature = "http")]
+            Command::LsRemote { .. } | Command::Debug { .. } => false,
+            #[cfg(feature = "server")]
+            Command::Daemon { .. } | Command::Serve { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+#[derive(Subcommand)]
+enum DebugCommand {
+    /// Replay a captured server response through the decoding of fetches,
+    /// printing each packet and what the response decodes to
+    ReplayWire {
+        /// The raw response body, as the server sent it
+        file: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum SparseCheckoutCommand {
+    /// Set the patterns of the paths to materialize
+    Set {
+        /// The patterns to include
+        #[arg(required = true)]
+        patterns: Vec<String>,
+    },
+    /// List the current patterns
+    List,
+    /// Materialize every path again
+    Disable,
+}
+
+#[derive(Subcommand)]
+enum SubtreeCommand {
+    /// Add the tree of a commit as a new directory, merging its history
+    Add {
+        /// The directory of the subtree
+        #[arg(short = 'P', long)]
+        prefix: String,
+        /// The commit to add
+        commit: String,
+    },
+    /// Merge later commits of the subtree project into the directory
+    Merge {
+        /// The directory of the subtree
+        #[arg(short = 'P', long)]
+        prefix: String,
+        /// The commit to merge
+        commit: String,
+    },
+    #[cfg(feature = "http")]
+    /// Fetch a branch of a remote and merge it into the directory
+    Pull {
+        /// The directory of the subtree
+        #[arg(short = 'P', long)]
+        prefix: String,
+        /// The remote to fetch
+        remote: String,
+        /// The branch of the remote to merge
+        branch: String,
+    },
+    /// Extract the history of the directory into commits of its own, and
+    /// print the last one
+    Split {
+        /// The directory of the subtree
+        #[arg(short = 'P', long)]
+        prefix: String,
+        /// The commit whose history is split. Defaults to HEAD
+        commit: Option<String>,
+        /// Create or fast-forward this branch to the split history
+        #[arg(short, long)]
+        branch: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum IgnoreCommand {
+    /// Add patterns to a `.gitignore`, skipping those already there
+    Add {
+        /// The patterns to add
+        patterns: Vec<String>,
+        /// Also add the patterns of a template file
+        #[arg(long)]
+        template: Option<PathBuf>,
+        /// The directory whose `.gitignore` gets the patterns. Defaults to the
+        /// top of the worktree
+        #[arg(long)]
+        dir: Option<String>,
+        /// Remove the tracked files the patterns ignore from the index
+        #[arg(long)]
+        cached: bool,
+    },
+}
+
+/// Apply the `-C` and `--git-dir` options before the subcommand, which say
+/// where the repository is, and so must be handled before anything is read
+/// from it, aliases included. Returns the git directory, if given.
+fn apply_location_options(args: &[String]) -> Result<Option<PathBuf>> {
+    // the options before the command which take a separate value
+    let command = cli_command();
+    let takes_value = |option: &str| {
+        command.get_arguments().any(|arg| {
+            let named = match option.strip_prefix("--") {
+                Some(long) => arg.get_long() == Some(long),
+                None => arg
+                    .get_short()
+                    .is_some_and(|short| option == format!("-{}", short)),
+            };
+            named && arg.get_action().takes_values() && !arg.is_require_equals_set()
+        })
+    };
+
+    let mut git_dir = None;
+    let mut i = 1;
+    while i < args.len() {
+        let (option, value) = match args[i].as_str() {
+            arg if takes_value(arg) => {
+                i += 1;
+                (arg, args.get(i).map(String::as_str))
+            }
+            arg if arg.starts_with("--git-dir=") => ("--git-dir", arg.strip_prefix("--git-dir=")),
+            arg if arg.starts_with("-C") => ("-C", arg.strip_prefix("-C")),
+            arg if arg.starts_with('-') => (arg, None),
+            _ => break,
+        };
+        match (option, value) {
+            // each directory is relative to the previous one
+            ("-C", Some(dir)) if !dir.is_empty() => std::env::set_current_dir(dir)
+                .map_err(|e| anyhow!("cannot change to '{}': {}", dir, e))?,
+            ("--git-dir", Some(dir)) => git_dir = Some(PathBuf::from(dir)),
+            _ => (),
+        }
+        i += 1;
+    }
+    Ok(git_dir)
+}
+
+/// The command line of `mg`. `-C` and `--git-dir` are applied before it is
+/// parsed, as aliases are read from the repository they find, so they have
+/// no field in `Cli`.
+fn cli_command() -> clap::Command {
+    Cli::command()
+        .arg(
+            Arg::new("directory")
+                .short('C')
+                .value_name("PATH")
+                .action(ArgAction::Append)
+                .help("Run as if started in this directory")
+                .display_order(0),
+        )
+        .arg(
+            Arg::new("git_dir")
+                .long("git-dir")
+                .value_name("PATH")
+                .help("Use this git directory instead of looking for `.git`")
+                .display_order(0),
+        )
+}
+
+/// Report a command line which does not parse, exiting with 129 as git
+/// does on usage errors; `--help` and `--version` exit with 0.
+fn exit_on_usage(e: clap::Error) -> ! {
+    let _ = e.print();
+    trace2::exit(if e.use_stderr() { 129 } else { 0 })
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let cli = Cli::parse();
-
-    let mut repo = Repository::new()?;
+    let args: Vec<String> = std::env::args().collect();
+    trace2::start(&args);
+    let git_dir = match apply_location_options(&args) {
+        Ok(git_dir) => git_dir,
+        Err(e) => {
+            eprintln!("Failed to open repository: {}", e);
+            trace2::exit(MgError::exit_code(&e));
+        }
+    };
+    // commands creating a repository, or not using one, still read the
+    // configuration and aliases of the current directory
+    let (mut repo, not_found) = match Repository::discover(git_dir) {
+        Ok(repo) => (repo, None),
+        Err(e) => (Repository::new()?, Some(e)),
+    };
+    trace2::def_repo(&repo.path);
+    // before anything, aliases included, is read from its config
+    if let Err(e) = repo.ensure_safe_directory() {
+        eprintln!("Failed to open repository: {}", e);
+        trace2::exit(MgError::exit_code(&e));
+    }
 
-    match cli.command {
-        Command::Init { path } => match repo.init_repository(&path) {
-            Ok(path) => println!("Initialized empty Git repository in {:?}", path),
-            Err(e) => eprintln!("Failed to initialize repository: {}", e),
+    let is_command = |name: &str| cli_command().find_subcommand(name).is_some();
+    let cli = match repo.expand_alias(args, is_command) {
+        Ok(Expansion::Args(args)) => {
+            let matches = cli_command()
+                .try_get_matches_from(args)
+                .unwrap_or_else(|e| exit_on_usage(e));
+            trace2::cmd_name(matches.subcommand_name().unwrap_or_default());
+            Cli::from_arg_matches(&matches).unwrap_or_else(|e| exit_on_usage(e))
+        }
+        Ok(Expansion::Shell(command, args)) => match repo.run_shell_alias(&command, &args) {
+            Ok(code) => trace2::exit(code),
+            Err(e) => {
+                eprintln!("Failed to run alias: {}", e);
+                trace2::exit(MgError::exit_code(&e));
+            }
         },
-        Command::CatFile { hash } => match repo.read_object(&hash) {
-            Ok(mut obj) => print!("{}", obj.string()?),
-            Err(e) => eprintln!("Failed to read object: {}", e),
+        Err(e) => {
+            eprintln!("Failed to expand alias: {}", e);
+            trace2::exit(MgError::exit_code(&e));
+        }
+    };
+
+    match not_found {
+        Some(e) if cli.command.needs_repository() => {
+            eprintln!("Failed to open repository: {}", e);
+            trace2::exit(MgError::exit_code(&e));
+        }
+        // a clone goes in the current directory, even inside another worktree
+        _ if !cli.command.needs_repository() => repo = Repository::new()?,
+        _ => (),
+    }
+
+    if cli.no_replace_objects {
+        repo.replace_objects = false;
+    }
+    repo.memory_budget = cli.memory_budget;
+    if cli.no_optional_locks {
+        repo.optional_locks = false;
+    }
+    // graph-export and archive take formats of their own
+    if !matches!(
+        cli.command,
+        Command::GraphExport { .. } | Command::Archive { .. }
+    ) {
+        match cli.format.as_deref().map(OutputFormat::parse).transpose() {
+            Ok(format) => repo.output_format = format.unwrap_or_default(),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                trace2::exit(129);
+            }
+        }
+    }
+    repo.color = cli.color;
+    if cli.progress || cli.quiet {
+        repo.progress = Some(cli.progress);
+    }
+
+    if let Err(e) = run(repo, cli.command, cli.format).await {
+        eprintln!("{:#}", e);
+        trace2::exit(MgError::exit_code(&e));
+    }
+
+    trace2::finish(0);
+
+    Ok(())
+}
+
+/// Run `command`, whose failure `main` reports, with the exit code of
+/// its kind. `format` is the global `--format`.
+async fn run(mut repo: Repository, command: Command, format: Option<String>) -> Result<(), Error> {
+    match command {
+        Command::Init {
+            path,
+            bare,
+            shared,
+            initial_branch,
+        } => match shared
+            .as_deref()
+            .map(SharedMode::parse)
+            .transpose()
+            .and_then(|shared| {
+                let options = InitOptions {
+                    bare,
+                    shared,
+                    initial_branch,
+                };
+                repo.init_repository(&path, &options)
+            }) {
+            Ok((path, false)) => println!("Initialized empty Git repository in {:?}", path),
+            Ok((path, true)) => println!("Reinitialized existing Git repository in {:?}", path),
+            Err(e) => return Err(e.context("Failed to initialize repository")),
         },
+        Command::CatFile {
+            show_type,
+            size,
+            batch,
+            batch_check,
+            textconv,
+            hash,
+            ..
+        } => {
+            let result = match hash {
+                _ if batch || batch_check => {
+                    let stdout = std::io::stdout().lock();
+                    repo.cat_file_batch(std::io::stdin().lock(), stdout, batch)
+                }
+                Some(hash) if textconv => repo
+                    .textconv_file(&hash)
+                    .and_then(|text| Ok(std::io::stdout().write_all(&text)?)),
+                Some(hash) => repo.cat_file(&hash).and_then(|mut object| {
+                    if show_type {
+                        println!("{}", object.kind());
+                    } else if size {
+                        println!("{}", object.size());
+                    } else if matches!(object.kind(), Kind::Tree) {
+                        // trees in a readable form
+                        println!("{}", object.string()?);
+                    } else {
+                        object.copy_content(&mut std::io::stdout().lock())?;
+                    }
+                    Ok(())
+                }),
+                None => unreachable!("clap requires an object"),
+            };
+            result.context("Failed to read object")?;
+        }
         Command::WriteBlob { file } => match repo.write_blob(&file) {
             Ok(hash) => println!("{}", hex::encode(hash)),
-            Err(e) => eprintln!("Failed to write object: {}", e),
+            Err(e) => return Err(e.context("Failed to write object")),
         },
-        Command::WriteTree { path } => match repo.write_tree(&path) {
+        Command::WriteTree { path } => match match path {
+            Some(path) => repo.write_tree(&path),
+            None => repo.write_tree_from_index(),
+        } {
             Ok(hash) => println!("{}", hex::encode(hash)),
-            Err(e) => eprintln!("Failed to write tree: {}", e),
+            Err(e) => return Err(e.context("Failed to write tree")),
         },
-        Command::Commit { message } => match repo.commit(&message) {
-            Ok(hash) => println!("{}", hex::encode(hash)),
-            Err(e) => eprintln!("Failed to commit: {}", e),
+        Command::Add { paths } => repo
+            .pathspec(&paths)
+            .and_then(|spec| repo.add(&spec))
+            .context("Failed to add")?,
+        Command::Commit {
+            positional_message,
+            message,
+            file,
+            fixup,
+            squash,
+            no_edit,
+            no_verify,
+            gpg_sign,
+            amend,
+            all,
+            allow_empty,
+            date,
+            reproducible,
+            override_protection,
+        } => {
+            let options = CommitOptions {
+                no_verify,
+                sign: gpg_sign,
+                amend,
+                all,
+                allow_empty,
+                no_edit,
+                date,
+                reproducible,
+                override_protection,
+            };
+            let message = match file {
+                Some(file) => {
+                    Some(commit_message::read_message_file(&file).context("Failed to commit")?)
+                }
+                None => message.or(positional_message),
+            };
+            let fixup = fixup
+                .map(|rev| (rev, false))
+                .or(squash.map(|rev| (rev, true)));
+            let message = match fixup {
+                Some((rev, squash)) => Some(
+                    repo.fixup_message(&rev, squash, message.as_deref())
+                        .context("Failed to commit")?,
+                ),
+                None => message,
+            };
+            let hash = repo
+                .commit(message.as_deref(), &options)
+                .context("Failed to commit")?;
+            println!("{}", hex::encode(hash));
+        }
+        Command::Tag {
+            name,
+            target,
+            message,
+            sign,
+        } => {
+            repo.create_tag(&name, target.as_deref(), message.as_deref(), sign)
+                .context("Failed to create tag")?;
+        }
+        Command::Release {
+            version,
+            sign,
+            changelog,
+        } => {
+            repo.release(&version, &ReleaseOptions { sign, changelog })
+                .context("Failed to release")?;
+        }
+        Command::VerifyIndex => match repo.verify_index() {
+            Ok(true) => (),
+            Ok(false) => trace2::exit(1),
+            Err(e) => return Err(e.context("Failed to verify the index")),
         },
-        Command::Branch => match repo.current_branch() {
-            Ok(branch) => println!("{}", branch),
-            Err(e) => eprintln!("Failed to get branch: {}", e),
+        Command::VerifyCommit { commits, verbose } => {
+            let mut good = true;
+            for commit in &commits {
+                match repo.verify_object(commit, false, verbose) {
+                    Ok(ok) => good &= ok,
+                    Err(e) => {
+                        eprintln!("Failed to verify commit: {}", e);
+                        good = false;
+                    }
+                }
+            }
+            if !good {
+                trace2::exit(1);
+            }
+        }
+        Command::VerifyTag { tags, verbose } => {
+            let mut good = true;
+            for tag in &tags {
+                match repo.verify_object(tag, true, verbose) {
+                    Ok(ok) => good &= ok,
+                    Err(e) => {
+                        eprintln!("Failed to verify tag: {}", e);
+                        good = false;
+                    }
+                }
+            }
+            if !good {
+                trace2::exit(1);
+            }
+        }
+        Command::Branch {
+            name,
+            start_point,
+            list,
+            remotes,
+            all,
+            delete,
+            force_delete,
+            force,
+            override_protection,
+        } => match (name, delete || force_delete) {
+            (Some(name), true) => {
+                repo.delete_branch(&name, force || force_delete, override_protection)
+                    .context("Failed to delete branch")?;
+            }
+            (Some(name), false) => repo
+                .create_branch(&name, start_point.as_deref())
+                .context("Failed to create branch")?,
+            (None, _) if list || remotes || all => {
+                let branches = repo
+                    .list_branches(remotes, all)
+                    .context("Failed to list branches")?;
+                print_branches(&repo, &branches)?;
+            }
+            (None, _) => match repo.current_branch() {
+                Ok(branch) => println!("{}", branch),
+                Err(e) => return Err(e.context("Failed to get branch")),
+            },
         },
-        Command::Show { hash } => match repo.show(hash) {
-            Ok(_) => (),
-            Err(e) => eprintln!("Failed to show: {}", e),
+        Command::Show { hash } => {
+            let shown = repo.show(hash).context("Failed to show")?;
+            print_shown(shown)?;
+        }
+        Command::Log {
+            line_range,
+            decorate,
+            max_count,
+            oneline,
+            since,
+            until,
+            author,
+            graph,
+            first_parent,
+            topo_order,
+            revisions,
+            paths,
+        } => {
+            let result = match line_range {
+                Some(spec) => LineRange::parse(&spec).and_then(|range| repo.log_line_range(&range)),
+                None => {
+                    let now = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)?
+                        .as_secs() as i64;
+                    let since = since.map(|date| parse_date(&date, now)).transpose();
+                    let until = until.map(|date| parse_date(&date, now)).transpose();
+                    since.and_then(|since| {
+                        let options = LogOptions {
+                            max_count,
+                            oneline,
+                            decorate,
+                            since,
+                            until: until?,
+                            author,
+                            pathspec: repo.pathspec(&paths)?,
+                            graph,
+                            first_parent,
+                            topo_order,
+                        };
+                        repo.print_log(&revisions, &options)
+                    })
+                }
+            };
+            result.context("Failed to show log")?;
+        }
+        Command::GraphExport { revisions } => {
+            repo.graph_export(&revisions, format.as_deref().unwrap_or("dot"))
+                .context("Failed to export graph")?;
+        }
+        Command::Shortlog => repo.shortlog().context("Failed to show shortlog")?,
+        Command::LsIndex { resolve_undo } => {
+            let listings = repo
+                .read_index(resolve_undo)
+                .context("Failed to list index")?;
+            for listing in listings {
+                match (repo.output_format, resolve_undo) {
+                    (OutputFormat::Json, _) => println!("{}", listing.json()),
+                    (_, true) => println!(
+                        "{:06o} {} {}\t{}",
+                        listing.mode,
+                        hex::encode(listing.hash),
+                        listing.stage,
+                        listing.path
+                    ),
+                    (_, false) => println!("{} {}", hex::encode(listing.hash), listing.path),
+                }
+            }
+        }
+        Command::WriteIndex => repo.write_index().context("Failed to write index")?,
+        Command::DumpPackFiles => repo
+            .dump_pack_files()
+            .context("Failed to dump pack files")?,
+        Command::DumpPack { file } => repo.dump_pack(&file).context("Failed to dump pack")?,
+        Command::DumpPackIndexFile { pack_id } => repo
+            .dump_pack_index_file(&pack_id)
+            .context("Failed to dump pack index file")?,
+        Command::UnpackObjects { file } => match repo.unpack_pack(&file) {
+            Ok(hashes) => println!("Unpacked {} objects", hashes.len()),
+            Err(e) => return Err(e.context("Failed to unpack objects")),
         },
-        Command::Log => match repo.log() {
-            Ok(_) => (),
-            Err(e) => eprintln!("Failed to show log: {}", e),
+        Command::PrunePacked { dry_run } => {
+            repo.prune_packed(dry_run)
+                .context("Failed to prune packed objects")?;
+        }
+        Command::PackRedundant => match repo.redundant_packs() {
+            Ok(packs) => {
+                for pack in packs {
+                    let pack = pack.strip_prefix(&repo.path).unwrap_or(&pack);
+                    println!("{}", pack.with_extension("idx").display());
+                    println!("{}", pack.display());
+                }
+            }
+            Err(e) => return Err(e.context("Failed to find redundant packs")),
         },
-        Command::LsIndex => match repo.read_index() {
-            Ok(_) => (),
-            Err(e) => eprintln!("Failed to list index: {}", e),
+        Command::Gc => repo.gc().context("Failed to collect garbage")?,
+        Command::IndexPack { file } => match repo.index_pack(&file) {
+            Ok(idx_path) => println!("{}", idx_path.display()),
+            Err(e) => return Err(e.context("Failed to index pack")),
         },
-        Command::WriteIndex => match repo.write_index() {
-            Ok(_) => (),
-            Err(e) => eprintln!("Failed to write index: {}", e),
+        Command::IngestPack { file, refs } => match repo.ingest_pack(&file, refs.as_deref()) {
+            Ok(name) => println!("{}", name),
+            Err(e) => return Err(e.context("Failed to ingest pack")),
         },
-        Command::DumpPackFiles => match repo.dump_pack_files() {
-            Ok(_) => (),
-            Err(e) => eprintln!("Failed to dump pack files: {}", e),
+        Command::PackObjects {
+            stdout,
+            reproducible,
+            base_name,
+        } => {
+            let base_name = if stdout { None } else { base_name.as_deref() };
+            repo.pack_objects(base_name, reproducible)
+                .context("Failed to pack objects")?;
+        }
+        Command::HashObject { file } => match hash_object(&file) {
+            Ok(hash) => println!("{}", hex::encode(hash)),
+            Err(e) => return Err(e.context("Failed to hash object")),
         },
-        Command::DumpPack { file } => match repo.dump_pack(&file) {
-            Ok(_) => (),
-            Err(e) => eprintln!("Failed to dump pack: {}", e),
+        Command::Clone {
+            repo: url,
+            local,
+            shared,
+            no_hardlinks,
+            depth,
+            filter,
+            ..
+        } if local || shared || !url.contains("://") => {
+            if depth.is_some() || filter.is_some() {
+                eprintln!("warning: --depth and --filter are ignored in local clones");
+            }
+            repo.clone_local(Path::new(&url), shared, no_hardlinks)
+                .context("Failed to clone")?;
+        }
+        #[cfg(feature = "http")]
+        Command::Clone {
+            repo: url,
+            depth,
+            filter,
+            ipv4,
+            ipv6,
+            ..
+        } => {
+            let result = match repo.transport_options(IpFamily::from_flags(ipv4, ipv6)) {
+                Ok(options) => clone(&repo, &url, depth, filter.as_deref(), &options).await,
+                Err(e) => Err(e),
+            };
+            result.context("Failed to clone")?;
+        }
+        #[cfg(not(feature = "http"))]
+        Command::Clone { repo: url, .. } => {
+            return Err(anyhow!("{} needs the http feature", url).context("Failed to clone"));
+        }
+        #[cfg(feature = "http")]
+        Command::Fetch {
+            remotes,
+            all,
+            jobs,
+            ipv4,
+            ipv6,
+            deepen,
+            unshallow,
+        } => {
+            let deepen = match (deepen, unshallow) {
+                (_, true) => Some(Deepen::Unshallow),
+                (Some(depth), false) => Some(Deepen::By(depth)),
+                (None, false) => None,
+            };
+            let ip_family = IpFamily::from_flags(ipv4, ipv6);
+            match fetch(repo, remotes, all, jobs, ip_family, deepen).await {
+                Ok(true) => (),
+                Ok(false) => trace2::exit(1),
+                Err(e) => return Err(e.context("Failed to fetch")),
+            }
+        }
+        #[cfg(feature = "http")]
+        Command::LsRemote {
+            url,
+            patterns,
+            heads,
+            tags,
+            ipv4,
+            ipv6,
+        } => {
+            let result = match repo.transport_options(IpFamily::from_flags(ipv4, ipv6)) {
+                Ok(options) => ls_remote(&url, heads, tags, &patterns, &options).await,
+                Err(e) => Err(e),
+            };
+            result.context("Failed to list remote refs")?;
+        }
+        Command::Checkout {
+            rev,
+            dry_run,
+            paths,
+        } => {
+            let result = match rev {
+                Some(rev) if paths.is_empty() => repo.checkout(&rev, dry_run),
+                rev => repo
+                    .pathspec(&paths)
+                    .and_then(|spec| repo.checkout_paths(rev.as_deref(), &spec)),
+            };
+            result.context("Failed to checkout")?;
+        }
+        Command::Grep {
+            pattern,
+            extended_regexp,
+            fixed_strings,
+            cached,
+            ignore_case,
+            line_number,
+            files_with_matches,
+            threads,
+            untracked,
+            revisions,
+            paths,
+        } => {
+            let syntax = match (extended_regexp, fixed_strings) {
+                (true, _) => Syntax::Extended,
+                (_, true) => Syntax::Fixed,
+                _ => Syntax::Basic,
+            };
+            let result = repo.pathspec(&paths).and_then(|pathspec| {
+                let options = GrepOptions {
+                    syntax,
+                    ignore_case,
+                    line_number,
+                    files_with_matches,
+                    jobs: threads,
+                    pathspec,
+                    untracked,
+                };
+                repo.grep(&pattern, cached, &revisions, &options)
+            });
+            // as in git, nothing found is not an error but exits with 1
+            if !result.context("Failed to grep")? {
+                trace2::exit(1);
+            }
+        }
+        Command::Apply { patch, reject } => {
+            if !repo
+                .apply(&patch, reject)
+                .context("Failed to apply patch")?
+            {
+                trace2::exit(1);
+            }
+        }
+        Command::Ignore { command } => match command {
+            IgnoreCommand::Add {
+                patterns,
+                template,
+                dir,
+                cached,
+            } => {
+                repo.ignore_add(&patterns, template.as_deref(), dir.as_deref(), cached)
+                    .context("Failed to add ignore patterns")?;
+            }
         },
-        Command::DumpPackIndexFile { pack_id } => match repo.dump_pack_index_file(&pack_id) {
-            Ok(_) => (),
-            Err(e) => eprintln!("Failed to dump pack index file: {}", e),
+        Command::CheckIgnore {
+            verbose,
+            non_matching,
+            no_index,
+            paths,
+        } => match repo.check_ignore(&paths, verbose, non_matching, no_index) {
+            Ok(true) => (),
+            Ok(false) => trace2::exit(1),
+            Err(e) => return Err(e.context("Failed to check ignored paths")),
         },
-        Command::HashObject { file } => match hash_object(&file) {
-            Ok(hash) => println!("{}", hex::encode(hash)),
-            Err(e) => eprintln!("Failed to hash object: {}", e),
+        Command::CheckAttr {
+            all,
+            mut attrs,
+            mut paths,
+        } => {
+            // without `--`, the first argument is the attribute
+            if paths.is_empty() {
+                let split = if all { 0 } else { attrs.len().min(1) };
+                paths = attrs.split_off(split);
+            }
+            repo.check_attr(&attrs, &paths, all)
+                .context("Failed to check attributes")?;
+        }
+        Command::Rebase {
+            upstream,
+            interactive,
+            autosquash,
+            override_protection,
+        } => {
+            // like git, rebase.autoSquash only applies to interactive rebases
+            let autosquash = autosquash
+                || (interactive
+                    && repo
+                        .config()
+                        .is_ok_and(|config| config.get_bool("rebase.autosquash") == Some(true)));
+            repo.rebase(&upstream, interactive, autosquash, override_protection)
+                .context("Failed to rebase")?;
+        }
+        Command::Status {
+            porcelain,
+            branch,
+            paths,
+        } => {
+            let report = repo
+                .pathspec(&paths)
+                .and_then(|spec| repo.status(porcelain.as_deref(), branch, &spec))
+                .context("Failed to get status")?;
+            print!("{}", report);
+        }
+        Command::MergeBase {
+            a, b, is_ancestor, ..
+        } if is_ancestor => {
+            let result = repo
+                .resolve_revision(&a)
+                .and_then(|a| Ok((a, repo.resolve_revision(&b)?)))
+                .and_then(|(a, b)| repo.is_ancestor(&a, &b));
+            match result {
+                Ok(true) => (),
+                Ok(false) => trace2::exit(1),
+                Err(e) => return Err(e.context("Failed to check ancestry")),
+            }
+        }
+        Command::MergeBase { a, b, all, .. } => match repo.merge_base(&a, &b, all) {
+            Ok(true) => (),
+            Ok(false) => trace2::exit(1),
+            Err(e) => return Err(e.context("Failed to find merge base")),
+        },
+        Command::Diff {
+            cached,
+            html,
+            side_by_side,
+            revisions,
+            paths,
+        } => match html {
+            Some(dir) => match repo.diff_html(&revisions, cached, &dir, side_by_side) {
+                Ok(_) => println!("Wrote {}", dir.join("index.html").display()),
+                Err(e) => return Err(e.context("Failed to write diff report")),
+            },
+            None => {
+                let diffs = repo
+                    .pathspec(&paths)
+                    .and_then(|spec| repo.diff(&revisions, cached, &spec))
+                    .context("Failed to diff")?;
+                print_diffs(&diffs, repo.use_color("diff")?);
+            }
+        },
+        #[cfg(feature = "ui")]
+        Command::Difftool {
+            cached,
+            tool,
+            no_prompt,
+            trust_exit_code,
+            revisions,
+        } => repo
+            .difftool(
+                &revisions,
+                cached,
+                tool.as_deref(),
+                no_prompt,
+                trust_exit_code,
+            )
+            .context("Failed to run difftool")?,
+        #[cfg(feature = "ui")]
+        Command::Mergetool {
+            tool,
+            no_prompt,
+            paths,
+        } => match repo.mergetool(&paths, tool.as_deref(), no_prompt) {
+            Ok(true) => (),
+            Ok(false) => trace2::exit(1),
+            Err(e) => return Err(e.context("Failed to run mergetool")),
+        },
+        Command::Archive {
+            prefix,
+            output,
+            reproducible,
+            tree_ish,
+        } => {
+            let format = format.unwrap_or_else(|| {
+                let name = output.as_deref().unwrap_or(Path::new("")).to_string_lossy();
+                if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+                    "tar.gz".to_string()
+                } else {
+                    "tar".to_string()
+                }
+            });
+            let result = match (format.as_str(), &output) {
+                ("tar" | "tar.gz" | "tgz", Some(path)) => std::fs::File::create(path)
+                    .map_err(Error::from)
+                    .and_then(|file| {
+                        let out = std::io::BufWriter::new(file);
+                        repo.archive(&tree_ish, &prefix, format != "tar", reproducible, out)
+                    }),
+                ("tar" | "tar.gz" | "tgz", None) => {
+                    let out = std::io::BufWriter::new(std::io::stdout().lock());
+                    repo.archive(&tree_ish, &prefix, format != "tar", reproducible, out)
+                }
+                _ => Err(MgError::Usage(format!("unknown archive format '{}'", format)).into()),
+            };
+            result.context("Failed to create archive")?;
+        }
+        #[cfg(feature = "server")]
+        Command::Daemon {
+            base_path,
+            listen,
+            port,
+            export_all,
+        } => {
+            daemon::daemon(&base_path, &listen, port, export_all)
+                .context("Failed to run daemon")?;
+        }
+        #[cfg(feature = "server")]
+        Command::Serve {
+            http,
+            base_path,
+            export_all,
+        } => {
+            serve::serve_http(&http, &base_path, export_all).context("Failed to serve")?;
+        }
+        #[cfg(feature = "http")]
+        Command::Debug { command } => match command {
+            DebugCommand::ReplayWire { file } => {
+                replay_wire::replay_wire(&file).context("Failed to replay response")?
+            }
+        },
+        Command::Subtree { command } => match command {
+            SubtreeCommand::Add { prefix, commit } => {
+                repo.subtree_add(&prefix, &commit)
+                    .context("Failed to add subtree")?;
+            }
+            SubtreeCommand::Merge { prefix, commit } => {
+                match repo.subtree_merge(&prefix, &commit) {
+                    Ok(Some(_)) => (),
+                    Ok(None) => println!("Already up to date."),
+                    Err(e) => return Err(e.context("Failed to merge subtree")),
+                }
+            }
+            #[cfg(feature = "http")]
+            SubtreeCommand::Pull {
+                prefix,
+                remote,
+                branch,
+            } => {
+                let fetched =
+                    fetch(repo.clone(), vec![remote.clone()], false, None, None, None).await;
+                let result = fetched.and_then(|_| {
+                    repo.subtree_merge(&prefix, &format!("refs/remotes/{}/{}", remote, branch))
+                });
+                match result {
+                    Ok(Some(_)) => (),
+                    Ok(None) => println!("Already up to date."),
+                    Err(e) => return Err(e.context("Failed to pull subtree")),
+                }
+            }
+            SubtreeCommand::Split {
+                prefix,
+                commit,
+                branch,
+            } => match repo.subtree_split(&prefix, commit.as_deref(), branch.as_deref()) {
+                Ok(hash) => println!("{}", hex::encode(hash)),
+                Err(e) => return Err(e.context("Failed to split subtree")),
+            },
         },
-        Command::Clone { repo } => match clone(&repo).await {
-            Ok(_) => (),
-            Err(e) => eprintln!("Failed to clone: {}", e),
+        Command::SparseCheckout { command } => match command {
+            SparseCheckoutCommand::Set { patterns } => repo
+                .sparse_checkout_set(&patterns)
+                .context("Failed to set sparse-checkout patterns")?,
+            SparseCheckoutCommand::List => repo
+                .sparse_checkout_list()
+                .context("Failed to list sparse-checkout patterns")?,
+            SparseCheckoutCommand::Disable => repo
+                .sparse_checkout_disable()
+                .context("Failed to disable sparse-checkout")?,
         },
+        Command::RevList {
+            revisions,
+            objects,
+            disk_usage,
+            use_bitmap_index,
+        } => repo
+            .rev_list(
+                &revisions,
+                objects,
+                disk_usage.map(|format| format == "human"),
+                use_bitmap_index,
+            )
+            .context("Failed to list revisions")?,
+        Command::Config {
+            name,
+            value,
+            value_type,
+            get_all,
+            add,
+            unset,
+            unset_all,
+            list,
+            ..
+        } => {
+            let result = match name {
+                _ if list => repo.config_list().map(|_| true),
+                Some(name) => {
+                    let action = match value {
+                        Some(value) if add => ConfigAction::Add(value),
+                        Some(value) => ConfigAction::Set(value),
+                        None if get_all => ConfigAction::GetAll,
+                        None if unset => ConfigAction::Unset,
+                        None if unset_all => ConfigAction::UnsetAll,
+                        None => ConfigAction::Get,
+                    };
+                    repo.config_command(&name, action, value_type)
+                }
+                None => unreachable!("clap requires a name"),
+            };
+            match result {
+                Ok(true) => (),
+                Ok(false) => trace2::exit(1),
+                Err(e) => return Err(e.context("Failed to configure")),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// List branches as `mg branch` does. In color, the current branch is
+/// green and the remote-tracking ones red.
+fn print_branches(repo: &Repository, branches: &[BranchListing]) -> Result<()> {
+    let color = repo.use_color("branch")?;
+    for branch in branches {
+        let name = match (branch.current, branch.remote) {
+            (true, _) => paint(color.then_some(GREEN), &branch.name),
+            (_, true) => paint(color.then_some(RED), &branch.name),
+            _ => branch.name.clone(),
+        };
+        let marker = if branch.current { '*' } else { ' ' };
+        match &branch.target {
+            Some(target) => println!("{} {} -> {}", marker, name, target),
+            None => println!("{} {}", marker, name),
+        }
+    }
+    Ok(())
+}
+
+/// Print `diffs` as unified diffs, with git's colors if `color`.
+fn print_diffs(diffs: &[FileDiff], color: bool) {
+    for file_diff in diffs {
+        let diff = file_diff.unified(3);
+        if color {
+            print!("{}", color_diff(&diff));
+        } else {
+            print!("{}", diff);
+        }
+    }
+}
+
+/// Print a commit with its author, date and message, followed by its
+/// changes from its first parent, or another object as is.
+fn print_shown(shown: Shown) -> Result<()> {
+    let (hash, commit, (name, email), diffs) = match shown {
+        Shown::Commit {
+            hash,
+            commit,
+            author,
+            diffs,
+        } => (hash, commit, author, diffs),
+        Shown::File(content) => return Ok(std::io::stdout().write_all(&content)?),
+        Shown::Object(text) => {
+            println!("{}", text);
+            return Ok(());
+        }
+    };
+
+    println!("commit {}", hex::encode(hash));
+    if commit.parents.len() > 1 {
+        let parents: Vec<String> = commit
+            .parents
+            .iter()
+            .map(|parent| hex::encode(parent)[..7].to_string())
+            .collect();
+        println!("Merge: {}", parents.join(" "));
+    }
+    println!("Author: {} <{}>", name, email);
+    println!(
+        "Date:   {}",
+        format_date(commit.author.timestamp, &commit.author.timezone)
+    );
+    println!();
+    for line in commit.message.lines() {
+        println!("    {}", line);
     }
+    println!();
+    print_diffs(&diffs, false);
 
     Ok(())
 }
@@ -0,0 +1,77 @@
+use std::collections::{HashSet, VecDeque};
+
+use anyhow::Result;
+
+use crate::repository::Repository;
+
+impl Repository {
+    /// Every commit reachable from `commit`, itself included.
+    pub fn ancestors(&self, commit: &[u8; 20]) -> Result<HashSet<[u8; 20]>> {
+        let shallow = self.shallow_commits()?;
+        let mut seen = HashSet::new();
+        let mut queue = vec![*commit];
+
+        while let Some(commit) = queue.pop() {
+            if !seen.insert(commit) || shallow.contains(&commit) {
+                continue;
+            }
+            queue.extend(self.commit_parents(&commit)?);
+        }
+
+        Ok(seen)
+    }
+
+    /// The best common ancestors of two commits: the common ancestors which
+    /// are not themselves ancestors of another common ancestor.
+    pub fn merge_bases(&self, a: &[u8; 20], b: &[u8; 20]) -> Result<Vec<[u8; 20]>> {
+        let shallow = self.shallow_commits()?;
+        let a_ancestors = self.ancestors(a)?;
+
+        // walk from `b` breadth first, stopping at the first common commits of
+        // each line of history
+        let mut candidates = Vec::new();
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([*b]);
+        while let Some(commit) = queue.pop_front() {
+            if !seen.insert(commit) {
+                continue;
+            }
+            if a_ancestors.contains(&commit) {
+                candidates.push(commit);
+                continue;
+            }
+            if !shallow.contains(&commit) {
+                queue.extend(self.commit_parents(&commit)?);
+            }
+        }
+
+        let mut bases = Vec::new();
+        for candidate in &candidates {
+            let mut redundant = false;
+            for other in &candidates {
+                if other != candidate && self.is_ancestor(candidate, other)? {
+                    redundant = true;
+                    break;
+                }
+            }
+            if !redundant {
+                bases.push(*candidate);
+            }
+        }
+
+        Ok(bases)
+    }
+
+    /// Resolve two revisions and print their best common ancestor, or all of
+    /// them with `all`.
+    pub fn merge_base(&self, a: &str, b: &str, all: bool) -> Result<bool> {
+        let bases = self.merge_bases(&self.resolve_revision(a)?, &self.resolve_revision(b)?)?;
+
+        let count = if all { bases.len() } else { 1 };
+        for base in bases.iter().take(count) {
+            println!("{}", hex::encode(base));
+        }
+
+        Ok(!bases.is_empty())
+    }
+}
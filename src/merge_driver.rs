@@ -0,0 +1,243 @@
+use std::{
+    fs,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    attributes::{AttrState, Attributes},
+    diff::{diff, Edit},
+    repository::Repository,
+};
+
+/// The length of conflict markers unless `conflict-marker-size` says
+/// otherwise.
+const DEFAULT_MARKER_SIZE: usize = 7;
+
+/// For each line of `base`, the line of `side` it was kept as, if any.
+fn kept_lines(base: &[&str], side: &[&str]) -> Vec<Option<usize>> {
+    let mut kept = vec![None; base.len()];
+    for edit in diff(base, side) {
+        if let Edit::Equal(old, new) = edit {
+            kept[old] = Some(new);
+        }
+    }
+    kept
+}
+
+/// Merge the lines of `ours` and `theirs`, both changed from `base`. Where
+/// both sides changed the same lines, the lines of both are kept, ours
+/// first, as the `union` driver does.
+pub fn union_merge<'a>(base: &[&'a str], ours: &[&'a str], theirs: &[&'a str]) -> Vec<&'a str> {
+    let kept_ours = kept_lines(base, ours);
+    let kept_theirs = kept_lines(base, theirs);
+    let mut merged = Vec::new();
+    let (mut b, mut o, mut t) = (0, 0, 0);
+
+    loop {
+        // the next base line both sides kept, past what was merged
+        let stable = (b..base.len()).find(|&i| {
+            kept_ours[i].is_some_and(|j| j >= o) && kept_theirs[i].is_some_and(|k| k >= t)
+        });
+        let (base_end, ours_end, theirs_end) = match stable {
+            Some(i) => (i, kept_ours[i].unwrap_or(o), kept_theirs[i].unwrap_or(t)),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+
+        let (base_chunk, ours_chunk, theirs_chunk) = (
+            &base[b..base_end],
+            &ours[o..ours_end],
+            &theirs[t..theirs_end],
+        );
+        if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            merged.extend_from_slice(theirs_chunk);
+        } else if theirs_chunk == base_chunk {
+            merged.extend_from_slice(ours_chunk);
+        } else {
+            merged.extend_from_slice(ours_chunk);
+            merged.extend_from_slice(theirs_chunk);
+        }
+
+        let Some(i) = stable else {
+            return merged;
+        };
+        merged.push(base[i]);
+        (b, o, t) = (i + 1, ours_end + 1, theirs_end + 1);
+    }
+}
+
+/// Quote `value` for the shell.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// The command of a merge driver with its placeholders expanded: `%O`,
+/// `%A` and `%B` for the files of the base, ours and theirs, `%L` for the
+/// size of conflict markers and `%P` for the path being merged.
+fn expand_driver_command(
+    command: &str,
+    files: [&str; 3],
+    marker_size: usize,
+    path: &str,
+) -> String {
+    let mut expanded = String::new();
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('O') => expanded.push_str(&shell_quote(files[0])),
+            Some('A') => expanded.push_str(&shell_quote(files[1])),
+            Some('B') => expanded.push_str(&shell_quote(files[2])),
+            Some('L') => expanded.push_str(&marker_size.to_string()),
+            Some('P') => expanded.push_str(&shell_quote(path)),
+            Some(other) => {
+                if other != '%' {
+                    expanded.push('%');
+                }
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+impl Repository {
+    /// Merge the blobs `base`, `ours` and `theirs` of `path` with its merge
+    /// driver: the `merge` attribute, `merge.default` or `text`. Returns
+    /// `None` on a conflict.
+    pub fn merge_file(
+        &self,
+        attributes: &mut Attributes,
+        path: &str,
+        base: &[u8; 20],
+        ours: &[u8; 20],
+        theirs: &[u8; 20],
+    ) -> Result<Option<Vec<u8>>> {
+        let config = self.config()?;
+        let driver = match attributes.merge_driver(path)? {
+            Some(driver) => driver,
+            None => config
+                .get("merge.default")
+                .unwrap_or_else(|| "text".to_string()),
+        };
+
+        match driver.as_str() {
+            "text" => self.merge_blob(base, theirs, ours),
+            "binary" => Ok(None),
+            "union" => {
+                let read = |hash: &[u8; 20]| -> Result<String> {
+                    let content = self.read_object(&hex::encode(hash))?.content()?;
+                    Ok(String::from_utf8_lossy(&content).into_owned())
+                };
+                let (base, ours, theirs) = (read(base)?, read(ours)?, read(theirs)?);
+                let base_lines: Vec<&str> = base.lines().collect();
+                let our_lines: Vec<&str> = ours.lines().collect();
+                let their_lines: Vec<&str> = theirs.lines().collect();
+                let merged = union_merge(&base_lines, &our_lines, &their_lines);
+
+                let mut content = merged.join("\n");
+                if !merged.is_empty() {
+                    content.push('\n');
+                }
+                Ok(Some(content.into_bytes()))
+            }
+            name => match config.get(&format!("merge.{}.driver", name)) {
+                Some(command) => {
+                    let marker_size = match attributes.value(path, "conflict-marker-size")? {
+                        AttrState::Value(size) => size.parse().unwrap_or(DEFAULT_MARKER_SIZE),
+                        _ => DEFAULT_MARKER_SIZE,
+                    };
+                    self.run_merge_driver(name, &command, path, marker_size, [base, ours, theirs])
+                }
+                // like git, an undefined driver merges as text
+                None => self.merge_blob(base, theirs, ours),
+            },
+        }
+    }
+
+    /// Run the merge driver `command` on temporary files holding `blobs`
+    /// (base, ours and theirs). The driver leaves the result in the file of
+    /// ours, and exits non-zero when it conflicts.
+    fn run_merge_driver(
+        &self,
+        name: &str,
+        command: &str,
+        path: &str,
+        marker_size: usize,
+        blobs: [&[u8; 20]; 3],
+    ) -> Result<Option<Vec<u8>>> {
+        let git_dir = self.git_dir.clone();
+        let mut files = Vec::new();
+        for (blob, side) in blobs.iter().zip(["base", "ours", "theirs"]) {
+            let file = git_dir.join(format!(".merge_file_{}_{}", std::process::id(), side));
+            fs::write(&file, self.read_object(&hex::encode(blob))?.content()?)?;
+            files.push(file);
+        }
+        let names: Vec<String> = files
+            .iter()
+            .map(|file| file.to_string_lossy().into_owned())
+            .collect();
+        let expanded = expand_driver_command(
+            command,
+            [&names[0], &names[1], &names[2]],
+            marker_size,
+            path,
+        );
+
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&expanded)
+            .current_dir(&self.path)
+            .stdin(Stdio::null())
+            .status();
+        let merged = fs::read(&files[1]);
+        for file in &files {
+            fs::remove_file(file)?;
+        }
+
+        let status = status.map_err(|e| anyhow!("could not run merge driver '{}': {}", name, e))?;
+        match status.success() {
+            true => Ok(Some(merged?)),
+            false => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_union_merge() {
+        let base = ["# Changes", "", "old"];
+        let ours = ["# Changes", "", "ours", "old"];
+        let theirs = ["# Changes", "", "theirs", "old"];
+        assert_eq!(
+            union_merge(&base, &ours, &theirs),
+            ["# Changes", "", "ours", "theirs", "old"]
+        );
+        let theirs = ["# Changes", "", "old", "appended"];
+        assert_eq!(
+            union_merge(&base, &ours, &theirs),
+            ["# Changes", "", "ours", "old", "appended"]
+        );
+    }
+
+    #[test]
+    fn test_expand_driver_command() {
+        assert_eq!(
+            expand_driver_command(
+                "merge-lock %O %A %B -L%L %P 100%%",
+                ["o", "a", "b"],
+                7,
+                "it's"
+            ),
+            "merge-lock 'o' 'a' 'b' -L7 'it'\\''s' 100%"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::{fs::Metadata, path::Path};
+
+use anyhow::Result;
+
+use crate::repository::Repository;
+
+/// The stat data of a file, as the index records it. Platforms without some
+/// of the fields leave them 0, so that they always compare equal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatData {
+    pub ctime_s: u32,
+    pub ctime_n: u32,
+    pub mtime_s: u32,
+    pub mtime_n: u32,
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+}
+
+#[cfg(unix)]
+pub fn stat_data(metadata: &Metadata) -> StatData {
+    use std::os::unix::fs::MetadataExt;
+
+    StatData {
+        ctime_s: metadata.ctime() as u32,
+        ctime_n: metadata.ctime_nsec() as u32,
+        mtime_s: metadata.mtime() as u32,
+        mtime_n: metadata.mtime_nsec() as u32,
+        dev: metadata.dev() as u32,
+        ino: metadata.ino() as u32,
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        size: metadata.size() as u32,
+    }
+}
+
+/// Without a change time, the creation time stands for it; the mode only
+/// tells the type of the file.
+#[cfg(not(unix))]
+pub fn stat_data(metadata: &Metadata) -> StatData {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let since_epoch = |time: std::io::Result<SystemTime>| {
+        time.ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default()
+    };
+    let modified = since_epoch(metadata.modified());
+    let created = since_epoch(metadata.created());
+    let mode = if metadata.file_type().is_symlink() {
+        0o120000
+    } else if metadata.is_dir() {
+        0o040000
+    } else {
+        0o100644
+    };
+
+    StatData {
+        ctime_s: created.as_secs() as u32,
+        ctime_n: created.subsec_nanos(),
+        mtime_s: modified.as_secs() as u32,
+        mtime_n: modified.subsec_nanos(),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        size: metadata.len() as u32,
+    }
+}
+
+/// Whether the file `metadata` describes has an executable bit set.
+#[cfg(unix)]
+pub fn is_executable(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+pub fn is_executable(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Whether the worktree file at `path` is executable. Without a trusted
+/// executable bit, it is emulated: the file keeps the mode it has in the
+/// index, `index_mode`, if any.
+pub fn is_worktree_executable(
+    path: &Path,
+    trust_executable_bit: bool,
+    index_mode: Option<u32>,
+) -> Result<bool> {
+    if trust_executable_bit {
+        Ok(is_executable(&path.metadata()?))
+    } else {
+        Ok(index_mode.is_some_and(|mode| mode & 0o100 != 0))
+    }
+}
+
+impl Repository {
+    /// Whether the executable bit of worktree files is meaningful:
+    /// `core.fileMode`, true by default where files have one.
+    pub fn trust_executable_bit(&self) -> Result<bool> {
+        Ok(self
+            .config()?
+            .get_bool("core.filemode")
+            .unwrap_or(cfg!(unix)))
+    }
+}
@@ -0,0 +1,95 @@
+use std::{fs::File, ops::Deref, path::Path};
+
+use anyhow::{anyhow, Result};
+
+/// A file mapped read-only into memory, read as a byte slice without being
+/// copied. The file is expected not to change while it is mapped, which
+/// holds for the index, replaced by rename, and for pack files, which are
+/// never written in place.
+pub struct Mmap {
+    #[cfg(unix)]
+    ptr: *mut libc::c_void,
+    #[cfg(unix)]
+    len: usize,
+    /// Without mmap, the content read upfront
+    #[cfg(not(unix))]
+    data: Vec<u8>,
+}
+
+// the mapping is read-only and owned by this value alone
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        Mmap::map(&file).map_err(|e| anyhow!("cannot map {}: {}", path.display(), e))
+    }
+
+    #[cfg(unix)]
+    pub fn map(file: &File) -> Result<Self> {
+        use std::{os::unix::io::AsRawFd, ptr};
+
+        let len = usize::try_from(file.metadata()?.len())?;
+        // an empty mapping is invalid, and an empty file needs none
+        if len == 0 {
+            return Ok(Mmap {
+                ptr: ptr::null_mut(),
+                len,
+            });
+        }
+
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(Mmap { ptr, len })
+    }
+
+    #[cfg(not(unix))]
+    pub fn map(mut file: &File) -> Result<Self> {
+        use std::io::Read;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Ok(Mmap { data })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    #[cfg(unix)]
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    #[cfg(not(unix))]
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
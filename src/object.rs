@@ -1,20 +1,33 @@
+use crate::object_cache::CacheKey;
+use crate::object_header::{oid_for, ObjectHeader};
+use crate::pack::HashWriter;
 use crate::repository::Repository;
-use crate::{error::RuntimeError, kind::Kind};
+use crate::{error::MgError, kind::Kind, trace2};
 use anyhow::{anyhow, Context, Result};
 use flate2::{write::ZlibEncoder, Compression};
+use hex::FromHex;
 
 use sha1::{Digest, Sha1};
+
 use std::io::Write;
 use std::{
-    fs::{create_dir, File},
-    io::BufRead,
+    fs::{create_dir, remove_file, rename, File},
+    io::{self, BufRead, Cursor, Read},
     path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
+/// Numbers the temporary files of the objects being written, which
+/// threads of a process may write at the same time.
+static TEMP_OBJECTS: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug)]
 pub struct Object<Reader> {
     kind: Kind,
-    _size: usize,
+    size: usize,
     data: Reader,
 }
 
@@ -26,14 +39,104 @@ pub struct TreeObject {
     pub hash: [u8; 20],
 }
 
+/// The entries of a tree object, parsed one at a time as they are read.
+/// Iteration stops after the first error.
+pub struct TreeIter<R> {
+    data: R,
+    done: bool,
+}
+
+impl<R: BufRead> TreeIter<R> {
+    fn read_entry(&mut self) -> Result<Option<TreeObject>> {
+        let mut mode_name = Vec::new();
+        if self.data.read_until(0, &mut mode_name)? == 0 {
+            return Ok(None);
+        }
+        if mode_name.pop() != Some(0) {
+            return Err(anyhow!("truncated tree entry"));
+        }
+
+        let mut splits = mode_name.splitn(2, |&b| b == b' ');
+        let mode = splits
+            .next()
+            .ok_or_else(|| anyhow!("could not parse mode"))?;
+        let mode = std::str::from_utf8(mode)?;
+        let name = splits
+            .next()
+            .ok_or_else(|| anyhow!("could not parse name"))?;
+        let name = std::str::from_utf8(name)?;
+
+        let mut hash = [0; 20];
+        self.data.read_exact(&mut hash)?;
+
+        Ok(Some(TreeObject {
+            name: name.to_string(),
+            kind: Kind::from_mode(mode)?,
+            mode: mode.to_string(),
+            hash,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for TreeIter<R> {
+    type Item = Result<TreeObject>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.read_entry().transpose();
+        self.done = !matches!(entry, Some(Ok(_)));
+        entry
+    }
+}
+
+/// The entries of a tree as `cat-file -p` shows them, sorted by name.
+fn format_tree_entries(mut entries: Vec<TreeObject>) -> String {
+    let max_name_len = entries.iter().map(|e| e.name.len()).max().unwrap_or(0);
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{:0>6} {} {}    {:name_len$}",
+                entry.mode,
+                entry.kind,
+                hex::encode(entry.hash),
+                entry.name,
+                name_len = max_name_len
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
 impl Repository {
+    /// Read an object, loose or packed.
     pub fn read_object(&self, object: &str) -> Result<Object<impl BufRead>> {
-        let object_path = self
-            .path
-            .join(".git")
-            .join("objects")
-            .join(&object[..2])
-            .join(&object[2..]);
+        let _timer = trace2::timer("object", "read");
+        let replacement = self.replacement_object(object)?;
+        let object = replacement.as_deref().unwrap_or(object);
+
+        let object_path = self.loose_object_path(object)?;
+        if !object_path.exists() {
+            if let Some(packed) = self.read_packed(object)? {
+                return Ok(packed);
+            }
+            // in a partial clone, objects left out by the filter are fetched on
+            // demand, loose or packed
+            if self.promisor_remote()?.is_none() {
+                return Err(MgError::ObjectNotFound(object.to_string()).into());
+            }
+            self.fetch_missing_objects(&[object.to_string()])?;
+            if !object_path.exists() {
+                if let Some(packed) = self.read_packed(object)? {
+                    return Ok(packed);
+                }
+                return Err(MgError::ObjectNotFound(object.to_string()).into());
+            }
+        }
 
         let fd = File::open(&object_path).context("opening the object")?;
         let zfd = flate2::read::ZlibDecoder::new(fd);
@@ -46,29 +149,63 @@ impl Repository {
 
         match buf.pop() {
             Some(0) => {}
-            Some(_) | None => return Err(RuntimeError::UnexpectedChar.into()),
+            Some(_) | None => {
+                return Err(MgError::CorruptObject {
+                    object: object.to_string(),
+                    reason: "no object header".to_string(),
+                }
+                .into())
+            }
         };
 
-        let header = String::from_utf8(buf.clone()).context("converting header to utf-8")?;
+        let header = ObjectHeader::decode(&buf).map_err(|e| MgError::CorruptObject {
+            object: object.to_string(),
+            reason: e.to_string(),
+        })?;
 
-        let Some((object_type, object_size)) = header.split_once(' ') else {
-            anyhow::bail!("could not parse object header correctly");
+        Ok(Object {
+            kind: header.kind,
+            size: header.size,
+            data: Box::new(buf_reader) as Box<dyn BufRead>,
+        })
+    }
+
+    fn read_packed(&self, object: &str) -> Result<Option<Object<Box<dyn BufRead>>>> {
+        let Ok(hash) = <[u8; 20]>::from_hex(object) else {
+            return Ok(None);
         };
 
-        let object_type = match object_type {
-            "blob" => Kind::Blob(true),
-            "commit" => Kind::Commit,
-            "tree" => Kind::Tree,
-            _ => anyhow::bail!("invalid object type found"),
+        let key = CacheKey::Object(hash);
+        let (kind, content) = match self.object_cache.get(&key) {
+            Some(cached) => cached,
+            None => {
+                let Some((kind, content)) = self.read_packed_object(&hash)? else {
+                    return Ok(None);
+                };
+                let content: Arc<[u8]> = content.into();
+                self.object_cache.insert(key, kind.clone(), content.clone());
+                (kind, content)
+            }
         };
 
-        let object_size = object_size.parse::<usize>()?;
+        Ok(Some(Object {
+            kind,
+            size: content.len(),
+            data: Box::new(Cursor::new(content)) as Box<dyn BufRead>,
+        }))
+    }
+
+    /// Read the tree `hash`, to iterate over its entries.
+    pub fn read_tree(&self, hash: &[u8; 20]) -> Result<TreeIter<impl BufRead>> {
+        self.read_object(&hex::encode(hash))?.into_tree_iter()
+    }
 
-        Ok(Object {
-            kind: object_type,
-            _size: object_size,
-            data: buf_reader,
-        })
+    pub fn has_object(&self, hash: &[u8; 20]) -> bool {
+        self.loose_object_path(&hex::encode(hash))
+            .is_ok_and(|path| path.exists())
+            || self
+                .find_packed_object(hash)
+                .is_ok_and(|found| found.is_some())
     }
 
     pub fn write_blob(&self, file: &Path) -> Result<[u8; 20]> {
@@ -76,51 +213,102 @@ impl Repository {
             return Err(anyhow!("path does not exist"));
         }
 
+        let relative = file.strip_prefix(&self.path).unwrap_or(file);
+        let relative = relative.to_string_lossy();
+        let mut attributes = self.attributes()?;
+        if !self.converts_to_git(&mut attributes, &relative)? {
+            let size = file.metadata()?.len();
+            return self.stream_object(Kind::Blob(false), size, File::open(file)?);
+        }
+
         let content = std::fs::read(file)?;
+        let content = self.convert_to_git(&mut attributes, &relative, content)?;
 
         self.write_object(Kind::Blob(false), &content)
     }
 
-    pub fn write_object(&self, kind: Kind, content: &[u8]) -> Result<[u8; 20]> {
-        let mut hasher = Sha1::new();
-        hasher.update(format!("{} {}\0", kind, content.len()).as_bytes());
-        hasher.update(content);
-        let hash = hasher.finalize().into();
-        let hash_str = hex::encode(hash);
+    /// Write an object of `kind` from the `size` bytes `reader` gives,
+    /// hashing and compressing them in chunks rather than in memory. The
+    /// object goes to a temporary file, moved in place once its id is known.
+    pub fn stream_object(&self, kind: Kind, size: u64, reader: impl Read) -> Result<[u8; 20]> {
+        let _timer = trace2::timer("object", "write");
+        let objects_dir = self.git_dir.join("objects");
+        let temp = objects_dir.join(format!(
+            "tmp_obj_{}_{}",
+            std::process::id(),
+            TEMP_OBJECTS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut out = HashWriter {
+            inner: ZlibEncoder::new(File::create(&temp)?, Compression::default()),
+            hasher: Sha1::new(),
+        };
 
-        let target_dir = self.path.join(".git").join("objects").join(&hash_str[..2]);
-        if !target_dir.exists() {
-            create_dir(&target_dir).context("could not create directory in .git/objects")?;
+        let written = out
+            .write_all(&ObjectHeader::new(kind, size as usize).encode())
+            .and_then(|_| io::copy(&mut reader.take(size), &mut out));
+        let finished = written.and_then(|written| Ok((written, out.inner.finish()?)));
+        let hash: [u8; 20] = out.hasher.finalize().into();
+        let result = match finished {
+            Ok((written, _)) if written != size => {
+                Err(anyhow!("the file changed while being read"))
+            }
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            let _ = remove_file(&temp);
+            return Err(e);
         }
 
-        let target_file = target_dir.join(&hash_str[2..]);
-        if target_file.exists() {
+        // objects borrowed from an alternate are not duplicated
+        if self.has_object(&hash) {
+            remove_file(&temp)?;
             return Ok(hash);
         }
+        let hash_str = hex::encode(hash);
+        let target_dir = objects_dir.join(&hash_str[..2]);
+        if !target_dir.exists() {
+            // another thread writing to the same directory may create it first
+            match create_dir(&target_dir) {
+                Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                    return Err(e).context("could not create directory in .git/objects")
+                }
+                _ => self.adjust_shared_perm(&target_dir)?,
+            }
+        }
+        let target_file = target_dir.join(&hash_str[2..]);
+        rename(&temp, &target_file)?;
+        self.adjust_shared_perm(&target_file)?;
 
-        let file_out_fd = File::create(target_file).context("could not open target file")?;
+        Ok(hash)
+    }
 
-        let mut zlib_out = ZlibEncoder::new(file_out_fd, Compression::default());
-        write!(zlib_out, "{} {}\0", kind, content.len()).context("could not write header")?;
-        zlib_out.write_all(content)?;
-        zlib_out
-            .finish()
-            .context("could not compress or write file")?;
+    pub fn write_object(&self, kind: Kind, content: &[u8]) -> Result<[u8; 20]> {
+        let hash = oid_for(&kind, content);
+        // objects borrowed from an alternate are not duplicated
+        if self.has_object(&hash) {
+            return Ok(hash);
+        }
 
-        Ok(hash)
+        self.stream_object(kind, content.len() as u64, content)
     }
 }
 
+/// The blob id of the content of `file`, read in chunks.
 pub fn hash_object(file: &Path) -> Result<[u8; 20]> {
-    let content = std::fs::read(file)?;
-
-    let kind = Kind::Blob(false);
+    let size = file.metadata()?.len();
     let mut hasher = Sha1::new();
-    hasher.update(format!("{} {}\0", kind, content.len()).as_bytes());
-    hasher.update(content);
-    let hash = hasher.finalize().into();
+    hasher.update(ObjectHeader::new(Kind::Blob(false), size as usize).encode());
+    let read = io::copy(&mut File::open(file)?.take(size), &mut hasher)?;
+    if read != size {
+        return Err(anyhow!("{} changed while being read", file.display()));
+    }
+
+    Ok(hasher.finalize().into())
+}
 
-    Ok(hash)
+pub fn hash_blob(content: &[u8]) -> [u8; 20] {
+    oid_for(&Kind::Blob(false), content)
 }
 
 fn is_path_in_repo(repo_path: &Path, file_path: &Path) -> Result<bool> {
@@ -136,69 +324,63 @@ fn is_path_in_repo(repo_path: &Path, file_path: &Path) -> Result<bool> {
 }
 
 impl<R: BufRead> Object<R> {
+    pub fn kind(&self) -> &Kind {
+        &self.kind
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Read the raw object content, without the header.
+    pub fn content(&mut self) -> Result<Vec<u8>> {
+        let mut buf: Vec<u8> = Vec::with_capacity(self.size);
+        self.data.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Copy the raw object content to `out` as it is read, returning its
+    /// size.
+    pub fn copy_content<W: Write>(&mut self, out: &mut W) -> Result<u64> {
+        Ok(io::copy(&mut self.data, out)?)
+    }
+
+    /// Iterate over the entries of a tree object as they are read.
+    pub fn tree_iter(&mut self) -> Result<TreeIter<&mut R>> {
+        if !matches!(self.kind, Kind::Tree) {
+            return Err(anyhow!("not a tree object: a {}", self.kind));
+        }
+        Ok(TreeIter {
+            data: &mut self.data,
+            done: false,
+        })
+    }
+
+    /// Iterate over the entries of a tree object, consuming it.
+    pub fn into_tree_iter(self) -> Result<TreeIter<R>> {
+        if !matches!(self.kind, Kind::Tree) {
+            return Err(anyhow!("not a tree object: a {}", self.kind));
+        }
+        Ok(TreeIter {
+            data: self.data,
+            done: false,
+        })
+    }
+
+    /// Every entry of a tree object.
+    pub fn tree_entries(&mut self) -> Result<Vec<TreeObject>> {
+        self.tree_iter()?.collect()
+    }
+
     pub fn string(&mut self) -> Result<String> {
         let mut buf: Vec<u8> = Vec::new();
-        let mut buf_hash: [u8; 20] = [0; 20];
 
         let res = match self.kind {
-            Kind::Blob(_) | Kind::Commit => {
+            Kind::Blob(_) | Kind::Commit | Kind::Tag => {
                 self.data.read_to_end(&mut buf)?;
                 String::from_utf8(buf)?
             }
-            Kind::Tree => {
-                let mut max_name_len = 0;
-                let mut entries = Vec::new();
-                loop {
-                    let read_bytes_len = self.data.read_until(0, &mut buf)?;
-                    if read_bytes_len == 0 {
-                        break;
-                    }
-
-                    let mode_name = buf.clone();
-                    buf.clear();
-                    let mut splits = mode_name.splitn(2, |&b| b == b' ');
-
-                    let mode = splits
-                        .next()
-                        .ok_or_else(|| anyhow::anyhow!("could not parse mode"))?;
-                    let mode = std::str::from_utf8(mode)?;
-                    let name = splits
-                        .next()
-                        .ok_or_else(|| anyhow::anyhow!("could not parse name"))?;
-                    let name = std::str::from_utf8(name)?;
-
-                    self.data.read_exact(&mut buf_hash)?;
-
-                    if name.len() > max_name_len {
-                        max_name_len = name.len();
-                    }
-
-                    entries.push(TreeObject {
-                        name: name.to_string(),
-                        kind: Kind::from_mode(mode)?,
-                        mode: mode.to_string(),
-                        hash: buf_hash,
-                    });
-                }
-
-                entries.sort_by(|a, b| a.name.cmp(&b.name));
-
-                entries
-                    .iter()
-                    .map(|entry| {
-                        let hash = hex::encode(entry.hash);
-                        format!(
-                            "{:0>6} {} {}    {:name_len$}",
-                            entry.mode,
-                            entry.kind,
-                            hash,
-                            entry.name,
-                            name_len = max_name_len
-                        )
-                    })
-                    .collect::<Vec<String>>()
-                    .join("\n")
-            }
+            Kind::Tree => format_tree_entries(self.tree_entries()?),
             _ => unimplemented!(),
         };
 
@@ -209,7 +391,6 @@ impl<R: BufRead> Object<R> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use hex::FromHex;
     use std::io::Cursor;
 
     #[test]
@@ -217,7 +398,7 @@ mod tests {
         let data = b"hello";
         let _obj = Object {
             kind: Kind::Blob(true),
-            _size: 5,
+            size: 5,
             data: Cursor::new(data),
         };
 
@@ -232,4 +413,120 @@ mod tests {
             <[u8; 20]>::from_hex("b6fc4c620b67d95f953a5c1c1230aaab5db5a1b0").unwrap(),
         );
     }
+
+    #[test]
+    fn test_tree_iter() {
+        let mut data = b"100644 a.txt\0".to_vec();
+        data.extend([1; 20]);
+        data.extend(b"40000 src\0");
+        data.extend([2; 20]);
+        let mut tree = Object {
+            kind: Kind::Tree,
+            size: data.len(),
+            data: Cursor::new(data.clone()),
+        };
+        let entries: Vec<(String, [u8; 20])> = tree
+            .tree_iter()
+            .unwrap()
+            .map(|entry| entry.map(|entry| (entry.name, entry.hash)))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            entries,
+            [("a.txt".to_string(), [1; 20]), ("src".to_string(), [2; 20])]
+        );
+
+        // a truncated entry ends the iteration with an error
+        let mut iter = TreeIter {
+            data: Cursor::new(&data[..40]),
+            done: false,
+        };
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_read_object_fetched_from_promisor() {
+        use crate::repository::InitOptions;
+        use std::{
+            io::{BufReader, Write},
+            net::TcpListener,
+        };
+
+        let base = std::env::temp_dir().join(format!("mg-promisor-read-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let (source_path, path) = (base.join("source"), base.join("partial"));
+        let mut repos = Vec::new();
+        for path in [&source_path, &path] {
+            std::fs::create_dir_all(path).unwrap();
+            let mut repo = Repository::open(path.clone()).unwrap();
+            repo.init_repository(path, &InitOptions::default()).unwrap();
+            repo.progress = Some(false);
+            repos.push(repo);
+        }
+        let (source, repo) = (&repos[0], &repos[1]);
+        let blob = source
+            .write_object(Kind::Blob(false), b"fetched\n")
+            .unwrap();
+        let mut pack = Vec::new();
+        source
+            .write_pack(&[blob], &mut pack, Compression::default())
+            .unwrap();
+
+        // a promisor answering a single fetch with the pack
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/source", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut input = BufReader::new(stream.try_clone().unwrap());
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                input.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_ascii_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            input.read_exact(&mut vec![0; length]).unwrap();
+
+            let mut body = b"000dpackfile\n".to_vec();
+            body.extend(format!("{:04x}", pack.len() + 5).as_bytes());
+            body.push(1);
+            body.extend(&pack);
+            body.extend(b"0000");
+            let mut out = stream;
+            write!(
+                out,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            out.write_all(&body).unwrap();
+        });
+
+        repo.set_config("remote.origin.url", &url).unwrap();
+        repo.set_config("remote.origin.promisor", "true").unwrap();
+        // library callers may read from within a current-thread runtime
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let content = runtime
+            .block_on(async { repo.read_object(&hex::encode(blob))?.content() })
+            .unwrap();
+        assert_eq!(content, b"fetched\n");
+        server.join().unwrap();
+
+        // the pack is kept and marked, not exploded into loose objects
+        assert!(!repo.loose_object_path(&hex::encode(blob)).unwrap().exists());
+        let pack = &repo.pack_paths().unwrap()[0];
+        assert!(pack.with_extension("promisor").exists());
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
 }
@@ -0,0 +1,104 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::kind::Kind;
+
+/// Bytes of object content kept, as git's `core.deltaBaseCacheLimit`
+/// defaults to.
+const CACHE_LIMIT: usize = 96 * 1024 * 1024;
+
+/// What a cached object is found by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CacheKey {
+    /// An object read by its id
+    Object([u8; 20]),
+    /// The entry at an offset of a pack file, with its deltas applied
+    PackEntry(PathBuf, u64),
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<CacheKey, (Kind, Arc<[u8]>, u64)>,
+    /// The keys by the time they were last used
+    used: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    size: usize,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &CacheKey) -> Option<(Kind, Arc<[u8]>)> {
+        self.clock += 1;
+        let clock = self.clock;
+        let (kind, data, used) = self.entries.get_mut(key)?;
+        let key = self.used.remove(used).expect("entries are in use order");
+        *used = clock;
+        let found = (kind.clone(), data.clone());
+        self.used.insert(clock, key);
+        Some(found)
+    }
+}
+
+/// The content of the objects read last, so that reading them again, or
+/// resolving deltas against them, does not inflate them again. Content is
+/// never stale: objects are found by id, and pack files are named after
+/// what they hold. Clones of a cache share it.
+#[derive(Clone, Default)]
+pub struct ObjectCache {
+    lru: Arc<Mutex<Lru>>,
+}
+
+impl ObjectCache {
+    pub fn get(&self, key: &CacheKey) -> Option<(Kind, Arc<[u8]>)> {
+        self.lru.lock().expect("object cache poisoned").touch(key)
+    }
+
+    /// Keep `data`, dropping the objects used least recently to make room.
+    pub fn insert(&self, key: CacheKey, kind: Kind, data: Arc<[u8]>) {
+        // an object filling most of the cache would only push out the rest
+        if data.len() > CACHE_LIMIT / 4 {
+            return;
+        }
+
+        let mut lru = self.lru.lock().expect("object cache poisoned");
+        if lru.touch(&key).is_some() {
+            return;
+        }
+        while lru.size + data.len() > CACHE_LIMIT {
+            let Some((_, oldest)) = lru.used.pop_first() else {
+                break;
+            };
+            if let Some((_, dropped, _)) = lru.entries.remove(&oldest) {
+                lru.size -= dropped.len();
+            }
+        }
+
+        lru.clock += 1;
+        let clock = lru.clock;
+        lru.size += data.len();
+        lru.used.insert(clock, key.clone());
+        lru.entries.insert(key, (kind, data, clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_cache_drops_least_recently_used() {
+        let cache = ObjectCache::default();
+        let chunk: Arc<[u8]> = vec![0; CACHE_LIMIT / 4].into();
+        for id in 0..4u8 {
+            cache.insert(CacheKey::Object([id; 20]), Kind::Blob(false), chunk.clone());
+        }
+        // the first one is used again, so the second one goes
+        assert!(cache.get(&CacheKey::Object([0; 20])).is_some());
+        cache.insert(CacheKey::Object([4; 20]), Kind::Blob(false), chunk);
+        assert!(cache.get(&CacheKey::Object([0; 20])).is_some());
+        assert!(cache.get(&CacheKey::Object([1; 20])).is_none());
+        assert!(cache.get(&CacheKey::Object([4; 20])).is_some());
+    }
+}
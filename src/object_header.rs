@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Result};
+use sha1::{Digest, Sha1};
+
+use crate::kind::Kind;
+
+/// The `<type> <size>` header of an object. Followed by a NUL, it prefixes
+/// the content of loose objects and is hashed with the content to give the
+/// object id.
+#[derive(Debug)]
+pub struct ObjectHeader {
+    pub kind: Kind,
+    pub size: usize,
+}
+
+impl ObjectHeader {
+    pub fn new(kind: Kind, size: usize) -> Self {
+        ObjectHeader { kind, size }
+    }
+
+    /// The object type name, symlinks being stored as blobs.
+    fn type_name(&self) -> &'static str {
+        match self.kind {
+            Kind::Blob(_) | Kind::Symlink => "blob",
+            Kind::Commit => "commit",
+            Kind::Tree => "tree",
+            Kind::Tag => "tag",
+        }
+    }
+
+    /// The header with its terminating NUL.
+    pub fn encode(&self) -> Vec<u8> {
+        format!("{} {}\0", self.type_name(), self.size).into_bytes()
+    }
+
+    /// Parse a header, without its terminating NUL.
+    pub fn decode(header: &[u8]) -> Result<Self> {
+        let header = std::str::from_utf8(header)
+            .map_err(|_| anyhow!("could not parse object header correctly"))?;
+        let Some((object_type, size)) = header.split_once(' ') else {
+            return Err(anyhow!("could not parse object header correctly"));
+        };
+
+        let kind = match object_type {
+            "blob" => Kind::Blob(true),
+            "commit" => Kind::Commit,
+            "tree" => Kind::Tree,
+            "tag" => Kind::Tag,
+            _ => return Err(anyhow!("invalid object type found")),
+        };
+
+        Ok(ObjectHeader {
+            kind,
+            size: size.parse()?,
+        })
+    }
+
+    /// The type number of the object in pack entry headers.
+    pub fn pack_type(&self) -> u8 {
+        match self.kind {
+            Kind::Commit => 1,
+            Kind::Tree => 2,
+            Kind::Blob(_) | Kind::Symlink => 3,
+            Kind::Tag => 4,
+        }
+    }
+}
+
+/// The id of an object of `kind` holding `data`.
+pub fn oid_for(kind: &Kind, data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(ObjectHeader::new(kind.clone(), data.len()).encode());
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_header() {
+        let header = ObjectHeader::new(Kind::Symlink, 12);
+        assert_eq!(header.encode(), b"blob 12\0");
+        assert_eq!(header.pack_type(), 3);
+
+        let header = ObjectHeader::decode(b"commit 250").unwrap();
+        assert!(matches!(header.kind, Kind::Commit));
+        assert_eq!(header.size, 250);
+        assert!(ObjectHeader::decode(b"chunk 3").is_err());
+
+        // the id of the empty blob
+        assert_eq!(
+            hex::encode(oid_for(&Kind::Blob(false), b"")),
+            "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391"
+        );
+    }
+}
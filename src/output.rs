@@ -0,0 +1,34 @@
+use anyhow::{anyhow, Result};
+
+use crate::{commit::Signature, graph_export::json_string};
+
+/// How commands print the data they produce: as text for people, or as
+/// JSON, one object per line, for scripts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(anyhow!("unknown output format '{}'", value)),
+        }
+    }
+}
+
+/// A signature as an object, its name and email as given, for mailmaps to
+/// apply, and its time in seconds since the epoch.
+pub fn signature_json(name: &str, email: &str, signature: &Signature) -> String {
+    format!(
+        "{{\"name\":{},\"email\":{},\"time\":{},\"timezone\":{}}}",
+        json_string(name),
+        json_string(email),
+        signature.timestamp,
+        json_string(&signature.timezone)
+    )
+}
@@ -1,14 +1,38 @@
 use std::{
-    fs::File,
-    io::{BufReader, Cursor, Read, Seek, SeekFrom},
-    path::Path,
+    fs::{create_dir_all, remove_file, rename, File, OpenOptions},
+    io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 use anyhow::Error;
-use flate2::read::ZlibDecoder;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use hex::FromHex;
 use sha1::{Digest, Sha1};
 
-use crate::repository::Repository;
+use crate::{
+    error::MgError,
+    graph_export::json_string,
+    kind::Kind,
+    mmap::Mmap,
+    object_cache::{CacheKey, ObjectCache},
+    object_header::{oid_for, ObjectHeader},
+    output::OutputFormat,
+    progress::Progress,
+    repository::Repository,
+    spill::{SpillBuffer, SpillRecord},
+};
+
+/// Numbers the temporary packs, as a process may receive several at once.
+static TEMP_PACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// A pack or pack index not in the format expected.
+fn format_error(message: impl Into<String>) -> Error {
+    MgError::PackFormat(message.into()).into()
+}
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -22,13 +46,13 @@ struct PackHeader {
 #[allow(dead_code)]
 struct PackObject {
     object_type: PackObjectType,
-    object_size: u32,
+    object_size: u64,
     object_data: Vec<u8>,
     pos: u64,
     end_pos: u64,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum PackObjectType {
     Commit,
     Tree,
@@ -47,7 +71,30 @@ impl PackObjectType {
             4 => Ok(PackObjectType::Tag),
             6 => Ok(PackObjectType::OfsDelta),
             7 => Ok(PackObjectType::RefDelta),
-            _ => Err(Error::msg("Unknown object type")),
+            _ => Err(format_error("Unknown object type")),
+        }
+    }
+
+    /// The kind of the objects of this type, deltas having none.
+    fn from_kind(kind: &Kind) -> Self {
+        match kind {
+            Kind::Commit => PackObjectType::Commit,
+            Kind::Tree => PackObjectType::Tree,
+            Kind::Tag => PackObjectType::Tag,
+            Kind::Blob(_) | Kind::Symlink => PackObjectType::Blob,
+        }
+    }
+
+    fn kind(&self) -> Result<Kind, Error> {
+        match self {
+            PackObjectType::Commit => Ok(Kind::Commit),
+            PackObjectType::Tree => Ok(Kind::Tree),
+            PackObjectType::Blob => Ok(Kind::Blob(false)),
+            PackObjectType::Tag => Ok(Kind::Tag),
+            object_type => Err(format_error(format!(
+                "unsupported object type in pack: {}",
+                object_type
+            ))),
         }
     }
 }
@@ -67,18 +114,175 @@ impl std::fmt::Display for PackObjectType {
     }
 }
 
-fn parse_pack_header(file: &mut File) -> Result<PackHeader, Error> {
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PackIndexEntry {
+    pub hash: [u8; 20],
+    pub crc32: u32,
+    pub offset: u64,
+}
+
+impl SpillRecord for PackIndexEntry {
+    const SIZE: usize = 32;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.hash);
+        out.extend_from_slice(&self.crc32.to_be_bytes());
+        out.extend_from_slice(&self.offset.to_be_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        PackIndexEntry {
+            hash: bytes[..20].try_into().expect("20 bytes"),
+            crc32: u32::from_be_bytes(bytes[20..24].try_into().expect("4 bytes")),
+            offset: u64::from_be_bytes(bytes[24..32].try_into().expect("8 bytes")),
+        }
+    }
+}
+
+/// Hashes everything written through it, for trailing checksums.
+pub struct HashWriter<W: Write> {
+    pub inner: W,
+    pub hasher: Sha1,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encode the variable-length type and size header of a pack entry.
+pub fn encode_pack_entry_header(object_type: u8, size: u64) -> Vec<u8> {
+    let mut header = Vec::new();
+    let mut byte = (object_type << 4) | (size & 0x0f) as u8;
+    let mut size = size >> 4;
+
+    while size != 0 {
+        header.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    header.push(byte);
+
+    header
+}
+
+/// Write a version 2 pack index for the given entries and return its checksum.
+pub fn write_pack_index(
+    path: &Path,
+    entries: &mut [PackIndexEntry],
+    pack_checksum: &[u8; 20],
+) -> Result<[u8; 20], Error> {
+    entries.sort_by_key(|e| e.hash);
+    write_sorted_pack_index(path, || Ok(entries.iter().cloned().map(Ok)), pack_checksum)
+}
+
+/// Write a version 2 pack index from entries sorted by hash. `entries` is
+/// called once per table of the index, so the entries never need to be held
+/// in memory all at once.
+fn write_sorted_pack_index<F, I>(
+    path: &Path,
+    mut entries: F,
+    pack_checksum: &[u8; 20],
+) -> Result<[u8; 20], Error>
+where
+    F: FnMut() -> Result<I, Error>,
+    I: Iterator<Item = Result<PackIndexEntry, Error>>,
+{
+    let mut out = HashWriter {
+        inner: BufWriter::new(File::create(path)?),
+        hasher: Sha1::new(),
+    };
+    out.write_all(&[0xff, b't', b'O', b'c'])?;
+    out.write_all(&2u32.to_be_bytes())?;
+
+    let mut fanout = [0u32; 256];
+    for entry in entries()? {
+        fanout[entry?.hash[0] as usize] += 1;
+    }
+    let mut total = 0;
+    for count in fanout.iter_mut() {
+        total += *count;
+        *count = total;
+    }
+    for count in fanout {
+        out.write_all(&count.to_be_bytes())?;
+    }
+
+    for entry in entries()? {
+        out.write_all(&entry?.hash)?;
+    }
+    for entry in entries()? {
+        out.write_all(&entry?.crc32.to_be_bytes())?;
+    }
+
+    // offsets which do not fit in 31 bits go to the large offset table
+    let mut large_offsets = Vec::new();
+    for entry in entries()? {
+        let offset = entry?.offset;
+        if offset < 0x8000_0000 {
+            out.write_all(&(offset as u32).to_be_bytes())?;
+        } else {
+            let large_index = 0x8000_0000 | large_offsets.len() as u32;
+            out.write_all(&large_index.to_be_bytes())?;
+            large_offsets.push(offset);
+        }
+    }
+    for offset in large_offsets {
+        out.write_all(&offset.to_be_bytes())?;
+    }
+
+    out.write_all(pack_checksum)?;
+    let checksum: [u8; 20] = out.hasher.finalize_reset().into();
+    out.inner.write_all(&checksum)?;
+    out.inner.flush()?;
+    out.inner.get_ref().sync_all()?;
+
+    Ok(checksum)
+}
+
+/// The object ids listed in a version 2 pack index, in order.
+pub fn read_pack_index(path: &Path) -> Result<Vec<[u8; 20]>, Error> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut header = [0; 8];
+    file.read_exact(&mut header)?;
+    if header[..4] != [0xff, b't', b'O', b'c'] || header[4..] != 2u32.to_be_bytes() {
+        return Err(format_error(format!(
+            "{} is not a version 2 pack index",
+            path.display()
+        )));
+    }
+
+    let mut fanout = [0u8; 256 * 4];
+    file.read_exact(&mut fanout)?;
+    let num_objects = u32::from_be_bytes(fanout[255 * 4..].try_into().expect("4 bytes"));
+
+    let mut hashes = vec![[0u8; 20]; num_objects as usize];
+    for hash in hashes.iter_mut() {
+        file.read_exact(hash)?;
+    }
+
+    Ok(hashes)
+}
+
+fn parse_pack_header<R: Read + Seek>(file: &mut R) -> Result<PackHeader, Error> {
     let mut header = [0; 12];
     file.read_exact(&mut header)?;
 
     let signature: &[u8] = &header[0..4];
     if signature != b"PACK" {
-        return Err(Error::msg("Invalid pack file"));
+        return Err(format_error("Invalid pack file"));
     }
 
     let version = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
     if version != 2 {
-        return Err(Error::msg("Invalid pack file version"));
+        return Err(format_error("Invalid pack file version"));
     }
 
     let num_objects = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
@@ -91,16 +295,19 @@ fn parse_pack_header(file: &mut File) -> Result<PackHeader, Error> {
     })
 }
 
-fn read_vli_le<R>(file: &mut BufReader<R>) -> Result<u32, Error>
+fn read_vli_le<R>(file: &mut BufReader<R>) -> Result<u64, Error>
 where
     R: Read,
 {
-    let mut val: u32 = 0;
+    let mut val: u64 = 0;
     let mut shift = 0;
     loop {
         let mut byte = [0; 1];
         file.read_exact(&mut byte)?;
-        let byt = byte[0] as u32;
+        let byt = byte[0] as u64;
+        if shift > 63 {
+            return Err(format_error("delta size overflows 64 bits"));
+        }
 
         val |= (byt & 0x7f) << shift;
         shift += 7;
@@ -113,13 +320,16 @@ where
     Ok(val)
 }
 
-fn read_vli_be(file: &mut File, offset: bool) -> Result<u32, Error> {
-    let mut val: u32 = 0;
+fn read_vli_be<R: Read + Seek>(file: &mut R, offset: bool) -> Result<u64, Error> {
+    let mut val: u64 = 0;
     loop {
         let mut byte = [0; 1];
         file.read_exact(&mut byte)?;
-        let byt = byte[0] as u32;
+        let byt = byte[0] as u64;
 
+        if val >> 57 != 0 {
+            return Err(format_error("delta base offset overflows 64 bits"));
+        }
         val = (val << 7) | (byt & 0x7f);
         if byt & 0x80 == 0 {
             break;
@@ -133,7 +343,7 @@ fn read_vli_be(file: &mut File, offset: bool) -> Result<u32, Error> {
     Ok(val)
 }
 
-fn decompress_file(file: &mut File) -> Result<Vec<u8>, Error> {
+fn decompress_file<R: Read + Seek>(file: &mut R) -> Result<Vec<u8>, Error> {
     let mut object_data = Vec::new();
 
     let pos = file.stream_position()?;
@@ -145,15 +355,16 @@ fn decompress_file(file: &mut File) -> Result<Vec<u8>, Error> {
     Ok(object_data)
 }
 
-fn make_delta_obj(
-    file: &mut File,
-    base_obj: PackObject,
-    object_size: u32,
+fn make_delta_obj<R: Read + Seek>(
+    file: &mut R,
+    base_type: PackObjectType,
+    base_data: &[u8],
+    object_size: u64,
 ) -> Result<PackObject, Error> {
     let current_pos = file.stream_position()?;
     let object_data = decompress_file(file)?;
 
-    assert_eq!(object_data.len(), object_size as usize);
+    assert_eq!(object_data.len() as u64, object_size);
 
     let mut fp2 = BufReader::new(Cursor::new(object_data.as_slice()));
 
@@ -176,8 +387,9 @@ fn make_delta_obj(
         }
 
         if byt & 0x80 != 0 {
-            // copy data from base object
-            let mut vals = [0; 6];
+            // copy data from base object: a 4-byte offset and 3-byte size,
+            // each byte present only if its bit is set
+            let mut vals = [0; 7];
 
             for (i, val) in vals.iter_mut().enumerate() {
                 let bmask = 1 << i;
@@ -189,13 +401,14 @@ fn make_delta_obj(
                 }
             }
 
-            let start = u32::from_le_bytes(vals[0..4].try_into().expect("4 bytes"));
-            let nbytes = u16::from_le_bytes(vals[4..6].try_into().expect("2 bytes"));
-            let nbytes = if nbytes == 0 { 0x10000 } else { nbytes as u32 };
+            let start = u32::from_le_bytes(vals[0..4].try_into().expect("4 bytes")) as usize;
+            let nbytes = u32::from_le_bytes([vals[4], vals[5], vals[6], 0]) as usize;
+            let nbytes = if nbytes == 0 { 0x10000 } else { nbytes };
 
-            obj_data.extend_from_slice(
-                &base_obj.object_data[start as usize..(start + nbytes) as usize],
-            );
+            let copied = base_data
+                .get(start..start + nbytes)
+                .ok_or_else(|| format_error("delta copies past the end of its base"))?;
+            obj_data.extend_from_slice(copied);
         } else {
             // add new data
             let nbytes = byt & 0x7f;
@@ -208,10 +421,10 @@ fn make_delta_obj(
 
     // println!("Final object data: #bytes={}", obj_data.len());
 
-    assert_eq!(obj_data.len(), patched_obj_size as usize);
+    assert_eq!(obj_data.len() as u64, patched_obj_size);
 
     Ok(PackObject {
-        object_type: base_obj.object_type,
+        object_type: base_type,
         object_size: patched_obj_size,
         object_data: obj_data,
         pos: current_pos,
@@ -219,58 +432,88 @@ fn make_delta_obj(
     })
 }
 
-fn parse_pack_ofs_delta_object(
-    file: &mut File,
-    object_size: u32,
+fn parse_pack_ofs_delta_object<R: Read + Seek>(
+    file: &mut R,
+    object_size: u64,
     fpos: u64,
+    resolve: BaseResolver,
+    cache: BaseCache,
 ) -> Result<PackObject, Error> {
-    // println!("pos: 0x{:x}", file.seek(SeekFrom::Current(0))?);
-
-    // let mut reader = BufReader::new(&mut *file);
     let offset = read_vli_be(file, true)?;
-    // let new_position = reader.stream_position()?;
-    // file.seek(SeekFrom::Start(new_position))?;
-
-    let base_obj_offset = fpos - offset as u64;
-
-    // println!(
-    //     "offset:0x{:x} base_obj_offset:0x{:x}",
-    //     offset, base_obj_offset
-    // );
-
-    let prev_pos = file.stream_position()?;
-    file.seek(SeekFrom::Start(base_obj_offset))?;
-
-    let base_obj = parse_pack_entry(file)?;
+    let base_obj_offset = fpos
+        .checked_sub(offset)
+        .ok_or_else(|| format_error("delta base offset points before the pack"))?;
+
+    // bases shared by the deltas of a chain are only inflated once
+    let key = |pack: &Path| CacheKey::PackEntry(pack.to_path_buf(), base_obj_offset);
+    let cached = cache.and_then(|(cache, pack)| cache.get(&key(pack)));
+    let (base_type, base_data) = match cached {
+        Some((kind, data)) => (PackObjectType::from_kind(&kind), data),
+        None => {
+            let prev_pos = file.stream_position()?;
+            file.seek(SeekFrom::Start(base_obj_offset))?;
+            let base_obj = parse_pack_entry_with(file, resolve, cache)?;
+            file.seek(SeekFrom::Start(prev_pos))?;
+
+            let data: Arc<[u8]> = base_obj.object_data.into();
+            if let Some((cache, pack)) = cache {
+                cache.insert(key(pack), base_obj.object_type.kind()?, data.clone());
+            }
+            (base_obj.object_type, data)
+        }
+    };
     assert!([
         PackObjectType::Commit,
         PackObjectType::Tree,
         PackObjectType::Blob,
         PackObjectType::Tag
     ]
-    .contains(&base_obj.object_type));
-
-    file.seek(SeekFrom::Start(prev_pos))?;
+    .contains(&base_type));
 
-    make_delta_obj(file, base_obj, object_size)
+    make_delta_obj(file, base_type, &base_data, object_size)
 }
 
-fn parse_pack_entry(file: &mut File) -> Result<PackObject, Error> {
-    let object_pos = file.stream_position()?;
+/// Looks up the base of a ref-delta entry, which is not in the pack itself
+/// when the pack is thin or when the base is stored after the delta.
+type BaseResolver<'a> = &'a dyn Fn(&[u8; 20]) -> Result<Option<PackObject>, Error>;
 
+/// Where the bases of offset deltas are kept once read, with the path of
+/// the pack they are read from.
+type BaseCache<'a> = Option<(&'a ObjectCache, &'a Path)>;
+
+fn parse_pack_entry<R: Read + Seek>(file: &mut R) -> Result<PackObject, Error> {
+    parse_pack_entry_with(file, &|_| Ok(None), None)
+}
+
+/// Read the type and size of the entry starting at the current position.
+fn read_entry_header<R: Read + Seek>(file: &mut R) -> Result<(u8, u64), Error> {
     let mut byte = [0; 1];
     file.read_exact(&mut byte)?;
     let object_type: u8 = (byte[0] & 0x70) >> 4;
-    let object_data;
 
-    let mut object_size: u32 = (byte[0] & 0x0f) as u32;
+    let mut object_size: u64 = (byte[0] & 0x0f) as u64;
     let mut bshift = 4;
     while (byte[0] & 0x80) == 0x80 {
         file.read_exact(&mut byte)?;
-        object_size += (byte[0] as u32 & 0x7f) << bshift;
+        if bshift > 63 {
+            return Err(format_error("object size overflows 64 bits"));
+        }
+        object_size += (byte[0] as u64 & 0x7f) << bshift;
         bshift += 7;
     }
 
+    Ok((object_type, object_size))
+}
+
+fn parse_pack_entry_with<R: Read + Seek>(
+    file: &mut R,
+    resolve: BaseResolver,
+    cache: BaseCache,
+) -> Result<PackObject, Error> {
+    let object_pos = file.stream_position()?;
+    let (object_type, object_size) = read_entry_header(file)?;
+    let object_data;
+
     // println!(
     //     "Reading object: fpos=0x{:x}, type:{} size:{}",
     //     object_pos,
@@ -284,12 +527,32 @@ fn parse_pack_entry(file: &mut File) -> Result<PackObject, Error> {
         | PackObjectType::Blob
         | PackObjectType::Tag => {
             object_data = decompress_file(file)?;
-            assert_eq!(object_data.len(), object_size as usize);
+            assert_eq!(object_data.len() as u64, object_size);
         }
         PackObjectType::OfsDelta => {
-            return parse_pack_ofs_delta_object(file, object_size, object_pos);
+            let mut obj =
+                parse_pack_ofs_delta_object(file, object_size, object_pos, resolve, cache)?;
+            obj.pos = object_pos;
+            return Ok(obj);
+        }
+        PackObjectType::RefDelta => {
+            let mut base_hash = [0; 20];
+            file.read_exact(&mut base_hash)?;
+            let base_obj = resolve(&base_hash)?.ok_or_else(|| {
+                format_error(format!(
+                    "ref-delta base {} not found",
+                    hex::encode(base_hash)
+                ))
+            })?;
+            let mut obj = make_delta_obj(
+                file,
+                base_obj.object_type,
+                &base_obj.object_data,
+                object_size,
+            )?;
+            obj.pos = object_pos;
+            return Ok(obj);
         }
-        PackObjectType::RefDelta => unimplemented!(),
     }
 
     Ok(PackObject {
@@ -301,9 +564,391 @@ fn parse_pack_entry(file: &mut File) -> Result<PackObject, Error> {
     })
 }
 
+/// The type and size of each entry of the pack file `path` as stored, with
+/// deltas left unresolved, and whether the trailing checksum matches.
+pub fn stored_pack_entries(path: &Path) -> Result<(Vec<(String, u64)>, bool), Error> {
+    let mut file = File::open(path)?;
+    let header = parse_pack_header(&mut file)?;
+
+    let mut entries = Vec::with_capacity(header.num_objects as usize);
+    for _ in 0..header.num_objects {
+        let (object_type, object_size) = read_entry_header(&mut file)?;
+        let object_type = PackObjectType::from_u8(object_type)?;
+        match object_type {
+            PackObjectType::OfsDelta => {
+                read_vli_be(&mut file, true)?;
+            }
+            PackObjectType::RefDelta => file.read_exact(&mut [0; 20])?,
+            _ => (),
+        }
+        decompress_file(&mut file)?;
+        entries.push((object_type.to_string(), object_size));
+    }
+
+    let content_end = file.stream_position()?;
+    let mut checksum_pack = [0; 20];
+    file.read_exact(&mut checksum_pack)?;
+    let mut hasher = Sha1::new();
+    file.seek(SeekFrom::Start(0))?;
+    std::io::copy(&mut (&mut file).take(content_end), &mut hasher)?;
+
+    Ok((entries, hasher.finalize().as_slice() == checksum_pack))
+}
+
 impl Repository {
+    /// The zlib level for packs and archives: `pack.compression`, else
+    /// `core.compression`. Reproducible output ignores the configuration so
+    /// it does not depend on the machine producing it.
+    pub fn compression(&self, reproducible: bool) -> Result<Compression, Error> {
+        if reproducible {
+            return Ok(Compression::default());
+        }
+
+        let config = self.config()?;
+        let level = match config.get_int("pack.compression")? {
+            Some(level) => Some(level),
+            None => config.get_int("core.compression")?,
+        };
+        match level {
+            Some(level @ 0..=9) => Ok(Compression::new(level as u32)),
+            Some(-1) | None => Ok(Compression::default()),
+            Some(level) => Err(Error::msg(format!("bad zlib compression level {}", level))),
+        }
+    }
+
+    /// Write a pack of the given objects, without deltas, in this order to
+    /// `out`. Returns the pack checksum and the index entries of the objects.
+    /// Objects are stored as they are, whatever replaces them.
+    pub fn write_pack<W: Write>(
+        &self,
+        objects: &[[u8; 20]],
+        out: W,
+        compression: Compression,
+    ) -> Result<([u8; 20], Vec<PackIndexEntry>), Error> {
+        // a replacement stored under the id of the object it replaces would
+        // corrupt the pack
+        let repo = self.stored_objects();
+
+        let mut out = HashWriter {
+            inner: out,
+            hasher: Sha1::new(),
+        };
+        out.write_all(b"PACK")?;
+        out.write_all(&2u32.to_be_bytes())?;
+        out.write_all(&(objects.len() as u32).to_be_bytes())?;
+
+        let mut entries = Vec::with_capacity(objects.len());
+        let mut offset = 12;
+        for hash in objects {
+            let mut object = repo.read_object(&hex::encode(hash))?;
+            let object_type = ObjectHeader::new(object.kind().clone(), object.size()).pack_type();
+            let content = object.content()?;
+
+            let mut entry = encode_pack_entry_header(object_type, content.len() as u64);
+            let mut zlib_out = ZlibEncoder::new(entry, compression);
+            zlib_out.write_all(&content)?;
+            entry = zlib_out.finish()?;
+            out.write_all(&entry)?;
+
+            entries.push(PackIndexEntry {
+                hash: *hash,
+                crc32: crc32fast::hash(&entry),
+                offset,
+            });
+            offset += entry.len() as u64;
+        }
+
+        let checksum: [u8; 20] = out.hasher.finalize_reset().into();
+        out.inner.write_all(&checksum)?;
+        out.inner.flush()?;
+
+        Ok((checksum, entries))
+    }
+
+    /// Pack the objects whose ids are read from stdin, one per line, into
+    /// `<base_name>-<checksum>.pack` and its index, printing the checksum, or
+    /// to stdout without a base name. With `reproducible`, objects are
+    /// ordered by id rather than as given.
+    pub fn pack_objects(&self, base_name: Option<&Path>, reproducible: bool) -> Result<(), Error> {
+        let mut objects = Vec::new();
+        let mut seen = std::collections::HashSet::new();
+        for line in std::io::stdin().lines() {
+            let line = line?;
+            // `<id> <path>` lines, as produced by rev-list --objects, are accepted
+            let Some(id) = line.split_whitespace().next() else {
+                continue;
+            };
+            let hash = <[u8; 20]>::from_hex(id)?;
+            if seen.insert(hash) {
+                objects.push(hash);
+            }
+        }
+        if reproducible {
+            objects.sort();
+        }
+
+        let compression = self.compression(reproducible)?;
+        let Some(base_name) = base_name else {
+            self.write_pack(
+                &objects,
+                BufWriter::new(std::io::stdout().lock()),
+                compression,
+            )?;
+            return Ok(());
+        };
+
+        let tmp_path = base_name.with_file_name(format!("tmp_pack_{}", std::process::id()));
+        let result = File::create(&tmp_path)
+            .map_err(Error::from)
+            .and_then(|file| self.write_pack(&objects, BufWriter::new(file), compression));
+        let (checksum, mut entries) = match result {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        };
+
+        let name = format!(
+            "{}-{}",
+            base_name.file_name().unwrap_or_default().to_string_lossy(),
+            hex::encode(checksum)
+        );
+        std::fs::rename(
+            &tmp_path,
+            base_name.with_file_name(format!("{}.pack", name)),
+        )?;
+        write_pack_index(
+            &base_name.with_file_name(format!("{}.idx", name)),
+            &mut entries,
+            &checksum,
+        )?;
+        println!("{}", hex::encode(checksum));
+
+        Ok(())
+    }
+
+    /// The object `hash` of the repository as the base of a ref-delta, if
+    /// it has it.
+    fn delta_base(&self, hash: &[u8; 20]) -> Result<Option<PackObject>, Error> {
+        if !self.has_object(hash) {
+            return Ok(None);
+        }
+        let mut object = self.read_object(&hex::encode(hash))?;
+        let object_type = PackObjectType::from_kind(object.kind());
+        let object_data = object.content()?;
+        Ok(Some(PackObject {
+            object_type,
+            object_size: object_data.len() as u64,
+            object_data,
+            pos: 0,
+            end_pos: 0,
+        }))
+    }
+
+    /// Read the object stored at `offset` in the pack file `path`, with
+    /// its deltas applied.
+    pub fn read_pack_entry(&self, path: &Path, offset: u64) -> Result<(Kind, Vec<u8>), Error> {
+        // the entry is decompressed straight out of the mapped pack
+        let pack = Mmap::open(path)?;
+        let mut file = Cursor::new(&pack[..]);
+        file.seek(SeekFrom::Start(offset))?;
+        let obj = parse_pack_entry_with(
+            &mut file,
+            &|hash| self.delta_base(hash),
+            Some((&self.object_cache, path)),
+        )?;
+
+        Ok((obj.object_type.kind()?, obj.object_data))
+    }
+
+    /// Write every object of a pack file as a loose object, returning their ids.
+    pub fn unpack_pack(&self, path: &Path) -> Result<Vec<[u8; 20]>, Error> {
+        let mut file = File::open(path)?;
+        let header = parse_pack_header(&mut file)?;
+        let show_progress = self.show_progress();
+
+        // objects are written as they are read, so ref-delta bases stored
+        // earlier in the pack are found among the loose objects
+        let resolve = |hash: &[u8; 20]| self.delta_base(hash);
+        // the pack may be a temporary file, so its bases are not kept past
+        // this call
+        let cache = ObjectCache::default();
+
+        // the whole objects first, the deltas then resolved against them
+        let mut hashes = vec![[0; 20]; header.num_objects as usize];
+        let mut deltas = Vec::new();
+        let mut progress = Progress::new(
+            "Unpacking objects",
+            Some(header.num_objects.into()),
+            show_progress,
+        );
+        for (i, hash) in hashes.iter_mut().enumerate() {
+            let pos = file.stream_position()?;
+            let (object_type, _) = read_entry_header(&mut file)?;
+            match PackObjectType::from_u8(object_type)? {
+                PackObjectType::OfsDelta => {
+                    read_vli_be(&mut file, true)?;
+                    decompress_file(&mut file)?;
+                    deltas.push((i, pos));
+                }
+                PackObjectType::RefDelta => {
+                    file.read_exact(&mut [0; 20])?;
+                    decompress_file(&mut file)?;
+                    deltas.push((i, pos));
+                }
+                _ => {
+                    file.seek(SeekFrom::Start(pos))?;
+                    let obj = parse_pack_entry_with(&mut file, &resolve, Some((&cache, path)))?;
+                    *hash = self.write_object(obj.object_type.kind()?, &obj.object_data)?;
+                }
+            }
+            progress.tick();
+        }
+        progress.finish();
+
+        // in pack order, bases come before the deltas against them
+        let mut progress = Progress::new(
+            "Resolving deltas",
+            Some(deltas.len() as u64),
+            show_progress && !deltas.is_empty(),
+        );
+        for (i, pos) in deltas {
+            file.seek(SeekFrom::Start(pos))?;
+            let obj = parse_pack_entry_with(&mut file, &resolve, Some((&cache, path)))?;
+            hashes[i] = self.write_object(obj.object_type.kind()?, &obj.object_data)?;
+            progress.tick();
+        }
+        progress.finish();
+
+        Ok(hashes)
+    }
+
+    /// Build a version 2 index for a pack file, written next to it with an
+    /// `.idx` extension.
+    pub fn index_pack(&self, path: &Path) -> Result<PathBuf, Error> {
+        let mut file = File::open(path)?;
+        let header = parse_pack_header(&mut file)?;
+
+        // the entries spill next to the pack once over the memory budget
+        let spill_dir = path.parent().unwrap_or(Path::new("."));
+        let mut entries = SpillBuffer::new(spill_dir, self.memory_budget()?);
+        let cache = ObjectCache::default();
+        for _ in 0..header.num_objects {
+            let obj = parse_pack_entry_with(&mut file, &|_| Ok(None), Some((&cache, path)))?;
+            let end_pos = file.stream_position()?;
+
+            let hash = oid_for(&obj.object_type.kind()?, &obj.object_data);
+
+            // the CRC covers the raw entry, header and compressed data included
+            let mut raw = vec![0u8; (obj.end_pos - obj.pos) as usize];
+            file.seek(SeekFrom::Start(obj.pos))?;
+            file.read_exact(&mut raw)?;
+            file.seek(SeekFrom::Start(end_pos))?;
+
+            entries.push(PackIndexEntry {
+                hash,
+                crc32: crc32fast::hash(&raw),
+                offset: obj.pos,
+            })?;
+        }
+
+        let content_end = file.stream_position()?;
+        let mut checksum_pack = [0; 20];
+        file.read_exact(&mut checksum_pack)?;
+
+        let mut hasher = Sha1::new();
+        file.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut (&mut file).take(content_end), &mut hasher)?;
+        if hasher.finalize().as_slice() != checksum_pack {
+            return Err(format_error("pack checksum mismatch"));
+        }
+
+        let idx_path = path.with_extension("idx");
+        let entries = entries.finish();
+        write_sorted_pack_index(&idx_path, || entries.iter(), &checksum_pack)?;
+
+        Ok(idx_path)
+    }
+
+    /// A new temporary file in `objects/pack` for a pack being received.
+    /// It has no `.pack` extension, so it is never read as a pack.
+    pub(crate) fn create_temp_pack(&self) -> Result<(PathBuf, File), Error> {
+        let pack_dir = self.git_dir.join("objects").join("pack");
+        create_dir_all(&pack_dir)?;
+        let path = pack_dir.join(format!(
+            "tmp_pack_{}_{}",
+            std::process::id(),
+            TEMP_PACKS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok((path, file))
+    }
+
+    /// Write the pack read from `input` to a temporary file and index it,
+    /// returning both paths for `install_pack`. Nothing is left behind if
+    /// the pack is invalid.
+    pub(crate) fn receive_pack<R: Read>(&self, mut input: R) -> Result<(PathBuf, PathBuf), Error> {
+        let (temp, mut file) = self.create_temp_pack()?;
+        let indexed = std::io::copy(&mut input, &mut file)
+            .map_err(Error::from)
+            .and_then(|_| self.index_pack(&temp));
+        match indexed {
+            Ok(idx) => Ok((temp, idx)),
+            Err(e) => {
+                let _ = remove_file(&temp);
+                let _ = remove_file(temp.with_extension("idx"));
+                Err(e)
+            }
+        }
+    }
+
+    /// Move the complete temporary pack `temp` and its index `idx` to their
+    /// final names, `pack-<checksum>`, which is returned. A pack from the
+    /// promisor remote is marked with a `.promisor` file.
+    ///
+    /// Readers find packs through their index, so the pack goes in place
+    /// first and the index last, each synced before it is renamed, and the
+    /// directory synced after: a crash never leaves an index without its
+    /// pack, nor either of them partly written.
+    pub(crate) fn install_pack(
+        &self,
+        temp: &Path,
+        idx: &Path,
+        promisor: bool,
+    ) -> Result<String, Error> {
+        let pack_dir = self.git_dir.join("objects").join("pack");
+        let installed = (|| {
+            let mut file = File::open(temp)?;
+            file.seek(SeekFrom::End(-20))?;
+            let mut checksum = [0; 20];
+            file.read_exact(&mut checksum)?;
+            file.sync_all()?;
+            File::open(idx)?.sync_all()?;
+
+            // the name of a pack is its trailing checksum
+            let name = format!("pack-{}", hex::encode(checksum));
+            rename(temp, pack_dir.join(format!("{}.pack", name)))?;
+            if promisor {
+                File::create(pack_dir.join(format!("{}.promisor", name)))?.sync_all()?;
+            }
+            rename(idx, pack_dir.join(format!("{}.idx", name)))?;
+            File::open(&pack_dir)?.sync_all()?;
+            Ok(name)
+        })();
+        if installed.is_err() {
+            let _ = remove_file(temp);
+            let _ = remove_file(idx);
+        }
+        installed
+    }
+
     pub fn dump_pack_files(&self) -> Result<(), Error> {
-        let pack_dir = self.path.join(".git/objects/pack");
+        let pack_dir = self.git_dir.join("objects").join("pack");
 
         for entry in pack_dir.read_dir()? {
             let entry = entry?;
@@ -322,18 +967,35 @@ impl Repository {
         let mut file = File::open(path)?;
 
         let header = parse_pack_header(&mut file)?;
-        println!("{:?}", header);
+        let json = self.output_format == OutputFormat::Json;
+        if json {
+            println!(
+                "{{\"type\":\"pack\",\"path\":{},\"version\":{},\"objects\":{}}}",
+                json_string(&path.to_string_lossy()),
+                header.version,
+                header.num_objects
+            );
+        } else {
+            println!("{:?}", header);
+        }
 
         for _ in 0..header.num_objects {
             let obj = parse_pack_entry(&mut file)?;
 
-            let mut hasher = Sha1::new();
-            hasher.update(format!("{} {}\0", obj.object_type, obj.object_size).as_bytes());
-            hasher.update(obj.object_data);
-
+            if json {
+                println!(
+                    "{{\"type\":\"object\",\"id\":\"{}\",\"kind\":\"{}\",\"size\":{},\"packed_size\":{},\"offset\":{}}}",
+                    hex::encode(oid_for(&obj.object_type.kind()?, &obj.object_data)),
+                    obj.object_type,
+                    obj.object_size,
+                    obj.end_pos - obj.pos,
+                    obj.pos,
+                );
+                continue;
+            }
             println!(
                 "{} {} {} {} {}",
-                hex::encode(hasher.finalize()),
+                hex::encode(oid_for(&obj.object_type.kind()?, &obj.object_data)),
                 obj.object_type,
                 obj.object_size,
                 obj.end_pos - obj.pos,
@@ -349,31 +1011,34 @@ impl Repository {
 
     pub fn dump_pack_file(&self, pack_id: &str) -> Result<(), Error> {
         let file_path = self
-            .path
-            .join(format!(".git/objects/pack/pack-{}.pack", pack_id));
+            .git_dir
+            .join(format!("objects/pack/pack-{}.pack", pack_id));
 
         self.dump_pack(&file_path)
     }
 
     pub fn dump_pack_index_file(&self, pack_id: &str) -> Result<(), Error> {
         let file_path = self
-            .path
-            .join(format!(".git/objects/pack/pack-{}.idx", pack_id));
+            .git_dir
+            .join(format!("objects/pack/pack-{}.idx", pack_id));
 
         let mut file = File::open(file_path)?;
 
         let mut buf = [0; 4];
         file.read_exact(&mut buf)?;
 
-        if buf[0] != 0xff || "t0c".as_bytes() == &buf[1..4] {
-            return Err(Error::msg("Invalid pack index magic"));
+        if buf != [0xff, b't', b'O', b'c'] {
+            return Err(format_error("Invalid pack index magic"));
         }
 
         file.read_exact(&mut buf)?;
         let version = u32::from_be_bytes(buf);
-        println!("{}", version);
+        let json = self.output_format == OutputFormat::Json;
+        if !json {
+            println!("{}", version);
+        }
         if version != 2 {
-            return Err(Error::msg("Invalid pack index version"));
+            return Err(format_error("Invalid pack index version"));
         }
 
         let mut num_objects: u32 = 0;
@@ -400,15 +1065,56 @@ impl Repository {
 
         let mut offsets_buf = vec![0u8; 4 * num_objects as usize];
         file.read_exact(&mut offsets_buf)?;
-        let offsets: Vec<u32> = offsets_buf
+        let small_offsets: Vec<u32> = offsets_buf
             .chunks_exact(4)
             .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
             .collect();
 
+        // offsets past 2GB are in a table of 8-byte offsets, which the
+        // 4-byte entries with the high bit set point into
+        let large_count = small_offsets
+            .iter()
+            .filter(|offset| *offset & 0x8000_0000 != 0)
+            .count();
+        let mut large_buf = vec![0u8; 8 * large_count];
+        file.read_exact(&mut large_buf)?;
+        let large_offsets: Vec<u64> = large_buf
+            .chunks_exact(8)
+            .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        let offsets = small_offsets
+            .iter()
+            .map(|&offset| {
+                if offset & 0x8000_0000 == 0 {
+                    return Ok(offset as u64);
+                }
+                large_offsets
+                    .get((offset & 0x7fff_ffff) as usize)
+                    .copied()
+                    .ok_or_else(|| format_error("Invalid pack index large offset"))
+            })
+            .collect::<Result<Vec<u64>, Error>>()?;
+
+        if json {
+            println!(
+                "{{\"type\":\"index\",\"version\":{},\"objects\":{}}}",
+                version, num_objects
+            );
+        }
         for i in 0..num_objects {
             let offset = offsets[i as usize];
             let crc32 = crc32[i as usize];
             let name = &names[(i * 20) as usize..(i * 20 + 20) as usize];
+            if json {
+                println!(
+                    "{{\"type\":\"entry\",\"id\":\"{}\",\"offset\":{},\"crc32\":{}}}",
+                    hex::encode(name),
+                    offset,
+                    crc32
+                );
+                continue;
+            }
             println!(
                 "{} offset: 0x{:x} crc32: {}",
                 hex::encode(name),
@@ -425,3 +1131,41 @@ impl Repository {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::InitOptions;
+
+    #[test]
+    fn test_write_pack_ignores_replacements() {
+        let path = std::env::temp_dir().join(format!("mg-pack-replace-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        let mut repo = Repository::open(path.clone()).unwrap();
+        repo.init_repository(&path, &InitOptions::default())
+            .unwrap();
+
+        let original = repo.write_object(Kind::Blob(false), b"original\n").unwrap();
+        let replacement = repo
+            .write_object(Kind::Blob(false), b"replacement\n")
+            .unwrap();
+        repo.update_ref(
+            &format!("refs/replace/{}", hex::encode(original)),
+            &replacement,
+        )
+        .unwrap();
+        let mut read = repo.read_object(&hex::encode(original)).unwrap();
+        assert_eq!(read.content().unwrap(), b"replacement\n");
+
+        let mut pack = Vec::new();
+        repo.write_pack(&[original], &mut pack, Compression::default())
+            .unwrap();
+        let mut cursor = Cursor::new(pack);
+        parse_pack_header(&mut cursor).unwrap();
+        let obj = parse_pack_entry_with(&mut cursor, &|_| Ok(None), None).unwrap();
+        assert_eq!(obj.object_data, b"original\n");
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{error::MgError, kind::Kind, mmap::Mmap, repository::Repository};
+
+/// Size of the header and fanout table of a version 2 pack index.
+const TABLES_START: usize = 8 + 256 * 4;
+
+/// A version 2 pack index opened for lookups. The file is mapped, and only
+/// the entries the binary search visits are read.
+pub struct PackIndex {
+    data: Mmap,
+    /// For each first byte, the number of objects whose id starts with a
+    /// byte up to it
+    fanout: [u32; 256],
+}
+
+impl PackIndex {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = Mmap::open(path)?;
+        let header = data
+            .get(..TABLES_START)
+            .ok_or_else(|| MgError::PackFormat(format!("{} is truncated", path.display())))?;
+        if header[..4] != [0xff, b't', b'O', b'c'] || header[4..8] != 2u32.to_be_bytes() {
+            return Err(MgError::PackFormat(format!(
+                "{} is not a version 2 pack index",
+                path.display()
+            ))
+            .into());
+        }
+
+        let mut fanout = [0; 256];
+        for (i, count) in fanout.iter_mut().enumerate() {
+            let start = 8 + i * 4;
+            *count = u32::from_be_bytes(header[start..start + 4].try_into().expect("4 bytes"));
+        }
+
+        let index = PackIndex { data, fanout };
+        // the ids, CRCs and offsets, then the two checksums
+        if index.data.len() < TABLES_START + index.len() as usize * 28 + 40 {
+            return Err(MgError::PackFormat(format!("{} is truncated", path.display())).into());
+        }
+        Ok(index)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.fanout[255]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The checksum of the pack the index is for, before its own.
+    pub fn pack_checksum(&self) -> Result<[u8; 20]> {
+        self.read_at(self.data.len() - 40)
+    }
+
+    /// The `N` bytes at `position`.
+    fn read_at<const N: usize>(&self, position: usize) -> Result<[u8; N]> {
+        self.data
+            .get(position..position + N)
+            .map(|bytes| bytes.try_into().expect("N bytes"))
+            .ok_or_else(|| {
+                MgError::PackFormat("pack index entry past the end of the file".to_string()).into()
+            })
+    }
+
+    /// The id of the `n`th object, in sorted order.
+    fn hash_at(&self, n: u32) -> Result<[u8; 20]> {
+        self.read_at(TABLES_START + n as usize * 20)
+    }
+
+    /// The pack offset of the `n`th object. Offsets past 2GB are stored in
+    /// a table of 8-byte offsets, pointed to by 4-byte entries with the high
+    /// bit set.
+    fn offset_at(&self, n: u32) -> Result<u64> {
+        let count = self.len() as usize;
+        // after the ids and the CRCs
+        let offsets = TABLES_START + count * 24;
+
+        let offset = u32::from_be_bytes(self.read_at(offsets + n as usize * 4)?);
+        if offset & 0x8000_0000 == 0 {
+            return Ok(offset as u64);
+        }
+
+        let large_index = (offset & 0x7fff_ffff) as usize;
+        Ok(u64::from_be_bytes(
+            self.read_at(offsets + count * 4 + large_index * 8)?,
+        ))
+    }
+
+    /// The ids of the objects of the pack and their offsets, in id order.
+    pub fn entries(&self) -> Result<Vec<([u8; 20], u64)>> {
+        (0..self.len())
+            .map(|n| Ok((self.hash_at(n)?, self.offset_at(n)?)))
+            .collect()
+    }
+
+    /// The offset of the object `hash` in the pack, found by a binary
+    /// search among the ids sharing its first byte.
+    pub fn find_offset(&self, hash: &[u8; 20]) -> Result<Option<u64>> {
+        let first = hash[0] as usize;
+        let mut low = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1]
+        };
+        let mut high = self.fanout[first];
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.hash_at(middle)?.cmp(hash) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return self.offset_at(middle).map(Some),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The ids starting with the hex digits `prefix`, at least two of them.
+    pub fn find_prefix(&self, prefix: &str) -> Result<Vec<[u8; 20]>> {
+        // the smallest id with the prefix, then those following it
+        let mut lowest = [0; 20];
+        let padded = format!("{:0<40}", prefix);
+        hex::decode_to_slice(&padded, &mut lowest)?;
+
+        let first = lowest[0] as usize;
+        let mut low = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1]
+        };
+        let mut high = self.fanout[first];
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.hash_at(middle)? < lowest {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        let mut found = Vec::new();
+        for n in low..self.fanout[first] {
+            let hash = self.hash_at(n)?;
+            if !hex::encode(hash).starts_with(prefix) {
+                break;
+            }
+            found.push(hash);
+        }
+
+        Ok(found)
+    }
+}
+
+impl Repository {
+    /// The pack files with an index, in every object directory.
+    pub fn pack_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut packs = Vec::new();
+        for dir in self.object_dirs()? {
+            let pack_dir = dir.join("pack");
+            if !pack_dir.is_dir() {
+                continue;
+            }
+            let mut paths = Vec::new();
+            for entry in fs::read_dir(&pack_dir)? {
+                let path = entry?.path();
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "pack")
+                    && path.with_extension("idx").is_file()
+                {
+                    paths.push(path);
+                }
+            }
+            paths.sort();
+            packs.extend(paths);
+        }
+
+        Ok(packs)
+    }
+
+    /// The pack holding the object `hash` and its offset there, if any.
+    pub fn find_packed_object(&self, hash: &[u8; 20]) -> Result<Option<(PathBuf, u64)>> {
+        for pack in self.pack_paths()? {
+            let index = PackIndex::open(&pack.with_extension("idx"))?;
+            if let Some(offset) = index.find_offset(hash)? {
+                return Ok(Some((pack, offset)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The size every packed object takes in its pack: from its offset to
+    /// the next object, or to the trailing checksum for the last one.
+    pub fn packed_sizes(&self) -> Result<HashMap<[u8; 20], u64>> {
+        let mut sizes = HashMap::new();
+        for pack in self.pack_paths()? {
+            let mut entries = PackIndex::open(&pack.with_extension("idx"))?.entries()?;
+            entries.sort_by_key(|(_, offset)| *offset);
+
+            let end = fs::metadata(&pack)?.len().saturating_sub(20);
+            let next_offsets = entries
+                .iter()
+                .skip(1)
+                .map(|(_, offset)| *offset)
+                .chain([end]);
+            for ((hash, offset), next) in entries.iter().zip(next_offsets) {
+                sizes.entry(*hash).or_insert(next - offset);
+            }
+        }
+
+        Ok(sizes)
+    }
+
+    /// The kind and content of the object `hash`, if a pack holds it.
+    pub fn read_packed_object(&self, hash: &[u8; 20]) -> Result<Option<(Kind, Vec<u8>)>> {
+        let Some((pack, offset)) = self.find_packed_object(hash)? else {
+            return Ok(None);
+        };
+
+        self.read_pack_entry(&pack, offset).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::{write_pack_index, PackIndexEntry};
+
+    #[test]
+    fn test_find_offset_past_2gb() {
+        let path = std::env::temp_dir().join(format!("mg-large-{}.idx", std::process::id()));
+        let offsets = [12, 0x8000_0000, 0x1_2345_6789];
+        let mut entries: Vec<PackIndexEntry> = offsets
+            .iter()
+            .enumerate()
+            .map(|(i, &offset)| PackIndexEntry {
+                hash: [i as u8 * 0x40; 20],
+                crc32: 0,
+                offset,
+            })
+            .collect();
+        write_pack_index(&path, &mut entries, &[0; 20]).unwrap();
+
+        let index = PackIndex::open(&path).unwrap();
+        for (i, &offset) in offsets.iter().enumerate() {
+            assert_eq!(
+                index.find_offset(&[i as u8 * 0x40; 20]).unwrap(),
+                Some(offset)
+            );
+        }
+        assert_eq!(index.find_offset(&[0xff; 20]).unwrap(), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
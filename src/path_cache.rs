@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use hex::FromHex;
+
+use crate::{pathspec::Pathspec, repository::Repository};
+
+/// Whether commits change paths relative to their first parent, that is
+/// whether they are not TREESAME for them. Results are shared by the queries
+/// of a command and, with `mg.pathCache`, kept in `.git/mg-cache` for later
+/// commands, one `<commit> <0|1> <path>` line each.
+#[derive(Default)]
+pub struct PathDiffCache {
+    results: HashMap<([u8; 20], String), bool>,
+    /// The results found since loading, to be appended to the file
+    added: Vec<([u8; 20], String, bool)>,
+    file: Option<PathBuf>,
+}
+
+impl PathDiffCache {
+    fn get(&self, hash: &[u8; 20], path: &str) -> Option<bool> {
+        self.results.get(&(*hash, path.to_string())).copied()
+    }
+
+    fn insert(&mut self, hash: [u8; 20], path: &str, changed: bool) {
+        self.results.insert((hash, path.to_string()), changed);
+        if self.file.is_some() {
+            self.added.push((hash, path.to_string(), changed));
+        }
+    }
+
+    /// Append the new results to the cache file, if it is kept.
+    pub fn save(&mut self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if self.added.is_empty() {
+            return Ok(());
+        }
+
+        let mut out = String::new();
+        for (hash, path, changed) in self.added.drain(..) {
+            out.push_str(&format!(
+                "{} {} {}\n",
+                hex::encode(hash),
+                u8::from(changed),
+                path
+            ));
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)?
+            .write_all(out.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+impl Repository {
+    /// The path diff cache, loaded from `.git/mg-cache` when `mg.pathCache`
+    /// is set.
+    pub fn path_diff_cache(&self) -> Result<PathDiffCache> {
+        let mut cache = PathDiffCache::default();
+        if !self.config()?.get_bool("mg.pathcache").unwrap_or(false) {
+            return Ok(cache);
+        }
+
+        let file = self.git_dir.join("mg-cache");
+        if file.exists() {
+            for line in fs::read_to_string(&file)?.lines() {
+                let mut fields = line.splitn(3, ' ');
+                let (Some(hash), Some(changed), Some(path)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    continue;
+                };
+                let Ok(hash) = <[u8; 20]>::from_hex(hash) else {
+                    continue;
+                };
+                cache
+                    .results
+                    .insert((hash, path.to_string()), changed == "1");
+            }
+        }
+        cache.file = Some(file);
+
+        Ok(cache)
+    }
+
+    /// Whether a commit changes what `pathspec` matches relative to its
+    /// first parent. Only the results for plain files and directories are
+    /// cached.
+    pub fn commit_touches(
+        &self,
+        cache: &mut PathDiffCache,
+        hash: &[u8; 20],
+        pathspec: &Pathspec,
+    ) -> Result<bool> {
+        let plain = pathspec.plain_paths();
+        let mut unknown = Vec::new();
+        if let Some(paths) = &plain {
+            if pathspec.is_empty() || paths.iter().any(|path| path.is_empty()) {
+                return Ok(true);
+            }
+            if paths.iter().any(|path| cache.get(hash, path) == Some(true)) {
+                return Ok(true);
+            }
+            unknown = paths
+                .iter()
+                .filter(|path| cache.get(hash, path).is_none())
+                .copied()
+                .collect();
+            if unknown.is_empty() {
+                return Ok(false);
+            }
+        }
+
+        // commits at the shallow boundary lose their parent until they are
+        // deepened, so what they change is not cached
+        let shallow = self.shallow_commits()?.contains(hash);
+        let tree = self.commit_tree(hash)?;
+        let parent_tree = match self.commit_parents(hash)?.first() {
+            Some(parent) if !shallow => Some(self.commit_tree(parent)?),
+            _ => None,
+        };
+        if plain.is_none() {
+            return self.trees_differ(parent_tree.as_ref(), &tree, pathspec);
+        }
+
+        let mut touched = false;
+        for path in unknown {
+            let entry = self.tree_lookup(&tree, path)?.map(|entry| entry.hash);
+            let parent_entry = match &parent_tree {
+                Some(parent_tree) => self.tree_lookup(parent_tree, path)?.map(|entry| entry.hash),
+                None => None,
+            };
+            let changed = entry != parent_entry;
+            if !shallow {
+                cache.insert(*hash, path, changed);
+            }
+            if changed {
+                touched = true;
+                break;
+            }
+        }
+
+        Ok(touched)
+    }
+}
@@ -0,0 +1,276 @@
+use std::{borrow::Cow, collections::HashMap, env};
+
+use anyhow::{anyhow, Result};
+
+use crate::{pattern::fnmatch, repository::Repository};
+
+/// One item of a pathspec, with its path from the top of the worktree.
+#[derive(Debug, Clone)]
+struct PathspecItem {
+    /// The item as given, for messages
+    original: String,
+    /// Empty for the whole worktree; a trailing `/` only matches what is
+    /// inside a directory
+    path: String,
+    icase: bool,
+    literal: bool,
+    exclude: bool,
+}
+
+impl PathspecItem {
+    fn parse(spec: &str, prefix: &str) -> Result<Self> {
+        let mut item = PathspecItem {
+            original: spec.to_string(),
+            path: String::new(),
+            icase: false,
+            literal: false,
+            exclude: false,
+        };
+        let mut top = false;
+
+        let path = if let Some(rest) = spec.strip_prefix(":(") {
+            let (magic, rest) = rest
+                .split_once(')')
+                .ok_or_else(|| anyhow!("missing ')' at the end of pathspec magic in '{}'", spec))?;
+            for word in magic.split(',').map(str::trim) {
+                match word {
+                    "icase" => item.icase = true,
+                    "literal" => item.literal = true,
+                    "top" => top = true,
+                    "exclude" => item.exclude = true,
+                    _ => return Err(anyhow!("invalid pathspec magic '{}' in '{}'", word, spec)),
+                }
+            }
+            rest
+        } else if let Some(rest) = spec.strip_prefix(':') {
+            // short magic: `:/` for the top, `:!` or `:^` to exclude
+            let magic_end = rest
+                .find(|c| !matches!(c, '/' | '!' | '^'))
+                .unwrap_or(rest.len());
+            top = rest[..magic_end].contains('/');
+            item.exclude = rest[..magic_end].contains(['!', '^']);
+            let rest = &rest[magic_end..];
+            rest.strip_prefix(':').unwrap_or(rest)
+        } else {
+            spec
+        };
+
+        let mut components: Vec<&str> = Vec::new();
+        let base = if top { "" } else { prefix };
+        for component in base.split('/').chain(path.split('/')) {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    if components.pop().is_none() {
+                        return Err(anyhow!("'{}' is outside the repository", spec));
+                    }
+                }
+                component => components.push(component),
+            }
+        }
+        item.path = components.join("/");
+        if path.ends_with('/') && !item.path.is_empty() {
+            item.path.push('/');
+        }
+
+        Ok(item)
+    }
+
+    fn has_wildcards(&self) -> bool {
+        !self.literal && self.path.contains(['*', '?', '['])
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        if self.path.is_empty() {
+            return true;
+        }
+        let (pattern, path) = match self.icase {
+            true => (
+                Cow::Owned(self.path.to_lowercase()),
+                Cow::Owned(path.to_lowercase()),
+            ),
+            false => (Cow::Borrowed(self.path.as_str()), Cow::Borrowed(path)),
+        };
+
+        let in_directory = match pattern.ends_with('/') {
+            true => path.starts_with(pattern.as_ref()),
+            false => path
+                .strip_prefix(pattern.as_ref())
+                .is_some_and(|rest| rest.starts_with('/')),
+        };
+        path == pattern || in_directory || (self.has_wildcards() && fnmatch(&pattern, &path))
+    }
+}
+
+/// The paths a command is limited to: files, directories, which stand for
+/// all they contain, and patterns whose wildcards also match `/`. The magic
+/// prefixes `:(icase)`, `:(literal)` (no wildcards), `:(top)` or `:/` (from
+/// the top of the worktree rather than the current directory) and
+/// `:(exclude)`, `:!` or `:^` change how an item matches. An empty pathspec
+/// matches everything.
+#[derive(Debug, Clone, Default)]
+pub struct Pathspec {
+    items: Vec<PathspecItem>,
+}
+
+impl Pathspec {
+    /// Parse `specs`, given in the directory `prefix` of the worktree.
+    pub fn parse(specs: &[String], prefix: &str) -> Result<Self> {
+        Ok(Pathspec {
+            items: specs
+                .iter()
+                .map(|spec| PathspecItem::parse(spec, prefix))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// A pathspec of the paths `paths`, taken literally.
+    pub fn literal(paths: &[&str]) -> Result<Self> {
+        let specs: Vec<String> = paths
+            .iter()
+            .map(|path| format!(":(literal){}", path))
+            .collect();
+        Pathspec::parse(&specs, "")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Whether `path`, from the top of the worktree, is matched.
+    pub fn matches(&self, path: &str) -> bool {
+        let mut includes = self.items.iter().filter(|item| !item.exclude).peekable();
+        let included = includes.peek().is_none() || includes.any(|item| item.matches(path));
+        included
+            && !self
+                .items
+                .iter()
+                .any(|item| item.exclude && item.matches(path))
+    }
+
+    /// The paths of the items when they are all plain files or directories,
+    /// which can be looked up in trees rather than matched.
+    pub fn plain_paths(&self) -> Option<Vec<&str>> {
+        self.items
+            .iter()
+            .map(|item| {
+                let plain = !item.exclude && !item.icase && !item.has_wildcards();
+                plain.then(|| item.path.trim_end_matches('/'))
+            })
+            .collect()
+    }
+
+    /// The first item, as given, which matches none of `paths`. Excluding
+    /// items are not expected to match.
+    pub fn unmatched<'a>(&'a self, paths: &[&str]) -> Option<&'a str> {
+        self.items
+            .iter()
+            .filter(|item| !item.exclude)
+            .find(|item| !paths.iter().any(|path| item.matches(path)))
+            .map(|item| item.original.as_str())
+    }
+}
+
+impl Repository {
+    /// The directory the command runs in, from the top of the worktree:
+    /// empty at the top or outside of it.
+    fn cwd_prefix(&self) -> Result<String> {
+        let cwd = env::current_dir()?.canonicalize()?;
+        let top = self.path.canonicalize()?;
+        Ok(match cwd.strip_prefix(&top) {
+            Ok(prefix) => prefix.to_string_lossy().replace('\\', "/"),
+            Err(_) => String::new(),
+        })
+    }
+
+    /// Parse `specs` given on the command line, relative to the current
+    /// directory.
+    pub fn pathspec(&self, specs: &[String]) -> Result<Pathspec> {
+        Pathspec::parse(specs, &self.cwd_prefix()?)
+    }
+
+    /// Whether the tree `new` differs from `old` (or from nothing) in the
+    /// paths `pathspec` matches.
+    pub fn trees_differ(
+        &self,
+        old: Option<&[u8; 20]>,
+        new: &[u8; 20],
+        pathspec: &Pathspec,
+    ) -> Result<bool> {
+        if pathspec.is_empty() {
+            return Ok(old != Some(new));
+        }
+        if let Some(paths) = pathspec.plain_paths() {
+            for path in paths {
+                if path.is_empty() {
+                    return Ok(old != Some(new));
+                }
+                let new_entry = self.tree_lookup(new, path)?.map(|entry| entry.hash);
+                let old_entry = match old {
+                    Some(old) => self.tree_lookup(old, path)?.map(|entry| entry.hash),
+                    None => None,
+                };
+                if new_entry != old_entry {
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+
+        let mut files: HashMap<String, ([u8; 20], &str)> = HashMap::new();
+        if let Some(old) = old {
+            for file in self.flatten_tree(old)? {
+                if pathspec.matches(&file.path) {
+                    files.insert(file.path, (file.hash, file.kind.to_mode()));
+                }
+            }
+        }
+        for file in self.flatten_tree(new)? {
+            if pathspec.matches(&file.path) {
+                let entry = (file.hash, file.kind.to_mode());
+                if files.remove(&file.path) != Some(entry) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(!files.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pathspec(specs: &[&str], prefix: &str) -> Pathspec {
+        let specs: Vec<String> = specs.iter().map(|spec| spec.to_string()).collect();
+        Pathspec::parse(&specs, prefix).unwrap()
+    }
+
+    #[test]
+    fn test_pathspec_matches() {
+        let spec = pathspec(&["src", "*.md"], "");
+        assert!(spec.matches("src/main.rs"));
+        assert!(spec.matches("docs/guide/intro.md"));
+        assert!(!spec.matches("srcs/main.rs"));
+
+        let spec = pathspec(&["../README", "*.rs", ":/Cargo.toml"], "src/bin");
+        assert!(spec.matches("src/README"));
+        assert!(spec.matches("src/bin/tool/main.rs"));
+        assert!(!spec.matches("src/lib.rs"));
+        assert!(spec.matches("Cargo.toml"));
+
+        let spec = pathspec(&[":(icase)docs/", ":!docs/draft.md"], "");
+        assert!(spec.matches("Docs/intro.md"));
+        assert!(!spec.matches("docs/draft.md"));
+        assert!(!spec.matches("docs"));
+
+        let spec = pathspec(&[":(literal)a*"], "");
+        assert!(spec.matches("a*"));
+        assert!(!spec.matches("ab"));
+
+        assert!(pathspec(&[":^target"], "").matches("src/main.rs"));
+        assert!(pathspec(&["."], "").matches("anything"));
+        assert!(Pathspec::parse(&["../..".to_string()], "src").is_err());
+        assert!(Pathspec::parse(&[":(nope)x".to_string()], "").is_err());
+    }
+}
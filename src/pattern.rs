@@ -0,0 +1,243 @@
+/// A single gitignore-style pattern, as used by sparse-checkout, ignore and
+/// attribute files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    pub pattern: String,
+    pub negated: bool,
+    pub dir_only: bool,
+    pub anchored: bool,
+}
+
+impl Pattern {
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+
+        // a slash anywhere but at the end anchors the pattern to its base directory
+        let anchored = line.contains('/');
+        let pattern = line.strip_prefix('/').unwrap_or(line);
+        if pattern.is_empty() {
+            return None;
+        }
+
+        Some(Pattern {
+            pattern: pattern.to_string(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    /// Check whether this pattern matches `path` (relative to the pattern's base
+    /// directory, `/`-separated).
+    pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        if self.anchored {
+            wildmatch(&self.pattern, path)
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            wildmatch(&self.pattern, name)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PatternList {
+    pub patterns: Vec<Pattern>,
+}
+
+impl PatternList {
+    pub fn parse(content: &str) -> Self {
+        PatternList {
+            patterns: content.lines().filter_map(Pattern::parse).collect(),
+        }
+    }
+
+    /// Return the last pattern matching `path` or one of its parent
+    /// directories, following gitignore precedence rules.
+    pub fn matched(&self, path: &str, is_dir: bool) -> Option<&Pattern> {
+        // a file inside an excluded directory cannot be re-included
+        let mut prefix_end = 0;
+        while let Some(pos) = path[prefix_end..].find('/') {
+            let dir = &path[..prefix_end + pos];
+            if let Some(pattern) = self.last_match(dir, true) {
+                if !pattern.negated {
+                    return Some(pattern);
+                }
+            }
+            prefix_end += pos + 1;
+        }
+
+        self.last_match(path, is_dir)
+    }
+
+    fn last_match(&self, path: &str, is_dir: bool) -> Option<&Pattern> {
+        self.patterns.iter().rev().find(|p| p.matches(path, is_dir))
+    }
+
+    /// Check whether `path` is included by the list, i.e. the last matching
+    /// pattern is not a negation.
+    pub fn includes(&self, path: &str, is_dir: bool) -> bool {
+        self.matched(path, is_dir).is_some_and(|p| !p.negated)
+    }
+}
+
+/// Match `text` against a shell glob supporting `*`, `?`, `[...]` and `**`.
+/// Wildcards other than `**` never match a `/`.
+pub fn wildmatch(pattern: &str, text: &str) -> bool {
+    wildmatch_bytes(pattern.as_bytes(), text.as_bytes(), true)
+}
+
+/// Match `text` against a shell glob whose wildcards also match `/`, as
+/// those of pathspecs do.
+pub fn fnmatch(pattern: &str, text: &str) -> bool {
+    wildmatch_bytes(pattern.as_bytes(), text.as_bytes(), false)
+}
+
+/// With `pathname`, wildcards stop at slashes and `**` crosses them.
+fn wildmatch_bytes(pattern: &[u8], text: &[u8], pathname: bool) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' if pathname && pattern.get(p + 1) == Some(&b'*') => {
+                // `**/` matches zero or more directories, a trailing `**` everything
+                let rest = &pattern[p + 2..];
+                let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+                if rest.is_empty() {
+                    return true;
+                }
+                for start in t..=text.len() {
+                    if (start == t || text[start - 1] == b'/')
+                        && wildmatch_bytes(rest, &text[start..], pathname)
+                    {
+                        return true;
+                    }
+                }
+                return false;
+            }
+            b'*' => {
+                let rest = &pattern[p + 1..];
+                for end in t..=text.len() {
+                    if wildmatch_bytes(rest, &text[end..], pathname) {
+                        return true;
+                    }
+                    if pathname && end < text.len() && text[end] == b'/' {
+                        break;
+                    }
+                }
+                return false;
+            }
+            b'?' => {
+                if t >= text.len() || (pathname && text[t] == b'/') {
+                    return false;
+                }
+                p += 1;
+                t += 1;
+            }
+            b'[' => {
+                let Some(close) = pattern[p + 1..].iter().position(|&c| c == b']') else {
+                    // an unterminated class is a literal `[`
+                    if text.get(t) != Some(&b'[') {
+                        return false;
+                    }
+                    p += 1;
+                    t += 1;
+                    continue;
+                };
+                if t >= text.len() || (pathname && text[t] == b'/') {
+                    return false;
+                }
+                let class = &pattern[p + 1..p + 1 + close];
+                let (negated, class) = match class.first() {
+                    Some(b'!') | Some(b'^') => (true, &class[1..]),
+                    _ => (false, class),
+                };
+                if class_matches(class, text[t]) == negated {
+                    return false;
+                }
+                p += close + 2;
+                t += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                if text.get(t) != Some(&pattern[p + 1]) {
+                    return false;
+                }
+                p += 2;
+                t += 1;
+            }
+            c => {
+                if text.get(t) != Some(&c) {
+                    return false;
+                }
+                p += 1;
+                t += 1;
+            }
+        }
+    }
+
+    t == text.len()
+}
+
+fn class_matches(class: &[u8], c: u8) -> bool {
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            if class[i] <= c && c <= class[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildmatch() {
+        assert!(wildmatch("*.rs", "main.rs"));
+        assert!(!wildmatch("*.rs", "src/main.rs"));
+        assert!(wildmatch("src/**/*.rs", "src/main.rs"));
+        assert!(wildmatch("src/**/*.rs", "src/a/b/main.rs"));
+        assert!(wildmatch("**/foo", "a/b/foo"));
+        assert!(wildmatch("a/**", "a/b/c"));
+        assert!(wildmatch("fo?.[a-c]", "foo.b"));
+        assert!(!wildmatch("fo?.[!a-c]", "foo.b"));
+    }
+
+    #[test]
+    fn test_pattern_list() {
+        let list = PatternList::parse("# comment\n*.log\n!keep.log\nbuild/\n/root.txt\n");
+
+        assert!(list.includes("a/debug.log", false));
+        assert!(!list.includes("keep.log", false));
+        assert!(list.includes("build/out.o", false));
+        assert!(!list.includes("build", false));
+        assert!(list.includes("root.txt", false));
+        assert!(!list.includes("sub/root.txt", false));
+    }
+}
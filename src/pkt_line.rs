@@ -0,0 +1,29 @@
+use std::io::Read;
+
+use anyhow::{anyhow, Result};
+
+/// Frame `data` as a pkt-line.
+pub fn packet_line(data: &str) -> Vec<u8> {
+    let length = format!("{:04x}", data.len() + 4);
+    let mut line = Vec::new();
+    line.extend_from_slice(length.as_bytes());
+    line.extend_from_slice(data.as_bytes());
+    line
+}
+
+/// Read a pkt-line, or `None` for a flush packet.
+pub fn read_pkt_line<R: Read>(input: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut length = [0; 4];
+    input.read_exact(&mut length)?;
+    let length = usize::from_str_radix(std::str::from_utf8(&length)?, 16)?;
+    if length == 0 {
+        return Ok(None);
+    }
+    if length < 4 {
+        return Err(anyhow!("invalid packet length {}", length));
+    }
+
+    let mut data = vec![0; length - 4];
+    input.read_exact(&mut data)?;
+    Ok(Some(data))
+}
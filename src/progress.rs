@@ -0,0 +1,138 @@
+use std::{
+    io::{self, IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{repository::Repository, rev_list::human_size};
+
+/// Between two reports of a count without a total.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// A phase of a long command reported on stderr as git does, e.g.
+/// `Receiving objects:  42% (42/100)`, rewritten in place and ended with
+/// `, done.`. Reports are throttled, and nothing is shown when disabled.
+pub struct Progress {
+    title: &'static str,
+    total: Option<u64>,
+    count: u64,
+    /// Whether the count is a number of bytes, shown as a size
+    bytes: bool,
+    enabled: bool,
+    start: Instant,
+    /// Until when nothing is shown, so that quick phases stay quiet
+    delay: Duration,
+    shown: bool,
+    last_percent: Option<u64>,
+    last_shown: Option<Instant>,
+}
+
+impl Progress {
+    /// Report `title` out of `total` items, if known.
+    pub fn new(title: &'static str, total: Option<u64>, enabled: bool) -> Self {
+        Progress {
+            title,
+            total,
+            count: 0,
+            bytes: false,
+            enabled,
+            start: Instant::now(),
+            delay: Duration::ZERO,
+            shown: false,
+            last_percent: None,
+            last_shown: None,
+        }
+    }
+
+    /// Report a number of bytes instead of items.
+    #[cfg(feature = "http")]
+    pub fn bytes(mut self) -> Self {
+        self.bytes = true;
+        self
+    }
+
+    /// Show nothing before `delay` has passed.
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn tick(&mut self) {
+        self.update(self.count + 1);
+    }
+
+    pub fn update(&mut self, count: u64) {
+        self.count = count;
+        if !self.enabled || self.start.elapsed() < self.delay {
+            return;
+        }
+        match self.percent() {
+            Some(percent) if self.last_percent == Some(percent) => return,
+            Some(percent) => self.last_percent = Some(percent),
+            None => {
+                if self
+                    .last_shown
+                    .is_some_and(|last| last.elapsed() < INTERVAL)
+                {
+                    return;
+                }
+            }
+        }
+        self.show("");
+    }
+
+    /// End the phase, reporting its final count if anything was shown or
+    /// the phase outlasted its delay.
+    pub fn finish(mut self) {
+        if !self.enabled || (!self.shown && self.start.elapsed() < self.delay) {
+            return;
+        }
+        self.last_percent = self.percent();
+        self.show(", done.\n");
+    }
+
+    fn percent(&self) -> Option<u64> {
+        self.total
+            .map(|total| self.count * 100 / total.max(1))
+            .map(|percent| percent.min(100))
+    }
+
+    fn show(&mut self, end: &str) {
+        let count = if self.bytes {
+            human_size(self.count)
+        } else {
+            self.count.to_string()
+        };
+        let line = match (self.total, self.last_percent) {
+            (Some(total), Some(percent)) => {
+                format!("{}: {:3}% ({}/{})", self.title, percent, count, total)
+            }
+            _ => format!("{}: {}", self.title, count),
+        };
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r{}{}", line, end);
+        let _ = stderr.flush();
+        self.shown = true;
+        self.last_shown = Some(Instant::now());
+    }
+}
+
+/// Messages of the server on the progress side band, each line prefixed
+/// with `remote: ` as git does, carriage returns kept for the counts it
+/// rewrites in place.
+#[cfg(feature = "http")]
+pub fn print_remote_progress(message: &[u8]) {
+    let mut stderr = io::stderr().lock();
+    for line in message.split_inclusive(|&c| c == b'\n' || c == b'\r') {
+        let _ = stderr.write_all(b"remote: ");
+        let _ = stderr.write_all(line);
+    }
+    let _ = stderr.flush();
+}
+
+impl Repository {
+    /// Whether long commands report their progress: as `--progress` or
+    /// `--quiet` say, else when stderr is a terminal.
+    pub fn show_progress(&self) -> bool {
+        self.progress.unwrap_or_else(|| io::stderr().is_terminal())
+    }
+}
@@ -0,0 +1,82 @@
+#[cfg(feature = "http")]
+use std::thread;
+
+use anyhow::{anyhow, Result};
+
+use crate::repository::Repository;
+#[cfg(feature = "http")]
+use crate::{http::fetch_pack, shallow::ShallowRequest};
+
+impl Repository {
+    /// The url of the remote promising to provide objects missing from a
+    /// partial clone, if any.
+    pub fn promisor_remote(&self) -> Result<Option<String>> {
+        let config = self.config()?;
+
+        for remote in config.subsections("remote") {
+            if config.get_bool(&format!("remote.{}.promisor", remote)) == Some(true) {
+                return Ok(config.get(&format!("remote.{}.url", remote)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Fetch the given objects from the promisor remote and keep the pack
+    /// they come in, marked as a promisor pack as git does.
+    #[cfg(feature = "http")]
+    pub fn fetch_missing_objects(&self, hashes: &[String]) -> Result<()> {
+        let url = self
+            .promisor_remote()?
+            .ok_or_else(|| anyhow!("no promisor remote configured"))?;
+
+        // object reads are synchronous and may happen within any runtime,
+        // so the transport runs to completion on a thread and runtime of its own
+        let options = self.transport_options(None)?;
+        let shallow = ShallowRequest::default();
+        let (_, pack_data, _) = thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?
+                        .block_on(fetch_pack(&url, hashes, &[], &shallow, None, &options))
+                })
+                .join()
+        })
+        .map_err(|_| anyhow!("the fetch of missing objects panicked"))??;
+
+        let (temp, idx) = self.receive_pack(pack_data.as_slice())?;
+        self.install_pack(&temp, &idx, true)?;
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "http"))]
+    pub fn fetch_missing_objects(&self, hashes: &[String]) -> Result<()> {
+        Err(anyhow!(
+            "cannot fetch {} missing object(s): mg was built without http support",
+            hashes.len()
+        ))
+    }
+
+    /// Fetch, in a single request, the objects among `hashes` which are not
+    /// present locally. This is a no-op outside of partial clones.
+    pub fn prefetch_objects(&self, hashes: &[[u8; 20]]) -> Result<()> {
+        if self.promisor_remote()?.is_none() {
+            return Ok(());
+        }
+
+        let missing: Vec<String> = hashes
+            .iter()
+            .filter(|hash| !self.has_object(hash))
+            .map(hex::encode)
+            .collect();
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        self.fetch_missing_objects(&missing)
+    }
+}
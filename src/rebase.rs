@@ -0,0 +1,566 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    apply::{apply_hunks, Hunk},
+    diff::{make_hunks, DiffLine, DiffTarget},
+    ident::Role,
+    kind::Kind,
+    repository::Repository,
+};
+
+/// What to do with a commit of the todo list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TodoAction {
+    Pick,
+    /// Pick the commit and edit its message
+    Reword,
+    /// Meld the commit into the previous one, dropping its message
+    Fixup,
+    /// Meld the commit into the previous one, editing both messages
+    Squash,
+    Drop,
+}
+
+impl TodoAction {
+    fn parse(word: &str) -> Option<Self> {
+        match word {
+            "pick" | "p" => Some(TodoAction::Pick),
+            "reword" | "r" => Some(TodoAction::Reword),
+            "fixup" | "f" => Some(TodoAction::Fixup),
+            "squash" | "s" => Some(TodoAction::Squash),
+            "drop" | "d" => Some(TodoAction::Drop),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            TodoAction::Pick => "pick",
+            TodoAction::Reword => "reword",
+            TodoAction::Fixup => "fixup",
+            TodoAction::Squash => "squash",
+            TodoAction::Drop => "drop",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TodoItem {
+    pub action: TodoAction,
+    pub commit: [u8; 20],
+    pub subject: String,
+}
+
+const TODO_HELP: &str = "\
+#
+# Commands:
+# p, pick <commit> = use commit
+# r, reword <commit> = use commit, but edit the commit message
+# s, squash <commit> = use commit, but meld into previous commit
+# f, fixup <commit> = like \"squash\", but discard this commit's log message
+# d, drop <commit> = remove commit
+#
+# These lines can be re-ordered; they are executed from top to bottom.
+# If you remove everything, the rebase will be aborted.
+";
+
+/// The action a `fixup! ` or `squash! ` subject asks for, and the subject
+/// of the commit it amends. Fixups of fixups amend the same commit.
+fn autosquash_target(subject: &str) -> Option<(TodoAction, &str)> {
+    let (action, mut target) = if let Some(rest) = subject.strip_prefix("fixup! ") {
+        (TodoAction::Fixup, rest)
+    } else if let Some(rest) = subject.strip_prefix("squash! ") {
+        (TodoAction::Squash, rest)
+    } else {
+        return None;
+    };
+    while let Some(rest) = target
+        .strip_prefix("fixup! ")
+        .or_else(|| target.strip_prefix("squash! "))
+    {
+        target = rest;
+    }
+
+    Some((action, target))
+}
+
+/// Move the `fixup!` and `squash!` commits right after the earlier commit
+/// they amend, found by subject or by id prefix, turning their `pick` into
+/// `fixup` or `squash`.
+pub fn autosquash(todo: &[TodoItem]) -> Vec<TodoItem> {
+    let mut targets: Vec<Option<(usize, TodoAction)>> = Vec::with_capacity(todo.len());
+    for (i, item) in todo.iter().enumerate() {
+        let target = autosquash_target(&item.subject).and_then(|(action, subject)| {
+            let is_target = |j: &usize| {
+                targets[*j].is_none()
+                    && (todo[*j].subject == subject
+                        || (subject.len() >= 4
+                            && hex::encode(todo[*j].commit).starts_with(subject)))
+            };
+            (0..i).find(is_target).map(|j| (j, action))
+        });
+        targets.push(target);
+    }
+
+    let mut result = Vec::with_capacity(todo.len());
+    for (i, item) in todo.iter().enumerate() {
+        if targets[i].is_some() {
+            continue;
+        }
+        result.push(item.clone());
+        for (j, fixup) in todo.iter().enumerate().skip(i + 1) {
+            if let Some((target, action)) = targets[j] {
+                if target == i {
+                    let action = match fixup.action {
+                        TodoAction::Pick => action,
+                        other => other,
+                    };
+                    result.push(TodoItem {
+                        action,
+                        ..fixup.clone()
+                    });
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// The mode and object id of every file of a tree, by path.
+type Files = BTreeMap<String, (String, [u8; 20])>;
+
+impl Repository {
+    /// Replay the commits of the current branch which are not in `upstream`
+    /// on top of it, then check out the result. With `interactive`, the
+    /// todo list is edited first; with `autosquash`, `fixup!` and `squash!`
+    /// commits are moved after the commit they amend. Nothing is changed
+    /// if a commit does not apply.
+    pub fn rebase(
+        &self,
+        upstream: &str,
+        interactive: bool,
+        autosquash: bool,
+        override_protection: bool,
+    ) -> Result<()> {
+        let head = self.current_commit()?;
+        let onto = self.resolve_revision(upstream)?;
+
+        let head_tree = DiffTarget::Tree(self.commit_tree(&head)?);
+        if !self.diff_targets(head_tree, DiffTarget::Index)?.is_empty()
+            || !self
+                .diff_targets(DiffTarget::Index, DiffTarget::Worktree)?
+                .is_empty()
+        {
+            return Err(anyhow!("cannot rebase: you have uncommitted changes"));
+        }
+
+        let mut todo = self.rebase_todo(&head, &onto)?;
+        // replaying no commit only fast-forwards the branch
+        if !todo.is_empty() {
+            self.check_current_protection("rebase", override_protection)?;
+        }
+        if autosquash {
+            todo = self::autosquash(&todo);
+        }
+        if interactive {
+            todo = self.edit_todo(&todo)?;
+            if todo.is_empty() {
+                return Err(anyhow!("nothing to do"));
+            }
+        }
+
+        let mut current = onto;
+        let mut picked = false;
+        for item in &todo {
+            current = self.replay(item, &current, picked)?;
+            picked |= item.action != TodoAction::Drop;
+        }
+
+        self.materialize_tree(&self.commit_tree(&current)?, false)?;
+        self.set_current_commit(&current)?;
+        let message = format!("rebase (finish): onto {}", hex::encode(onto));
+        if self.read_head()?.starts_with("ref: ") {
+            let branch = format!("refs/heads/{}", self.current_branch()?);
+            self.append_reflog(&branch, Some(&head), &current, &message)?;
+            println!("Successfully rebased and updated {}.", branch);
+        }
+        self.append_reflog("HEAD", Some(&head), &current, &message)?;
+
+        Ok(())
+    }
+
+    /// The commits from `head` back to `onto` or its ancestors, oldest
+    /// first, each to be picked.
+    fn rebase_todo(&self, head: &[u8; 20], onto: &[u8; 20]) -> Result<Vec<TodoItem>> {
+        let upstream = self.ancestors(onto)?;
+        let mut todo = Vec::new();
+
+        let mut commit = *head;
+        while !upstream.contains(&commit) {
+            let parents = self.commit_parents(&commit)?;
+            if parents.len() > 1 {
+                return Err(anyhow!(
+                    "cannot rebase the merge commit {}",
+                    hex::encode(commit)
+                ));
+            }
+            let message = self.commit_message(&commit)?;
+            todo.push(TodoItem {
+                action: TodoAction::Pick,
+                commit,
+                subject: message.lines().next().unwrap_or_default().to_string(),
+            });
+            match parents.first() {
+                Some(parent) => commit = *parent,
+                None => break,
+            }
+        }
+        todo.reverse();
+
+        Ok(todo)
+    }
+
+    /// Let the user edit the todo list in `.git/rebase-merge/git-rebase-todo`.
+    fn edit_todo(&self, todo: &[TodoItem]) -> Result<Vec<TodoItem>> {
+        let dir = self.git_dir.join("rebase-merge");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("git-rebase-todo");
+
+        let mut content = String::new();
+        for item in todo {
+            content.push_str(&format!(
+                "{} {} {}\n",
+                item.action.name(),
+                &hex::encode(item.commit)[..7],
+                item.subject
+            ));
+        }
+        content.push_str(TODO_HELP);
+        std::fs::write(&path, content)?;
+
+        let edited = self.edit_file(&path);
+        let content = std::fs::read_to_string(&path);
+        std::fs::remove_dir_all(&dir)?;
+        edited?;
+
+        let mut todo = Vec::new();
+        for line in content?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(3, char::is_whitespace);
+            let (Some(action), Some(commit)) = (fields.next(), fields.next()) else {
+                return Err(anyhow!("invalid line in the todo list: {}", line));
+            };
+            let action = TodoAction::parse(action)
+                .ok_or_else(|| anyhow!("unknown command '{}' in the todo list", action))?;
+            todo.push(TodoItem {
+                action,
+                commit: self.resolve_revision(commit)?,
+                subject: fields.next().unwrap_or_default().to_string(),
+            });
+        }
+
+        Ok(todo)
+    }
+
+    /// Apply the todo `item` on top of `current`, returning the new tip.
+    /// `picked` tells whether `current` was made by the rebase, and so may
+    /// be melded with.
+    fn replay(&self, item: &TodoItem, current: &[u8; 20], picked: bool) -> Result<[u8; 20]> {
+        let short = &hex::encode(item.commit)[..7];
+        let parents = self.commit_parents(&item.commit)?;
+        let message = self.commit_message(&item.commit)?;
+
+        let tree = match item.action {
+            TodoAction::Drop => return Ok(*current),
+            TodoAction::Pick if parents.first() == Some(current) => return Ok(item.commit),
+            _ => self
+                .pick_tree(&self.commit_tree(current)?, &item.commit)
+                .map_err(|e| anyhow!("could not apply {}... {}: {}", short, item.subject, e))?,
+        };
+
+        match item.action {
+            TodoAction::Fixup | TodoAction::Squash => {
+                if !picked {
+                    return Err(anyhow!(
+                        "cannot '{}' without a previous commit",
+                        item.action.name()
+                    ));
+                }
+                let mut melded = self.commit_message(current)?;
+                if item.action == TodoAction::Squash {
+                    // the `squash! <subject>` line only says where it goes
+                    let body = match autosquash_target(&item.subject) {
+                        Some(_) => message.split_once('\n').map_or("", |(_, body)| body),
+                        None => &message,
+                    };
+                    melded = format!("{}\n\n{}", melded, body.trim_start());
+                    melded = self.edit_message(&format!("{}\n", melded))?;
+                }
+                let author = self.commit_author(current)?;
+                self.write_rebased_commit(&tree, &self.commit_parents(current)?, &author, &melded)
+            }
+            _ => {
+                let message = match item.action {
+                    TodoAction::Reword => self.edit_message(&format!("{}\n", message))?,
+                    _ => message,
+                };
+                let author = self.commit_author(&item.commit)?;
+                self.write_rebased_commit(&tree, &[*current], &author, &message)
+            }
+        }
+    }
+
+    /// The `author` header of a commit.
+    fn commit_author(&self, hash: &[u8; 20]) -> Result<String> {
+        Ok(self.read_commit(hash)?.author.to_string())
+    }
+
+    pub fn write_rebased_commit(
+        &self,
+        tree: &[u8; 20],
+        parents: &[[u8; 20]],
+        author: &str,
+        message: &str,
+    ) -> Result<[u8; 20]> {
+        let mut out = format!("tree {}\n", hex::encode(tree));
+        for parent in parents {
+            out.push_str(&format!("parent {}\n", hex::encode(parent)));
+        }
+        out.push_str(&format!("author {}\n", author));
+        out.push_str(&format!("committer {}\n", self.identity(Role::Committer)?));
+        out.push_str(&format!("\n{}\n", message));
+
+        self.write_object(Kind::Commit, out.as_bytes())
+    }
+
+    /// The tree `ours` with the changes `commit` made to its first parent.
+    /// Files changed on both sides are merged when the hunks of `commit`
+    /// still apply, and are a conflict otherwise.
+    fn pick_tree(&self, ours: &[u8; 20], commit: &[u8; 20]) -> Result<[u8; 20]> {
+        let base = match self.commit_parents(commit)?.first() {
+            Some(parent) => Some(self.commit_tree(parent)?),
+            None => None,
+        };
+        self.merge_trees(base.as_ref(), ours, &self.commit_tree(commit)?)
+    }
+
+    /// The tree `ours` with the changes from `base` (the empty tree if
+    /// none) to `theirs`, merging the files changed on both sides.
+    pub fn merge_trees(
+        &self,
+        base: Option<&[u8; 20]>,
+        ours: &[u8; 20],
+        theirs: &[u8; 20],
+    ) -> Result<[u8; 20]> {
+        let base = match base {
+            Some(base) => self.tree_files(base)?,
+            None => Files::new(),
+        };
+        let theirs = self.tree_files(theirs)?;
+        let mut result = self.tree_files(ours)?;
+        let mut attributes = self.attributes()?;
+
+        let mut paths: Vec<&String> = base.keys().chain(theirs.keys()).collect();
+        paths.sort();
+        paths.dedup();
+        for path in paths {
+            let (base, theirs) = (base.get(path), theirs.get(path));
+            if base == theirs {
+                continue;
+            }
+            let ours = result.get(path);
+            if ours == theirs {
+                continue;
+            }
+            if ours == base {
+                match theirs {
+                    Some(theirs) => result.insert(path.clone(), theirs.clone()),
+                    None => result.remove(path),
+                };
+                continue;
+            }
+
+            let merged = match (base, theirs, ours) {
+                (Some(base), Some(theirs), Some(ours))
+                    if [&base.0, &theirs.0, &ours.0]
+                        .iter()
+                        .all(|mode| mode.starts_with("100")) =>
+                {
+                    self.merge_file(&mut attributes, path, &base.1, &ours.1, &theirs.1)?
+                        .map(|content| (theirs.0.clone(), content))
+                }
+                _ => None,
+            };
+            let Some((mode, content)) = merged else {
+                return Err(anyhow!("conflict in {}", path));
+            };
+            let hash = self.write_object(Kind::Blob(false), &content)?;
+            result.insert(path.clone(), (mode, hash));
+        }
+
+        let files: Vec<(&str, &str, [u8; 20])> = result
+            .iter()
+            .map(|(path, (mode, hash))| (path.as_str(), mode.as_str(), *hash))
+            .collect();
+        self.write_tree_of_files(&files)
+    }
+
+    fn tree_files(&self, tree: &[u8; 20]) -> Result<Files> {
+        Ok(self
+            .flatten_tree(tree)?
+            .into_iter()
+            .map(|file| (file.path, (file.kind.to_mode().to_string(), file.hash)))
+            .collect())
+    }
+
+    /// Apply the changes from `base` to `theirs` to `ours`, if they apply.
+    pub fn merge_blob(
+        &self,
+        base: &[u8; 20],
+        theirs: &[u8; 20],
+        ours: &[u8; 20],
+    ) -> Result<Option<Vec<u8>>> {
+        let read = |hash: &[u8; 20]| -> Result<Vec<u8>> {
+            self.read_object(&hex::encode(hash))?.content()
+        };
+        let (base, theirs, ours) = (read(base)?, read(theirs)?, read(ours)?);
+        if [&base, &theirs, &ours]
+            .iter()
+            .any(|content| content.contains(&0))
+        {
+            return Ok(None);
+        }
+        let (base, theirs, ours) = (
+            String::from_utf8_lossy(&base),
+            String::from_utf8_lossy(&theirs),
+            String::from_utf8_lossy(&ours),
+        );
+
+        let base_lines: Vec<&str> = base.lines().collect();
+        let their_lines: Vec<&str> = theirs.lines().collect();
+        let hunks: Vec<Hunk> = make_hunks(&base_lines, &their_lines, 3)
+            .into_iter()
+            .map(|hunk| Hunk {
+                header: hunk.header(),
+                old_start: hunk.old_start,
+                old_count: hunk.old_count,
+                new_count: hunk.new_count,
+                lines: hunk
+                    .lines
+                    .into_iter()
+                    .map(|line| match line {
+                        DiffLine::Context(line) => (' ', line),
+                        DiffLine::Removed(line) => ('-', line),
+                        DiffLine::Added(line) => ('+', line),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let our_lines: Vec<String> = ours.lines().map(str::to_string).collect();
+        let (lines, rejected) = apply_hunks(&our_lines, &hunks);
+        if !rejected.is_empty() {
+            return Ok(None);
+        }
+
+        let trailing_newline = if base.ends_with('\n') != theirs.ends_with('\n') {
+            theirs.ends_with('\n')
+        } else {
+            ours.ends_with('\n')
+        };
+        let mut merged = lines.join("\n");
+        if trailing_newline && !lines.is_empty() {
+            merged.push('\n');
+        }
+
+        Ok(Some(merged.into_bytes()))
+    }
+
+    /// Write the trees of `files`, given as `(path, mode, id)` sorted by
+    /// path, and return the root tree id.
+    pub fn write_tree_of_files(&self, files: &[(&str, &str, [u8; 20])]) -> Result<[u8; 20]> {
+        let mut out = Vec::new();
+        let mut i = 0;
+
+        while i < files.len() {
+            let (path, mode, hash) = files[i];
+            let (mode, name, hash) = match path.split_once('/') {
+                None => {
+                    i += 1;
+                    (mode, path, hash)
+                }
+                Some((dir, _)) => {
+                    let prefix = format!("{}/", dir);
+                    let end = i + files[i..]
+                        .iter()
+                        .take_while(|(path, _, _)| path.starts_with(&prefix))
+                        .count();
+                    let children: Vec<(&str, &str, [u8; 20])> = files[i..end]
+                        .iter()
+                        .map(|(path, mode, hash)| (&path[prefix.len()..], *mode, *hash))
+                        .collect();
+                    i = end;
+                    (
+                        Kind::Tree.to_mode(),
+                        dir,
+                        self.write_tree_of_files(&children)?,
+                    )
+                }
+            };
+
+            out.extend_from_slice(mode.as_bytes());
+            out.push(b' ');
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+            out.extend_from_slice(&hash);
+        }
+
+        self.write_object(Kind::Tree, &out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(n: u8, subject: &str) -> TodoItem {
+        TodoItem {
+            action: TodoAction::Pick,
+            commit: [n; 20],
+            subject: subject.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_autosquash() {
+        let todo = vec![
+            item(1, "Add parser"),
+            item(2, "Add lexer"),
+            item(3, "fixup! Add parser"),
+            item(4, "squash! fixup! Add parser"),
+            item(5, "fixup! 0202"),
+            item(6, "fixup! Unknown"),
+        ];
+        let result: Vec<(u8, TodoAction)> = autosquash(&todo)
+            .iter()
+            .map(|item| (item.commit[0], item.action))
+            .collect();
+        assert_eq!(
+            result,
+            vec![
+                (1, TodoAction::Pick),
+                (3, TodoAction::Fixup),
+                (4, TodoAction::Squash),
+                (2, TodoAction::Pick),
+                (5, TodoAction::Fixup),
+                (6, TodoAction::Pick),
+            ]
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use std::{
+    fs::{create_dir_all, OpenOptions},
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{ident::Role, repository::Repository};
+
+impl Repository {
+    /// Record in the reflog of `ref_name` (`HEAD` or a full ref name) that it
+    /// moved from `old` (`None` for a new ref) to `new`.
+    pub fn append_reflog(
+        &self,
+        ref_name: &str,
+        old: Option<&[u8; 20]>,
+        new: &[u8; 20],
+        message: &str,
+    ) -> Result<()> {
+        let path = self.git_dir.join("logs").join(ref_name);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let identity = match self.identity(Role::Committer) {
+            Ok(identity) => identity,
+            Err(_) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                format!("unknown <unknown> {} +0000", now)
+            }
+        };
+        let old = old.map(hex::encode).unwrap_or_else(|| "0".repeat(40));
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(
+            file,
+            "{} {} {}\t{}",
+            old,
+            hex::encode(new),
+            identity,
+            message.lines().next().unwrap_or("")
+        )?;
+
+        Ok(())
+    }
+}
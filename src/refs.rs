@@ -0,0 +1,421 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{create_dir_all, read_to_string, remove_file},
+    io::Write,
+};
+
+use anyhow::{anyhow, Result};
+use hex::FromHex;
+
+use crate::{
+    error::MgError, kind::Kind, lockfile::LockFile, pack_index::PackIndex, repository::Repository,
+    shared::adjust_shared_perm,
+};
+
+/// The name of a ref as shown to users: `main` for `refs/heads/main`,
+/// `origin/main` for `refs/remotes/origin/main` and `v1` for `refs/tags/v1`.
+pub fn short_ref_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+impl Repository {
+    /// Read a ref by its full name (e.g. `refs/heads/main`), looking at loose
+    /// refs first and then at `packed-refs`.
+    pub fn read_ref(&self, name: &str) -> Result<Option<[u8; 20]>> {
+        let ref_path = self.git_dir.join(name);
+        if ref_path.is_file() {
+            let content = read_to_string(ref_path)?;
+            let content = content.trim();
+
+            if let Some(target) = content.strip_prefix("ref: ") {
+                return self.read_ref(target);
+            }
+
+            return Ok(Some(<[u8; 20]>::from_hex(content)?));
+        }
+
+        let packed_refs_path = self.git_dir.join("packed-refs");
+        if !packed_refs_path.exists() {
+            return Ok(None);
+        }
+
+        for line in read_to_string(packed_refs_path)?.lines() {
+            if line.starts_with('#') || line.starts_with('^') {
+                continue;
+            }
+
+            if let Some((hash, ref_name)) = line.split_once(' ') {
+                if ref_name == name {
+                    return Ok(Some(<[u8; 20]>::from_hex(hash)?));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The ref a symbolic ref such as `refs/remotes/origin/HEAD` points to,
+    /// or `None` if `name` is not a symbolic ref.
+    pub fn read_symbolic_ref(&self, name: &str) -> Result<Option<String>> {
+        let ref_path = self.git_dir.join(name);
+        if !ref_path.is_file() {
+            return Ok(None);
+        }
+
+        let content = read_to_string(ref_path)?;
+        Ok(content
+            .trim()
+            .strip_prefix("ref: ")
+            .map(|target| target.to_string()))
+    }
+
+    /// The names to decorate commits with, by commit: `HEAD -> <branch>` (or
+    /// `HEAD` when detached), then the branches, remote-tracking branches
+    /// and tags (as `tag: <name>`) pointing at them.
+    pub fn decorations(&self) -> Result<HashMap<[u8; 20], Vec<String>>> {
+        let mut decorations: HashMap<[u8; 20], Vec<String>> = HashMap::new();
+
+        let head = self.read_head()?;
+        let head_branch = head.trim().strip_prefix("ref: ").map(String::from);
+        if let Some(commit) = self.head_commit()? {
+            let name = match &head_branch {
+                Some(branch) => format!("HEAD -> {}", short_ref_name(branch)),
+                None => "HEAD".to_string(),
+            };
+            decorations.entry(commit).or_default().push(name);
+        }
+
+        for (name, mut hash) in self.list_refs("refs/")? {
+            if head_branch.as_deref() == Some(name.as_str()) {
+                continue;
+            }
+            let decoration = if name.starts_with("refs/tags/") {
+                while let Some(target) = self.peel_tag(&hash)? {
+                    hash = target;
+                }
+                format!("tag: {}", short_ref_name(&name))
+            } else if name.starts_with("refs/heads/") || name.starts_with("refs/remotes/") {
+                short_ref_name(&name).to_string()
+            } else {
+                continue;
+            };
+            decorations.entry(hash).or_default().push(decoration);
+        }
+
+        Ok(decorations)
+    }
+
+    /// Point a ref at an object, creating it if needed.
+    pub fn update_ref(&self, name: &str, hash: &[u8; 20]) -> Result<()> {
+        let git_dir = self.git_dir.clone();
+        let ref_path = git_dir.join(name);
+        let shared = self.shared_mode()?;
+        if let Some(parent) = ref_path.parent() {
+            if !parent.is_dir() {
+                create_dir_all(parent)?;
+                for dir in parent.ancestors().take_while(|dir| *dir != git_dir) {
+                    adjust_shared_perm(dir, shared)?;
+                }
+            }
+        }
+
+        let mut lock = LockFile::acquire(&ref_path)?;
+        writeln!(lock, "{}", hex::encode(hash))?;
+        lock.commit()?;
+        adjust_shared_perm(&ref_path, shared)?;
+        if name.starts_with("refs/replace/") {
+            self.forget_replacements();
+        }
+
+        Ok(())
+    }
+
+    /// Point the symbolic ref `name`, such as `HEAD`, at the ref `target`.
+    pub fn update_symbolic_ref(&self, name: &str, target: &str) -> Result<()> {
+        let mut lock = LockFile::acquire(&self.git_dir.join(name))?;
+        writeln!(lock, "ref: {}", target)?;
+        lock.commit()
+    }
+
+    /// Delete a ref, both as a loose file and from `packed-refs`.
+    pub fn delete_ref(&self, name: &str) -> Result<()> {
+        if name.starts_with("refs/replace/") {
+            self.forget_replacements();
+        }
+        let ref_path = self.git_dir.join(name);
+        if ref_path.is_file() {
+            // held while the ref goes, so that no writer races the removal
+            let _lock = LockFile::acquire(&ref_path)?;
+            remove_file(ref_path)?;
+        }
+
+        let packed_refs_path = self.git_dir.join("packed-refs");
+        if !packed_refs_path.exists() {
+            return Ok(());
+        }
+        let mut lock = LockFile::acquire(&packed_refs_path)?;
+
+        // drop the ref line along with its peeled `^` line
+        let mut content = String::new();
+        let mut skipping = false;
+        for line in read_to_string(&packed_refs_path)?.lines() {
+            if line.starts_with('^') && skipping {
+                continue;
+            }
+            skipping = line
+                .split_once(' ')
+                .is_some_and(|(_, ref_name)| ref_name == name);
+            if !skipping {
+                content.push_str(line);
+                content.push('\n');
+            }
+        }
+        lock.write_all(content.as_bytes())?;
+        lock.commit()?;
+
+        Ok(())
+    }
+
+    /// List the refs under a prefix such as `refs/heads/`, sorted by name.
+    pub fn list_refs(&self, prefix: &str) -> Result<Vec<(String, [u8; 20])>> {
+        let mut refs = std::collections::BTreeMap::new();
+
+        let packed_refs_path = self.git_dir.join("packed-refs");
+        if packed_refs_path.exists() {
+            for line in read_to_string(packed_refs_path)?.lines() {
+                if line.starts_with('#') || line.starts_with('^') {
+                    continue;
+                }
+                if let Some((hash, name)) = line.split_once(' ') {
+                    if name.starts_with(prefix) {
+                        refs.insert(name.to_string(), <[u8; 20]>::from_hex(hash)?);
+                    }
+                }
+            }
+        }
+
+        let git_dir = self.git_dir.clone();
+        let ref_dir = git_dir.join(prefix);
+        if ref_dir.is_dir() {
+            for entry in walkdir::WalkDir::new(&ref_dir) {
+                let entry = entry?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let name = entry
+                    .path()
+                    .strip_prefix(&git_dir)?
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                if let Some(hash) = self.read_ref(&name)? {
+                    refs.insert(name, hash);
+                }
+            }
+        }
+
+        Ok(refs.into_iter().collect())
+    }
+
+    /// Resolve a revision (`HEAD`, a ref name, a branch, a tag, a
+    /// remote-tracking branch such as `origin/main`, a remote standing for
+    /// its `HEAD`, or a full or abbreviated object id) to an object id.
+    /// `<rev>:<path>` names a file or directory of a commit or tree, and
+    /// `:<path>` a file of the index.
+    pub fn resolve_revision(&self, rev: &str) -> Result<[u8; 20]> {
+        if let Some((rev, path)) = rev.split_once(':') {
+            return self.resolve_path_revision(rev, path);
+        }
+
+        if rev == "HEAD" || rev == "@" {
+            return self.current_commit();
+        }
+
+        if rev.len() == 40 {
+            if let Ok(hash) = <[u8; 20]>::from_hex(rev) {
+                return Ok(hash);
+            }
+        }
+
+        let mut found: Vec<(String, [u8; 20])> = Vec::new();
+        for candidate in [
+            rev.to_string(),
+            format!("refs/{}", rev),
+            format!("refs/tags/{}", rev),
+            format!("refs/heads/{}", rev),
+            format!("refs/remotes/{}", rev),
+            format!("refs/remotes/{}/HEAD", rev),
+        ] {
+            if let Some(hash) = self.read_ref(&candidate)? {
+                found.push((candidate, hash));
+            }
+        }
+        if let Some((_, hash)) = found.first() {
+            if found.iter().any(|(_, other)| other != hash) {
+                return Err(ambiguous_ref(rev, &found));
+            }
+            return Ok(*hash);
+        }
+
+        if rev.len() >= 4 && rev.chars().all(|c| c.is_ascii_hexdigit()) {
+            return self.resolve_abbreviated(&rev.to_lowercase());
+        }
+
+        Err(MgError::InvalidRef(rev.to_string()).into())
+    }
+
+    fn resolve_path_revision(&self, rev: &str, path: &str) -> Result<[u8; 20]> {
+        let path = path.trim_start_matches("./").trim_end_matches('/');
+        if rev.is_empty() {
+            return self
+                .load_index()?
+                .entries
+                .into_iter()
+                .find(|entry| entry.file_path == path)
+                .map(|entry| entry.sha1)
+                .ok_or_else(|| anyhow!("path '{}' is not in the index", path));
+        }
+
+        let hash = self.resolve_revision(rev)?;
+        let tree = match self.read_object(&hex::encode(hash))?.kind() {
+            Kind::Tree => hash,
+            _ => self.commit_tree(&hash)?,
+        };
+        if path.is_empty() {
+            return Ok(tree);
+        }
+
+        self.tree_lookup(&tree, path)?
+            .map(|entry| entry.hash)
+            .ok_or_else(|| anyhow!("path '{}' does not exist in '{}'", path, rev))
+    }
+
+    /// Resolve `A..B` to `(A, B)` and `A...B` to `(merge base of A and B, B)`,
+    /// a missing side standing for HEAD. Returns `None` if `rev` is not a range.
+    pub fn resolve_range(&self, rev: &str) -> Result<Option<([u8; 20], [u8; 20])>> {
+        let (from, to, symmetric) = if let Some((from, to)) = rev.split_once("...") {
+            (from, to, true)
+        } else if let Some((from, to)) = rev.split_once("..") {
+            (from, to, false)
+        } else {
+            return Ok(None);
+        };
+
+        let from = self.resolve_revision(if from.is_empty() { "HEAD" } else { from })?;
+        let to = self.resolve_revision(if to.is_empty() { "HEAD" } else { to })?;
+
+        if !symmetric {
+            return Ok(Some((from, to)));
+        }
+
+        match self.merge_bases(&from, &to)?.first() {
+            Some(base) => Ok(Some((*base, to))),
+            None => Err(anyhow!("{}: no merge base", rev)),
+        }
+    }
+
+    /// The objects whose id starts with the hex digits `prefix`, loose or
+    /// packed, in any object directory.
+    pub fn objects_with_prefix(&self, prefix: &str) -> Result<Vec<[u8; 20]>> {
+        let mut matches = BTreeSet::new();
+        for objects in self.object_dirs()? {
+            let object_dir = objects.join(&prefix[..2]);
+            if !object_dir.is_dir() {
+                continue;
+            }
+            for entry in object_dir.read_dir()? {
+                let name = entry?.file_name().to_string_lossy().to_string();
+                if name.starts_with(&prefix[2..]) {
+                    if let Ok(hash) = <[u8; 20]>::from_hex(format!("{}{}", &prefix[..2], name)) {
+                        matches.insert(hash);
+                    }
+                }
+            }
+        }
+        for pack in self.pack_paths()? {
+            let index = PackIndex::open(&pack.with_extension("idx"))?;
+            matches.extend(index.find_prefix(prefix)?);
+        }
+
+        Ok(matches.into_iter().collect())
+    }
+
+    fn resolve_abbreviated(&self, prefix: &str) -> Result<[u8; 20]> {
+        let matches = self.objects_with_prefix(prefix)?;
+        match matches.as_slice() {
+            [hash] => Ok(*hash),
+            [] => Err(MgError::InvalidRef(prefix.to_string()).into()),
+            _ => Err(self.ambiguous_object(prefix, &matches)?),
+        }
+    }
+
+    /// The error for an abbreviated id matching several objects, listing
+    /// them as git does.
+    fn ambiguous_object(&self, prefix: &str, matches: &[[u8; 20]]) -> Result<anyhow::Error> {
+        let hexes: Vec<String> = matches.iter().map(hex::encode).collect();
+        // the shortest abbreviation telling the candidates apart
+        let length = (prefix.len().max(7)..40)
+            .find(|&length| {
+                let short: BTreeSet<&str> = hexes.iter().map(|hex| &hex[..length]).collect();
+                short.len() == hexes.len()
+            })
+            .unwrap_or(40);
+
+        let mut message = format!(
+            "short object ID {} is ambiguous\nhint: The candidates are:",
+            prefix
+        );
+        for hex in &hexes {
+            let mut object = self.read_object(hex)?;
+            let description = match object.kind().clone() {
+                Kind::Commit => {
+                    let content = object.string()?;
+                    let subject = content
+                        .split_once("\n\n")
+                        .and_then(|(_, message)| message.lines().next())
+                        .unwrap_or_default();
+                    format!("commit - {}", subject)
+                }
+                Kind::Tag => {
+                    let content = object.string()?;
+                    let name = content
+                        .lines()
+                        .find_map(|line| line.strip_prefix("tag "))
+                        .unwrap_or_default();
+                    format!("tag - {}", name)
+                }
+                kind => kind.to_string(),
+            };
+            message.push_str(&format!("\nhint:   {} {}", &hex[..length], description));
+        }
+
+        Ok(anyhow!(message))
+    }
+}
+
+/// The error for a name matching refs pointing to different objects.
+fn ambiguous_ref(rev: &str, found: &[(String, [u8; 20])]) -> anyhow::Error {
+    let mut message = format!("refname '{}' is ambiguous, it could be:", rev);
+    for (name, hash) in found {
+        let kind = if name.starts_with("refs/heads/") {
+            "branch"
+        } else if name.starts_with("refs/tags/") {
+            "tag"
+        } else if name.starts_with("refs/remotes/") {
+            "remote-tracking branch"
+        } else {
+            "ref"
+        };
+        message.push_str(&format!(
+            "\n  {} ({} at {})",
+            name,
+            kind,
+            &hex::encode(hash)[..7]
+        ));
+    }
+    message.push_str("\nhint: use the full ref name to choose one");
+
+    anyhow!(message)
+}
@@ -0,0 +1,530 @@
+use anyhow::{anyhow, Result};
+
+/// Repetition counts past which an interval is refused, as POSIX's
+/// `RE_DUP_MAX`.
+const MAX_REPEAT: u32 = 255;
+
+/// How `grep` reads its pattern, as git's `-G`, `-E` and `-F`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Syntax {
+    /// POSIX basic regular expressions, with GNU's `\+`, `\?` and `\|`
+    #[default]
+    Basic,
+    /// POSIX extended regular expressions
+    Extended,
+    /// A fixed string
+    Fixed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Assertion {
+    LineStart,
+    LineEnd,
+    WordBoundary,
+    NotWordBoundary,
+    WordStart,
+    WordEnd,
+}
+
+#[derive(Clone, Debug)]
+enum ClassItem {
+    Range(char, char),
+    Named(fn(char) -> bool),
+}
+
+/// A bracket expression, or one of the `\w`, `\s` and `\d` shorthands.
+#[derive(Clone, Debug)]
+struct Class {
+    negated: bool,
+    items: Vec<ClassItem>,
+}
+
+impl Class {
+    fn named(matches: fn(char) -> bool, negated: bool) -> Self {
+        Class {
+            negated,
+            items: vec![ClassItem::Named(matches)],
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        let found = self.items.iter().any(|item| match *item {
+            ClassItem::Range(low, high) => low <= c && c <= high,
+            ClassItem::Named(matches) => matches(c),
+        });
+        found != self.negated
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Empty,
+    Literal(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+/// An instruction of the program a pattern compiles to, run by a Pike VM
+/// so that matching takes linear time in the length of the line.
+#[derive(Clone, Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn named_class(name: &str) -> Option<fn(char) -> bool> {
+    let matches: fn(char) -> bool = match name {
+        "alpha" => |c| c.is_alphabetic(),
+        "digit" => |c| c.is_ascii_digit(),
+        "alnum" => |c| c.is_alphanumeric(),
+        "upper" => |c| c.is_uppercase(),
+        "lower" => |c| c.is_lowercase(),
+        "space" => |c| c.is_whitespace(),
+        "blank" => |c| c == ' ' || c == '\t',
+        "punct" => |c| c.is_ascii_punctuation(),
+        "print" => |c| !c.is_control(),
+        "graph" => |c| !c.is_control() && !c.is_whitespace(),
+        "cntrl" => |c| c.is_control(),
+        "xdigit" => |c| c.is_ascii_hexdigit(),
+        _ => return None,
+    };
+    Some(matches)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    extended: bool,
+}
+
+impl Parser {
+    /// Whether the operator `op` comes next: bare in extended syntax,
+    /// escaped in basic syntax.
+    fn peek_op(&self, op: char) -> bool {
+        if self.extended {
+            self.chars.get(self.pos) == Some(&op)
+        } else {
+            self.chars.get(self.pos) == Some(&'\\') && self.chars.get(self.pos + 1) == Some(&op)
+        }
+    }
+
+    fn eat_op(&mut self, op: char) -> bool {
+        let found = self.peek_op(op);
+        if found {
+            self.pos += if self.extended { 1 } else { 2 };
+        }
+        found
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.get(self.pos).copied();
+        self.pos += 1;
+        c
+    }
+
+    fn parse_alternation(&mut self, depth: usize) -> Result<Node> {
+        let mut branches = vec![self.parse_concat(depth)?];
+        while self.eat_op('|') {
+            branches.push(self.parse_concat(depth)?);
+        }
+        Ok(match branches.len() {
+            1 => branches.pop().expect("one branch"),
+            _ => Node::Alternate(branches),
+        })
+    }
+
+    fn parse_concat(&mut self, depth: usize) -> Result<Node> {
+        let mut items = Vec::new();
+        while self.pos < self.chars.len() && !self.peek_op('|') && !(depth > 0 && self.peek_op(')'))
+        {
+            let atom = self.parse_atom(items.is_empty(), depth)?;
+            items.push(self.parse_repeats(atom)?);
+        }
+        Ok(match items.len() {
+            0 => Node::Empty,
+            1 => items.pop().expect("one item"),
+            _ => Node::Concat(items),
+        })
+    }
+
+    /// Whether the end of a branch comes next, where `$` anchors in basic
+    /// syntax.
+    fn at_branch_end(&self) -> bool {
+        self.pos >= self.chars.len() || self.peek_op('|') || self.peek_op(')')
+    }
+
+    fn parse_atom(&mut self, at_start: bool, depth: usize) -> Result<Node> {
+        if self.eat_op('(') {
+            let node = self.parse_alternation(depth + 1)?;
+            if !self.eat_op(')') {
+                return Err(anyhow!("unmatched ( in pattern"));
+            }
+            return Ok(node);
+        }
+        // an unmatched ) is taken literally in extended syntax, as by GNU
+        // grep, but \) is an error in basic syntax
+        if !self.extended && self.peek_op(')') {
+            return Err(anyhow!("unmatched \\) in pattern"));
+        }
+
+        let c = self.next().expect("not at the end");
+        Ok(match c {
+            '^' if self.extended || at_start => Node::Assert(Assertion::LineStart),
+            '$' if self.extended || self.at_branch_end() => Node::Assert(Assertion::LineEnd),
+            // a repetition with nothing to repeat is taken literally
+            '*' if at_start => Node::Literal('*'),
+            '.' => Node::Any,
+            '[' => Node::Class(self.parse_class()?),
+            '\\' => match self.next() {
+                None => return Err(anyhow!("trailing backslash in pattern")),
+                Some('w') => Node::Class(Class::named(is_word, false)),
+                Some('W') => Node::Class(Class::named(is_word, true)),
+                Some('s') => Node::Class(Class::named(char::is_whitespace, false)),
+                Some('S') => Node::Class(Class::named(char::is_whitespace, true)),
+                Some('d') => Node::Class(Class::named(|c| c.is_ascii_digit(), false)),
+                Some('D') => Node::Class(Class::named(|c| c.is_ascii_digit(), true)),
+                Some('b') => Node::Assert(Assertion::WordBoundary),
+                Some('B') => Node::Assert(Assertion::NotWordBoundary),
+                Some('<') => Node::Assert(Assertion::WordStart),
+                Some('>') => Node::Assert(Assertion::WordEnd),
+                Some(c) => Node::Literal(c),
+            },
+            c => Node::Literal(c),
+        })
+    }
+
+    /// A bracket expression, after its `[`.
+    fn parse_class(&mut self) -> Result<Class> {
+        let unterminated = || anyhow!("unterminated [ in pattern");
+        let negated = self.chars.get(self.pos) == Some(&'^');
+        if negated {
+            self.pos += 1;
+        }
+
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next().ok_or_else(unterminated)?;
+            match c {
+                ']' if !first => break,
+                '[' if self.chars.get(self.pos) == Some(&':') => {
+                    let rest: String = self.chars[self.pos + 1..].iter().collect();
+                    let end = rest.find(":]").ok_or_else(unterminated)?;
+                    let name = &rest[..end];
+                    let matches = named_class(name)
+                        .ok_or_else(|| anyhow!("invalid character class '{}'", name))?;
+                    items.push(ClassItem::Named(matches));
+                    self.pos += 1 + name.chars().count() + 2;
+                }
+                c if self.chars.get(self.pos) == Some(&'-')
+                    && self.chars.get(self.pos + 1).is_some_and(|&end| end != ']') =>
+                {
+                    let end = self.chars[self.pos + 1];
+                    if end < c {
+                        return Err(anyhow!("invalid range {}-{} in pattern", c, end));
+                    }
+                    items.push(ClassItem::Range(c, end));
+                    self.pos += 2;
+                }
+                c => items.push(ClassItem::Range(c, c)),
+            }
+            first = false;
+        }
+        Ok(Class { negated, items })
+    }
+
+    fn parse_repeats(&mut self, mut node: Node) -> Result<Node> {
+        loop {
+            let (min, max) = if self.chars.get(self.pos) == Some(&'*') {
+                self.pos += 1;
+                (0, None)
+            } else if self.eat_op('+') {
+                (1, None)
+            } else if self.eat_op('?') {
+                (0, Some(1))
+            } else if self.eat_op('{') {
+                self.parse_interval()?
+            } else {
+                return Ok(node);
+            };
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+            };
+        }
+    }
+
+    /// The bounds of `{m}`, `{m,}` or `{m,n}`, after the `{`.
+    fn parse_interval(&mut self) -> Result<(u32, Option<u32>)> {
+        let invalid = || anyhow!("invalid interval in pattern");
+        let number = |parser: &mut Parser| -> Option<u32> {
+            let start = parser.pos;
+            while parser
+                .chars
+                .get(parser.pos)
+                .is_some_and(char::is_ascii_digit)
+            {
+                parser.pos += 1;
+            }
+            let digits: String = parser.chars[start..parser.pos].iter().collect();
+            digits.parse().ok()
+        };
+
+        let min = number(self).ok_or_else(invalid)?;
+        let max = if self.chars.get(self.pos) == Some(&',') {
+            self.pos += 1;
+            number(self)
+        } else {
+            Some(min)
+        };
+        if !self.eat_op('}') || max.is_some_and(|max| max < min) {
+            return Err(invalid());
+        }
+        if min.max(max.unwrap_or(0)) > MAX_REPEAT {
+            return Err(anyhow!("interval over {} in pattern", MAX_REPEAT));
+        }
+        Ok((min, max))
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) {
+    match node {
+        Node::Empty => {}
+        Node::Literal(c) => program.push(Inst::Char(*c)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class(class) => program.push(Inst::Class(class.clone())),
+        Node::Assert(assertion) => program.push(Inst::Assert(*assertion)),
+        Node::Concat(nodes) => nodes.iter().for_each(|node| compile(node, program)),
+        Node::Alternate(branches) => {
+            let mut jumps = Vec::new();
+            for (i, branch) in branches.iter().enumerate() {
+                if i + 1 == branches.len() {
+                    compile(branch, program);
+                    break;
+                }
+                let split = program.len();
+                program.push(Inst::Split(split + 1, 0));
+                compile(branch, program);
+                jumps.push(program.len());
+                program.push(Inst::Jump(0));
+                program[split] = Inst::Split(split + 1, program.len());
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        Node::Repeat { node, min, max } => {
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(node, program);
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+                Some(max) => {
+                    for _ in *min..*max {
+                        let split = program.len();
+                        program.push(Inst::Split(split + 1, 0));
+                        compile(node, program);
+                        program[split] = Inst::Split(split + 1, program.len());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Fixed(String),
+    Program(Vec<Inst>),
+}
+
+/// A pattern `grep` looks for in each line.
+#[derive(Debug)]
+pub struct Regex {
+    matcher: Matcher,
+    ignore_case: bool,
+}
+
+impl Regex {
+    pub fn new(pattern: &str, syntax: Syntax, ignore_case: bool) -> Result<Self> {
+        let matcher = match syntax {
+            Syntax::Fixed if ignore_case => Matcher::Fixed(pattern.to_lowercase()),
+            Syntax::Fixed => Matcher::Fixed(pattern.to_string()),
+            Syntax::Basic | Syntax::Extended => {
+                let mut parser = Parser {
+                    chars: pattern.chars().collect(),
+                    pos: 0,
+                    extended: syntax == Syntax::Extended,
+                };
+                let node = parser.parse_alternation(0)?;
+                let mut program = Vec::new();
+                compile(&node, &mut program);
+                program.push(Inst::Match);
+                Matcher::Program(program)
+            }
+        };
+        Ok(Regex {
+            matcher,
+            ignore_case,
+        })
+    }
+
+    /// Whether the pattern matches somewhere in `line`.
+    pub fn is_match(&self, line: &str) -> bool {
+        let program = match &self.matcher {
+            Matcher::Fixed(needle) if self.ignore_case => {
+                return line.to_lowercase().contains(needle.as_str())
+            }
+            Matcher::Fixed(needle) => return line.contains(needle.as_str()),
+            Matcher::Program(program) => program,
+        };
+
+        let chars: Vec<char> = line.chars().collect();
+        // the position each instruction was last added to a list at
+        let mut added = vec![usize::MAX; program.len()];
+        let mut current = Vec::new();
+        for pos in 0..=chars.len() {
+            // a match may start anywhere
+            self.add_thread(program, &chars, pos, 0, &mut current, &mut added);
+            let mut next = Vec::new();
+            for &pc in &current {
+                let c = chars.get(pos).copied();
+                let advances = match (&program[pc], c) {
+                    (Inst::Match, _) => return true,
+                    (Inst::Char(expected), Some(c)) => self.char_matches(*expected, c),
+                    (Inst::Any, Some(_)) => true,
+                    (Inst::Class(class), Some(c)) => self.class_matches(class, c),
+                    _ => false,
+                };
+                if advances {
+                    self.add_thread(program, &chars, pos + 1, pc + 1, &mut next, &mut added);
+                }
+            }
+            current = next;
+        }
+        false
+    }
+
+    /// Add the instruction `pc` to `list` at `pos`, following the jumps,
+    /// splits and assertions which do not consume a character.
+    fn add_thread(
+        &self,
+        program: &[Inst],
+        chars: &[char],
+        pos: usize,
+        pc: usize,
+        list: &mut Vec<usize>,
+        added: &mut [usize],
+    ) {
+        if added[pc] == pos {
+            return;
+        }
+        added[pc] = pos;
+        match &program[pc] {
+            Inst::Jump(target) => self.add_thread(program, chars, pos, *target, list, added),
+            Inst::Split(first, second) => {
+                self.add_thread(program, chars, pos, *first, list, added);
+                self.add_thread(program, chars, pos, *second, list, added);
+            }
+            Inst::Assert(assertion) => {
+                if holds(*assertion, chars, pos) {
+                    self.add_thread(program, chars, pos, pc + 1, list, added);
+                }
+            }
+            _ => list.push(pc),
+        }
+    }
+
+    fn char_matches(&self, expected: char, c: char) -> bool {
+        expected == c || (self.ignore_case && fold(expected) == fold(c))
+    }
+
+    fn class_matches(&self, class: &Class, c: char) -> bool {
+        if !self.ignore_case {
+            return class.matches(c);
+        }
+        let lower = c.to_lowercase().next().unwrap_or(c);
+        let upper = c.to_uppercase().next().unwrap_or(c);
+        // a negated class must miss every case of the character
+        if class.negated {
+            class.matches(lower) && class.matches(upper)
+        } else {
+            class.matches(lower) || class.matches(upper)
+        }
+    }
+}
+
+fn fold(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+fn holds(assertion: Assertion, chars: &[char], pos: usize) -> bool {
+    let before = pos > 0 && is_word(chars[pos - 1]);
+    let after = chars.get(pos).is_some_and(|&c| is_word(c));
+    match assertion {
+        Assertion::LineStart => pos == 0,
+        Assertion::LineEnd => pos == chars.len(),
+        Assertion::WordBoundary => before != after,
+        Assertion::NotWordBoundary => before == after,
+        Assertion::WordStart => !before && after,
+        Assertion::WordEnd => before && !after,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, syntax: Syntax, line: &str) -> bool {
+        Regex::new(pattern, syntax, false).unwrap().is_match(line)
+    }
+
+    #[test]
+    fn test_regex() {
+        use Syntax::*;
+        assert!(matches("fo*bar", Basic, "xx fooobar"));
+        assert!(matches("^a.c$", Basic, "abc"));
+        assert!(!matches("^a.c$", Basic, "abcd"));
+        // in basic syntax, the operators of extended syntax are literal
+        assert!(matches("a+(b)", Basic, "a+(b)"));
+        assert!(matches("a\\+\\(b\\|c\\)", Basic, "aaac"));
+        assert!(matches("a\\{2,3\\}b", Basic, "xaab"));
+        assert!(!matches("^a\\{2,3\\}b", Basic, "ab"));
+        assert!(matches("(foo|ba[rz])+$", Extended, "bazfoo"));
+        assert!(!matches("^(foo|ba[rz])+$", Extended, "bazfox"));
+        assert!(matches("[[:digit:]]{3}", Extended, "a123"));
+        assert!(matches("[^a-z]", Extended, "abC"));
+        assert!(matches("\\bword\\b", Extended, "a word here"));
+        assert!(!matches("\\bword\\b", Extended, "swordfish"));
+        assert!(matches("a.c", Fixed, "xa.c"));
+        assert!(!matches("a.c", Fixed, "abc"));
+        assert!(Regex::new("FOO", Basic, true).unwrap().is_match("a foo"));
+        assert!(Regex::new("a\\(b", Basic, false).is_err());
+        assert!(Regex::new("[ab", Extended, false).is_err());
+    }
+}
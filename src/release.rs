@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    commit::CommitOptions,
+    log::{civil_from_days, commit_line, LogOptions},
+    pathspec::Pathspec,
+    repository::Repository,
+};
+
+#[derive(Default)]
+pub struct ReleaseOptions {
+    /// Sign the tag, as `tag.gpgSign` does
+    pub sign: bool,
+    /// Add the changelog section at the top of this file, and commit it
+    /// before tagging
+    pub changelog: Option<PathBuf>,
+}
+
+/// `content` of a changelog with `section` added first, under the title of
+/// the file if it has one.
+fn prepend_section(content: &str, section: &str) -> String {
+    match content.split_once('\n') {
+        Some((title, rest)) if title.starts_with("# ") => {
+            format!(
+                "{}\n\n{}\n{}",
+                title,
+                section,
+                rest.trim_start_matches('\n')
+            )
+        }
+        _ if content.is_empty() => section.to_string(),
+        _ => format!("{}\n{}", section, content),
+    }
+}
+
+impl Repository {
+    /// The commits tagged by `refs/tags/`, with the name of a tag of each.
+    fn tagged_commits(&self) -> Result<HashMap<[u8; 20], String>> {
+        let mut tagged = HashMap::new();
+        for (name, mut hash) in self.list_refs("refs/tags/")? {
+            while let Some(target) = self.peel_tag(&hash)? {
+                hash = target;
+            }
+            tagged.insert(hash, name.trim_start_matches("refs/tags/").to_string());
+        }
+        Ok(tagged)
+    }
+
+    /// The changelog section of `version`: the first-parent commits since
+    /// the previous tag, or every commit without one, as `log --oneline`
+    /// shows them. Returns the section and the list of commits alone.
+    fn changelog_section(&self, version: &str) -> Result<(String, String)> {
+        let tagged = self.tagged_commits()?;
+        let mailmap = self.load_mailmap()?;
+        let options = LogOptions {
+            oneline: true,
+            ..Default::default()
+        };
+
+        let mut entries = Vec::new();
+        let mut previous = None;
+        self.walk_first_parent(|hash, commit| {
+            if let Some(tag) = tagged.get(hash) {
+                previous = Some(tag.clone());
+                return Ok(false);
+            }
+            entries.push(format!(
+                "- {}",
+                commit_line(hash, commit, &options, &HashMap::new(), &mailmap, false)
+            ));
+            Ok(true)
+        })?;
+        if entries.is_empty() {
+            return Err(anyhow!(
+                "nothing to release since {}",
+                previous.unwrap_or_default()
+            ));
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let (year, month, day) = civil_from_days(now.div_euclid(86400));
+        let list = entries.join("\n");
+        let section = format!(
+            "## {} ({:04}-{:02}-{:02})\n\n{}\n",
+            version, year, month, day, list
+        );
+
+        Ok((section, list))
+    }
+
+    /// Tag HEAD as `version` with an annotated tag listing the commits since
+    /// the previous tag, once the worktree is checked clean. With a changelog
+    /// file, the list goes at its top in a commit of its own, which is the
+    /// one tagged.
+    pub fn release(&self, version: &str, options: &ReleaseOptions) -> Result<[u8; 20]> {
+        self.ensure_clean_worktree("release")?;
+        if self.read_ref(&format!("refs/tags/{}", version))?.is_some() {
+            return Err(anyhow!("tag '{}' already exists", version));
+        }
+
+        let (section, list) = self.changelog_section(version)?;
+        print!("{}", section);
+
+        if let Some(changelog) = &options.changelog {
+            let path = self.path.join(changelog);
+            let content = fs::read_to_string(&path).unwrap_or_default();
+            fs::write(&path, prepend_section(&content, &section))?;
+            let changelog = changelog.to_string_lossy();
+            self.add(&Pathspec::literal(&[&changelog])?)?;
+            self.commit(
+                Some(&format!("Release {}", version)),
+                &CommitOptions::default(),
+            )?;
+        }
+
+        let message = format!("Release {}\n\n{}", version, list);
+        self.create_tag(version, None, Some(&message), options.sign)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepend_section() {
+        let section = "## v2 (2024-01-02)\n\n- 1234567 Fix\n";
+        assert_eq!(
+            prepend_section("# Changelog\n\n## v1\n", section),
+            "# Changelog\n\n## v2 (2024-01-02)\n\n- 1234567 Fix\n\n## v1\n"
+        );
+        assert_eq!(prepend_section("", section), section);
+        assert_eq!(
+            prepend_section("## v1\n", section),
+            format!("{}\n## v1\n", section)
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+/// The score of identical files, as git's `MAX_SCORE`.
+const MAX_SCORE: u64 = 60000;
+/// The score a file must reach to be a rename, 50% by default in git.
+const MIN_SCORE: u64 = MAX_SCORE / 2;
+const HASHBASE: u32 = 107927;
+/// Pairs of files compared for inexact renames, past which only exact
+/// renames are found, as git's default `diff.renameLimit` of 1000 files.
+const RENAME_LIMIT: usize = 1000 * 1000;
+
+/// A file a rename may go from or to.
+pub struct RenameFile<'a> {
+    pub path: &'a str,
+    pub hash: [u8; 20],
+    pub content: &'a [u8],
+}
+
+/// The bytes of `content` in each chunk hash, the chunks ending at newlines
+/// or after 64 bytes, as git's `hash_chars`.
+fn span_hashes(content: &[u8]) -> HashMap<u32, u64> {
+    // git, reading text, ignores the CR of CRLF
+    let is_text = !content[..content.len().min(8000)].contains(&0);
+    let mut hashes = HashMap::new();
+    let (mut accum1, mut accum2, mut n) = (0u32, 0u32, 0u64);
+    for (i, &c) in content.iter().enumerate() {
+        if is_text && c == b'\r' && content.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        let old1 = accum1;
+        accum1 = (accum1 << 7) ^ (accum2 >> 25);
+        accum2 = (accum2 << 7) ^ (old1 >> 25);
+        accum1 = accum1.wrapping_add(c as u32);
+        n += 1;
+        if n < 64 && c != b'\n' {
+            continue;
+        }
+        let hash = accum1.wrapping_add(accum2.wrapping_mul(0x61)) % HASHBASE;
+        *hashes.entry(hash).or_insert(0) += n;
+        (accum1, accum2, n) = (0, 0, 0);
+    }
+    if n > 0 {
+        let hash = accum1.wrapping_add(accum2.wrapping_mul(0x61)) % HASHBASE;
+        *hashes.entry(hash).or_insert(0) += n;
+    }
+    hashes
+}
+
+/// How much of `new` is copied from `old`, from 0 to `MAX_SCORE`, as git's
+/// `estimate_similarity`.
+fn similarity(old: &[u8], new: &[u8]) -> u64 {
+    let max_size = old.len().max(new.len()) as u64;
+    let delta_size = max_size - old.len().min(new.len()) as u64;
+    // files so different in size are not compared
+    if new.is_empty() || max_size * (MAX_SCORE - MIN_SCORE) < delta_size * MAX_SCORE {
+        return 0;
+    }
+
+    let new_hashes = span_hashes(new);
+    let copied: u64 = span_hashes(old)
+        .into_iter()
+        .filter_map(|(hash, count)| Some(count.min(*new_hashes.get(&hash)?)))
+        .sum();
+    copied * MAX_SCORE / max_size
+}
+
+fn basename(path: &str) -> &str {
+    path.rsplit_once('/').map_or(path, |(_, name)| name)
+}
+
+/// The renames from the `deleted` files to the `added` ones, as indices in
+/// them with the similarity in percent. Identical files pair first, then
+/// the most similar ones; empty files are never renamed.
+pub fn find_renames(deleted: &[RenameFile], added: &[RenameFile]) -> Vec<(usize, usize, u32)> {
+    let mut renames = Vec::new();
+    let mut from_used = vec![false; deleted.len()];
+    let mut to_used = vec![false; added.len()];
+
+    for (to, new) in added.iter().enumerate() {
+        if new.content.is_empty() {
+            continue;
+        }
+        // of the identical files, one of the same name is preferred
+        let exact = (0..deleted.len())
+            .filter(|&from| !from_used[from] && deleted[from].hash == new.hash)
+            .max_by_key(|&from| {
+                (
+                    basename(deleted[from].path) == basename(new.path),
+                    std::cmp::Reverse(from),
+                )
+            });
+        if let Some(from) = exact {
+            renames.push((from, to, 100));
+            from_used[from] = true;
+            to_used[to] = true;
+        }
+    }
+
+    let sources: Vec<usize> = (0..deleted.len())
+        .filter(|&from| !from_used[from] && !deleted[from].content.is_empty())
+        .collect();
+    let targets: Vec<usize> = (0..added.len())
+        .filter(|&to| !to_used[to] && !added[to].content.is_empty())
+        .collect();
+    if sources.len() * targets.len() > RENAME_LIMIT {
+        return renames;
+    }
+
+    let mut candidates = Vec::new();
+    for &to in &targets {
+        for &from in &sources {
+            let score = similarity(deleted[from].content, added[to].content);
+            if score >= MIN_SCORE {
+                let same_name = basename(deleted[from].path) == basename(added[to].path);
+                candidates.push((score, same_name, to, from));
+            }
+        }
+    }
+    // the best first, then of the same name, then in order
+    candidates.sort_by(|a, b| {
+        (b.0, b.1)
+            .cmp(&(a.0, a.1))
+            .then((a.2, a.3).cmp(&(b.2, b.3)))
+    });
+    for (score, _, to, from) in candidates {
+        if from_used[from] || to_used[to] {
+            continue;
+        }
+        renames.push((from, to, (score * 100 / MAX_SCORE) as u32));
+        from_used[from] = true;
+        to_used[to] = true;
+    }
+    renames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file<'a>(path: &'a str, content: &'a [u8]) -> RenameFile<'a> {
+        RenameFile {
+            path,
+            hash: crate::object::hash_blob(content),
+            content,
+        }
+    }
+
+    #[test]
+    fn test_find_renames() {
+        let lines: Vec<String> = (0..10).map(|i| format!("line {}\n", i)).collect();
+        let old = lines.concat();
+        let mut edited = lines.clone();
+        edited[9] = "changed\n".to_string();
+        let edited = edited.concat();
+
+        let deleted = [
+            file("a", old.as_bytes()),
+            file("b", old.as_bytes()),
+            file("e", b""),
+        ];
+        let added = [
+            file("dir/b", old.as_bytes()),
+            file("c", edited.as_bytes()),
+            file("f", b""),
+        ];
+        // an identical file of the same name first, the other is then edited
+        assert_eq!(
+            find_renames(&deleted, &added),
+            vec![(1, 0, 100), (0, 1, 88)]
+        );
+        assert_eq!(similarity(old.as_bytes(), b"unrelated\n"), 0);
+    }
+}
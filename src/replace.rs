@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    io::ErrorKind,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use hex::FromHex;
+
+use crate::repository::Repository;
+
+/// What `refs/replace/` and `.git/info/grafts` say, read once when an object
+/// is first read and shared by the clones of a repository.
+#[derive(Clone, Default)]
+pub struct Replacements {
+    loaded: Arc<Mutex<Option<Arc<ReplaceMap>>>>,
+}
+
+#[derive(Default)]
+struct ReplaceMap {
+    objects: HashMap<String, String>,
+    grafts: HashMap<[u8; 20], Vec<[u8; 20]>>,
+}
+
+impl ReplaceMap {
+    fn load(repo: &Repository) -> Result<ReplaceMap> {
+        let objects = repo
+            .list_refs("refs/replace/")?
+            .into_iter()
+            .filter_map(|(name, hash)| {
+                let object = name.strip_prefix("refs/replace/")?;
+                Some((object.to_string(), hex::encode(hash)))
+            })
+            .collect();
+
+        let mut grafts = HashMap::new();
+        let content = match read_to_string(repo.git_dir.join("info").join("grafts")) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        for line in content.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+
+            // lines which are not ids are skipped, as git skips bad grafts
+            let Ok(mut hashes) = line
+                .split_whitespace()
+                .map(<[u8; 20]>::from_hex)
+                .collect::<Result<Vec<_>, _>>()
+            else {
+                continue;
+            };
+            if hashes.is_empty() {
+                continue;
+            }
+            let commit = hashes.remove(0);
+            // the first graft of a commit wins
+            grafts.entry(commit).or_insert(hashes);
+        }
+
+        Ok(ReplaceMap { objects, grafts })
+    }
+}
+
+impl Repository {
+    fn replace_map(&self) -> Result<Arc<ReplaceMap>> {
+        let mut loaded = self
+            .replacements
+            .loaded
+            .lock()
+            .expect("replacements poisoned");
+        if let Some(map) = &*loaded {
+            return Ok(map.clone());
+        }
+
+        let map = Arc::new(ReplaceMap::load(self)?);
+        *loaded = Some(map.clone());
+        Ok(map)
+    }
+
+    /// Read `refs/replace/` and the grafts again on next use, after a
+    /// replace ref changed.
+    pub(crate) fn forget_replacements(&self) {
+        *self
+            .replacements
+            .loaded
+            .lock()
+            .expect("replacements poisoned") = None;
+    }
+
+    /// Whether any object or commit parents are replaced, unless
+    /// replacements are disabled.
+    pub fn has_replacements(&self) -> Result<bool> {
+        if !self.replace_objects {
+            return Ok(false);
+        }
+
+        let map = self.replace_map()?;
+        Ok(!map.objects.is_empty() || !map.grafts.is_empty())
+    }
+
+    /// This repository reading objects as they are stored, whatever replaces
+    /// them, as packs for other repositories and checks of the object store
+    /// need.
+    pub fn stored_objects(&self) -> Repository {
+        let mut repo = self.clone();
+        repo.replace_objects = false;
+        repo
+    }
+
+    /// The object replacing `object` through `refs/replace/<object>`, unless
+    /// replacements are disabled.
+    pub fn replacement_object(&self, object: &str) -> Result<Option<String>> {
+        if !self.replace_objects || object.len() != 40 {
+            return Ok(None);
+        }
+
+        Ok(self.replace_map()?.objects.get(object).cloned())
+    }
+
+    /// The parents recorded for `commit` in `.git/info/grafts`, unless
+    /// replacements are disabled.
+    pub fn grafted_parents(&self, commit: &[u8; 20]) -> Result<Option<Vec<[u8; 20]>>> {
+        if !self.replace_objects {
+            return Ok(None);
+        }
+
+        Ok(self.replace_map()?.grafts.get(commit).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kind::Kind, repository::InitOptions};
+
+    #[test]
+    fn test_replacements_follow_ref_updates() {
+        let path = std::env::temp_dir().join(format!("mg-replace-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        let mut repo = Repository::open(path.clone()).unwrap();
+        repo.init_repository(&path, &InitOptions::default())
+            .unwrap();
+
+        let original = repo.write_object(Kind::Blob(false), b"original\n").unwrap();
+        let replacement = repo
+            .write_object(Kind::Blob(false), b"replacement\n")
+            .unwrap();
+        let name = format!("refs/replace/{}", hex::encode(original));
+        assert!(!repo.has_replacements().unwrap());
+
+        repo.update_ref(&name, &replacement).unwrap();
+        let mut read = repo.read_object(&hex::encode(original)).unwrap();
+        assert_eq!(read.content().unwrap(), b"replacement\n");
+        let mut read = repo
+            .stored_objects()
+            .read_object(&hex::encode(original))
+            .unwrap();
+        assert_eq!(read.content().unwrap(), b"original\n");
+
+        repo.delete_ref(&name).unwrap();
+        assert!(!repo.has_replacements().unwrap());
+
+        std::fs::write(
+            path.join(".git/info/grafts"),
+            format!(
+                "# comment\nnot a graft\n{} {}\n",
+                hex::encode(original),
+                hex::encode(replacement)
+            ),
+        )
+        .unwrap();
+        repo.forget_replacements();
+        assert_eq!(
+            repo.grafted_parents(&original).unwrap(),
+            Some(vec![replacement])
+        );
+        assert_eq!(
+            repo.stored_objects().grafted_parents(&original).unwrap(),
+            None
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
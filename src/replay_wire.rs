@@ -0,0 +1,166 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    http::{decode_git_response, parse_ls_refs, parse_refs},
+    pack::stored_pack_entries,
+};
+
+/// A packet of a pkt-line stream.
+#[derive(Debug, PartialEq, Eq)]
+enum Packet<'a> {
+    Flush,
+    Delimiter,
+    ResponseEnd,
+    Data(&'a [u8]),
+}
+
+/// The packet at `offset` in `content`, and the offset of the next one.
+fn read_packet(content: &[u8], offset: usize) -> Result<(Packet<'_>, usize)> {
+    let length = content
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("truncated packet length at offset {:#x}", offset))?;
+    let length = std::str::from_utf8(length)
+        .ok()
+        .and_then(|length| usize::from_str_radix(length, 16).ok())
+        .ok_or_else(|| anyhow!("invalid packet length at offset {:#x}", offset))?;
+
+    let packet = match length {
+        0 => Packet::Flush,
+        1 => Packet::Delimiter,
+        2 => Packet::ResponseEnd,
+        3 => return Err(anyhow!("invalid packet length 3 at offset {:#x}", offset)),
+        _ => Packet::Data(content.get(offset + 4..offset + length).ok_or_else(|| {
+            anyhow!(
+                "packet of {} bytes at offset {:#x} is cut short",
+                length,
+                offset
+            )
+        })?),
+    };
+    Ok((packet, offset + length.max(4)))
+}
+
+/// How `data` is shown: as text, or by side-band once in the packfile.
+fn describe_data(data: &[u8], in_packfile: bool) -> String {
+    if !in_packfile {
+        return format!("{:?}", String::from_utf8_lossy(data));
+    }
+    match data.split_first() {
+        Some((1, pack)) => format!("band 1, {} bytes of pack data", pack.len()),
+        Some((band, message)) => {
+            format!("band {} {:?}", band, String::from_utf8_lossy(message))
+        }
+        None => "empty".to_string(),
+    }
+}
+
+/// Replay the server response captured in `file` through the decoding of
+/// fetches, without a network: each packet is printed as it is read, then
+/// what the client would make of the whole response. A ref advertisement
+/// gives its refs, an `ls-refs` response its refs, and a `fetch` response
+/// its shallow boundary and a summary of its pack.
+pub fn replay_wire(file: &Path) -> Result<()> {
+    let content = fs::read(file)?;
+
+    let mut first_line = None;
+    let mut in_packfile = false;
+    let mut offset = 0;
+    while offset < content.len() {
+        let (packet, next) = read_packet(&content, offset)?;
+        let description = match packet {
+            Packet::Flush => "flush".to_string(),
+            Packet::Delimiter => "delim".to_string(),
+            Packet::ResponseEnd => "response-end".to_string(),
+            Packet::Data(data) => {
+                let description = describe_data(data, in_packfile);
+                let line = String::from_utf8_lossy(data).trim_end().to_string();
+                in_packfile |= line == "packfile";
+                first_line.get_or_insert(line);
+                description
+            }
+        };
+        println!("{:06x} {:04x} {}", offset, next - offset, description);
+        offset = next;
+    }
+
+    let first_line = first_line.unwrap_or_default();
+    match first_line.as_str() {
+        line if line.starts_with("# service=") => {
+            println!("ref advertisement:");
+            for (name, id) in parse_refs(&content)? {
+                println!("{} {}", id, name);
+            }
+        }
+        "version 2" => println!("capability advertisement"),
+        "acknowledgments" | "shallow-info" | "wanted-refs" | "packfile-uris" | "packfile" => {
+            println!("fetch response:");
+            let (pack, shallow) = decode_git_response(&content, true)?;
+            for id in &shallow.shallow {
+                println!("shallow {}", id);
+            }
+            for id in &shallow.unshallow {
+                println!("unshallow {}", id);
+            }
+            if !pack.is_empty() {
+                print_pack_summary(&pack)?;
+            }
+        }
+        _ => {
+            println!("ls-refs response:");
+            for (name, id) in parse_ls_refs(&content)? {
+                println!("{} {}", id, name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the number of entries of each type in `pack`, and whether its
+/// checksum matches.
+fn print_pack_summary(pack: &[u8]) -> Result<()> {
+    let path = std::env::temp_dir().join(format!("mg_replay_{}.pack", std::process::id()));
+    fs::write(&path, pack)?;
+    let entries = stored_pack_entries(&path);
+    fs::remove_file(&path)?;
+    let (entries, checksum_ok) = entries?;
+
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    for (object_type, _) in entries.iter() {
+        match counts.iter_mut().find(|(name, _)| name == object_type) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((object_type.clone(), 1)),
+        }
+    }
+    println!(
+        "pack: {} bytes, {} objects, checksum {}",
+        pack.len(),
+        entries.len(),
+        if checksum_ok { "ok" } else { "mismatch" }
+    );
+    for (object_type, count) in counts {
+        println!("  {} {}", object_type, count);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_packet() {
+        let content = b"0009done\n00000001000";
+        assert_eq!(
+            read_packet(content, 0).unwrap(),
+            (Packet::Data(b"done\n"), 9)
+        );
+        assert_eq!(read_packet(content, 9).unwrap(), (Packet::Flush, 13));
+        assert_eq!(read_packet(content, 13).unwrap(), (Packet::Delimiter, 17));
+        assert!(read_packet(content, 17).is_err());
+        assert!(read_packet(b"0010short", 0).is_err());
+    }
+}
@@ -1,13 +1,50 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+
+use crate::{
+    color::ColorWhen,
+    config::{parse_bool, parse_size, Config},
+    error::MgError,
+    object_cache::ObjectCache,
+    output::OutputFormat,
+    replace::Replacements,
+    shared::{adjust_shared_perm, SharedMode},
+};
 use std::{
     env,
-    fs::{create_dir, read_to_string},
+    fs::{self, create_dir_all},
     path::{Path, PathBuf},
 };
 
+#[derive(Clone)]
 pub struct Repository {
+    /// The top of the worktree
     pub path: PathBuf,
-    pub ignore: Vec<String>,
+    /// The git directory: `.git` in the worktree unless `GIT_DIR` or
+    /// `--git-dir` says otherwise
+    pub git_dir: PathBuf,
+    /// The index file, overriding `index` in the git directory, as
+    /// `GIT_INDEX_FILE` does
+    pub index_file: Option<PathBuf>,
+    /// Whether `refs/replace/` and `info/grafts` are honored when reading objects
+    pub replace_objects: bool,
+    /// Bytes commands may use for large intermediate tables before spilling
+    /// them to disk, overriding `core.memoryBudget`
+    pub memory_budget: Option<usize>,
+    /// Whether read-only commands may take the index lock to refresh its
+    /// stat data, unless `--no-optional-locks` or `GIT_OPTIONAL_LOCKS=0`
+    pub optional_locks: bool,
+    /// How commands print what they produce, as `--format` says
+    pub output_format: OutputFormat,
+    /// When output is colored, as `--color` says, overriding `color.*`
+    pub color: Option<ColorWhen>,
+    /// Whether progress is reported, as `--progress` or `--quiet` say,
+    /// instead of when stderr is a terminal
+    pub progress: Option<bool>,
+    /// The objects read last, shared by the clones of the repository
+    pub object_cache: ObjectCache,
+    /// The replace refs and grafts, read once and shared by the clones of
+    /// the repository
+    pub replacements: Replacements,
 }
 
 pub fn default_init_path() -> PathBuf {
@@ -16,43 +53,310 @@ pub fn default_init_path() -> PathBuf {
         .unwrap_or_else(|_| PathBuf::from("."))
 }
 
+/// The `info/exclude` of a new repository.
+const DEFAULT_EXCLUDE: &str = "\
+# git ls-files --others --exclude-from=.git/info/exclude
+# Lines that start with '#' are comments.
+# For a project mostly in C, the following would be a good set of
+# exclude patterns (uncomment them if you want to use them):
+# *.[oa]
+# *~
+";
+
+#[derive(Default)]
+pub struct InitOptions {
+    /// Create a bare repository, without a worktree
+    pub bare: bool,
+    /// Share the repository with the group or everybody
+    pub shared: Option<SharedMode>,
+    /// The branch HEAD points to, instead of `init.defaultBranch` or `main`
+    pub initial_branch: Option<String>,
+}
+
+/// Whether `name` can be the name of a branch.
+fn is_valid_branch_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && !name.starts_with('/')
+        && !name.ends_with('/')
+        && !name.ends_with(".lock")
+        && !name.contains("..")
+        && !name.contains("//")
+        && !name.contains("@{")
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+}
+
+/// The directories of `GIT_CEILING_DIRECTORIES`, which discovery does not
+/// go up into.
+fn ceiling_directories() -> Vec<PathBuf> {
+    env::var_os("GIT_CEILING_DIRECTORIES")
+        .map(|value| {
+            env::split_paths(&value)
+                .filter(|dir| dir.is_absolute())
+                .map(|dir| dir.canonicalize().unwrap_or(dir))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The deepest of `ceilings` strictly above `dir`, past which a search
+/// starting at `dir` stops.
+fn ceiling_of<'a>(ceilings: &'a [PathBuf], dir: &Path) -> Option<&'a Path> {
+    ceilings
+        .iter()
+        .filter(|ceiling| dir.starts_with(ceiling) && ceiling.as_path() != dir)
+        .max_by_key(|ceiling| ceiling.components().count())
+        .map(PathBuf::as_path)
+}
+
+#[cfg(unix)]
+fn device_of(dir: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    dir.metadata().ok().map(|metadata| metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_of(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// The worktree containing `start`: the first of it and its parents with a
+/// `.git` directory. Like git, the search stops below the directories of
+/// `GIT_CEILING_DIRECTORIES`, and at the mount point of the filesystem of
+/// `start` unless `GIT_DISCOVERY_ACROSS_FILESYSTEM` is set.
+pub fn discover_worktree(start: &Path) -> Result<PathBuf> {
+    if start.join(".git").is_dir() {
+        return Ok(start.to_path_buf());
+    }
+
+    let start = start.canonicalize()?;
+    let ceilings = ceiling_directories();
+    let ceiling = ceiling_of(&ceilings, &start);
+    let across_filesystems = env::var("GIT_DISCOVERY_ACROSS_FILESYSTEM")
+        .ok()
+        .and_then(|value| parse_bool(&value))
+        .unwrap_or(false);
+    let device = device_of(&start);
+
+    let mut dir = start.as_path();
+    while let Some(parent) = dir.parent() {
+        if ceiling.is_some_and(|ceiling| ceiling.starts_with(parent)) {
+            break;
+        }
+        if !across_filesystems && device_of(parent) != device {
+            return Err(MgError::NotARepository(format!(
+                "not a git repository (or any parent up to mount point {})\n\
+                 Stopping at filesystem boundary (GIT_DISCOVERY_ACROSS_FILESYSTEM not set).",
+                dir.display()
+            ))
+            .into());
+        }
+        if parent.join(".git").is_dir() {
+            return Ok(parent.to_path_buf());
+        }
+        dir = parent;
+    }
+
+    Err(MgError::NotARepository(
+        "not a git repository (or any of the parent directories): .git".to_string(),
+    )
+    .into())
+}
+
 impl Repository {
     pub fn new() -> Result<Repository> {
-        let path = default_init_path();
+        Repository::open(default_init_path())
+    }
 
-        let mut repo = Repository {
-            path,
-            ignore: Vec::new(),
-        };
+    /// Open the repository of the environment: the git directory `git_dir`,
+    /// else `GIT_DIR`, else the one found from the current directory, or
+    /// `REPO_PATH`. The worktree is `GIT_WORK_TREE` if set; with an explicit
+    /// git directory it is otherwise the current directory, as in git.
+    pub fn discover(git_dir: Option<PathBuf>) -> Result<Repository> {
+        let git_dir = git_dir.or_else(|| env::var_os("GIT_DIR").map(PathBuf::from));
+        let work_tree = env::var_os("GIT_WORK_TREE").map(PathBuf::from);
 
-        repo.load_ignore()?;
+        let mut repo = match git_dir {
+            Some(git_dir) => {
+                if !git_dir.join("objects").is_dir() || !git_dir.join("HEAD").is_file() {
+                    return Err(MgError::NotARepository(format!(
+                        "not a git repository: '{}'",
+                        git_dir.display()
+                    ))
+                    .into());
+                }
+                let mut repo = Repository::open(work_tree.unwrap_or_else(default_init_path))?;
+                // hooks and drivers run from the worktree, so the git
+                // directory must not depend on the current one
+                repo.git_dir = git_dir.canonicalize()?;
+                repo
+            }
+            None => {
+                let path = discover_worktree(&default_init_path())?;
+                let git_dir = path.join(".git");
+                let mut repo = Repository::open(work_tree.unwrap_or(path))?;
+                repo.git_dir = git_dir;
+                repo
+            }
+        };
+        repo.index_file = env::var_os("GIT_INDEX_FILE").map(PathBuf::from);
 
         Ok(repo)
     }
 
-    fn load_ignore(&mut self) -> Result<bool> {
-        let ignore_path = self.path.join(".gitignore");
-        if !ignore_path.exists() {
-            return Ok(false);
+    /// Open the repository whose worktree is `path`.
+    pub fn open(path: PathBuf) -> Result<Repository> {
+        Ok(Repository {
+            git_dir: path.join(".git"),
+            path,
+            index_file: None,
+            replace_objects: env::var_os("GIT_NO_REPLACE_OBJECTS").is_none(),
+            memory_budget: None,
+            optional_locks: env::var("GIT_OPTIONAL_LOCKS").map_or(true, |value| value != "0"),
+            output_format: OutputFormat::default(),
+            color: None,
+            progress: None,
+            object_cache: ObjectCache::default(),
+            replacements: Replacements::default(),
+        })
+    }
+
+    /// The path of the index file.
+    pub fn index_path(&self) -> PathBuf {
+        match &self.index_file {
+            Some(path) => path.clone(),
+            None => self.git_dir.join("index"),
         }
+    }
 
-        let ignore_content = read_to_string(ignore_path)?;
-        self.ignore = ignore_content.lines().map(String::from).collect();
-        self.ignore.push("/.git/".to_string());
+    /// The memory budget of the command, if any.
+    pub fn memory_budget(&self) -> Result<Option<usize>> {
+        if self.memory_budget.is_some() {
+            return Ok(self.memory_budget);
+        }
 
-        Ok(true)
+        self.config()?
+            .get("core.memorybudget")
+            .map(|value| parse_size(&value))
+            .transpose()
     }
 
-    pub fn init_repository(&mut self, path: &Path) -> Result<PathBuf> {
+    /// Create a repository at `path`, in its `.git` directory unless `bare`.
+    /// With `shared`, the repository is set up to be written by its group (or
+    /// everybody) and refuses non-fast-forward pushes. Running it again on a
+    /// repository only adds what is missing, leaving HEAD, the configuration
+    /// and the refs alone. Returns the worktree and whether the repository
+    /// existed already.
+    pub fn init_repository(
+        &mut self,
+        path: &Path,
+        options: &InitOptions,
+    ) -> Result<(PathBuf, bool)> {
         self.path = path.to_path_buf();
-        let git_dir = self.path.join(".git");
+        self.git_dir = if options.bare {
+            self.path.clone()
+        } else {
+            self.path.join(".git")
+        };
+        let git_dir = self.git_dir.clone();
+        let head_path = git_dir.join("HEAD");
+        let reinit = head_path.is_file();
+        let branch = match &options.initial_branch {
+            Some(branch) if reinit => {
+                eprintln!("warning: re-init: ignored --initial-branch={}", branch);
+                None
+            }
+            _ if reinit => None,
+            Some(branch) => Some(branch.clone()),
+            None => Some(
+                Config::load_protected()?
+                    .get("init.defaultbranch")
+                    .unwrap_or_else(|| "main".to_string()),
+            ),
+        };
+        if let Some(branch) = branch
+            .as_ref()
+            .filter(|branch| !is_valid_branch_name(branch))
+        {
+            return Err(anyhow!("invalid initial branch name: '{}'", branch));
+        }
+
+        let dirs = [
+            git_dir.clone(),
+            git_dir.join("objects"),
+            git_dir.join("refs"),
+            git_dir.join("refs").join("heads"),
+            git_dir.join("refs").join("tags"),
+            git_dir.join("hooks"),
+            git_dir.join("info"),
+        ];
+        for dir in &dirs {
+            create_dir_all(dir)?;
+        }
+        let exclude_path = git_dir.join("info").join("exclude");
+        if !exclude_path.exists() {
+            fs::write(&exclude_path, DEFAULT_EXCLUDE)?;
+        }
 
-        create_dir(&git_dir)?;
-        create_dir(git_dir.join("objects"))?;
-        create_dir(git_dir.join("refs"))?;
+        if let Some(branch) = branch {
+            fs::write(&head_path, format!("ref: refs/heads/{}\n", branch))?;
+        }
 
-        std::fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n")?;
+        let config_path = git_dir.join("config");
+        let mut config = Config::load(&config_path)?;
+        if !config_path.exists() {
+            config.set("core.repositoryformatversion", "0")?;
+            config.set("core.filemode", if cfg!(unix) { "true" } else { "false" })?;
+            config.set("core.bare", if options.bare { "true" } else { "false" })?;
+            if !options.bare {
+                config.set("core.logallrefupdates", "true")?;
+            }
+        }
+        let shared = options.shared;
+        if let Some(shared) = shared.filter(|shared| *shared != SharedMode::Umask) {
+            config.set("core.sharedrepository", &shared.config_value())?;
+            config.set("receive.denynonfastforwards", "true")?;
+        }
+        config.write(&config_path)?;
+
+        if let Some(shared) = shared {
+            for path in dirs.into_iter().chain([head_path, config_path]) {
+                adjust_shared_perm(&path, shared)?;
+            }
+        }
+
+        Ok((self.path.clone(), reinit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_branch_name() {
+        assert!(is_valid_branch_name("main"));
+        assert!(is_valid_branch_name("release/v1"));
+        assert!(!is_valid_branch_name(""));
+        assert!(!is_valid_branch_name("a..b"));
+        assert!(!is_valid_branch_name("with space"));
+        assert!(!is_valid_branch_name("topic.lock"));
+    }
 
-        Ok(self.path.clone())
+    #[test]
+    fn test_ceiling_of() {
+        let ceilings = [PathBuf::from("/home"), PathBuf::from("/home/me/src")];
+        assert_eq!(
+            ceiling_of(&ceilings, Path::new("/home/me/src/mg/docs")),
+            Some(Path::new("/home/me/src"))
+        );
+        assert_eq!(
+            ceiling_of(&ceilings, Path::new("/home/me/src")),
+            Some(Path::new("/home"))
+        );
+        assert_eq!(ceiling_of(&ceilings, Path::new("/tmp/mg")), None);
     }
 }
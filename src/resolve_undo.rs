@@ -0,0 +1,143 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+
+use crate::index::IndexEntry;
+
+/// A resolved path of the `REUC` index extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveUndoEntry {
+    pub path: String,
+    /// mode and id of the base, ours and theirs stages, when present
+    pub stages: [Option<(u32, [u8; 20])>; 3],
+}
+
+/// The `REUC` index extension: the conflicted stages of the paths resolved
+/// since, so that their conflict can be recreated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolveUndo {
+    /// sorted by path
+    pub entries: Vec<ResolveUndoEntry>,
+}
+
+impl ResolveUndo {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn parse(mut data: &[u8]) -> Result<Self> {
+        let invalid = || anyhow!("invalid resolve-undo extension");
+        let field = |data: &mut &[u8]| -> Result<String> {
+            let nul = data.iter().position(|&b| b == 0).ok_or_else(invalid)?;
+            let value = String::from_utf8(data[..nul].to_vec())?;
+            *data = &data[nul + 1..];
+            Ok(value)
+        };
+
+        // <path> NUL, then each stage's octal mode NUL, then the id of each
+        // stage whose mode is not zero
+        let mut entries = Vec::new();
+        while !data.is_empty() {
+            let path = field(&mut data)?;
+            let mut modes = [0; 3];
+            for mode in modes.iter_mut() {
+                *mode = u32::from_str_radix(&field(&mut data)?, 8)?;
+            }
+
+            let mut stages = [None; 3];
+            for (stage, mode) in stages.iter_mut().zip(modes) {
+                if mode == 0 {
+                    continue;
+                }
+                let hash = data.get(..20).ok_or_else(invalid)?;
+                *stage = Some((mode, hash.try_into().expect("20 bytes")));
+                data = &data[20..];
+            }
+            entries.push(ResolveUndoEntry { path, stages });
+        }
+
+        Ok(ResolveUndo { entries })
+    }
+
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        for entry in &self.entries {
+            out.extend_from_slice(entry.path.as_bytes());
+            out.push(0);
+            for stage in &entry.stages {
+                let mode = stage.map_or(0, |(mode, _)| mode);
+                out.extend_from_slice(format!("{:o}", mode).as_bytes());
+                out.push(0);
+            }
+            for (_, hash) in entry.stages.iter().flatten() {
+                out.extend_from_slice(hash);
+            }
+        }
+    }
+
+    /// Remember the conflicted stages among `entries` of the paths being
+    /// resolved, replacing what was remembered for them.
+    pub fn record<'a>(&mut self, entries: impl IntoIterator<Item = &'a IndexEntry>) {
+        let mut recorded = HashSet::new();
+        for entry in entries {
+            let stage = entry.stage() as usize;
+            if !(1..=3).contains(&stage) {
+                continue;
+            }
+
+            let position = match self
+                .entries
+                .binary_search_by(|e| e.path.as_str().cmp(&entry.file_path))
+            {
+                Ok(position) => {
+                    if recorded.insert(entry.file_path.as_str()) {
+                        self.entries[position].stages = [None; 3];
+                    }
+                    position
+                }
+                Err(position) => {
+                    recorded.insert(entry.file_path.as_str());
+                    self.entries.insert(
+                        position,
+                        ResolveUndoEntry {
+                            path: entry.file_path.clone(),
+                            stages: [None; 3],
+                        },
+                    );
+                    position
+                }
+            };
+            self.entries[position].stages[stage - 1] = Some((entry.mode, entry.sha1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_undo_roundtrip() {
+        let undo = ResolveUndo {
+            entries: vec![
+                ResolveUndoEntry {
+                    path: "added.txt".to_string(),
+                    stages: [None, Some((0o100644, [1; 20])), Some((0o100755, [2; 20]))],
+                },
+                ResolveUndoEntry {
+                    path: "src/main.rs".to_string(),
+                    stages: [
+                        Some((0o100644, [3; 20])),
+                        Some((0o100644, [4; 20])),
+                        Some((0o100644, [5; 20])),
+                    ],
+                },
+            ],
+        };
+
+        let mut data = Vec::new();
+        undo.serialize(&mut data);
+        assert!(data.starts_with(b"added.txt\x000\x00100644\x00100755\x00"));
+        assert_eq!(ResolveUndo::parse(&data).unwrap(), undo);
+        assert!(ResolveUndo::parse(&data[..data.len() - 1]).is_err());
+    }
+}
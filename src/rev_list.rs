@@ -0,0 +1,257 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use hex::FromHex;
+
+use crate::{kind::Kind, repository::Repository, revwalk::RevWalkOptions};
+
+/// A size in bytes as `git rev-list --disk-usage=human` shows it, e.g.
+/// `1.50 MiB`.
+pub(crate) fn human_size(bytes: u64) -> String {
+    const UNITS: [(&str, u64); 3] = [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)];
+    for (unit, size) in UNITS {
+        if bytes >= size {
+            return format!("{:.2} {}", bytes as f64 / size as f64, unit);
+        }
+    }
+
+    match bytes {
+        1 => "1 byte".to_string(),
+        bytes => format!("{} bytes", bytes),
+    }
+}
+
+/// The commits revisions include, and those they exclude with the commits
+/// reachable from them.
+pub struct RevisionRoots {
+    pub include: Vec<[u8; 20]>,
+    pub exclude: Vec<[u8; 20]>,
+}
+
+impl Repository {
+    /// The commits `revisions` include and exclude. `A..B` includes `B`
+    /// and excludes `A`, `A...B` includes both and excludes their merge
+    /// bases, and `^A` excludes `A`; an end left out of a range is HEAD, and
+    /// no revision means HEAD.
+    pub fn revision_roots(&self, revisions: &[String]) -> Result<RevisionRoots> {
+        let mut include = Vec::new();
+        let mut exclude = Vec::new();
+        for rev in revisions {
+            if let Some((from, to)) = rev.split_once("...") {
+                let from = self.resolve_revision(if from.is_empty() { "HEAD" } else { from })?;
+                let to = self.resolve_revision(if to.is_empty() { "HEAD" } else { to })?;
+                exclude.extend(self.merge_bases(&from, &to)?);
+                include.extend([from, to]);
+            } else if let Some((from, to)) = self.resolve_range(rev)? {
+                exclude.push(from);
+                include.push(to);
+            } else if let Some(rev) = rev.strip_prefix('^') {
+                exclude.push(self.resolve_revision(rev)?);
+            } else {
+                include.push(self.resolve_revision(rev)?);
+            }
+        }
+        if include.is_empty() {
+            include.push(self.current_commit()?);
+        }
+
+        Ok(RevisionRoots { include, exclude })
+    }
+
+    /// Add the objects reachable from `roots` which are not in `seen` to
+    /// `objects`, marking them seen.
+    pub fn walk_objects(
+        &self,
+        roots: &[[u8; 20]],
+        seen: &mut HashSet<[u8; 20]>,
+        objects: &mut Vec<[u8; 20]>,
+    ) -> Result<()> {
+        let shallow = self.shallow_commits()?;
+        let mut stack = roots.to_vec();
+
+        while let Some(hash) = stack.pop() {
+            if !seen.insert(hash) {
+                continue;
+            }
+            objects.push(hash);
+
+            let mut object = self.read_object(&hex::encode(hash))?;
+            match object.kind() {
+                Kind::Commit | Kind::Tag => {
+                    let content = object.string()?;
+                    for line in content.lines().take_while(|line| !line.is_empty()) {
+                        let target = match line.split_once(' ') {
+                            Some(("tree", target)) | Some(("object", target)) => target,
+                            Some(("parent", _)) if shallow.contains(&hash) => continue,
+                            Some(("parent", target)) => target,
+                            _ => continue,
+                        };
+                        stack.push(<[u8; 20]>::from_hex(target)?);
+                    }
+                }
+                Kind::Tree => {
+                    for entry in object.tree_iter()? {
+                        let entry = entry?;
+                        match entry.kind {
+                            // submodule commits live in another repository
+                            Kind::Commit => {}
+                            Kind::Tree => stack.push(entry.hash),
+                            _ => {
+                                if seen.insert(entry.hash) {
+                                    objects.push(entry.hash);
+                                }
+                            }
+                        }
+                    }
+                }
+                Kind::Blob(_) | Kind::Symlink => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The commits reachable from `include` but not from `exclude`, the
+    /// most recently committed first.
+    fn rev_list_commits(
+        &self,
+        include: &[[u8; 20]],
+        exclude: &[[u8; 20]],
+    ) -> Result<Vec<[u8; 20]>> {
+        let mut walk = self.rev_walk(RevWalkOptions::default())?;
+        for commit in exclude {
+            walk.hide(commit)?;
+        }
+        for commit in include {
+            walk.push(commit)?;
+        }
+        walk.map(|entry| entry.map(|(hash, _)| hash)).collect()
+    }
+
+    /// The trees and blobs of `commit` which are not in `seen`, with their
+    /// paths, marking them seen.
+    fn commit_objects(
+        &self,
+        commit: &[u8; 20],
+        seen: &mut HashSet<[u8; 20]>,
+        objects: &mut Vec<([u8; 20], String)>,
+    ) -> Result<()> {
+        // depth first, in entry order, as git lists them
+        let mut stack = vec![(self.commit_tree(commit)?, String::new(), Kind::Tree)];
+        while let Some((hash, path, kind)) = stack.pop() {
+            if !seen.insert(hash) {
+                continue;
+            }
+            objects.push((hash, path.clone()));
+            if !matches!(kind, Kind::Tree) {
+                continue;
+            }
+
+            let entries = self.read_object(&hex::encode(hash))?.tree_entries()?;
+            for entry in entries.into_iter().rev() {
+                // submodule commits live in another repository
+                if matches!(entry.kind, Kind::Commit) {
+                    continue;
+                }
+                let entry_path = match path.as_str() {
+                    "" => entry.name,
+                    path => format!("{}/{}", path, entry.name),
+                };
+                stack.push((entry.hash, entry_path, entry.kind));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The size the object `hash` takes on disk: its loose file, or its
+    /// entry in a pack, as found in `packed`.
+    fn disk_size(&self, hash: &[u8; 20], packed: &HashMap<[u8; 20], u64>) -> Result<u64> {
+        let path = self.loose_object_path(&hex::encode(hash))?;
+        match path.metadata() {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(_) => Ok(packed.get(hash).copied().unwrap_or_default()),
+        }
+    }
+
+    /// List the commits of `revisions`, newest first, and with `objects`
+    /// the trees and blobs they add as `<id> <path>`. With `disk_usage`,
+    /// print instead the size these objects take on disk, in bytes or with
+    /// `human` in a readable unit. With `use_bitmap_index`, the objects are
+    /// found through the bitmap of a pack when there is one covering them,
+    /// and listed without paths: commits, trees, blobs then tags, each in
+    /// pack order.
+    pub fn rev_list(
+        &self,
+        revisions: &[String],
+        objects: bool,
+        disk_usage: Option<bool>,
+        use_bitmap_index: bool,
+    ) -> Result<()> {
+        let RevisionRoots { include, exclude } = self.revision_roots(revisions)?;
+        let bitmapped = match use_bitmap_index {
+            true => self.bitmap_reachable(&include, &exclude)?,
+            false => None,
+        };
+
+        let mut listed: Vec<([u8; 20], String)> = Vec::new();
+        let commits = if let Some((bitmap, reachable)) = &bitmapped {
+            if objects {
+                for kind in [Kind::Tree, Kind::Blob(false), Kind::Tag] {
+                    let found = bitmap.objects(reachable, &kind);
+                    listed.extend(found.into_iter().map(|hash| (hash, String::new())));
+                }
+            }
+            bitmap.objects(reachable, &Kind::Commit)
+        } else {
+            self.rev_list_commits(&include, &exclude)?
+        };
+
+        if objects && bitmapped.is_none() {
+            let mut seen = HashSet::new();
+            self.walk_objects(&exclude, &mut seen, &mut Vec::new())?;
+            for commit in &commits {
+                self.commit_objects(commit, &mut seen, &mut listed)?;
+            }
+        }
+
+        let Some(human) = disk_usage else {
+            for commit in &commits {
+                println!("{}", hex::encode(commit));
+            }
+            for (hash, path) in &listed {
+                match bitmapped {
+                    Some(_) => println!("{}", hex::encode(hash)),
+                    None => println!("{} {}", hex::encode(hash), path),
+                }
+            }
+            return Ok(());
+        };
+
+        let packed = self.packed_sizes()?;
+        let mut total = 0;
+        for hash in commits.iter().chain(listed.iter().map(|(hash, _)| hash)) {
+            total += self.disk_size(hash, &packed)?;
+        }
+        if human {
+            println!("{}", human_size(total));
+        } else {
+            println!("{}", total);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(1), "1 byte");
+        assert_eq!(human_size(1023), "1023 bytes");
+        assert_eq!(human_size(36343), "35.49 KiB");
+        assert_eq!(human_size(3 << 29), "1.50 GiB");
+    }
+}
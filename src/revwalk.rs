@@ -0,0 +1,306 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeSet, BinaryHeap, HashMap, HashSet},
+};
+
+use anyhow::Result;
+
+use crate::{commit::Commit, repository::Repository};
+
+/// The order a `RevWalk` yields commits in.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum Sort {
+    /// The most recently committed first
+    #[default]
+    Date,
+    /// No commit before its children, and the commits of a branch together
+    Topological,
+}
+
+#[derive(Default)]
+pub struct RevWalkOptions {
+    /// Follow only the first parent of merges
+    pub first_parent: bool,
+    pub sort: Sort,
+}
+
+/// How many uninteresting commits a limited walk goes on through, once it
+/// has found all it would yield.
+const SLOP: usize = 5;
+
+/// A commit waiting in the queue of a walk.
+struct Queued {
+    timestamp: i64,
+    order: u64,
+    hash: [u8; 20],
+    commit: Commit,
+}
+
+impl Queued {
+    /// The newest commit comes first, and the first queued among equals.
+    fn key(&self) -> (i64, Reverse<u64>) {
+        (self.timestamp, Reverse(self.order))
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// The order in which to show `commits`, given with their parents, so
+/// that each comes after all its children. Of the commits ready to be
+/// shown, the last one made ready goes first, which keeps branches
+/// together; commits are otherwise taken in the order given.
+fn topo_order(commits: &[([u8; 20], Vec<[u8; 20]>)]) -> Vec<usize> {
+    let index: HashMap<[u8; 20], usize> = commits
+        .iter()
+        .enumerate()
+        .map(|(i, (hash, _))| (*hash, i))
+        .collect();
+    let mut children = vec![0; commits.len()];
+    for (_, parents) in commits {
+        for parent in parents {
+            if let Some(&i) = index.get(parent) {
+                children[i] += 1;
+            }
+        }
+    }
+
+    let mut ready: Vec<usize> = (0..commits.len())
+        .rev()
+        .filter(|&i| children[i] == 0)
+        .collect();
+    let mut order = Vec::with_capacity(commits.len());
+    while let Some(i) = ready.pop() {
+        order.push(i);
+        for parent in &commits[i].1 {
+            if let Some(&p) = index.get(parent) {
+                children[p] -= 1;
+                if children[p] == 0 {
+                    ready.push(p);
+                }
+            }
+        }
+    }
+    order
+}
+
+/// A walk over the history of the commits pushed to it, merges followed
+/// through all their parents. Commits come out of a queue ordered by
+/// commit date, and commits reachable from a hidden commit are
+/// uninteresting: they are walked, to mark their own parents, but not
+/// yielded.
+pub struct RevWalk<'a> {
+    repo: &'a Repository,
+    options: RevWalkOptions,
+    shallow: BTreeSet<[u8; 20]>,
+    queue: BinaryHeap<Queued>,
+    queued: u64,
+    seen: HashSet<[u8; 20]>,
+    uninteresting: HashSet<[u8; 20]>,
+    /// The parents of the commits taken out of the queue
+    parents: HashMap<[u8; 20], Vec<[u8; 20]>>,
+    /// With hidden commits or a topological order, the whole result,
+    /// computed before the first commit is yielded
+    limited: Option<std::vec::IntoIter<([u8; 20], Commit)>>,
+}
+
+impl Repository {
+    /// Start a walk, to which commits are then pushed.
+    pub fn rev_walk(&self, options: RevWalkOptions) -> Result<RevWalk<'_>> {
+        Ok(RevWalk {
+            repo: self,
+            options,
+            shallow: self.shallow_commits()?,
+            queue: BinaryHeap::new(),
+            queued: 0,
+            seen: HashSet::new(),
+            uninteresting: HashSet::new(),
+            parents: HashMap::new(),
+            limited: None,
+        })
+    }
+}
+
+impl RevWalk<'_> {
+    /// Walk the history of `hash`.
+    pub fn push(&mut self, hash: &[u8; 20]) -> Result<()> {
+        self.enqueue(hash)
+    }
+
+    /// Leave out the history of `hash`, as `^hash` does.
+    pub fn hide(&mut self, hash: &[u8; 20]) -> Result<()> {
+        self.mark_uninteresting(hash);
+        self.enqueue(hash)
+    }
+
+    fn enqueue(&mut self, hash: &[u8; 20]) -> Result<()> {
+        if !self.seen.insert(*hash) {
+            return Ok(());
+        }
+        let commit = self.repo.read_commit(hash)?;
+        self.queue.push(Queued {
+            timestamp: commit.committer.timestamp,
+            order: self.queued,
+            hash: *hash,
+            commit,
+        });
+        self.queued += 1;
+        Ok(())
+    }
+
+    /// Mark `hash` uninteresting, and the ancestors of it already walked,
+    /// which a commit with a skewed date may have let through first.
+    fn mark_uninteresting(&mut self, hash: &[u8; 20]) {
+        let mut stack = vec![*hash];
+        while let Some(hash) = stack.pop() {
+            if self.uninteresting.insert(hash) {
+                if let Some(parents) = self.parents.get(&hash) {
+                    stack.extend(parents);
+                }
+            }
+        }
+    }
+
+    /// Take the next commit out of the queue and queue its parents. Returns
+    /// whether it is interesting too.
+    fn step(&mut self) -> Result<Option<([u8; 20], Commit, bool)>> {
+        let Some(Queued { hash, commit, .. }) = self.queue.pop() else {
+            return Ok(None);
+        };
+        let interesting = !self.uninteresting.contains(&hash);
+
+        // the parents of a shallow commit are not available locally, and
+        // uninteresting commits mark all their parents
+        let mut parents = match self.shallow.contains(&hash) {
+            true => Vec::new(),
+            false => commit.parents.clone(),
+        };
+        if interesting && self.options.first_parent {
+            parents.truncate(1);
+        }
+        for parent in &parents {
+            if !interesting {
+                self.mark_uninteresting(parent);
+            }
+            self.enqueue(parent)?;
+        }
+        self.parents.insert(hash, parents);
+
+        Ok(Some((hash, commit, interesting)))
+    }
+
+    /// Walk until only uninteresting commits are left in the queue, and
+    /// order what was found.
+    fn limit(&mut self) -> Result<Vec<([u8; 20], Commit)>> {
+        let mut commits = Vec::new();
+        let mut last_date = i64::MAX;
+        let mut slop = SLOP;
+        while let Some((hash, commit, interesting)) = self.step()? {
+            if interesting {
+                last_date = commit.committer.timestamp;
+                commits.push((hash, commit));
+                continue;
+            }
+
+            // once only uninteresting commits are left, a few more are walked
+            // as git does, in case one with a skewed date reaches back into
+            // what was found
+            let still_interesting = self
+                .queue
+                .iter()
+                .any(|queued| !self.uninteresting.contains(&queued.hash));
+            if still_interesting || self.queue.peek().is_some_and(|q| q.timestamp >= last_date) {
+                slop = SLOP;
+            } else {
+                slop -= 1;
+                if slop == 0 {
+                    break;
+                }
+            }
+        }
+        commits.retain(|(hash, _)| !self.uninteresting.contains(hash));
+
+        if self.options.sort == Sort::Topological {
+            let parents: Vec<([u8; 20], Vec<[u8; 20]>)> = commits
+                .iter()
+                .map(|(hash, _)| (*hash, self.parents[hash].clone()))
+                .collect();
+            let mut commits: Vec<Option<([u8; 20], Commit)>> =
+                commits.into_iter().map(Some).collect();
+            return Ok(topo_order(&parents)
+                .into_iter()
+                .filter_map(|i| commits[i].take())
+                .collect());
+        }
+        Ok(commits)
+    }
+
+    fn next_commit(&mut self) -> Result<Option<([u8; 20], Commit)>> {
+        if self.limited.is_none()
+            && (!self.uninteresting.is_empty() || self.options.sort == Sort::Topological)
+        {
+            self.limited = Some(self.limit()?.into_iter());
+        }
+        if let Some(limited) = &mut self.limited {
+            return Ok(limited.next());
+        }
+
+        while let Some((hash, commit, interesting)) = self.step()? {
+            if interesting {
+                return Ok(Some((hash, commit)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for RevWalk<'_> {
+    type Item = Result<([u8; 20], Commit)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_commit().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topo_order() {
+        // 3 merges 1 and 2, both on top of 0: whatever the dates, the
+        // merged branch comes right after the merge
+        let commits = [
+            ([3; 20], vec![[2; 20], [1; 20]]),
+            ([1; 20], vec![[0; 20]]),
+            ([2; 20], vec![[0; 20]]),
+            ([0; 20], vec![]),
+        ];
+        assert_eq!(topo_order(&commits), [0, 1, 2, 3]);
+
+        let commits = [
+            ([3; 20], vec![[1; 20], [2; 20]]),
+            ([2; 20], vec![[0; 20]]),
+            ([1; 20], vec![[0; 20]]),
+            ([0; 20], vec![]),
+        ];
+        assert_eq!(topo_order(&commits), [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{config::Config, repository::Repository};
+
+/// Whether `path` is listed in the `safe.directory` values `entries`. An
+/// empty value clears the entries before it, `*` allows every directory and
+/// a trailing `/*` every directory below a path.
+fn is_listed(entries: &[String], path: &Path) -> bool {
+    let start = entries
+        .iter()
+        .rposition(|entry| entry.is_empty())
+        .map_or(0, |empty| empty + 1);
+
+    entries[start..].iter().any(|entry| {
+        if entry == "*" {
+            return true;
+        }
+        let (entry, below) = match entry.strip_suffix("/*") {
+            Some(prefix) => (prefix, true),
+            None => (entry.as_str(), false),
+        };
+        let entry = match entry.strip_prefix("~/") {
+            Some(rest) => match env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(rest),
+                None => return false,
+            },
+            None => PathBuf::from(entry),
+        };
+        let entry = entry.canonicalize().unwrap_or(entry);
+
+        if below {
+            path.starts_with(&entry) && path != entry
+        } else {
+            path == entry
+        }
+    })
+}
+
+/// The user the repository must belong to: the current one, or for root,
+/// the user who ran `sudo`.
+#[cfg(unix)]
+fn expected_owner() -> u32 {
+    let uid = unsafe { libc::geteuid() };
+    if uid != 0 {
+        return uid;
+    }
+
+    env::var("SUDO_UID")
+        .ok()
+        .and_then(|sudo_uid| sudo_uid.parse().ok())
+        .unwrap_or(uid)
+}
+
+/// Whether `dirs` all belong to the expected owner.
+#[cfg(unix)]
+fn owned(dirs: &[&Path]) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let owner = expected_owner();
+    dirs.iter()
+        .all(|dir| dir.metadata().is_ok_and(|metadata| metadata.uid() == owner))
+}
+
+/// Ownership is only checked where files have a uid.
+#[cfg(not(unix))]
+fn owned(_dirs: &[&Path]) -> bool {
+    true
+}
+
+impl Repository {
+    /// Refuse to work on a repository owned by another user, whose config
+    /// and hooks could run anything as the current one, unless its worktree
+    /// is listed in `safe.directory` in the system or global configuration.
+    pub fn ensure_safe_directory(&self) -> Result<()> {
+        let git_dir = self.git_dir.clone();
+        if !git_dir.is_dir() {
+            return Ok(());
+        }
+
+        if owned(&[&self.path, &git_dir]) {
+            return Ok(());
+        }
+
+        let path = self.path.canonicalize()?;
+        if is_listed(&Config::load_protected()?.get_all("safe.directory"), &path) {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "detected dubious ownership in repository at '{}'\n\
+             To add an exception for this directory, call:\n\n\
+             \tgit config --global --add safe.directory {}",
+            path.display(),
+            path.display()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_listed() {
+        let entries = |values: &[&str]| -> Vec<String> {
+            values.iter().map(|value| value.to_string()).collect()
+        };
+        let path = Path::new("/nonexistent/shared/repo");
+
+        assert!(is_listed(&entries(&["/nonexistent/shared/repo"]), path));
+        assert!(is_listed(&entries(&["/nonexistent/shared/*"]), path));
+        assert!(is_listed(&entries(&["*"]), path));
+        assert!(!is_listed(&entries(&["/nonexistent/shared"]), path));
+        assert!(!is_listed(&entries(&["/nonexistent/shared/repo/*"]), path));
+        assert!(!is_listed(&entries(&["*", ""]), path));
+        assert!(is_listed(&entries(&["", "/nonexistent/shared/repo"]), path));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use thiserror::Error;
+
+use crate::{daemon::find_repository, pkt_line::packet_line, repository::Repository};
+
+/// Largest body taken, once decoded: upload-pack requests are lists of
+/// object ids, far below this.
+const MAX_BODY: u64 = 64 * 1024 * 1024;
+/// Longest request line, header line or chunk size line taken.
+const MAX_LINE: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// A request beyond the limits above, answered with 413 before anything
+/// is allocated for it.
+#[derive(Error, Debug)]
+#[error("request too large: {0}")]
+struct TooLarge(&'static str);
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn read_line<R: BufRead>(input: &mut R) -> Result<String> {
+    let mut line = String::new();
+    input.by_ref().take(MAX_LINE).read_line(&mut line)?;
+    if line.len() as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(TooLarge("line too long").into());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Append `length` bytes of `input` to `body`, within `MAX_BODY`.
+fn read_body<R: BufRead>(input: &mut R, body: &mut Vec<u8>, length: u64) -> Result<()> {
+    let total = (body.len() as u64)
+        .checked_add(length)
+        .filter(|&total| total <= MAX_BODY)
+        .ok_or(TooLarge("body too large"))?;
+    input.by_ref().take(length).read_to_end(body)?;
+    if body.len() as u64 != total {
+        return Err(anyhow!("request body ended early"));
+    }
+    Ok(())
+}
+
+/// Read a request, with its body decoded from chunked transfer encoding and
+/// gzip content encoding.
+fn read_request<R: BufRead>(input: &mut R) -> Result<Request> {
+    let request_line = read_line(input)?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(anyhow!("invalid request line: {}", request_line));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(input)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(TooLarge("too many headers").into());
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body: Vec::new(),
+    };
+
+    if request
+        .header("Transfer-Encoding")
+        .is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            let size = read_line(input)?;
+            let size = u64::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16)?;
+            if size == 0 {
+                // trailers, until an empty line
+                let mut trailers = 0;
+                while !read_line(input)?.is_empty() {
+                    trailers += 1;
+                    if trailers > MAX_HEADERS {
+                        return Err(TooLarge("too many trailers").into());
+                    }
+                }
+                break;
+            }
+            read_body(input, &mut request.body, size)?;
+            read_line(input)?;
+        }
+    } else if let Some(length) = request.header("Content-Length") {
+        let length = length.parse()?;
+        read_body(input, &mut request.body, length)?;
+    }
+
+    if request.header("Content-Encoding") == Some("gzip") {
+        let mut body = Vec::new();
+        GzDecoder::new(request.body.as_slice())
+            .take(MAX_BODY + 1)
+            .read_to_end(&mut body)?;
+        if body.len() as u64 > MAX_BODY {
+            return Err(TooLarge("body too large").into());
+        }
+        request.body = body;
+    }
+
+    Ok(request)
+}
+
+/// Write the status line and headers of a response whose body ends with the
+/// connection.
+fn write_head<W: Write>(out: &mut W, status: &str, content_type: &str) -> Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        status, content_type
+    )?;
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, base_path: &Path, export_all: bool) -> Result<()> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+    let request = match read_request(&mut input) {
+        Ok(request) => request,
+        Err(e) => {
+            if e.is::<TooLarge>() {
+                write_head(&mut out, "413 Payload Too Large", "text/plain")?;
+                out.flush()?;
+            }
+            return Err(e);
+        }
+    };
+
+    let (repo_path, service) = if let Some(repo) = request.path.strip_suffix("/info/refs") {
+        (repo, "info/refs")
+    } else if let Some(repo) = request.path.strip_suffix("/git-upload-pack") {
+        (repo, "git-upload-pack")
+    } else {
+        write_head(&mut out, "404 Not Found", "text/plain")?;
+        out.flush()?;
+        return Err(anyhow!("{} {}: not found", request.method, request.path));
+    };
+
+    let repo = find_repository(base_path, repo_path)
+        .filter(|path| export_all || path.join(".git").join("git-daemon-export-ok").exists());
+    let Some(path) = repo else {
+        write_head(&mut out, "404 Not Found", "text/plain")?;
+        out.flush()?;
+        return Err(anyhow!(
+            "{}: repository not found or not exported",
+            repo_path
+        ));
+    };
+
+    let repo = Repository::open(path)?.stored_objects();
+
+    match (request.method.as_str(), service) {
+        ("GET", "info/refs")
+            if request
+                .query
+                .split('&')
+                .any(|p| p == "service=git-upload-pack") =>
+        {
+            write_head(
+                &mut out,
+                "200 OK",
+                "application/x-git-upload-pack-advertisement",
+            )?;
+            out.write_all(&packet_line("# service=git-upload-pack\n"))?;
+            out.write_all(b"0000")?;
+            repo.advertise_refs(&mut out)?;
+        }
+        ("POST", "git-upload-pack") => {
+            write_head(&mut out, "200 OK", "application/x-git-upload-pack-result")?;
+            repo.upload_pack(&mut request.body.as_slice(), &mut out, true)?;
+        }
+        _ => {
+            // only fetching is served, and dumb clients are not
+            write_head(&mut out, "403 Forbidden", "text/plain")?;
+            out.flush()?;
+            return Err(anyhow!("{} {}: forbidden", request.method, request.path));
+        }
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+/// Serve the repositories under `base_path` to smart HTTP clients, each
+/// connection in its own thread.
+pub fn serve_http(addr: &str, base_path: &Path, export_all: bool) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Listening on http://{}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let base_path = base_path.to_path_buf();
+        thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default();
+            if let Err(e) = handle_connection(stream, &base_path, export_all) {
+                eprintln!("[{}] {}", peer, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request_limits() {
+        let request = b"POST /r/git-upload-pack HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let request = read_request(&mut &request[..]).unwrap();
+        assert_eq!(request.body, b"hello");
+
+        let huge = b"POST /r/git-upload-pack HTTP/1.1\r\nContent-Length: 99999999999999\r\n\r\n";
+        assert!(read_request(&mut &huge[..]).unwrap_err().is::<TooLarge>());
+
+        let chunked = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        1\r\na\r\nffffffffffffffff\r\n";
+        assert!(read_request(&mut &chunked[..])
+            .unwrap_err()
+            .is::<TooLarge>());
+
+        let short = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc";
+        assert!(read_request(&mut &short[..]).is_err());
+
+        let mut headers = b"GET / HTTP/1.1\r\n".to_vec();
+        for _ in 0..=MAX_HEADERS {
+            headers.extend(b"X: y\r\n");
+        }
+        assert!(read_request(&mut &headers[..])
+            .unwrap_err()
+            .is::<TooLarge>());
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    collections::BTreeSet,
+    fs::{read_to_string, remove_file},
+    io::Write,
+};
+
+use anyhow::Result;
+use hex::FromHex;
+
+use crate::{lockfile::LockFile, repository::Repository};
+
+/// The changes to the shallow boundary sent by a server along with a pack.
+#[derive(Debug, Default)]
+pub struct ShallowInfo {
+    pub shallow: Vec<String>,
+    pub unshallow: Vec<String>,
+}
+
+/// What a fetch tells the server about the shallow boundary, and how far it
+/// asks to move it.
+#[derive(Debug, Default)]
+pub struct ShallowRequest {
+    /// The commits of `.git/shallow`, whose parents are missing
+    pub shallow: Vec<String>,
+    /// The number of commits to fetch from the tips, or past the current
+    /// boundary when `relative`
+    pub depth: Option<u32>,
+    pub relative: bool,
+}
+
+/// The depth git asks for to fetch the whole history.
+pub const INFINITE_DEPTH: u32 = 0x7fffffff;
+
+impl Repository {
+    /// Commits whose parents are not present locally, as listed in `.git/shallow`.
+    pub fn shallow_commits(&self) -> Result<BTreeSet<[u8; 20]>> {
+        let shallow_path = self.git_dir.join("shallow");
+        if !shallow_path.exists() {
+            return Ok(BTreeSet::new());
+        }
+
+        read_to_string(shallow_path)?
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| Ok(<[u8; 20]>::from_hex(line.trim())?))
+            .collect()
+    }
+
+    /// Apply the `shallow`/`unshallow` lines sent by the server.
+    pub fn update_shallow(&self, info: &ShallowInfo) -> Result<()> {
+        let mut commits = self.shallow_commits()?;
+
+        for hash in &info.shallow {
+            commits.insert(<[u8; 20]>::from_hex(hash)?);
+        }
+        for hash in &info.unshallow {
+            commits.remove(&<[u8; 20]>::from_hex(hash)?);
+        }
+
+        let shallow_path = self.git_dir.join("shallow");
+        if commits.is_empty() {
+            if shallow_path.exists() {
+                remove_file(shallow_path)?;
+            }
+            return Ok(());
+        }
+
+        let mut lock = LockFile::acquire(&shallow_path)?;
+        for hash in &commits {
+            writeln!(lock, "{}", hex::encode(hash))?;
+        }
+        lock.commit()
+    }
+}
@@ -0,0 +1,144 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::repository::Repository;
+
+/// How `core.sharedRepository` opens up the permissions of the files of a
+/// repository shared by several users.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SharedMode {
+    /// Permissions follow the umask
+    Umask,
+    /// Group-writable
+    Group,
+    /// Group-writable and readable by everybody
+    All,
+    /// Exactly these permissions
+    Exact(u32),
+}
+
+impl SharedMode {
+    pub fn parse(value: &str) -> Result<SharedMode> {
+        match value.to_lowercase().as_str() {
+            "umask" | "false" | "no" | "off" | "0" => Ok(SharedMode::Umask),
+            "group" | "true" | "yes" | "on" | "1" => Ok(SharedMode::Group),
+            "all" | "world" | "everybody" | "2" => Ok(SharedMode::All),
+            octal if octal.starts_with('0') => {
+                let mode = u32::from_str_radix(octal, 8)
+                    .map_err(|_| anyhow!("invalid shared mode '{}'", value))?;
+                if mode & 0o600 != 0o600 || mode > 0o777 {
+                    return Err(anyhow!(
+                        "shared mode {} would prevent the owner from reading or writing",
+                        value
+                    ));
+                }
+                Ok(SharedMode::Exact(mode))
+            }
+            _ => Err(anyhow!("invalid shared mode '{}'", value)),
+        }
+    }
+
+    /// The value of `core.sharedRepository` for this mode.
+    pub fn config_value(&self) -> String {
+        match self {
+            SharedMode::Umask => "0".to_string(),
+            SharedMode::Group => "1".to_string(),
+            SharedMode::All => "2".to_string(),
+            SharedMode::Exact(mode) => format!("0{:o}", mode),
+        }
+    }
+
+    /// The permissions to give a file or directory created with `mode`.
+    /// Read-only files stay read-only, and directories are made searchable
+    /// wherever they are readable and setgid, so that their files keep the
+    /// group of the repository.
+    pub fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        let tweak = match self {
+            SharedMode::Umask => return mode,
+            SharedMode::Group => 0o660,
+            SharedMode::All => 0o664,
+            SharedMode::Exact(mode) => *mode,
+        };
+        let tweak = if mode & 0o200 != 0 {
+            tweak
+        } else {
+            tweak & !0o222
+        };
+
+        let mut new_mode = match self {
+            SharedMode::Exact(_) => (mode & !0o777) | tweak,
+            _ => mode | tweak,
+        };
+        if is_dir {
+            new_mode |= (new_mode & 0o444) >> 2;
+            new_mode |= 0o2000;
+        }
+        new_mode
+    }
+}
+
+impl Repository {
+    pub fn shared_mode(&self) -> Result<SharedMode> {
+        match self.config()?.get("core.sharedrepository") {
+            Some(value) => SharedMode::parse(&value),
+            None => Ok(SharedMode::Umask),
+        }
+    }
+
+    /// Give a file or directory just created in the repository the
+    /// permissions of `core.sharedRepository`.
+    pub fn adjust_shared_perm(&self, path: &Path) -> Result<()> {
+        adjust_shared_perm(path, self.shared_mode()?)
+    }
+}
+
+#[cfg(unix)]
+pub fn adjust_shared_perm(path: &Path, shared: SharedMode) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if shared == SharedMode::Umask {
+        return Ok(());
+    }
+
+    let metadata = std::fs::metadata(path)?;
+    let mode = metadata.permissions().mode() & 0o7777;
+    let new_mode = shared.apply(mode, metadata.is_dir());
+    if new_mode != mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(new_mode))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn adjust_shared_perm(_path: &Path, _shared: SharedMode) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_mode_apply() {
+        assert_eq!(SharedMode::Umask.apply(0o644, false), 0o644);
+        assert_eq!(SharedMode::Group.apply(0o644, false), 0o664);
+        assert_eq!(SharedMode::Group.apply(0o444, false), 0o444);
+        assert_eq!(SharedMode::Group.apply(0o755, true), 0o2775);
+        assert_eq!(SharedMode::All.apply(0o700, true), 0o2775);
+        assert_eq!(SharedMode::Exact(0o640).apply(0o644, false), 0o640);
+        assert_eq!(SharedMode::Exact(0o640).apply(0o755, true), 0o2750);
+    }
+
+    #[test]
+    fn test_shared_mode_parse() {
+        assert_eq!(SharedMode::parse("group").unwrap(), SharedMode::Group);
+        assert_eq!(SharedMode::parse("true").unwrap(), SharedMode::Group);
+        assert_eq!(SharedMode::parse("everybody").unwrap(), SharedMode::All);
+        assert_eq!(SharedMode::parse("0660").unwrap(), SharedMode::Exact(0o660));
+        assert_eq!(SharedMode::Exact(0o660).config_value(), "0660");
+        assert!(SharedMode::parse("0060").is_err());
+        assert!(SharedMode::parse("sometimes").is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{kind::Kind, repository::Repository};
+
+const PGP_BEGIN: &str = "-----BEGIN PGP SIGNATURE-----";
+const SSH_BEGIN: &str = "-----BEGIN SSH SIGNATURE-----";
+
+/// The kind of signatures made, from `gpg.format`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignatureFormat {
+    OpenPgp,
+    Ssh,
+}
+
+impl SignatureFormat {
+    fn of_signature(signature: &str) -> SignatureFormat {
+        if signature.starts_with(SSH_BEGIN) {
+            SignatureFormat::Ssh
+        } else {
+            SignatureFormat::OpenPgp
+        }
+    }
+}
+
+/// Add a `gpgsig` header holding `signature` after the headers of a commit.
+pub fn add_signature_header(commit: &[u8], signature: &str) -> Vec<u8> {
+    let headers_end = commit
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .map_or(commit.len(), |pos| pos + 1);
+
+    let mut header = String::from("gpgsig");
+    for line in signature.trim_end().lines() {
+        header.push(' ');
+        header.push_str(line);
+        header.push('\n');
+    }
+
+    let mut signed = commit[..headers_end].to_vec();
+    signed.extend_from_slice(header.as_bytes());
+    signed.extend_from_slice(&commit[headers_end..]);
+    signed
+}
+
+/// Split a commit into the signed payload (the commit without its `gpgsig`
+/// header) and the signature, if any.
+pub fn split_signed_commit(commit: &str) -> (String, Option<String>) {
+    let mut payload = String::new();
+    let mut signature: Option<String> = None;
+    let mut in_signature = false;
+    let mut in_headers = true;
+
+    for line in commit.split_inclusive('\n') {
+        if in_headers {
+            if let Some(rest) = line.strip_prefix("gpgsig ") {
+                signature = Some(rest.to_string());
+                in_signature = true;
+                continue;
+            }
+            if in_signature {
+                if let Some(rest) = line.strip_prefix(' ') {
+                    signature.get_or_insert_with(String::new).push_str(rest);
+                    continue;
+                }
+                in_signature = false;
+            }
+            if line == "\n" {
+                in_headers = false;
+            }
+        }
+        payload.push_str(line);
+    }
+
+    (payload, signature)
+}
+
+/// Split a tag into the signed payload and the signature which ends its
+/// message, if any.
+pub fn split_signed_tag(tag: &str) -> (String, Option<String>) {
+    let start = [PGP_BEGIN, SSH_BEGIN]
+        .iter()
+        .filter_map(|begin| tag.find(&format!("\n{}", begin)))
+        .min();
+
+    match start {
+        Some(start) => (
+            tag[..start + 1].to_string(),
+            Some(tag[start + 1..].to_string()),
+        ),
+        None => (tag.to_string(), None),
+    }
+}
+
+/// The outcome of checking a signature.
+pub struct Verification {
+    pub good: bool,
+    /// Who made the signature, if known
+    pub signer: Option<String>,
+    /// What the verifying program printed
+    pub output: String,
+}
+
+impl Repository {
+    fn signature_format(&self) -> Result<SignatureFormat> {
+        match self.config()?.get("gpg.format").as_deref() {
+            None | Some("openpgp") => Ok(SignatureFormat::OpenPgp),
+            Some("ssh") => Ok(SignatureFormat::Ssh),
+            Some(format) => Err(anyhow!("unsupported signature format '{}'", format)),
+        }
+    }
+
+    fn signing_program(&self, format: SignatureFormat) -> Result<String> {
+        let config = self.config()?;
+        Ok(match format {
+            SignatureFormat::OpenPgp => config
+                .get("gpg.openpgp.program")
+                .or_else(|| config.get("gpg.program"))
+                .unwrap_or_else(|| "gpg".to_string()),
+            SignatureFormat::Ssh => config
+                .get("gpg.ssh.program")
+                .unwrap_or_else(|| "ssh-keygen".to_string()),
+        })
+    }
+
+    /// A scratch file in the git directory, removed by the caller.
+    fn scratch_file(&self, name: &str, content: &[u8]) -> Result<PathBuf> {
+        let path = self
+            .git_dir
+            .join(format!("{}_{}", name, std::process::id()));
+        fs::write(&path, content)?;
+        Ok(path)
+    }
+
+    /// Sign `payload` with `user.signingKey` (for OpenPGP, the key of
+    /// `user.email` by default), returning the armored signature.
+    pub fn sign_payload(&self, payload: &[u8]) -> Result<String> {
+        let config = self.config()?;
+        let format = self.signature_format()?;
+        let program = self.signing_program(format)?;
+
+        let mut command = Command::new(&program);
+        let mut key_file = None;
+        match format {
+            SignatureFormat::OpenPgp => {
+                command.args(["--status-fd=2", "--detach-sign", "--armor"]);
+                if let Some(key) = config
+                    .get("user.signingkey")
+                    .or_else(|| config.get("user.email"))
+                {
+                    command.args(["--local-user", &key]);
+                }
+            }
+            SignatureFormat::Ssh => {
+                let key = config
+                    .get("user.signingkey")
+                    .ok_or_else(|| anyhow!("user.signingKey is needed to sign with ssh"))?;
+                // a literal public key is used through the agent
+                let literal = key
+                    .strip_prefix("key::")
+                    .or_else(|| key.starts_with("ssh-").then_some(key.as_str()));
+                let key_path = match literal {
+                    Some(literal) => {
+                        let path = self.scratch_file("SIGNING_KEY", literal.as_bytes())?;
+                        key_file = Some(path.clone());
+                        path
+                    }
+                    None => PathBuf::from(&key),
+                };
+                command.args(["-Y", "sign", "-n", "git", "-f"]);
+                command.arg(key_path);
+            }
+        }
+
+        let result = run_with_input(&mut command, payload);
+        if let Some(path) = key_file {
+            fs::remove_file(path)?;
+        }
+        let (success, stdout, stderr) =
+            result.map_err(|e| anyhow!("could not run {}: {}", program, e))?;
+        if !success || stdout.is_empty() {
+            return Err(anyhow!(
+                "{} failed to sign the data:\n{}",
+                program,
+                String::from_utf8_lossy(&stderr)
+            ));
+        }
+
+        Ok(String::from_utf8(stdout)?)
+    }
+
+    /// Check `signature` over `payload` against the keyring: the OpenPGP
+    /// keyring (`gpg.keyring` instead of the default one if set) or the
+    /// `gpg.ssh.allowedSignersFile` of SSH signatures.
+    pub fn verify_signature(&self, payload: &[u8], signature: &str) -> Result<Verification> {
+        let config = self.config()?;
+        let format = SignatureFormat::of_signature(signature);
+        let program = self.signing_program(format)?;
+        let signature_path = self.scratch_file("SIGNATURE", signature.as_bytes())?;
+
+        let result = match format {
+            SignatureFormat::OpenPgp => {
+                let mut command = Command::new(&program);
+                if let Some(keyring) = config.get("gpg.keyring") {
+                    command.args(["--no-default-keyring", "--keyring", &keyring]);
+                }
+                command.args(["--status-fd=1", "--verify"]);
+                command.arg(&signature_path).arg("-");
+                run_with_input(&mut command, payload).map(|(_, stdout, stderr)| {
+                    let status = String::from_utf8_lossy(&stdout);
+                    let signer = status.lines().find_map(|line| {
+                        line.strip_prefix("[GNUPG:] GOODSIG ")
+                            .and_then(|rest| rest.split_once(' '))
+                            .map(|(_, user)| user.to_string())
+                    });
+                    Verification {
+                        good: signer.is_some()
+                            && status.lines().any(|l| l.starts_with("[GNUPG:] VALIDSIG ")),
+                        signer,
+                        output: String::from_utf8_lossy(&stderr).into_owned(),
+                    }
+                })
+            }
+            SignatureFormat::Ssh => {
+                let allowed = config.get("gpg.ssh.allowedsignersfile").ok_or_else(|| {
+                    anyhow!("gpg.ssh.allowedSignersFile is needed to verify ssh signatures")
+                });
+                allowed.and_then(|allowed| {
+                    let mut find = Command::new(&program);
+                    find.args(["-Y", "find-principals", "-f", &allowed, "-s"])
+                        .arg(&signature_path);
+                    let (_, principals, _) = run_with_input(&mut find, b"")?;
+                    let principal = String::from_utf8_lossy(&principals)
+                        .lines()
+                        .next()
+                        .map(String::from);
+                    let Some(principal) = principal else {
+                        return Ok(Verification {
+                            good: false,
+                            signer: None,
+                            output: "no principal matched the signing key\n".to_string(),
+                        });
+                    };
+
+                    let mut verify = Command::new(&program);
+                    verify
+                        .args([
+                            "-Y", "verify", "-n", "git", "-f", &allowed, "-I", &principal, "-s",
+                        ])
+                        .arg(&signature_path);
+                    let (success, stdout, stderr) = run_with_input(&mut verify, payload)?;
+                    let mut output = String::from_utf8_lossy(&stdout).into_owned();
+                    output.push_str(&String::from_utf8_lossy(&stderr));
+                    Ok(Verification {
+                        good: success,
+                        signer: Some(principal),
+                        output,
+                    })
+                })
+            }
+        };
+
+        fs::remove_file(&signature_path)?;
+        result
+    }
+
+    /// Check the signature of a commit or, with `tag`, of an annotated tag,
+    /// printing what the verifying program says with `verbose`. Returns
+    /// whether the signature is good.
+    pub fn verify_object(&self, rev: &str, tag: bool, verbose: bool) -> Result<bool> {
+        let hash = self.resolve_revision(rev)?;
+        let mut object = self.read_object(&hex::encode(hash))?;
+        let (expected, kind) = match tag {
+            true => (matches!(object.kind(), Kind::Tag), "tag"),
+            false => (matches!(object.kind(), Kind::Commit), "commit"),
+        };
+        if !expected {
+            return Err(anyhow!("{}: cannot verify a non-{} object", rev, kind));
+        }
+
+        let content = object.string()?;
+        let (payload, signature) = match tag {
+            true => split_signed_tag(&content),
+            false => split_signed_commit(&content),
+        };
+        let Some(signature) = signature else {
+            eprintln!("error: no signature found in {} {}", kind, rev);
+            return Ok(false);
+        };
+
+        if verbose {
+            print!("{}", payload);
+        }
+        let verification = self.verify_signature(payload.as_bytes(), &signature)?;
+        eprint!("{}", verification.output);
+        match (&verification.signer, verification.good) {
+            (Some(signer), true) => eprintln!("Good signature from {}", signer),
+            (Some(signer), false) => eprintln!("BAD signature from {}", signer),
+            (None, _) => eprintln!("Could not verify the signature of {}", rev),
+        }
+
+        Ok(verification.good)
+    }
+}
+
+/// Run `command` with `input` on its standard input, returning whether it
+/// succeeded and what it printed.
+fn run_with_input(command: &mut Command, input: &[u8]) -> Result<(bool, Vec<u8>, Vec<u8>)> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    // the program may stop reading once it has seen enough
+    let _ = writer.join();
+
+    Ok((output.status.success(), output.stdout, output.stderr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMIT: &str = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\nmessage\n";
+
+    #[test]
+    fn test_commit_signature_roundtrip() {
+        let signature = format!("{}\n\nabcd\n=ef\n-----END PGP SIGNATURE-----\n", PGP_BEGIN);
+        let signed = add_signature_header(COMMIT.as_bytes(), &signature);
+        let signed = String::from_utf8(signed).unwrap();
+        assert!(signed.contains("\ngpgsig -----BEGIN PGP SIGNATURE-----\n \n abcd\n"));
+        assert!(signed.ends_with("-----END PGP SIGNATURE-----\n\nmessage\n"));
+
+        let (payload, found) = split_signed_commit(&signed);
+        assert_eq!(payload, COMMIT);
+        assert_eq!(found.as_deref(), Some(signature.as_str()));
+    }
+
+    #[test]
+    fn test_split_signed_tag() {
+        let tag = format!(
+            "object 4b825dc642cb6eb9a060e54bf8d69288fbee4904\ntype tree\ntag v1\n\nv1\n{}\nx\n-----END SSH SIGNATURE-----\n",
+            SSH_BEGIN
+        );
+        let (payload, signature) = split_signed_tag(&tag);
+        assert!(payload.ends_with("\n\nv1\n"));
+        assert!(signature.unwrap().starts_with(SSH_BEGIN));
+    }
+}
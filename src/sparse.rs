@@ -0,0 +1,142 @@
+use std::{
+    collections::HashSet,
+    fs::{create_dir_all, read_to_string, remove_dir, remove_file, write},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    kind::Kind, pathspec::Pathspec, pattern::PatternList, repository::Repository, tree::TreeFile,
+};
+
+impl Repository {
+    /// The sparse-checkout patterns, or `None` when sparse checkout is disabled.
+    pub fn sparse_patterns(&self) -> Result<Option<PatternList>> {
+        let sparse_path = self.git_dir.join("info").join("sparse-checkout");
+        if !sparse_path.exists() {
+            return Ok(None);
+        }
+
+        Ok(Some(PatternList::parse(&read_to_string(sparse_path)?)))
+    }
+
+    pub fn sparse_checkout_set(&self, patterns: &[String]) -> Result<()> {
+        let info_dir = self.git_dir.join("info");
+        create_dir_all(&info_dir)?;
+
+        let mut content = patterns.join("\n");
+        content.push('\n');
+        write(info_dir.join("sparse-checkout"), content)?;
+
+        self.reapply_sparse_checkout()
+    }
+
+    /// Print the sparse-checkout patterns as they are stored, without the
+    /// blank lines and comments.
+    pub fn sparse_checkout_list(&self) -> Result<()> {
+        let sparse_path = self.git_dir.join("info").join("sparse-checkout");
+        if !sparse_path.exists() {
+            return Ok(());
+        }
+
+        for line in read_to_string(sparse_path)?.lines() {
+            if !line.trim().is_empty() && !line.starts_with('#') {
+                println!("{}", line);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn sparse_checkout_disable(&self) -> Result<()> {
+        let sparse_path = self.git_dir.join("info").join("sparse-checkout");
+        if sparse_path.exists() {
+            remove_file(sparse_path)?;
+        }
+
+        self.reapply_sparse_checkout()
+    }
+
+    /// Update the worktree and the skip-worktree bits to match the current
+    /// patterns: the files now included are checked out, and those now
+    /// excluded removed. As in git, modified files are never overwritten or
+    /// removed: those left outside the patterns stay in the worktree without
+    /// their skip-worktree bit, with a warning.
+    fn reapply_sparse_checkout(&self) -> Result<()> {
+        if self.head_commit()?.is_none() {
+            return Ok(());
+        }
+
+        let patterns = self.sparse_patterns()?;
+        let (changes, _) = self.status_changes(&Pathspec::default())?;
+        let dirty: HashSet<&str> = changes.iter().map(|c| c.path.as_str()).collect();
+
+        let mut included = Vec::new();
+        let mut excluded = Vec::new();
+        for entry in self.load_index()?.entries {
+            if entry.stage() != 0 {
+                continue;
+            }
+            let wanted = patterns
+                .as_ref()
+                .is_none_or(|patterns| patterns.includes(&entry.file_path, false));
+            match (wanted, entry.skip_worktree()) {
+                (true, true) => included.push(TreeFile {
+                    kind: entry.kind(),
+                    hash: entry.sha1,
+                    path: entry.file_path,
+                }),
+                (false, false) => excluded.push(entry),
+                _ => {}
+            }
+        }
+
+        // download the blobs a partial clone is missing in one go
+        let needed: Vec<[u8; 20]> = included
+            .iter()
+            .filter(|file| !matches!(file.kind, Kind::Commit))
+            .map(|file| file.hash)
+            .collect();
+        self.prefetch_objects(&needed)?;
+        let missing: Vec<&TreeFile> = included
+            .iter()
+            // a file already there is kept, to show as modified if it differs
+            .filter(|file| self.path.join(&file.path).symlink_metadata().is_err())
+            .collect();
+        let mut attributes = self.checkout_attributes(missing.iter().copied())?;
+        for file in missing {
+            self.checkout_file(file, &mut attributes)
+                .with_context(|| format!("could not checkout {}", file.path))?;
+        }
+        let unskip: Vec<String> = included.into_iter().map(|file| file.path).collect();
+
+        let mut skip = Vec::new();
+        let mut left = Vec::new();
+        for entry in excluded {
+            if dirty.contains(entry.file_path.as_str()) {
+                left.push(entry.file_path);
+                continue;
+            }
+            let path = self.path.join(&entry.file_path);
+            match entry.kind() {
+                // submodules are empty directories
+                Kind::Commit => {
+                    let _ = remove_dir(&path);
+                }
+                _ if path.symlink_metadata().is_ok() => {
+                    self.remove_worktree_file(&entry.file_path)?
+                }
+                _ => {}
+            }
+            skip.push(entry.file_path);
+        }
+        if !left.is_empty() {
+            eprintln!(
+                "warning: the following paths are not up to date and were left despite sparse patterns:\n\t{}",
+                left.join("\n\t")
+            );
+        }
+
+        self.update_skip_worktree(&skip, &unskip)
+    }
+}
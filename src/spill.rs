@@ -0,0 +1,212 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+
+/// A fixed-size record which can be written to a spill file.
+pub trait SpillRecord: Ord + Clone {
+    const SIZE: usize;
+
+    fn encode(&self, out: &mut Vec<u8>);
+    fn decode(bytes: &[u8]) -> Self;
+}
+
+static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+
+/// A growing table of records kept under a memory budget: once the records
+/// in memory exceed the budget, they are sorted and written to a temporary
+/// file, and the sorted runs are merged back when iterating.
+pub struct SpillBuffer<T: SpillRecord> {
+    dir: PathBuf,
+    budget: Option<usize>,
+    items: Vec<T>,
+    runs: Vec<PathBuf>,
+}
+
+impl<T: SpillRecord> SpillBuffer<T> {
+    /// Create a buffer spilling to `dir`; without a budget everything stays in
+    /// memory.
+    pub fn new(dir: &Path, budget: Option<usize>) -> Self {
+        SpillBuffer {
+            dir: dir.to_path_buf(),
+            budget,
+            items: Vec::new(),
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, item: T) -> Result<()> {
+        self.items.push(item);
+
+        if let Some(budget) = self.budget {
+            if self.items.len() * T::SIZE >= budget.max(T::SIZE) {
+                self.spill()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.items.sort();
+
+        let run = NEXT_RUN.fetch_add(1, Ordering::Relaxed);
+        let path = self
+            .dir
+            .join(format!("tmp_spill_{}_{}", std::process::id(), run));
+        self.runs.push(path.clone());
+
+        let mut out = BufWriter::new(File::create(&path)?);
+        let mut buf = Vec::with_capacity(T::SIZE);
+        for item in self.items.drain(..) {
+            buf.clear();
+            item.encode(&mut buf);
+            out.write_all(&buf)?;
+        }
+        out.flush()?;
+
+        // give the memory back, the point is to stay under the budget
+        self.items.shrink_to_fit();
+
+        Ok(())
+    }
+
+    /// Stop adding records and sort them.
+    pub fn finish(mut self) -> SortedTable<T> {
+        self.items.sort();
+
+        SortedTable {
+            items: std::mem::take(&mut self.items),
+            runs: std::mem::take(&mut self.runs),
+        }
+    }
+}
+
+impl<T: SpillRecord> Drop for SpillBuffer<T> {
+    fn drop(&mut self) {
+        for path in &self.runs {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// The sorted records of a [`SpillBuffer`], part in memory and part in runs
+/// on disk.
+pub struct SortedTable<T: SpillRecord> {
+    items: Vec<T>,
+    runs: Vec<PathBuf>,
+}
+
+impl<T: SpillRecord> SortedTable<T> {
+    /// Iterate over every record in order. Can be called several times.
+    pub fn iter(&self) -> Result<SortedIter<'_, T>> {
+        let mut iter = SortedIter {
+            memory: &self.items,
+            position: 0,
+            runs: Vec::with_capacity(self.runs.len()),
+            heap: BinaryHeap::new(),
+        };
+        for path in &self.runs {
+            iter.runs.push(BufReader::new(File::open(path)?));
+        }
+        for source in 0..=iter.runs.len() {
+            iter.refill(source)?;
+        }
+
+        Ok(iter)
+    }
+}
+
+impl<T: SpillRecord> Drop for SortedTable<T> {
+    fn drop(&mut self) {
+        for path in &self.runs {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A k-way merge of the in-memory records (source 0) and the spilled runs.
+pub struct SortedIter<'a, T: SpillRecord> {
+    memory: &'a [T],
+    position: usize,
+    runs: Vec<BufReader<File>>,
+    heap: BinaryHeap<Reverse<(T, usize)>>,
+}
+
+impl<T: SpillRecord> SortedIter<'_, T> {
+    fn refill(&mut self, source: usize) -> Result<()> {
+        if source == 0 {
+            if let Some(item) = self.memory.get(self.position) {
+                self.heap.push(Reverse((item.clone(), 0)));
+                self.position += 1;
+            }
+            return Ok(());
+        }
+
+        let mut buf = vec![0; T::SIZE];
+        match self.runs[source - 1].read_exact(&mut buf) {
+            Ok(()) => self.heap.push(Reverse((T::decode(&buf), source))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: SpillRecord> Iterator for SortedIter<'_, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((item, source)) = self.heap.pop()?;
+        Some(self.refill(source).map(|_| item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl SpillRecord for u64 {
+        const SIZE: usize = 8;
+
+        fn encode(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&self.to_be_bytes());
+        }
+
+        fn decode(bytes: &[u8]) -> Self {
+            u64::from_be_bytes(bytes.try_into().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_spill_buffer_merges_runs() {
+        let dir = std::env::temp_dir();
+        let mut buffer = SpillBuffer::new(&dir, Some(8 * 10));
+
+        let values: Vec<u64> = (0..95).map(|i| (i * 37) % 101).collect();
+        for value in &values {
+            buffer.push(*value).unwrap();
+        }
+        assert_eq!(buffer.runs.len(), 9);
+
+        let table = buffer.finish();
+
+        let mut expected = values.clone();
+        expected.sort();
+        for _ in 0..2 {
+            let sorted: Vec<u64> = table.iter().unwrap().map(|v| v.unwrap()).collect();
+            assert_eq!(sorted, expected);
+        }
+
+        let runs = table.runs.clone();
+        drop(table);
+        assert!(runs.iter().all(|path| !path.exists()));
+    }
+}
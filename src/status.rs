@@ -0,0 +1,856 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt::{self, Write},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    color::{paint, GREEN, RED},
+    diff::{DiffEntry, DiffTarget},
+    error::MgError,
+    graph_export::json_string,
+    index::{list_all_files, IndexEntry},
+    metadata::is_executable,
+    output::OutputFormat,
+    pathspec::Pathspec,
+    rename::{find_renames, RenameFile},
+    repository::Repository,
+    revwalk::RevWalkOptions,
+};
+
+/// A path left conflicted in the index by a merge.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub path: String,
+    /// mode and id of the base, ours and theirs stages, when present
+    pub stages: [Option<(&'static str, [u8; 20])>; 3],
+    /// whether a directory stands where the path is a file, or the other way
+    pub directory_file: bool,
+}
+
+/// How conflicts are grouped in the summary of `status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConflictGroup {
+    /// Both sides changed the content
+    Content,
+    /// One side changed the file, the other deleted it
+    ModifyDelete,
+    /// A file on one side is a directory on the other
+    DirectoryFile,
+    /// Both deleted it, or one side added it alone
+    Other,
+}
+
+impl ConflictGroup {
+    fn title(&self) -> &str {
+        match self {
+            ConflictGroup::Content => "Content conflicts",
+            ConflictGroup::ModifyDelete => "Modify/delete conflicts",
+            ConflictGroup::DirectoryFile => "Directory/file conflicts",
+            ConflictGroup::Other => "Other conflicts",
+        }
+    }
+}
+
+impl Conflict {
+    /// The `XY` status of the conflict, from the stages it has.
+    pub fn code(&self) -> &'static str {
+        match self.stages.map(|stage| stage.is_some()) {
+            [true, false, false] => "DD",
+            [false, true, false] => "AU",
+            [true, true, false] => "UD",
+            [false, false, true] => "UA",
+            [true, false, true] => "DU",
+            [false, true, true] => "AA",
+            _ => "UU",
+        }
+    }
+
+    /// How `status` describes the conflict.
+    pub fn description(&self) -> &'static str {
+        match self.code() {
+            "DD" => "both deleted",
+            "AU" => "added by us",
+            "UD" => "deleted by them",
+            "UA" => "added by them",
+            "DU" => "deleted by us",
+            "AA" => "both added",
+            _ => "both modified",
+        }
+    }
+
+    pub fn group(&self) -> ConflictGroup {
+        if self.directory_file {
+            return ConflictGroup::DirectoryFile;
+        }
+        match self.code() {
+            "UU" | "AA" => ConflictGroup::Content,
+            "UD" | "DU" => ConflictGroup::ModifyDelete,
+            _ => ConflictGroup::Other,
+        }
+    }
+}
+
+/// The conflicts among the sorted index `entries`.
+pub fn conflicts(entries: &[IndexEntry]) -> Vec<Conflict> {
+    let paths: BTreeSet<&str> = entries.iter().map(|e| e.file_path.as_str()).collect();
+    let is_directory = |path: &str| {
+        let prefix = format!("{}/", path);
+        paths
+            .range(prefix.as_str()..)
+            .next()
+            .is_some_and(|next| next.starts_with(&prefix))
+    };
+
+    let mut conflicts: Vec<Conflict> = Vec::new();
+    for entry in entries.iter().filter(|e| e.stage() > 0) {
+        if conflicts.last().is_none_or(|c| c.path != entry.file_path) {
+            let path = &entry.file_path;
+            let ancestor_is_file = path
+                .match_indices('/')
+                .any(|(i, _)| paths.contains(&path[..i]));
+            // a merge moves the file aside to `<path>~<branch>`
+            let moved_aside = path
+                .rsplit_once('~')
+                .is_some_and(|(original, _)| is_directory(original));
+            conflicts.push(Conflict {
+                path: path.clone(),
+                stages: [None; 3],
+                directory_file: ancestor_is_file || moved_aside || is_directory(path),
+            });
+        }
+        let conflict = conflicts.last_mut().expect("pushed above");
+        conflict.stages[entry.stage() as usize - 1] = Some((entry.kind().to_mode(), entry.sha1));
+    }
+
+    conflicts
+}
+
+/// A path which differs between HEAD and the index, or between the index
+/// and the worktree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub path: String,
+    /// The `X` of `git status --porcelain`: how the index differs from HEAD
+    pub staged: char,
+    /// The `Y`: how the worktree differs from the index
+    pub unstaged: char,
+    /// The path in HEAD, in the index and in the worktree, when there
+    pub head: Option<DiffEntry>,
+    pub index: Option<DiffEntry>,
+    pub worktree: Option<DiffEntry>,
+    /// For a staged rename, the path in HEAD and the similarity in percent
+    pub renamed_from: Option<(String, u32)>,
+}
+
+/// The status code of a path going from `old` to `new`, a space if it is
+/// unchanged.
+fn change_code(old: Option<&DiffEntry>, new: Option<&DiffEntry>) -> char {
+    match (old, new) {
+        (None, None) => ' ',
+        (None, Some(_)) => 'A',
+        (Some(_), None) => 'D',
+        // a symlink or submodule in place of a file, or the other way
+        (Some(old), Some(new)) if old.mode[..2] != new.mode[..2] => 'T',
+        (Some(old), Some(new)) if old.hash != new.hash || old.mode != new.mode => 'M',
+        _ => ' ',
+    }
+}
+
+/// How the long format of `status` describes a change coded `code`.
+fn change_label(code: char) -> &'static str {
+    match code {
+        'A' => "new file:",
+        'D' => "deleted:",
+        'R' => "renamed:",
+        'T' => "typechange:",
+        _ => "modified:",
+    }
+}
+
+/// The `changes` at the indices of `files`, with their ids and `content`.
+fn rename_files<'a>(
+    changes: &'a [Change],
+    files: &[(usize, [u8; 20])],
+    content: &'a [Vec<u8>],
+) -> Vec<RenameFile<'a>> {
+    files
+        .iter()
+        .zip(content)
+        .map(|((i, hash), content)| RenameFile {
+            path: &changes[*i].path,
+            hash: *hash,
+            content,
+        })
+        .collect()
+}
+
+/// `untracked` files as `status` shows them: a directory holding no
+/// tracked file stands for all its files, with a trailing `/`.
+fn collapse_untracked(untracked: &[String], tracked: &[&str]) -> Vec<String> {
+    let mut tracked_dirs = HashSet::new();
+    for path in tracked {
+        for (i, _) in path.match_indices('/') {
+            tracked_dirs.insert(&path[..i]);
+        }
+    }
+
+    let mut shown: Vec<String> = Vec::new();
+    for file in untracked {
+        let collapsed = file
+            .match_indices('/')
+            .map(|(i, _)| &file[..i])
+            .find(|dir| !tracked_dirs.contains(dir))
+            .map_or_else(|| file.clone(), |dir| format!("{}/", dir));
+        if shown.last() != Some(&collapsed) {
+            shown.push(collapsed);
+        }
+    }
+    shown
+}
+
+/// The mode of the file at `path` in the worktree, 0 if there is none.
+fn worktree_mode(path: &std::path::Path) -> &'static str {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.file_type().is_symlink() => "120000",
+        Ok(metadata) if metadata.is_file() && is_executable(&metadata) => "100755",
+        Ok(metadata) if metadata.is_file() => "100644",
+        _ => "000000",
+    }
+}
+
+impl Repository {
+    /// Fail unless the index and the worktree match HEAD, before
+    /// `operation` replaces them.
+    pub fn ensure_clean_worktree(&self, operation: &str) -> Result<()> {
+        let base = match self.head_commit()? {
+            Some(commit) => DiffTarget::Tree(self.commit_tree(&commit)?),
+            None => DiffTarget::Index,
+        };
+        if !self.diff_targets(base, DiffTarget::Index)?.is_empty()
+            || !self
+                .diff_targets(DiffTarget::Index, DiffTarget::Worktree)?
+                .is_empty()
+        {
+            return Err(anyhow!(
+                "cannot {}: you have uncommitted changes",
+                operation
+            ));
+        }
+        Ok(())
+    }
+
+    /// The changes of the paths `pathspec` matches, outside of conflicts,
+    /// and the untracked files. Files whose stat data matches their index
+    /// entry are taken to be unchanged without being read, so only those
+    /// touched since the index was written are hashed; the untracked cache
+    /// spares reading the directories which did not change either.
+    pub fn status_changes(&self, pathspec: &Pathspec) -> Result<(Vec<Change>, Vec<String>)> {
+        let mut index = self.load_index()?;
+        // an untracked directory may hold files a pathspec matches only some of
+        let cached_untracked = match pathspec.is_empty() {
+            true => Some(self.untracked_files(&mut index)?),
+            false => None,
+        };
+        let head = match self.head_commit()? {
+            Some(commit) => DiffTarget::Tree(self.commit_tree(&commit)?),
+            None => DiffTarget::Empty,
+        };
+        let head = self.diff_snapshot(head)?;
+        let worktree = self.diff_snapshot(DiffTarget::Worktree)?;
+
+        let conflicted: HashSet<&str> = index
+            .entries
+            .iter()
+            .filter(|e| e.stage() > 0)
+            .map(|e| e.file_path.as_str())
+            .collect();
+        let staged = self.diff_snapshot(DiffTarget::Index)?;
+
+        let paths: BTreeSet<&str> = head
+            .keys()
+            .chain(staged.keys())
+            .map(String::as_str)
+            .filter(|path| !conflicted.contains(path) && pathspec.matches(path))
+            .collect();
+        let mut changes = Vec::new();
+        for path in paths {
+            let in_index = staged.get(path);
+            let in_worktree = in_index.and(worktree.get(path));
+            let change = Change {
+                path: path.to_string(),
+                staged: change_code(head.get(path), in_index),
+                unstaged: match in_index {
+                    Some(_) => change_code(in_index, in_worktree),
+                    None => ' ',
+                },
+                head: head.get(path).cloned(),
+                index: in_index.cloned(),
+                worktree: in_worktree.cloned(),
+                renamed_from: None,
+            };
+            if change.staged != ' ' || change.unstaged != ' ' {
+                changes.push(change);
+            }
+        }
+        let config = self.config()?;
+        let renames = config
+            .get_bool("status.renames")
+            .or_else(|| config.get_bool("diff.renames"))
+            .unwrap_or(true);
+        if renames {
+            self.find_staged_renames(&mut changes)?;
+        }
+
+        if let Some(untracked) = cached_untracked {
+            return Ok((changes, untracked));
+        }
+        let tracked: Vec<&str> = index.entries.iter().map(|e| e.file_path.as_str()).collect();
+        let known: HashSet<&str> = tracked.iter().copied().collect();
+        let untracked: Vec<String> = list_all_files(&self.path, &mut self.ignore_rules()?)?
+            .into_iter()
+            .filter(|file| !known.contains(file.as_str()) && pathspec.matches(file))
+            .collect();
+
+        Ok((changes, collapse_untracked(&untracked, &tracked)))
+    }
+
+    /// Pair the files deleted from the index with those added to it whose
+    /// content is mostly the same, as renames.
+    fn find_staged_renames(&self, changes: &mut Vec<Change>) -> Result<()> {
+        let regular_file = |entry: &Option<DiffEntry>| {
+            entry
+                .as_ref()
+                .filter(|entry| entry.mode.starts_with("100"))
+                .map(|entry| entry.hash)
+        };
+        let mut deleted = Vec::new();
+        let mut added = Vec::new();
+        for (i, change) in changes.iter().enumerate() {
+            match (
+                change.staged,
+                regular_file(&change.head),
+                regular_file(&change.index),
+            ) {
+                ('D', Some(hash), _) => deleted.push((i, hash)),
+                ('A', _, Some(hash)) => added.push((i, hash)),
+                _ => (),
+            }
+        }
+        if deleted.is_empty() || added.is_empty() {
+            return Ok(());
+        }
+
+        let read = |files: &[(usize, [u8; 20])]| -> Result<Vec<Vec<u8>>> {
+            files
+                .iter()
+                .map(|(_, hash)| self.read_object(&hex::encode(hash))?.content())
+                .collect()
+        };
+        let (deleted_content, added_content) = (read(&deleted)?, read(&added)?);
+        let renames = find_renames(
+            &rename_files(changes, &deleted, &deleted_content),
+            &rename_files(changes, &added, &added_content),
+        );
+
+        let mut gone = Vec::new();
+        for (from, to, score) in renames {
+            let (from, to) = (deleted[from].0, added[to].0);
+            let old = changes[from].clone();
+            let change = &mut changes[to];
+            change.staged = 'R';
+            change.head = old.head;
+            change.renamed_from = Some((old.path, score));
+            gone.push(from);
+        }
+        gone.sort_unstable();
+        for i in gone.into_iter().rev() {
+            changes.remove(i);
+        }
+        Ok(())
+    }
+
+    /// The report of `mg status`: the current branch, the paths left
+    /// conflicted by a merge, grouped by the kind of their conflict, the
+    /// staged and unstaged changes and the untracked files, or with
+    /// `porcelain` (`v1` or `v2`) in the stable format of `git status
+    /// --porcelain`, headed with the branch and its upstream with `branch`.
+    /// [`status_changes`](Self::status_changes) gives the same as data.
+    pub fn status(
+        &self,
+        porcelain: Option<&str>,
+        branch: bool,
+        pathspec: &Pathspec,
+    ) -> Result<String> {
+        let mut out = String::new();
+        let out = &mut out;
+        self.refresh_index()?;
+        let mut conflicts = conflicts(&self.load_index()?.entries);
+        conflicts.retain(|conflict| pathspec.matches(&conflict.path));
+        let (changes, untracked) = self.status_changes(pathspec)?;
+
+        if self.output_format == OutputFormat::Json {
+            write_json(
+                out,
+                self.read_head()?.trim(),
+                &conflicts,
+                &changes,
+                &untracked,
+            )?;
+            return Ok(out.clone());
+        }
+        match porcelain {
+            Some("1" | "v1") => {
+                if branch {
+                    writeln!(out, "## {}", self.branch_status()?.summary())?;
+                }
+                let mut lines: Vec<(&str, String)> = changes
+                    .iter()
+                    .map(|c| {
+                        let path = match &c.renamed_from {
+                            Some((from, _)) => format!("{} -> {}", from, c.path),
+                            None => c.path.clone(),
+                        };
+                        (
+                            c.path.as_str(),
+                            format!("{}{} {}", c.staged, c.unstaged, path),
+                        )
+                    })
+                    .chain(
+                        conflicts
+                            .iter()
+                            .map(|c| (c.path.as_str(), format!("{} {}", c.code(), c.path))),
+                    )
+                    .collect();
+                lines.sort();
+                for (_, line) in lines {
+                    writeln!(out, "{}", line)?;
+                }
+                for path in &untracked {
+                    writeln!(out, "?? {}", path)?;
+                }
+            }
+            Some("2" | "v2") => {
+                if branch {
+                    self.branch_status()?.write_headers(out)?;
+                }
+                // unlike v1, the conflicts come after the other changes
+                for change in &changes {
+                    writeln!(out, "{}", change_record(change))?;
+                }
+                for conflict in &conflicts {
+                    writeln!(out, "{}", self.conflict_record(conflict))?;
+                }
+                for path in &untracked {
+                    writeln!(out, "? {}", path)?;
+                }
+            }
+            Some(version) => {
+                return Err(
+                    MgError::Usage(format!("unsupported porcelain version '{}'", version)).into(),
+                )
+            }
+            None => {
+                let head = self.read_head()?;
+                match head.trim().strip_prefix("ref: refs/heads/") {
+                    Some(branch) => writeln!(out, "On branch {}", branch)?,
+                    None => writeln!(out, "HEAD detached at {}", &head.trim()[..7])?,
+                }
+                let color = self.use_color("status")?;
+                if !conflicts.is_empty() {
+                    write_conflicts(out, &conflicts, color)?;
+                    writeln!(out)?;
+                }
+                write_changes(out, &changes, &untracked, color)?;
+            }
+        }
+
+        Ok(out.clone())
+    }
+
+    /// The `u` record of porcelain v2 for `conflict`: the modes of its
+    /// stages and of the worktree file, then the ids of the stages.
+    fn conflict_record(&self, conflict: &Conflict) -> String {
+        let modes = conflict
+            .stages
+            .map(|stage| stage.map_or("000000", |(mode, _)| mode));
+        let ids = conflict
+            .stages
+            .map(|stage| hex::encode(stage.map_or([0; 20], |(_, id)| id)));
+        format!(
+            "u {} N... {} {} {} {} {} {} {} {}",
+            conflict.code(),
+            modes[0],
+            modes[1],
+            modes[2],
+            worktree_mode(&self.path.join(&conflict.path)),
+            ids[0],
+            ids[1],
+            ids[2],
+            conflict.path
+        )
+    }
+
+    fn branch_status(&self) -> Result<BranchStatus> {
+        let head = self.read_head()?;
+        let branch = head
+            .trim()
+            .strip_prefix("ref: refs/heads/")
+            .map(str::to_string);
+        let commit = self.head_commit()?;
+
+        let mut upstream = None;
+        if let Some(upstream_ref) = branch
+            .as_deref()
+            .map(|b| self.upstream_ref(b))
+            .transpose()?
+            .flatten()
+        {
+            let name = upstream_ref
+                .strip_prefix("refs/remotes/")
+                .or_else(|| upstream_ref.strip_prefix("refs/heads/"))
+                .unwrap_or(&upstream_ref)
+                .to_string();
+            let ahead_behind = match (commit, self.read_ref(&upstream_ref)?) {
+                (Some(commit), Some(tip)) => Some((
+                    self.count_missing(&commit, &tip)?,
+                    self.count_missing(&tip, &commit)?,
+                )),
+                _ => None,
+            };
+            upstream = Some((name, ahead_behind));
+        }
+
+        Ok(BranchStatus {
+            commit,
+            branch,
+            upstream,
+        })
+    }
+
+    /// The number of commits `from` has that `other` lacks.
+    fn count_missing(&self, from: &[u8; 20], other: &[u8; 20]) -> Result<usize> {
+        let mut walk = self.rev_walk(RevWalkOptions::default())?;
+        walk.push(from)?;
+        walk.hide(other)?;
+        walk.try_fold(0, |count, entry| entry.map(|_| count + 1))
+    }
+}
+
+/// Where HEAD is, for the headers of `status --branch`.
+struct BranchStatus {
+    /// None on a branch without commits yet
+    commit: Option<[u8; 20]>,
+    /// None when HEAD is detached
+    branch: Option<String>,
+    /// The short name of the upstream, with the commits the branch is
+    /// ahead and behind it unless the upstream is gone
+    upstream: Option<(String, Option<(usize, usize)>)>,
+}
+
+impl BranchStatus {
+    /// The `##` line of porcelain v1.
+    fn summary(&self) -> String {
+        let Some(branch) = &self.branch else {
+            return "HEAD (no branch)".to_string();
+        };
+        let mut line = match self.commit {
+            Some(_) => branch.clone(),
+            None => format!("No commits yet on {}", branch),
+        };
+        if let Some((upstream, ahead_behind)) = &self.upstream {
+            line.push_str(&format!("...{}", upstream));
+            match ahead_behind {
+                None => line.push_str(" [gone]"),
+                Some((0, 0)) => (),
+                Some((ahead, 0)) => line.push_str(&format!(" [ahead {}]", ahead)),
+                Some((0, behind)) => line.push_str(&format!(" [behind {}]", behind)),
+                Some((ahead, behind)) => {
+                    line.push_str(&format!(" [ahead {}, behind {}]", ahead, behind))
+                }
+            }
+        }
+        line
+    }
+
+    /// The `# branch.*` headers of porcelain v2.
+    fn write_headers(&self, out: &mut String) -> fmt::Result {
+        match self.commit {
+            Some(commit) => writeln!(out, "# branch.oid {}", hex::encode(commit))?,
+            None => writeln!(out, "# branch.oid (initial)")?,
+        }
+        writeln!(
+            out,
+            "# branch.head {}",
+            self.branch.as_deref().unwrap_or("(detached)")
+        )?;
+        if let Some((upstream, ahead_behind)) = &self.upstream {
+            writeln!(out, "# branch.upstream {}", upstream)?;
+            if let Some((ahead, behind)) = ahead_behind {
+                writeln!(out, "# branch.ab +{} -{}", ahead, behind)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `1` record of porcelain v2 for `change`, or `2` for a rename: its
+/// status, the modes in HEAD, the index and the worktree, then its ids in
+/// HEAD and the index.
+fn change_record(change: &Change) -> String {
+    let code = |code: char| if code == ' ' { '.' } else { code };
+    let mode = |entry: &Option<DiffEntry>| {
+        entry
+            .as_ref()
+            .map_or("000000".to_string(), |entry| format!("{:0>6}", entry.mode))
+    };
+    let id = |entry: &Option<DiffEntry>| hex::encode(entry.as_ref().map_or([0; 20], |e| e.hash));
+    let fields = format!(
+        "{}{} N... {} {} {} {} {}",
+        code(change.staged),
+        code(change.unstaged),
+        mode(&change.head),
+        mode(&change.index),
+        mode(&change.worktree),
+        id(&change.head),
+        id(&change.index)
+    );
+    match &change.renamed_from {
+        Some((from, score)) => format!("2 {} R{} {}\t{}", fields, score, change.path, from),
+        None => format!("1 {} {}", fields, change.path),
+    }
+}
+
+/// `status` as JSON: the head, then a line per conflicted, changed and
+/// untracked path.
+fn write_json(
+    out: &mut String,
+    head: &str,
+    conflicts: &[Conflict],
+    changes: &[Change],
+    untracked: &[String],
+) -> fmt::Result {
+    match head.strip_prefix("ref: refs/heads/") {
+        Some(branch) => writeln!(
+            out,
+            "{{\"type\":\"head\",\"branch\":{}}}",
+            json_string(branch)
+        )?,
+        None => writeln!(
+            out,
+            "{{\"type\":\"head\",\"branch\":null,\"commit\":{}}}",
+            json_string(head)
+        )?,
+    }
+    for conflict in conflicts {
+        writeln!(
+            out,
+            "{{\"type\":\"unmerged\",\"path\":{},\"code\":{}}}",
+            json_string(&conflict.path),
+            json_string(conflict.code())
+        )?;
+    }
+    // an unchanged side is null rather than a space
+    let code = |code: char| match code {
+        ' ' => "null".to_string(),
+        code => json_string(&code.to_string()),
+    };
+    for change in changes {
+        let renamed = match &change.renamed_from {
+            Some((from, score)) => format!(",\"from\":{},\"score\":{}", json_string(from), score),
+            None => String::new(),
+        };
+        writeln!(
+            out,
+            "{{\"type\":\"changed\",\"path\":{},\"index\":{},\"worktree\":{}{}}}",
+            json_string(&change.path),
+            code(change.staged),
+            code(change.unstaged),
+            renamed
+        )?;
+    }
+    for path in untracked {
+        writeln!(
+            out,
+            "{{\"type\":\"untracked\",\"path\":{}}}",
+            json_string(path)
+        )?;
+    }
+    Ok(())
+}
+
+fn write_conflicts(out: &mut String, conflicts: &[Conflict], color: bool) -> fmt::Result {
+    writeln!(out, "You have unmerged paths.")?;
+    writeln!(
+        out,
+        "  (fix conflicts and run \"mg add <file>...\" to mark resolution)"
+    )?;
+    let mut groups: Vec<ConflictGroup> = conflicts.iter().map(Conflict::group).collect();
+    groups.sort();
+    groups.dedup();
+    for group in groups {
+        writeln!(out, "\n{}:", group.title())?;
+        for conflict in conflicts.iter().filter(|c| c.group() == group) {
+            let line = format!(
+                "{:<17}{}",
+                format!("{}:", conflict.description()),
+                conflict.path
+            );
+            writeln!(out, "\t{}", paint(color.then_some(RED), &line))?;
+        }
+    }
+    Ok(())
+}
+
+/// The long format of the staged and unstaged changes and the untracked
+/// files, each in a section of its own, staged paths in green and the
+/// others in red with `color`.
+fn write_changes(
+    out: &mut String,
+    changes: &[Change],
+    untracked: &[String],
+    color: bool,
+) -> fmt::Result {
+    let (green, red) = (color.then_some(GREEN), color.then_some(RED));
+    let staged: Vec<&Change> = changes.iter().filter(|c| c.staged != ' ').collect();
+    if !staged.is_empty() {
+        writeln!(out, "Changes to be committed:")?;
+        for change in &staged {
+            let label = change_label(change.staged);
+            let line = match &change.renamed_from {
+                Some((from, _)) => format!("{:<12}{} -> {}", label, from, change.path),
+                None => format!("{:<12}{}", label, change.path),
+            };
+            writeln!(out, "\t{}", paint(green, &line))?;
+        }
+        writeln!(out)?;
+    }
+
+    let unstaged: Vec<&Change> = changes.iter().filter(|c| c.unstaged != ' ').collect();
+    if !unstaged.is_empty() {
+        writeln!(out, "Changes not staged for commit:")?;
+        writeln!(
+            out,
+            "  (use \"mg add <file>...\" to update what will be committed)"
+        )?;
+        for change in &unstaged {
+            let line = format!("{:<12}{}", change_label(change.unstaged), change.path);
+            writeln!(out, "\t{}", paint(red, &line))?;
+        }
+        writeln!(out)?;
+    }
+
+    if !untracked.is_empty() {
+        writeln!(out, "Untracked files:")?;
+        writeln!(
+            out,
+            "  (use \"mg add <file>...\" to include in what will be committed)"
+        )?;
+        for path in untracked {
+            writeln!(out, "\t{}", paint(red, path))?;
+        }
+        writeln!(out)?;
+    }
+
+    if !staged.is_empty() {
+        return Ok(());
+    }
+    match (unstaged.is_empty(), untracked.is_empty()) {
+        (false, _) => writeln!(out, "no changes added to commit (use \"mg add\")"),
+        (true, false) => {
+            writeln!(
+                out,
+                "nothing added to commit but untracked files present (use \"mg add\" to track)"
+            )
+        }
+        (true, true) => writeln!(out, "nothing to commit, working tree clean"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conflict(stages: [bool; 3], directory_file: bool) -> Conflict {
+        Conflict {
+            path: "file".to_string(),
+            stages: stages.map(|present| present.then_some(("100644", [0; 20]))),
+            directory_file,
+        }
+    }
+
+    #[test]
+    fn test_conflict_groups() {
+        let both = conflict([true, true, true], false);
+        assert_eq!((both.code(), both.group()), ("UU", ConflictGroup::Content));
+        let deleted = conflict([true, true, false], false);
+        assert_eq!(deleted.description(), "deleted by them");
+        assert_eq!(deleted.group(), ConflictGroup::ModifyDelete);
+        let added = conflict([false, true, false], true);
+        assert_eq!(
+            (added.code(), added.group()),
+            ("AU", ConflictGroup::DirectoryFile)
+        );
+        let gone = conflict([true, false, false], false);
+        assert_eq!((gone.code(), gone.group()), ("DD", ConflictGroup::Other));
+    }
+
+    #[test]
+    fn test_collapse_untracked() {
+        let untracked = ["d/x", "e/f/k", "e/w", "g/h/z", "g/y", "u"].map(String::from);
+        let tracked = ["a", "d/c", "e/f/y"];
+        assert_eq!(
+            collapse_untracked(&untracked, &tracked),
+            ["d/x", "e/f/k", "e/w", "g/", "u"]
+        );
+
+        let file = |mode: &str, hash| DiffEntry {
+            mode: mode.to_string(),
+            hash: [hash; 20],
+        };
+        assert_eq!(change_code(None, Some(&file("100644", 1))), 'A');
+        assert_eq!(
+            change_code(Some(&file("100644", 1)), Some(&file("100755", 1))),
+            'M'
+        );
+        assert_eq!(
+            change_code(Some(&file("100644", 1)), Some(&file("120000", 1))),
+            'T'
+        );
+        assert_eq!(
+            change_code(Some(&file("100644", 1)), Some(&file("100644", 1))),
+            ' '
+        );
+    }
+
+    #[test]
+    fn test_change_record() {
+        let file = |hash| DiffEntry {
+            mode: "100644".to_string(),
+            hash: [hash; 20],
+        };
+        let mut change = Change {
+            path: "new".to_string(),
+            staged: 'R',
+            unstaged: ' ',
+            head: Some(file(1)),
+            index: Some(file(2)),
+            worktree: Some(file(2)),
+            renamed_from: Some(("old".to_string(), 95)),
+        };
+        let ids = format!("{} {}", "01".repeat(20), "02".repeat(20));
+        assert_eq!(
+            change_record(&change),
+            format!("2 R. N... 100644 100644 100644 {} R95 new\told", ids)
+        );
+        change.renamed_from = None;
+        change.staged = 'M';
+        change.worktree = None;
+        change.unstaged = 'D';
+        assert_eq!(
+            change_record(&change),
+            format!("1 MD N... 100644 100644 000000 {} new", ids)
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+use hex::FromHex;
+
+use crate::{ident::Role, kind::Kind, repository::Repository};
+
+/// `prefix` without its trailing slashes, refusing the top of the worktree.
+fn normalize_prefix(prefix: &str) -> Result<&str> {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() || prefix.starts_with('/') || prefix.split('/').any(|c| c == "..") {
+        return Err(anyhow!("invalid prefix '{}'", prefix));
+    }
+    Ok(prefix)
+}
+
+/// The subtree commit a message of `subtree add` or of a merge of a split
+/// says it brought in at `prefix`.
+fn subtree_split_trailer(message: &str, prefix: &str) -> Option<[u8; 20]> {
+    let mut dir = None;
+    let mut split = None;
+    for line in message.lines() {
+        if let Some(value) = line.strip_prefix("git-subtree-dir: ") {
+            dir = Some(value.trim().trim_end_matches('/'));
+        } else if let Some(value) = line.strip_prefix("git-subtree-split: ") {
+            split = <[u8; 20]>::from_hex(value.trim()).ok();
+        }
+    }
+    split.filter(|_| dir == Some(prefix))
+}
+
+impl Repository {
+    /// The id of the tree at `prefix` in the tree of `commit`, if it has one.
+    fn subtree_at(&self, commit: &[u8; 20], prefix: &str) -> Result<Option<[u8; 20]>> {
+        Ok(self
+            .tree_lookup(&self.commit_tree(commit)?, prefix)?
+            .filter(|file| matches!(file.kind, Kind::Tree))
+            .map(|file| file.hash))
+    }
+
+    /// `tree` with `subtree` at `prefix` in place of what was there.
+    fn graft_tree(
+        &self,
+        tree: Option<&[u8; 20]>,
+        prefix: &str,
+        subtree: &[u8; 20],
+    ) -> Result<[u8; 20]> {
+        let dir = format!("{}/", prefix);
+        let mut files = Vec::new();
+        if let Some(tree) = tree {
+            for file in self.flatten_tree(tree)? {
+                if file.path != prefix && !file.path.starts_with(&dir) {
+                    files.push((file.path, file.kind.to_mode(), file.hash));
+                }
+            }
+        }
+        for file in self.flatten_tree(subtree)? {
+            files.push((
+                format!("{}{}", dir, file.path),
+                file.kind.to_mode(),
+                file.hash,
+            ));
+        }
+        files.sort();
+
+        let files: Vec<(&str, &str, [u8; 20])> = files
+            .iter()
+            .map(|(path, mode, hash)| (path.as_str(), *mode, *hash))
+            .collect();
+        self.write_tree_of_files(&files)
+    }
+
+    /// Commit `tree` on top of HEAD with `parents`, and check it out.
+    fn commit_subtree_merge(
+        &self,
+        tree: &[u8; 20],
+        parents: &[[u8; 20]],
+        message: &str,
+        reflog_message: &str,
+    ) -> Result<[u8; 20]> {
+        let old = self.head_commit()?;
+        let author = self.identity(Role::Author)?;
+        let commit = self.write_rebased_commit(tree, parents, &author, message)?;
+
+        self.materialize_tree(tree, false)?;
+        self.set_current_commit(&commit)?;
+        if self.read_head()?.starts_with("ref: ") {
+            let branch = format!("refs/heads/{}", self.current_branch()?);
+            self.append_reflog(&branch, old.as_ref(), &commit, reflog_message)?;
+        }
+        self.append_reflog("HEAD", old.as_ref(), &commit, reflog_message)?;
+
+        Ok(commit)
+    }
+
+    /// Add the tree of `revision` at `prefix`, with a merge commit keeping
+    /// its history.
+    pub fn subtree_add(&self, prefix: &str, revision: &str) -> Result<[u8; 20]> {
+        let prefix = normalize_prefix(prefix)?;
+        if self.path.join(prefix).exists() {
+            return Err(anyhow!("prefix '{}' already exists", prefix));
+        }
+        self.ensure_clean_worktree("add a subtree")?;
+
+        let split = self.resolve_revision(revision)?;
+        let head = self.head_commit()?;
+        let head_tree = head.map(|head| self.commit_tree(&head)).transpose()?;
+        let tree = self.graft_tree(head_tree.as_ref(), prefix, &self.commit_tree(&split)?)?;
+
+        let split_hex = hex::encode(split);
+        let mut message = format!(
+            "Add '{}/' from commit '{}'\n\ngit-subtree-dir: {}\n",
+            prefix, split_hex, prefix
+        );
+        if let Some(head) = head {
+            message.push_str(&format!("git-subtree-mainline: {}\n", hex::encode(head)));
+        }
+        message.push_str(&format!("git-subtree-split: {}", split_hex));
+        let parents: Vec<[u8; 20]> = head.into_iter().chain([split]).collect();
+
+        self.commit_subtree_merge(&tree, &parents, &message, "subtree add")
+    }
+
+    /// Merge the history of `revision` into the subtree at `prefix`. Returns
+    /// None when it is already merged.
+    pub fn subtree_merge(&self, prefix: &str, revision: &str) -> Result<Option<[u8; 20]>> {
+        let prefix = normalize_prefix(prefix)?;
+        self.ensure_clean_worktree("merge a subtree")?;
+
+        let head = self.current_commit()?;
+        let theirs = self.resolve_revision(revision)?;
+        if self.is_ancestor(&theirs, &head)? {
+            return Ok(None);
+        }
+        let Some(ours) = self.subtree_at(&head, prefix)? else {
+            return Err(anyhow!(
+                "'{}' does not exist; use 'mg subtree add' first",
+                prefix
+            ));
+        };
+
+        // the split history was merged as a second parent, so the merge base
+        // is a commit of the subtree history itself
+        let base = match self.merge_bases(&head, &theirs)?.first() {
+            Some(base) => Some(self.commit_tree(base)?),
+            None => None,
+        };
+        let merged = self.merge_trees(base.as_ref(), &ours, &self.commit_tree(&theirs)?)?;
+        let tree = self.graft_tree(Some(&self.commit_tree(&head)?), prefix, &merged)?;
+
+        let message = format!(
+            "Merge commit '{}'\n\ngit-subtree-dir: {}\ngit-subtree-split: {}",
+            hex::encode(theirs),
+            prefix,
+            hex::encode(theirs)
+        );
+        self.commit_subtree_merge(&tree, &[head, theirs], &message, "subtree merge")
+            .map(Some)
+    }
+
+    /// Extract the history of `prefix` from `revision` (HEAD by default)
+    /// into commits of its own, and point `branch` at the result if given.
+    /// The commits keep their authors, committers and messages, so that
+    /// splitting again gives the same commits.
+    pub fn subtree_split(
+        &self,
+        prefix: &str,
+        revision: Option<&str>,
+        branch: Option<&str>,
+    ) -> Result<[u8; 20]> {
+        let prefix = normalize_prefix(prefix)?;
+        let head = self.resolve_revision(revision.unwrap_or("HEAD"))?;
+
+        let commits = self.commits_oldest_first(&head)?;
+        // the commits `add` and `merge` brought in are the subtree history
+        // already
+        let mut split: HashMap<[u8; 20], Option<[u8; 20]>> = HashMap::new();
+        let mut split_trees: HashMap<[u8; 20], [u8; 20]> = HashMap::new();
+        for commit in &commits {
+            let message = self.commit_message(commit)?;
+            if let Some(known) = subtree_split_trailer(&message, prefix) {
+                split.insert(known, Some(known));
+                split_trees.insert(known, self.commit_tree(&known)?);
+            }
+        }
+
+        for commit in commits {
+            if split.contains_key(&commit) {
+                continue;
+            }
+            let mut parents: Vec<[u8; 20]> = Vec::new();
+            for parent in self.commit_parents(&commit)? {
+                if let Some(Some(parent)) = split.get(&parent) {
+                    if !parents.contains(parent) {
+                        parents.push(*parent);
+                    }
+                }
+            }
+
+            let mapped = match self.subtree_at(&commit, prefix)? {
+                // the history of the subtree goes on through commits without it
+                None => parents.first().copied(),
+                Some(tree) => {
+                    // a commit not changing the subtree maps to its parent,
+                    // unless it merges another line of its history
+                    let mut unchanged = None;
+                    for parent in &parents {
+                        if split_trees[parent] == tree {
+                            unchanged = Some(*parent);
+                            break;
+                        }
+                    }
+                    let mut merges = false;
+                    if let Some(same) = unchanged {
+                        for parent in parents.iter().filter(|p| **p != same) {
+                            merges |= !self.is_ancestor(parent, &same)?;
+                        }
+                    }
+                    match unchanged {
+                        Some(same) if !merges => Some(same),
+                        _ => {
+                            let new = self.write_split_commit(&commit, &tree, &parents)?;
+                            split_trees.insert(new, tree);
+                            Some(new)
+                        }
+                    }
+                }
+            };
+            split.insert(commit, mapped);
+        }
+
+        let Some(Some(result)) = split.get(&head).copied() else {
+            return Err(anyhow!(
+                "no commit of '{}' has the path '{}'",
+                hex::encode(head),
+                prefix
+            ));
+        };
+        if let Some(branch) = branch {
+            let name = format!("refs/heads/{}", branch);
+            if let Some(old) = self.read_ref(&name)? {
+                if !self.is_ancestor(&old, &result)? {
+                    return Err(anyhow!(
+                        "branch '{}' is not an ancestor of commit '{}'",
+                        branch,
+                        hex::encode(result)
+                    ));
+                }
+            }
+            let old = self.read_ref(&name)?;
+            self.update_ref(&name, &result)?;
+            self.append_reflog(&name, old.as_ref(), &result, "subtree split")?;
+        }
+
+        Ok(result)
+    }
+
+    /// The commits reachable from `head`, each after its parents.
+    fn commits_oldest_first(&self, head: &[u8; 20]) -> Result<Vec<[u8; 20]>> {
+        let shallow = self.shallow_commits()?;
+        let mut order = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![(*head, false)];
+
+        while let Some((commit, visited)) = stack.pop() {
+            if visited {
+                order.push(commit);
+                continue;
+            }
+            if !seen.insert(commit) {
+                continue;
+            }
+            stack.push((commit, true));
+            if !shallow.contains(&commit) {
+                for parent in self.commit_parents(&commit)?.into_iter().rev() {
+                    if !seen.contains(&parent) {
+                        stack.push((parent, false));
+                    }
+                }
+            }
+        }
+
+        Ok(order)
+    }
+
+    /// A copy of `commit` with `tree` and `parents`.
+    fn write_split_commit(
+        &self,
+        commit: &[u8; 20],
+        tree: &[u8; 20],
+        parents: &[[u8; 20]],
+    ) -> Result<[u8; 20]> {
+        let commit = self.read_commit(commit)?;
+
+        let mut out = format!("tree {}\n", hex::encode(tree));
+        for parent in parents {
+            out.push_str(&format!("parent {}\n", hex::encode(parent)));
+        }
+        out.push_str(&format!("author {}\n", commit.author));
+        out.push_str(&format!("committer {}\n", commit.committer));
+        if let Some(encoding) = commit.header("encoding") {
+            out.push_str(&format!("encoding {}\n", encoding));
+        }
+        out.push('\n');
+        out.push_str(&commit.message);
+
+        self.write_object(Kind::Commit, out.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_prefix() {
+        assert_eq!(normalize_prefix("vendor/lib/").unwrap(), "vendor/lib");
+        assert!(normalize_prefix("/").is_err());
+        assert!(normalize_prefix("../lib").is_err());
+    }
+
+    #[test]
+    fn test_subtree_split_trailer() {
+        let message = "Add 'lib/' from commit '1cb2'\n\n\
+                       git-subtree-dir: lib\n\
+                       git-subtree-split: 1cb29c18fbd0d279fc276b0f7a5f8356d0775914";
+        assert_eq!(
+            subtree_split_trailer(message, "lib").map(hex::encode),
+            Some("1cb29c18fbd0d279fc276b0f7a5f8356d0775914".to_string())
+        );
+        assert_eq!(subtree_split_trailer(message, "vendor"), None);
+    }
+}
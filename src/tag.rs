@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result};
+use hex::FromHex;
+
+use crate::{ident::Role, kind::Kind, repository::Repository};
+
+impl Repository {
+    /// The object a tag points to, or `None` if `hash` is not an annotated
+    /// tag.
+    pub fn peel_tag(&self, hash: &[u8; 20]) -> Result<Option<[u8; 20]>> {
+        let mut object = self.read_object(&hex::encode(hash))?;
+        if !matches!(object.kind(), Kind::Tag) {
+            return Ok(None);
+        }
+
+        let content = object.string()?;
+        match content
+            .lines()
+            .find_map(|line| line.strip_prefix("object "))
+        {
+            Some(target) => Ok(Some(<[u8; 20]>::from_hex(target)?)),
+            None => Err(anyhow!("tag {} has no object", hex::encode(hash))),
+        }
+    }
+
+    /// Create the tag `name` pointing at `target` (HEAD by default). With a
+    /// message or `sign`, an annotated tag object is made, signed when
+    /// `sign` is set or `tag.gpgSign` is configured.
+    pub fn create_tag(
+        &self,
+        name: &str,
+        target: Option<&str>,
+        message: Option<&str>,
+        sign: bool,
+    ) -> Result<[u8; 20]> {
+        let tag_ref = format!("refs/tags/{}", name);
+        if self.read_ref(&tag_ref)?.is_some() {
+            return Err(anyhow!("tag '{}' already exists", name));
+        }
+
+        let object = self.resolve_revision(target.unwrap_or("HEAD"))?;
+        let sign = sign || self.config()?.get_bool("tag.gpgsign").unwrap_or(false);
+        if message.is_none() && !sign {
+            self.update_ref(&tag_ref, &object)?;
+            return Ok(object);
+        }
+
+        let kind = self.read_object(&hex::encode(object))?.kind().to_string();
+        let mut tag = format!(
+            "object {}\ntype {}\ntag {}\ntagger {}\n\n",
+            hex::encode(object),
+            kind,
+            name,
+            self.identity(Role::Committer)?
+        );
+        let message = message.unwrap_or_default().trim_end();
+        if !message.is_empty() {
+            tag.push_str(message);
+            tag.push('\n');
+        }
+        if sign {
+            let signature = self.sign_payload(tag.as_bytes())?;
+            tag.push_str(&signature);
+        }
+
+        let hash = self.write_object(Kind::Tag, tag.as_bytes())?;
+        self.update_ref(&tag_ref, &hash)?;
+
+        Ok(hash)
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    attributes::Attributes, config::parse_size, diff::FileDiff, kind::Kind, repository::Repository,
+};
+
+/// The default bound on the size of the converted texts cached per driver.
+const DEFAULT_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+/// The converted texts of a diff driver, kept as notes on the converted
+/// blobs in `refs/notes/textconv/<driver>`. The notes commit records the
+/// textconv command, so changing it drops the cache.
+struct TextconvCache {
+    ref_name: String,
+    command: String,
+    /// The commit the cache was read from
+    parent: Option<[u8; 20]>,
+    /// Note blobs by the id of the converted blob
+    notes: BTreeMap<String, [u8; 20]>,
+}
+
+/// Run a textconv command on `content`, given as a temporary file named
+/// after `path` so that tools can tell the file type from its extension.
+fn run_textconv(git_dir: &Path, command: &str, path: &str, content: &[u8]) -> Result<Vec<u8>> {
+    let name = match Path::new(path).extension() {
+        Some(ext) => format!("textconv_{}.{}", std::process::id(), ext.to_string_lossy()),
+        None => format!("textconv_{}", std::process::id()),
+    };
+    let temp = git_dir.join(name);
+    fs::write(&temp, content)?;
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", command))
+        .arg(command)
+        .arg(&temp)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output();
+    fs::remove_file(&temp)?;
+
+    let output = output.map_err(|e| anyhow!("could not run textconv '{}': {}", command, e))?;
+    if !output.status.success() {
+        return Err(anyhow!("textconv '{}' failed for {}", command, path));
+    }
+
+    Ok(output.stdout)
+}
+
+impl Repository {
+    /// The textconv command of the diff driver of `path`, with the driver.
+    fn textconv_command(
+        &self,
+        attributes: &mut Attributes,
+        path: &str,
+    ) -> Result<Option<(String, String)>> {
+        let Some(driver) = attributes.diff_driver(path)? else {
+            return Ok(None);
+        };
+
+        Ok(self
+            .config()?
+            .get(&format!("diff.{}.textconv", driver))
+            .map(|command| (driver, command)))
+    }
+
+    /// Convert `content`, the blob `hash` if known, to text with the
+    /// textconv command of its diff driver. Returns `None` when `path` has no
+    /// textconv command. Results are cached when `diff.<driver>.cachetextconv`
+    /// is set.
+    pub fn textconv(
+        &self,
+        attributes: &mut Attributes,
+        path: &str,
+        hash: Option<&[u8; 20]>,
+        content: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let Some((driver, command)) = self.textconv_command(attributes, path)? else {
+            return Ok(None);
+        };
+
+        let config = self.config()?;
+        let hash = match hash {
+            Some(hash)
+                if config.get_bool(&format!("diff.{}.cachetextconv", driver)) == Some(true) =>
+            {
+                hash
+            }
+            _ => return run_textconv(&self.git_dir, &command, path, content).map(Some),
+        };
+
+        let mut cache = self.load_textconv_cache(&driver, &command)?;
+        if let Some(note) = cache.notes.get(&hex::encode(hash)) {
+            return Ok(Some(self.read_object(&hex::encode(note))?.content()?));
+        }
+
+        let text = run_textconv(&self.git_dir, &command, path, content)?;
+        let limit = match config.get(&format!("diff.{}.cachesize", driver)) {
+            Some(size) => parse_size(&size)?,
+            None => DEFAULT_CACHE_SIZE,
+        };
+        if text.len() <= limit {
+            self.cache_textconv(&mut cache, hash, &text, limit)?;
+        }
+
+        Ok(Some(text))
+    }
+
+    fn load_textconv_cache(&self, driver: &str, command: &str) -> Result<TextconvCache> {
+        let ref_name = format!("refs/notes/textconv/{}", driver);
+        let mut cache = TextconvCache {
+            ref_name,
+            command: command.to_string(),
+            parent: None,
+            notes: BTreeMap::new(),
+        };
+        let Some(commit) = self.read_ref(&cache.ref_name)? else {
+            return Ok(cache);
+        };
+        cache.parent = Some(commit);
+
+        let content = self.read_object(&hex::encode(commit))?.string()?;
+        let (headers, message) = content.split_once("\n\n").unwrap_or((&content, ""));
+        if message.trim_end() != command {
+            return Ok(cache);
+        }
+
+        let tree = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("tree "))
+            .ok_or_else(|| anyhow!("{} has no tree", cache.ref_name))?;
+        for entry in self.read_object(tree)?.into_tree_iter()? {
+            let entry = entry?;
+            cache.notes.insert(entry.name, entry.hash);
+        }
+
+        Ok(cache)
+    }
+
+    /// Add the text converted from `hash` to the cache, emptying it first if
+    /// the cached texts would exceed `limit` bytes.
+    fn cache_textconv(
+        &self,
+        cache: &mut TextconvCache,
+        hash: &[u8; 20],
+        text: &[u8],
+        limit: usize,
+    ) -> Result<()> {
+        let mut size = text.len();
+        for note in cache.notes.values() {
+            size += self.read_object(&hex::encode(note))?.size();
+        }
+        if size > limit {
+            cache.notes.clear();
+        }
+
+        let note = self.write_object(Kind::Blob(false), text)?;
+        cache.notes.insert(hex::encode(hash), note);
+
+        let mut tree = Vec::new();
+        for (name, note) in &cache.notes {
+            tree.extend_from_slice(format!("100644 {}\0", name).as_bytes());
+            tree.extend_from_slice(note);
+        }
+        let tree = self.write_object(Kind::Tree, &tree)?;
+
+        let mut commit = format!("tree {}\n", hex::encode(tree));
+        if let Some(parent) = cache.parent {
+            commit.push_str(&format!("parent {}\n", hex::encode(parent)));
+        }
+        commit.push_str(&format!("\n{}\n", cache.command));
+        let commit = self.write_object(Kind::Commit, commit.as_bytes())?;
+        self.update_ref(&cache.ref_name, &commit)?;
+        cache.parent = Some(commit);
+
+        Ok(())
+    }
+
+    /// Convert both sides of each diff with their textconv command, if any,
+    /// so that they are shown as a text diff.
+    pub fn apply_textconv(&self, diffs: &mut [FileDiff]) -> Result<()> {
+        let mut attributes = self.attributes()?;
+
+        for file_diff in diffs {
+            if self
+                .textconv_command(&mut attributes, &file_diff.path)?
+                .is_none()
+            {
+                continue;
+            }
+
+            for (entry, content) in [
+                (&file_diff.old, &mut file_diff.old_content),
+                (&file_diff.new, &mut file_diff.new_content),
+            ] {
+                let Some(entry) = entry else {
+                    continue;
+                };
+                if let Some(text) =
+                    self.textconv(&mut attributes, &file_diff.path, Some(&entry.hash), content)?
+                {
+                    *content = text;
+                }
+            }
+            file_diff.diff_as_text = Some(true);
+        }
+
+        Ok(())
+    }
+
+    /// The file `<rev>:<path>` (or `:<path>` for the index), converted with
+    /// its textconv command if it has one.
+    pub fn textconv_file(&self, spec: &str) -> Result<Vec<u8>> {
+        let (_, path) = spec
+            .split_once(':')
+            .ok_or_else(|| anyhow!("<rev>:<path> required, only '{}' given", spec))?;
+        let path = path.trim_start_matches("./");
+        let hash = self.resolve_revision(spec)?;
+        let mut object = self.read_object(&hex::encode(hash))?;
+        if !matches!(object.kind(), Kind::Blob(_)) {
+            return Err(anyhow!("{} is not a file", spec));
+        }
+        let content = object.content()?;
+
+        let mut attributes = self.attributes()?;
+        let text = self.textconv(&mut attributes, path, Some(&hash), &content)?;
+        Ok(text.unwrap_or(content))
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    panic::Location,
+    path::Path,
+    process::ExitStatus,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{graph_export::json_string, log::civil_from_days};
+
+/// Where the events go when `MG_TRACE2` is set, in the NDJSON format of
+/// git's trace2 event target, so that the tools reading `GIT_TRACE2_EVENT`
+/// can read them too.
+static TRACE2: OnceLock<Mutex<Trace2>> = OnceLock::new();
+
+/// The accumulated intervals of a timer.
+struct Timer {
+    intervals: u64,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+struct Trace2 {
+    out: Box<dyn Write + Send>,
+    sid: String,
+    start: Instant,
+    /// The number of regions entered and not left yet
+    nesting: usize,
+    children: u32,
+    timers: BTreeMap<(&'static str, &'static str), Timer>,
+}
+
+impl Trace2 {
+    /// Write the event `event`, emitted at `location`, with the JSON
+    /// members `fields`. Tracing never fails the command: errors are
+    /// ignored.
+    fn emit(&mut self, event: &str, location: &Location, fields: &str) {
+        let line = format!(
+            "{{\"event\":\"{}\",\"sid\":{},\"thread\":\"main\",\"time\":\"{}\",\
+             \"file\":{},\"line\":{}{}}}\n",
+            event,
+            json_string(&self.sid),
+            utc_time(SystemTime::now(), true),
+            json_string(location.file()),
+            location.line(),
+            fields
+        );
+        let _ = self.out.write_all(line.as_bytes());
+        let _ = self.out.flush();
+    }
+
+    fn elapsed(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+}
+
+/// `time` in UTC, as `2024-03-05T14:07:12.123456Z`, or compact as
+/// `20240305T140712.123456Z`.
+fn utc_time(time: SystemTime, separated: bool) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let of_day = seconds.rem_euclid(86400);
+    let (hours, minutes, seconds) = (of_day / 3600, of_day / 60 % 60, of_day % 60);
+    let micros = since_epoch.subsec_micros();
+    if separated {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+            year, month, day, hours, minutes, seconds, micros
+        )
+    } else {
+        format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}.{:06}Z",
+            year, month, day, hours, minutes, seconds, micros
+        )
+    }
+}
+
+pub fn json_array(values: &[&str]) -> String {
+    let values: Vec<String> = values.iter().map(|value| json_string(value)).collect();
+    format!("[{}]", values.join(","))
+}
+
+/// Run `f` on the trace, if tracing.
+fn with_trace(f: impl FnOnce(&mut Trace2)) {
+    if let Some(trace) = TRACE2.get() {
+        if let Ok(mut trace) = trace.lock() {
+            f(&mut trace);
+        }
+    }
+}
+
+/// The output `MG_TRACE2` names: `1`, `2` or `true` for standard error, an
+/// absolute path to append to, or an existing directory to write a file per
+/// process to.
+fn open_target(value: &str, sid: &str) -> Option<Box<dyn Write + Send>> {
+    match value {
+        "" | "0" | "false" => None,
+        "1" | "2" | "true" => Some(Box::new(io::stderr())),
+        path if Path::new(path).is_absolute() => {
+            let path = Path::new(path);
+            let file = if path.is_dir() {
+                File::create(path.join(sid))
+            } else {
+                OpenOptions::new().create(true).append(true).open(path)
+            };
+            match file {
+                Ok(file) => Some(Box::new(file)),
+                Err(e) => {
+                    eprintln!("warning: could not open '{}' for tracing: {}", value, e);
+                    None
+                }
+            }
+        }
+        _ => {
+            eprintln!(
+                "warning: MG_TRACE2 must be an absolute path, a directory, 1 or 2, not '{}'",
+                value
+            );
+            None
+        }
+    }
+}
+
+/// Start tracing if `MG_TRACE2` is set, with the `version` and `start`
+/// events.
+#[track_caller]
+pub fn start(argv: &[String]) {
+    let Ok(value) = env::var("MG_TRACE2") else {
+        return;
+    };
+    let start = Instant::now();
+    let sid = format!(
+        "{}-P{:08x}",
+        utc_time(SystemTime::now(), false),
+        std::process::id()
+    );
+    let Some(out) = open_target(&value, &sid) else {
+        return;
+    };
+
+    let trace = Trace2 {
+        out,
+        sid,
+        start,
+        nesting: 0,
+        children: 0,
+        timers: BTreeMap::new(),
+    };
+    if TRACE2.set(Mutex::new(trace)).is_err() {
+        return;
+    }
+
+    let location = Location::caller();
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    with_trace(|trace| {
+        trace.emit(
+            "version",
+            location,
+            &format!(
+                ",\"evt\":\"3\",\"exe\":{}",
+                json_string(env!("CARGO_PKG_VERSION"))
+            ),
+        );
+        let t_abs = trace.elapsed();
+        trace.emit(
+            "start",
+            location,
+            &format!(",\"t_abs\":{:.6},\"argv\":{}", t_abs, json_array(&argv)),
+        );
+    });
+}
+
+/// The `def_repo` event, for the repository at `worktree`.
+#[track_caller]
+pub fn def_repo(worktree: &Path) {
+    let location = Location::caller();
+    let worktree = worktree.canonicalize().unwrap_or(worktree.to_path_buf());
+    with_trace(|trace| {
+        trace.emit(
+            "def_repo",
+            location,
+            &format!(
+                ",\"repo\":1,\"worktree\":{}",
+                json_string(&worktree.to_string_lossy())
+            ),
+        )
+    });
+}
+
+/// The `cmd_name` event, for the subcommand `name`.
+#[track_caller]
+pub fn cmd_name(name: &str) {
+    let location = Location::caller();
+    with_trace(|trace| {
+        let name = json_string(name);
+        trace.emit(
+            "cmd_name",
+            location,
+            &format!(",\"name\":{},\"hierarchy\":{}", name, name),
+        )
+    });
+}
+
+/// A region of the command, left when dropped.
+pub struct Region {
+    location: &'static Location<'static>,
+    category: &'static str,
+    label: &'static str,
+    start: Instant,
+}
+
+/// Enter the region `label` of `category`, with a `region_enter` event, and
+/// a `region_leave` one with its duration when the returned guard is
+/// dropped.
+#[track_caller]
+pub fn region(category: &'static str, label: &'static str) -> Region {
+    let location = Location::caller();
+    with_trace(|trace| {
+        trace.nesting += 1;
+        let fields = format!(
+            ",\"repo\":1,\"nesting\":{},\"category\":\"{}\",\"label\":\"{}\"",
+            trace.nesting, category, label
+        );
+        trace.emit("region_enter", location, &fields);
+    });
+
+    Region {
+        location,
+        category,
+        label,
+        start: Instant::now(),
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        let t_rel = self.start.elapsed().as_secs_f64();
+        with_trace(|trace| {
+            let fields = format!(
+                ",\"repo\":1,\"t_rel\":{:.6},\"nesting\":{},\"category\":\"{}\",\"label\":\"{}\"",
+                t_rel, trace.nesting, self.category, self.label
+            );
+            trace.emit("region_leave", self.location, &fields);
+            trace.nesting = trace.nesting.saturating_sub(1);
+        });
+    }
+}
+
+/// A running interval of a timer, added to it when dropped.
+pub struct TimerInterval {
+    category: &'static str,
+    name: &'static str,
+    start: Instant,
+}
+
+/// Time an interval of the timer `name` of `category`. Each timer is
+/// reported once, with the count, total, shortest and longest of its
+/// intervals, in a `timer` event when the command exits.
+pub fn timer(category: &'static str, name: &'static str) -> TimerInterval {
+    TimerInterval {
+        category,
+        name,
+        start: Instant::now(),
+    }
+}
+
+impl Drop for TimerInterval {
+    fn drop(&mut self) {
+        let interval = self.start.elapsed();
+        with_trace(|trace| {
+            let timer = trace
+                .timers
+                .entry((self.category, self.name))
+                .or_insert(Timer {
+                    intervals: 0,
+                    total: Duration::ZERO,
+                    min: Duration::MAX,
+                    max: Duration::ZERO,
+                });
+            timer.intervals += 1;
+            timer.total += interval;
+            timer.min = timer.min.min(interval);
+            timer.max = timer.max.max(interval);
+        });
+    }
+}
+
+/// A child process, from its `child_start` event.
+pub struct Child {
+    id: u32,
+    start: Instant,
+}
+
+/// The `child_start` event for a child process of class `class`, e.g.
+/// `hook` or `editor`, running `argv`.
+#[track_caller]
+pub fn child_start(class: &str, hook_name: Option<&str>, use_shell: bool, argv: &[&str]) -> Child {
+    let location = Location::caller();
+    let mut id = 0;
+    with_trace(|trace| {
+        id = trace.children;
+        trace.children += 1;
+        let hook_name = hook_name
+            .map(|name| format!(",\"hook_name\":{}", json_string(name)))
+            .unwrap_or_default();
+        let fields = format!(
+            ",\"child_id\":{},\"child_class\":{}{},\"use_shell\":{},\"argv\":{}",
+            id,
+            json_string(class),
+            hook_name,
+            use_shell,
+            json_array(argv)
+        );
+        trace.emit("child_start", location, &fields);
+    });
+
+    Child {
+        id,
+        start: Instant::now(),
+    }
+}
+
+impl Child {
+    /// The `child_exit` event, for the process `pid` which exited with
+    /// `status`.
+    #[track_caller]
+    pub fn exit(self, pid: u32, status: &ExitStatus) {
+        let location = Location::caller();
+        let t_rel = self.start.elapsed().as_secs_f64();
+        let code = status.code().unwrap_or(-1);
+        with_trace(|trace| {
+            let fields = format!(
+                ",\"child_id\":{},\"pid\":{},\"code\":{},\"t_rel\":{:.6}",
+                self.id, pid, code, t_rel
+            );
+            trace.emit("child_exit", location, &fields);
+        });
+    }
+}
+
+/// The `timer`, `exit` and `atexit` events, for a command exiting with
+/// `code`.
+#[track_caller]
+pub fn finish(code: i32) {
+    let location = Location::caller();
+    with_trace(|trace| {
+        let timers = std::mem::take(&mut trace.timers);
+        for ((category, name), timer) in timers {
+            let fields = format!(
+                ",\"category\":\"{}\",\"name\":\"{}\",\"intervals\":{},\
+                 \"t_total\":{:.6},\"t_min\":{:.6},\"t_max\":{:.6}",
+                category,
+                name,
+                timer.intervals,
+                timer.total.as_secs_f64(),
+                timer.min.as_secs_f64(),
+                timer.max.as_secs_f64()
+            );
+            trace.emit("timer", location, &fields);
+        }
+        let t_abs = trace.elapsed();
+        let fields = format!(",\"t_abs\":{:.6},\"code\":{}", t_abs, code);
+        trace.emit("exit", location, &fields);
+        trace.emit("atexit", location, &fields);
+    });
+}
+
+/// Exit with `code`, after the final trace events.
+#[track_caller]
+pub fn exit(code: i32) -> ! {
+    finish(code);
+    std::process::exit(code)
+}
@@ -0,0 +1,152 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+use reqwest::{Certificate, Client, Identity};
+
+use crate::repository::Repository;
+
+/// The address family transports connect with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    /// The family chosen by `--ipv4` or `--ipv6`, if any.
+    pub fn from_flags(ipv4: bool, ipv6: bool) -> Option<IpFamily> {
+        match (ipv4, ipv6) {
+            (true, _) => Some(IpFamily::V4),
+            (_, true) => Some(IpFamily::V6),
+            _ => None,
+        }
+    }
+}
+
+/// How the HTTP transport connects to servers.
+#[derive(Debug, Default, Clone)]
+pub struct TransportOptions {
+    /// Connect only over this address family
+    pub ip_family: Option<IpFamily>,
+    /// The CA certificates to trust instead of the system ones
+    /// (`http.sslCAInfo`)
+    pub ca_info: Option<PathBuf>,
+    /// Whether server certificates are checked (`http.sslVerify`)
+    pub ssl_verify: bool,
+    /// The client certificate and its key (`http.sslCert` and `http.sslKey`,
+    /// the certificate file holding the key if unset)
+    pub ssl_cert: Option<(PathBuf, PathBuf)>,
+    /// Addresses to connect to instead of resolving host names, while still
+    /// presenting the host name for TLS (`http.curloptResolve`, as
+    /// `<host>:<port>:<address>`)
+    pub resolve: Vec<(String, SocketAddr)>,
+    /// Whether the server reports its progress, and the pack received is
+    pub progress: bool,
+}
+
+/// Parse a `<host>:<port>:<address>` override; IPv6 addresses may be given
+/// in brackets.
+fn parse_resolve(value: &str) -> Result<(String, SocketAddr)> {
+    let invalid = || anyhow!("invalid http.curloptResolve '{}'", value);
+    let (host, rest) = value.split_once(':').ok_or_else(invalid)?;
+    let (port, address) = rest.split_once(':').ok_or_else(invalid)?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+
+    let address = match address.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port),
+        Err(_) => (address, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(invalid)?,
+    };
+    Ok((host.to_string(), address))
+}
+
+impl TransportOptions {
+    /// An HTTP client following these options.
+    pub fn client(&self) -> Result<Client> {
+        let mut builder = Client::builder();
+
+        builder = match self.ip_family {
+            // binding to the unspecified address of a family restricts
+            // connections to it
+            Some(IpFamily::V4) => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            Some(IpFamily::V6) => builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            None => builder,
+        };
+
+        if let Some(ca_info) = &self.ca_info {
+            let pem = fs::read(ca_info)
+                .map_err(|e| anyhow!("could not read {}: {}", ca_info.display(), e))?;
+            builder = builder.tls_built_in_root_certs(false);
+            for certificate in Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if !self.ssl_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        if let Some((cert, key)) = &self.ssl_cert {
+            let cert =
+                fs::read(cert).map_err(|e| anyhow!("could not read {}: {}", cert.display(), e))?;
+            let key =
+                fs::read(key).map_err(|e| anyhow!("could not read {}: {}", key.display(), e))?;
+            builder = builder.identity(Identity::from_pkcs8_pem(&cert, &key)?);
+        }
+
+        for (host, address) in &self.resolve {
+            builder = builder.resolve(host, *address);
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+impl Repository {
+    /// The transport options of the configuration, connecting over
+    /// `ip_family` if given.
+    pub fn transport_options(&self, ip_family: Option<IpFamily>) -> Result<TransportOptions> {
+        let config = self.config()?;
+
+        let ssl_cert = config.get("http.sslcert").map(|cert| {
+            let key = config.get("http.sslkey").unwrap_or_else(|| cert.clone());
+            (PathBuf::from(cert), PathBuf::from(key))
+        });
+
+        Ok(TransportOptions {
+            ip_family,
+            ca_info: config.get("http.sslcainfo").map(PathBuf::from),
+            ssl_verify: config.get_bool("http.sslverify").unwrap_or(true),
+            ssl_cert,
+            resolve: config
+                .get_all("http.curloptresolve")
+                .iter()
+                .map(|value| parse_resolve(value))
+                .collect::<Result<_>>()?,
+            progress: self.show_progress(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resolve() {
+        let (host, address) = parse_resolve("git.example.com:443:10.0.0.7").unwrap();
+        assert_eq!(host, "git.example.com");
+        assert_eq!(address, "10.0.0.7:443".parse().unwrap());
+
+        let (_, address) = parse_resolve("example.com:8443:[::1]").unwrap();
+        assert_eq!(address, "[::1]:8443".parse().unwrap());
+
+        assert!(parse_resolve("example.com:10.0.0.7").is_err());
+    }
+}
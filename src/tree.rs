@@ -1,14 +1,50 @@
-use anyhow::{Context, Result};
-use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use crate::kind::Kind;
+use crate::metadata::is_worktree_executable;
 use crate::object::TreeObject;
+use crate::parallel::{default_jobs, parallel_map};
 use crate::repository::Repository;
 
+/// Add the files under `path`, outside of `.git`, to `files`.
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for file in std::fs::read_dir(path)? {
+        let file = file?;
+        if file.file_name() == ".git" {
+            continue;
+        }
+        if file.file_type()?.is_dir() {
+            collect_files(&file.path(), files)?;
+        } else {
+            files.push(file.path());
+        }
+    }
+    Ok(())
+}
+
 impl Repository {
-    pub fn write_tree(&self, path: &PathBuf) -> Result<[u8; 20]> {
+    /// Write the tree of the directory `path`. Its blobs are all written
+    /// first, in parallel, then the trees from the bottom up.
+    pub fn write_tree(&self, path: &Path) -> Result<[u8; 20]> {
+        let mut files = Vec::new();
+        collect_files(path, &mut files)?;
+        let hashes = parallel_map(&files, default_jobs(), |file| {
+            self.write_blob(file)
+                .context(format!("could not write object {:?}", file.file_name()))
+        });
+
+        let mut blobs = HashMap::with_capacity(files.len());
+        for (file, hash) in files.into_iter().zip(hashes) {
+            blobs.insert(file, hash?);
+        }
+        self.write_tree_of(path, &blobs)
+    }
+
+    fn write_tree_of(&self, path: &Path, blobs: &HashMap<PathBuf, [u8; 20]>) -> Result<[u8; 20]> {
         let mut entries = Vec::new();
+        let trust_executable_bit = self.trust_executable_bit()?;
 
         let files = std::fs::read_dir(path)?;
         for file in files {
@@ -27,15 +63,18 @@ impl Repository {
 
             if file_type.is_dir() {
                 hash = self
-                    .write_tree(&file_path)
+                    .write_tree_of(&file_path, blobs)
                     .context("could not write_tree of subtree")?;
                 kind = Kind::Tree;
             } else {
-                hash = self.write_blob(&file_path).context(format!(
-                    "could not write object {:?}",
-                    file_path.file_name()
-                ))?;
-                kind = Kind::Blob(file_path.metadata()?.mode() & 0o111 != 0);
+                hash = *blobs.get(&file_path).ok_or_else(|| {
+                    anyhow!("{} appeared while writing the tree", file_path.display())
+                })?;
+                kind = Kind::Blob(is_worktree_executable(
+                    &file_path,
+                    trust_executable_bit,
+                    None,
+                )?);
             }
 
             entries.push(TreeObject {
@@ -60,3 +99,81 @@ impl Repository {
         self.write_object(Kind::Tree, &out).context("Write")
     }
 }
+
+#[derive(Debug)]
+pub struct TreeFile {
+    pub path: String,
+    pub kind: Kind,
+    pub hash: [u8; 20],
+}
+
+impl Repository {
+    /// Recursively list every non-tree entry of a tree, with paths relative to
+    /// the tree root.
+    pub fn flatten_tree(&self, hash: &[u8; 20]) -> Result<Vec<TreeFile>> {
+        let mut files = Vec::new();
+        self.flatten_tree_into(hash, "", &mut files)?;
+        Ok(files)
+    }
+
+    fn flatten_tree_into(
+        &self,
+        hash: &[u8; 20],
+        prefix: &str,
+        files: &mut Vec<TreeFile>,
+    ) -> Result<()> {
+        for entry in self.read_tree(hash)? {
+            let entry = entry?;
+            let path = format!("{}{}", prefix, entry.name);
+            match entry.kind {
+                Kind::Tree => self.flatten_tree_into(&entry.hash, &format!("{}/", path), files)?,
+                kind => files.push(TreeFile {
+                    path,
+                    kind,
+                    hash: entry.hash,
+                }),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the entry at `path` (e.g. `src/main.rs`) in a tree.
+    pub fn tree_lookup(&self, tree: &[u8; 20], path: &str) -> Result<Option<TreeFile>> {
+        let mut current = *tree;
+        let mut components = path.split('/').peekable();
+
+        while let Some(name) = components.next() {
+            // stop reading the tree at the entry, or at the first error
+            let entry = self
+                .read_tree(&current)?
+                .find(|entry| match entry {
+                    Ok(entry) => entry.name == name,
+                    Err(_) => true,
+                })
+                .transpose()?;
+            let Some(entry) = entry else {
+                return Ok(None);
+            };
+
+            match (components.peek(), entry.kind) {
+                (None, kind) => {
+                    return Ok(Some(TreeFile {
+                        path: path.to_string(),
+                        kind,
+                        hash: entry.hash,
+                    }))
+                }
+                (Some(_), Kind::Tree) => current = entry.hash,
+                (Some(_), _) => return Ok(None),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Return the tree id of a commit.
+    pub fn commit_tree(&self, commit: &[u8; 20]) -> Result<[u8; 20]> {
+        Ok(self.read_commit(commit)?.tree)
+    }
+}
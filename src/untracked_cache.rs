@@ -0,0 +1,760 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    bitmap::{read_ewah, Bitmap},
+    ignore::IgnoreRules,
+    index::{Index, IndexEntry},
+    metadata::{stat_data, StatData},
+    object::hash_blob,
+    repository::Repository,
+    trace2,
+};
+
+const DIR_SHOW_OTHER_DIRECTORIES: u32 = 1 << 1;
+const DIR_HIDE_EMPTY_DIRECTORIES: u32 = 1 << 2;
+/// How `status` lists untracked files: a directory holding no tracked file
+/// stands for its content, and is left out when none of it is untracked
+const STATUS_DIR_FLAGS: u32 = DIR_SHOW_OTHER_DIRECTORIES | DIR_HIDE_EMPTY_DIRECTORIES;
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64> {
+    let byte = |pos: &mut usize| -> Result<u64> {
+        let byte = *data
+            .get(*pos)
+            .ok_or_else(|| anyhow!("untracked cache is truncated"))?;
+        *pos += 1;
+        Ok(byte as u64)
+    };
+
+    let mut c = byte(pos)?;
+    let mut value = c & 0x7f;
+    while c & 0x80 != 0 {
+        if value >> 56 != 0 {
+            return Err(anyhow!("untracked cache has an overlong number"));
+        }
+        c = byte(pos)?;
+        value = ((value + 1) << 7) | (c & 0x7f);
+    }
+    Ok(value)
+}
+
+/// The variable-length numbers of the index: 7 bits a byte, most
+/// significant first, each continued byte standing for one more.
+fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    while value >> 7 != 0 {
+        value = (value >> 7) - 1;
+        bytes.push(0x80 | (value & 0x7f) as u8);
+    }
+    out.extend(bytes.iter().rev());
+}
+
+fn read_bytes<const N: usize>(data: &[u8], pos: &mut usize) -> Result<[u8; N]> {
+    let bytes = data
+        .get(*pos..*pos + N)
+        .ok_or_else(|| anyhow!("untracked cache is truncated"))?;
+    *pos += N;
+    Ok(bytes.try_into().expect("N bytes"))
+}
+
+fn read_string(data: &[u8], pos: &mut usize) -> Result<String> {
+    let nul = data[*pos..]
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| anyhow!("untracked cache is truncated"))?;
+    let value = String::from_utf8(data[*pos..*pos + nul].to_vec())?;
+    *pos += nul + 1;
+    Ok(value)
+}
+
+/// Stat data as the extension stores it: the index entry fields from the
+/// ctime to the size, without the mode.
+fn read_stat(data: &[u8], pos: &mut usize) -> Result<StatData> {
+    let mut fields = [0; 9];
+    for field in &mut fields {
+        *field = u32::from_be_bytes(read_bytes(data, pos)?);
+    }
+    let [ctime_s, ctime_n, mtime_s, mtime_n, dev, ino, uid, gid, size] = fields;
+    Ok(StatData {
+        ctime_s,
+        ctime_n,
+        mtime_s,
+        mtime_n,
+        dev,
+        ino,
+        mode: 0,
+        uid,
+        gid,
+        size,
+    })
+}
+
+fn write_stat(stat: &StatData, out: &mut Vec<u8>) {
+    for field in [
+        stat.ctime_s,
+        stat.ctime_n,
+        stat.mtime_s,
+        stat.mtime_n,
+        stat.dev,
+        stat.ino,
+        stat.uid,
+        stat.gid,
+        stat.size,
+    ] {
+        out.extend_from_slice(&field.to_be_bytes());
+    }
+}
+
+/// Whether a directory is as it was, as far as its stat data tells. The
+/// device and mode are left out, as git does.
+fn stat_unchanged(old: &StatData, new: &StatData) -> bool {
+    old.mtime_s == new.mtime_s
+        && old.mtime_n == new.mtime_n
+        && old.ctime_s == new.ctime_s
+        && old.ctime_n == new.ctime_n
+        && old.ino == new.ino
+        && old.uid == new.uid
+        && old.gid == new.gid
+        && old.size == new.size
+}
+
+/// The id git gives the ignore file at `path`, null if there is none: the
+/// blob id of its content with a newline appended, as it is parsed.
+fn ignore_file_hash(path: &Path) -> [u8; 20] {
+    match fs::read(path) {
+        Ok(content) if content.is_empty() => hash_blob(&content),
+        Ok(mut content) => {
+            content.push(b'\n');
+            hash_blob(&content)
+        }
+        Err(_) => [0; 20],
+    }
+}
+
+#[cfg(unix)]
+fn system_name() -> String {
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        return String::new();
+    }
+    unsafe { std::ffi::CStr::from_ptr(name.sysname.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(not(unix))]
+fn system_name() -> String {
+    "Windows".to_string()
+}
+
+/// An ignore file as the cache last saw it: its stat data and blob id, or
+/// zeros for a missing file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct IgnoreFile {
+    stat: StatData,
+    hash: [u8; 20],
+}
+
+impl IgnoreFile {
+    fn read(path: Option<&Path>) -> Self {
+        let Some(path) = path else {
+            return IgnoreFile::default();
+        };
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => IgnoreFile {
+                stat: stat_data(&metadata),
+                hash: ignore_file_hash(path),
+            },
+            _ => IgnoreFile::default(),
+        }
+    }
+}
+
+/// The listing of a directory in the untracked cache.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct UntrackedDir {
+    /// path component of the directory, empty for the root
+    name: String,
+    /// whether `untracked` is what the directory held when `stat` was taken
+    valid: bool,
+    /// whether it was only read to find out if it holds an untracked file,
+    /// being outside of the index
+    check_only: bool,
+    stat: StatData,
+    /// blob id of its `.gitignore`, null without one
+    exclude_hash: [u8; 20],
+    /// its untracked files, and untracked directories with a trailing `/`
+    untracked: Vec<String>,
+    /// the subdirectories read, sorted by name
+    dirs: Vec<UntrackedDir>,
+}
+
+impl UntrackedDir {
+    fn named(name: &str) -> Self {
+        UntrackedDir {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.valid = false;
+        self.untracked.clear();
+    }
+
+    /// Invalidate the directory and everything below it, as when the
+    /// patterns ignoring their files change.
+    fn invalidate_all(&mut self) {
+        self.invalidate();
+        for dir in &mut self.dirs {
+            dir.invalidate_all();
+        }
+    }
+
+    fn parse(data: &[u8], pos: &mut usize) -> Result<Self> {
+        let untracked_count = read_varint(data, pos)?;
+        let dir_count = read_varint(data, pos)?;
+        let name = read_string(data, pos)?;
+        let untracked = (0..untracked_count)
+            .map(|_| read_string(data, pos))
+            .collect::<Result<_>>()?;
+        let dirs = (0..dir_count)
+            .map(|_| UntrackedDir::parse(data, pos))
+            .collect::<Result<_>>()?;
+
+        Ok(UntrackedDir {
+            name,
+            untracked,
+            dirs,
+            ..Default::default()
+        })
+    }
+
+    /// The directory and those below it, depth first, as the extension
+    /// lists them.
+    fn preorder<'a>(&'a self, dirs: &mut Vec<&'a UntrackedDir>) {
+        dirs.push(self);
+        for dir in &self.dirs {
+            dir.preorder(dirs);
+        }
+    }
+
+    fn visit_mut(&mut self, f: &mut impl FnMut(&mut UntrackedDir) -> Result<()>) -> Result<()> {
+        f(self)?;
+        for dir in &mut self.dirs {
+            dir.visit_mut(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `UNTR` index extension: the untracked files of each directory of the
+/// worktree, with the stat data of the directory when they were listed, so
+/// that `status` only reads the directories which changed since. It also
+/// records the ignore files it was listed with, whose changes invalidate
+/// what they apply to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UntrackedCache {
+    /// NUL-terminated descriptions of the worktrees and systems the cache
+    /// was made for; git does not use it elsewhere
+    ident: Vec<u8>,
+    info_exclude: IgnoreFile,
+    excludes_file: IgnoreFile,
+    dir_flags: u32,
+    /// the name of the per-directory ignore files
+    exclude_per_dir: String,
+    root: Option<UntrackedDir>,
+}
+
+impl UntrackedCache {
+    fn new(ident: &str) -> Self {
+        UntrackedCache {
+            ident: format!("{}\0", ident).into_bytes(),
+            info_exclude: IgnoreFile::default(),
+            excludes_file: IgnoreFile::default(),
+            dir_flags: STATUS_DIR_FLAGS,
+            exclude_per_dir: ".gitignore".to_string(),
+            root: None,
+        }
+    }
+
+    /// Whether the cache lists untracked files the way `status` does, in
+    /// the worktree and on the system `ident` describes.
+    fn is_usable(&self, ident: &str) -> bool {
+        self.dir_flags == STATUS_DIR_FLAGS
+            && self.exclude_per_dir == ".gitignore"
+            && self.ident.split(|&b| b == 0).any(|i| i == ident.as_bytes())
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut pos = 0;
+        let ident_len = read_varint(data, &mut pos)? as usize;
+        let ident = data
+            .get(pos..pos + ident_len)
+            .ok_or_else(|| anyhow!("untracked cache is truncated"))?
+            .to_vec();
+        pos += ident_len;
+
+        let info_stat = read_stat(data, &mut pos)?;
+        let excludes_stat = read_stat(data, &mut pos)?;
+        let dir_flags = u32::from_be_bytes(read_bytes(data, &mut pos)?);
+        let mut cache = UntrackedCache {
+            ident,
+            info_exclude: IgnoreFile {
+                stat: info_stat,
+                hash: read_bytes(data, &mut pos)?,
+            },
+            excludes_file: IgnoreFile {
+                stat: excludes_stat,
+                hash: read_bytes(data, &mut pos)?,
+            },
+            dir_flags,
+            exclude_per_dir: read_string(data, &mut pos)?,
+            root: None,
+        };
+
+        let count = read_varint(data, &mut pos)?;
+        if count == 0 {
+            return Ok(cache);
+        }
+        let mut root = UntrackedDir::parse(data, &mut pos)?;
+
+        // then, for the directories depth first, which are valid, which
+        // were only checked, and which have a .gitignore; the stat data of
+        // the valid ones and the ids of the .gitignore files follow
+        let valid = read_ewah(data, &mut pos)?;
+        let check_only = read_ewah(data, &mut pos)?;
+        let hashed = read_ewah(data, &mut pos)?;
+        let mut n = 0;
+        root.visit_mut(&mut |dir| {
+            dir.valid = valid.get(n);
+            dir.check_only = check_only.get(n);
+            if dir.valid {
+                dir.stat = read_stat(data, &mut pos)?;
+            } else {
+                dir.untracked.clear();
+            }
+            n += 1;
+            Ok(())
+        })?;
+        if n as u64 != count {
+            return Err(anyhow!(
+                "untracked cache has {} directories, not {}",
+                n,
+                count
+            ));
+        }
+        n = 0;
+        root.visit_mut(&mut |dir| {
+            if hashed.get(n) {
+                dir.exclude_hash = read_bytes(data, &mut pos)?;
+            }
+            n += 1;
+            Ok(())
+        })?;
+
+        cache.root = Some(root);
+        Ok(cache)
+    }
+
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        write_varint(self.ident.len() as u64, out);
+        out.extend_from_slice(&self.ident);
+        write_stat(&self.info_exclude.stat, out);
+        write_stat(&self.excludes_file.stat, out);
+        out.extend_from_slice(&self.dir_flags.to_be_bytes());
+        out.extend_from_slice(&self.info_exclude.hash);
+        out.extend_from_slice(&self.excludes_file.hash);
+        out.extend_from_slice(self.exclude_per_dir.as_bytes());
+        out.push(0);
+
+        let Some(root) = &self.root else {
+            write_varint(0, out);
+            return;
+        };
+        let mut dirs = Vec::new();
+        root.preorder(&mut dirs);
+
+        write_varint(dirs.len() as u64, out);
+        let mut valid = Bitmap::default();
+        let mut check_only = Bitmap::default();
+        let mut hashed = Bitmap::default();
+        for (n, dir) in dirs.iter().enumerate() {
+            let untracked: &[String] = if dir.valid { &dir.untracked } else { &[] };
+            write_varint(untracked.len() as u64, out);
+            write_varint(dir.dirs.len() as u64, out);
+            for name in std::iter::once(&dir.name).chain(untracked) {
+                out.extend_from_slice(name.as_bytes());
+                out.push(0);
+            }
+
+            if dir.valid {
+                valid.set(n as u32);
+                if dir.check_only {
+                    check_only.set(n as u32);
+                }
+            }
+            if dir.exclude_hash != [0; 20] {
+                hashed.set(n as u32);
+            }
+        }
+
+        valid.write_ewah(out);
+        check_only.write_ewah(out);
+        hashed.write_ewah(out);
+        for dir in dirs.iter().filter(|dir| dir.valid) {
+            write_stat(&dir.stat, out);
+        }
+        for dir in dirs.iter().filter(|dir| dir.exclude_hash != [0; 20]) {
+            out.extend_from_slice(&dir.exclude_hash);
+        }
+        out.push(0);
+    }
+
+    /// Forget the listing of the directories on the way to `path`, whose
+    /// untracked files it may be one of. A `.gitignore`, or a directory,
+    /// invalidates everything below its directory as well.
+    pub fn invalidate(&mut self, path: &str) {
+        let Some(mut dir) = self.root.as_mut() else {
+            return;
+        };
+        let mut components = path.split('/').peekable();
+        while let Some(component) = components.next() {
+            dir.invalidate();
+            if components.peek().is_none() {
+                if component.is_empty() || component == ".gitignore" {
+                    dir.invalidate_all();
+                }
+                break;
+            }
+            match dir.dirs.iter_mut().find(|d| d.name == component) {
+                Some(child) => dir = child,
+                None => break,
+            }
+        }
+    }
+
+    /// Invalidate everything if `info/exclude` or the user's ignore file
+    /// changed.
+    fn check_ignore_files(&mut self, info_exclude: IgnoreFile, excludes_file: IgnoreFile) {
+        if info_exclude.hash != self.info_exclude.hash {
+            self.info_exclude = info_exclude;
+            if let Some(root) = &mut self.root {
+                root.invalidate_all();
+            }
+        }
+        if excludes_file.hash != self.excludes_file.hash {
+            self.excludes_file = excludes_file;
+            if let Some(root) = &mut self.root {
+                root.invalidate_all();
+            }
+        }
+    }
+}
+
+/// A listing of the untracked files of the worktree, reading only the
+/// directories whose cached listing is not valid.
+struct UntrackedWalk<'a> {
+    worktree: &'a Path,
+    rules: IgnoreRules,
+    tracked: HashMap<&'a str, &'a IndexEntry>,
+    /// the directories holding tracked files
+    tracked_dirs: HashSet<&'a str>,
+    /// the modification time of the index read: a directory modified in
+    /// the same second or after may have changed again unseen
+    racy_from: Option<u32>,
+    /// whether a filesystem monitor invalidated the directories it saw
+    /// change, so that the others need not be looked at
+    monitored: bool,
+    found: Vec<String>,
+}
+
+impl<'a> UntrackedWalk<'a> {
+    fn new(worktree: &'a Path, rules: IgnoreRules, index: &'a Index) -> Self {
+        let tracked: HashMap<&str, &IndexEntry> = index
+            .entries
+            .iter()
+            .map(|e| (e.file_path.as_str(), e))
+            .collect();
+        let tracked_dirs = tracked
+            .keys()
+            .flat_map(|path| path.match_indices('/').map(|(i, _)| &path[..i]))
+            .collect();
+        UntrackedWalk {
+            worktree,
+            rules,
+            tracked,
+            tracked_dirs,
+            racy_from: index.timestamp,
+            monitored: false,
+            found: Vec::new(),
+        }
+    }
+
+    /// The id of the `.gitignore` of the directory `path`: that of its index
+    /// entry while it is up to date, as git takes it.
+    fn exclude_hash(&self, path: &str) -> [u8; 20] {
+        let relative = format!("{}.gitignore", path);
+        let full_path = self.worktree.join(&relative);
+        if let Some(entry) = self.tracked.get(relative.as_str()) {
+            let up_to_date = fs::metadata(&full_path)
+                .is_ok_and(|metadata| entry.is_up_to_date(&metadata, self.racy_from));
+            if entry.stage() == 0 && up_to_date {
+                return entry.sha1;
+            }
+        }
+        ignore_file_hash(&full_path)
+    }
+
+    /// Whether the cached listing of the directory `path` (empty or ending
+    /// with `/`) still holds, taking its stat data if not.
+    fn is_valid(&self, dir: &mut UntrackedDir, path: &str, check_only: bool) -> bool {
+        if !(self.monitored && dir.valid) {
+            // a changed .gitignore changes what is ignored below it too
+            let exclude_hash = self.exclude_hash(path);
+            if exclude_hash != dir.exclude_hash {
+                dir.invalidate_all();
+                dir.exclude_hash = exclude_hash;
+            }
+
+            let stat = fs::symlink_metadata(self.worktree.join(path))
+                .map(|metadata| stat_data(&metadata))
+                .unwrap_or_default();
+            let racy = self
+                .racy_from
+                .is_some_and(|timestamp| dir.stat.mtime_s >= timestamp);
+            if !dir.valid || racy || !stat_unchanged(&dir.stat, &stat) {
+                dir.stat = stat;
+                return false;
+            }
+        }
+
+        dir.check_only == check_only
+    }
+
+    /// List the untracked files of the directory `path` through `dir`,
+    /// reading it if its listing is not valid. With `check_only`, only
+    /// tell whether there are any. Returns whether there are.
+    fn read(&mut self, dir: &mut UntrackedDir, path: &str, check_only: bool) -> Result<bool> {
+        let mut any = false;
+        if self.is_valid(dir, path, check_only) {
+            for child in &mut dir.dirs {
+                let child_path = format!("{}{}/", path, child.name);
+                let child_check_only = child.check_only;
+                if self.read(child, &child_path, child_check_only)? && child_check_only {
+                    any = true;
+                    if !check_only {
+                        self.found.push(child_path);
+                    }
+                }
+            }
+            for name in &dir.untracked {
+                // an untracked directory is as its own listing finds it
+                let checked = name.strip_suffix('/').is_some_and(|name| {
+                    dir.dirs
+                        .iter()
+                        .any(|child| child.check_only && child.name == name)
+                });
+                if !checked {
+                    any = true;
+                    if !check_only {
+                        self.found.push(format!("{}{}", path, name));
+                    }
+                }
+            }
+            return Ok(any);
+        }
+
+        dir.invalidate();
+        dir.check_only = check_only;
+        let Ok(read_dir) = fs::read_dir(self.worktree.join(path)) else {
+            return Ok(false);
+        };
+        let mut entries = Vec::new();
+        for entry in read_dir {
+            let entry = entry?;
+            entries.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.file_type()?,
+            ));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut previous: HashMap<String, UntrackedDir> = std::mem::take(&mut dir.dirs)
+            .into_iter()
+            .map(|child| (child.name.clone(), child))
+            .collect();
+        for (name, file_type) in entries {
+            if name == ".git" {
+                continue;
+            }
+            let relative = format!("{}{}", path, name);
+            let untracked = if file_type.is_dir() {
+                // a submodule is tracked as a whole
+                if self.tracked.contains_key(relative.as_str()) {
+                    continue;
+                }
+                let has_tracked = self.tracked_dirs.contains(relative.as_str());
+                if !has_tracked && self.rules.is_ignored(&relative, true)? {
+                    continue;
+                }
+
+                // another repository is not walked into
+                if !has_tracked && self.worktree.join(&relative).join(".git").exists() {
+                    Some(format!("{}/", name))
+                } else {
+                    let mut child = previous
+                        .remove(&name)
+                        .unwrap_or_else(|| UntrackedDir::named(&name));
+                    let found = self.read(&mut child, &format!("{}/", relative), !has_tracked)?;
+                    dir.dirs.push(child);
+                    (found && !has_tracked).then(|| format!("{}/", name))
+                }
+            } else if file_type.is_file() || file_type.is_symlink() {
+                let ignored = self.tracked.contains_key(relative.as_str())
+                    || self.rules.is_ignored(&relative, false)?;
+                (!ignored).then_some(name)
+            } else {
+                None
+            };
+
+            if let Some(name) = untracked {
+                any = true;
+                if !check_only {
+                    self.found.push(format!("{}{}", path, name));
+                }
+                dir.untracked.push(name);
+            }
+        }
+
+        dir.valid = true;
+        Ok(any)
+    }
+}
+
+impl Repository {
+    /// What the untracked cache is made for: this worktree, on this system.
+    fn untracked_ident(&self) -> Result<String> {
+        Ok(format!(
+            "Location {}, system {}",
+            self.path.canonicalize()?.display(),
+            system_name()
+        ))
+    }
+
+    /// The untracked files of the worktree, and its directories holding
+    /// untracked files but no tracked one with a trailing `/`, sorted. The
+    /// untracked cache of `index` is used and updated, or made with
+    /// `core.untrackedCache`, then written back as `refresh_index` does;
+    /// with `core.fsmonitor`, only the directories the monitor saw change
+    /// are looked at.
+    pub fn untracked_files(&self, index: &mut Index) -> Result<Vec<String>> {
+        let config = self.config()?;
+        let enabled = match config.get("core.untrackedcache") {
+            Some(value) if value.eq_ignore_ascii_case("keep") => None,
+            Some(_) => config.get_bool("core.untrackedcache"),
+            None => None,
+        };
+        let ident = self.untracked_ident()?;
+        let before = index.untracked_cache.clone();
+        match enabled {
+            Some(false) => index.untracked_cache = None,
+            Some(true) if !before.as_ref().is_some_and(|cache| cache.is_usable(&ident)) => {
+                index.untracked_cache = Some(UntrackedCache::new(&ident));
+            }
+            _ => {}
+        }
+
+        let _region = trace2::region("dir", "read_directory");
+        let mut cache = index.untracked_cache.take();
+        let mut found = {
+            let mut walk = UntrackedWalk::new(&self.path, self.ignore_rules()?, index);
+            match cache.as_mut().filter(|cache| cache.is_usable(&ident)) {
+                Some(cache) => {
+                    cache.check_ignore_files(
+                        IgnoreFile::read(Some(&self.git_dir.join("info/exclude"))),
+                        IgnoreFile::read(self.excludes_file()?.as_deref()),
+                    );
+                    if let (Some(changed), _) = self.fsmonitor_changes()? {
+                        for path in changed {
+                            let is_dir = self.path.join(&path).is_dir();
+                            match is_dir && !path.ends_with('/') {
+                                true => cache.invalidate(&format!("{}/", path)),
+                                false => cache.invalidate(&path),
+                            }
+                        }
+                        walk.monitored = true;
+                    }
+                    let root = cache.root.get_or_insert_with(UntrackedDir::default);
+                    walk.read(root, "", false)?;
+                }
+                None => {
+                    walk.read(&mut UntrackedDir::default(), "", false)?;
+                }
+            }
+            walk.found
+        };
+        index.untracked_cache = cache;
+
+        if index.untracked_cache != before && self.optional_locks {
+            // a busy index gets its cache from a later command instead
+            let _ = index.write_to_file(&self.index_path());
+        }
+
+        found.sort();
+        found.dedup();
+        Ok(found)
+    }
+}
+
+/// Invalidate in `cache` what the index entries `old` and `new` differ in.
+pub fn invalidate_changed(cache: &mut UntrackedCache, old: &[IndexEntry], new: &[IndexEntry]) {
+    let old_paths: HashSet<&str> = old.iter().map(|e| e.file_path.as_str()).collect();
+    let new_paths: HashSet<&str> = new.iter().map(|e| e.file_path.as_str()).collect();
+    for path in old_paths.symmetric_difference(&new_paths) {
+        cache.invalidate(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_untracked_cache_roundtrip() {
+        let mut varint = Vec::new();
+        write_varint(300, &mut varint);
+        assert_eq!(read_varint(&varint, &mut 0).unwrap(), 300);
+
+        let mut cache = UntrackedCache::new("Location /repo, system Linux");
+        let mut root = UntrackedDir {
+            valid: true,
+            exclude_hash: [7; 20],
+            untracked: vec!["new/".to_string(), "notes".to_string()],
+            ..Default::default()
+        };
+        root.dirs.push(UntrackedDir {
+            valid: true,
+            check_only: true,
+            untracked: vec!["file".to_string()],
+            ..UntrackedDir::named("new")
+        });
+        root.dirs.push(UntrackedDir::named("src"));
+        cache.root = Some(root);
+
+        let mut data = Vec::new();
+        cache.serialize(&mut data);
+        assert_eq!(UntrackedCache::parse(&data).unwrap(), cache);
+        assert!(cache.is_usable("Location /repo, system Linux"));
+        assert!(!cache.is_usable("Location /elsewhere, system Linux"));
+
+        cache.invalidate("new/file");
+        let root = cache.root.as_ref().unwrap();
+        assert!(!root.valid && !root.dirs[0].valid && root.untracked.is_empty());
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{Read, Write},
+};
+
+use anyhow::{anyhow, Result};
+use hex::FromHex;
+
+use crate::{
+    config::Config,
+    pkt_line::{packet_line, read_pkt_line},
+    repository::Repository,
+};
+
+/// Frames everything written through it as side-band packets of `band`.
+struct SideBand<W: Write> {
+    inner: W,
+    band: u8,
+    max_data: usize,
+}
+
+impl<W: Write> Write for SideBand<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.max_data);
+        write!(self.inner, "{:04x}", n + 5)?;
+        self.inner.write_all(&[self.band])?;
+        self.inner.write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Whether the ref `name` is hidden by the `hideRefs` prefixes `rules`: the
+/// last one matching decides, a `!` exposing the refs it matches again.
+fn is_hidden(rules: &[String], name: &str) -> bool {
+    let mut hidden = false;
+    for rule in rules {
+        let (exposed, prefix) = match rule.strip_prefix('!') {
+            Some(prefix) => (true, prefix),
+            None => (false, rule.as_str()),
+        };
+        let prefix = prefix.trim_end_matches('/');
+        if name == prefix
+            || name
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('/'))
+        {
+            hidden = !exposed;
+        }
+    }
+
+    hidden
+}
+
+/// The ref tips of the repository as served to clients.
+struct RefTips {
+    /// HEAD and the refs which are not hidden, with the objects annotated
+    /// tags point to as `<tag>^{}`
+    advertised: Vec<(String, [u8; 20])>,
+    /// the tips of the hidden refs
+    hidden: Vec<[u8; 20]>,
+}
+
+/// Which unadvertised objects clients may want.
+struct WantPolicy {
+    /// the tips of hidden refs, with `uploadpack.allowTipSHA1InWant`
+    tip: bool,
+    /// objects reachable from any ref, with
+    /// `uploadpack.allowReachableSHA1InWant`
+    reachable: bool,
+    /// any object, with `uploadpack.allowAnySHA1InWant`
+    any: bool,
+}
+
+impl WantPolicy {
+    fn new(config: &Config) -> Self {
+        let allowed = |key: &str| config.get_bool(key) == Some(true);
+        let any = allowed("uploadpack.allowanysha1inwant");
+        let reachable = any || allowed("uploadpack.allowreachablesha1inwant");
+        let tip = reachable || allowed("uploadpack.allowtipsha1inwant");
+
+        WantPolicy {
+            tip,
+            reachable,
+            any,
+        }
+    }
+}
+
+impl Repository {
+    fn ref_tips(&self) -> Result<RefTips> {
+        let config = self.config()?;
+        let mut rules = config.get_all("transfer.hiderefs");
+        rules.extend(config.get_all("uploadpack.hiderefs"));
+
+        let mut tips = RefTips {
+            advertised: Vec::new(),
+            hidden: Vec::new(),
+        };
+        if let Some(head) = self.head_commit()? {
+            tips.advertised.push(("HEAD".to_string(), head));
+        }
+        for (name, hash) in self.list_refs("refs/")? {
+            if is_hidden(&rules, &name) {
+                tips.hidden.push(hash);
+                continue;
+            }
+            tips.advertised.push((name.clone(), hash));
+            if let Some(peeled) = self.peel_tag(&hash)? {
+                tips.advertised.push((format!("{}^{{}}", name), peeled));
+            }
+        }
+
+        Ok(tips)
+    }
+
+    /// The first of `wants` a client may not fetch, if any. Advertised tips
+    /// always may be fetched, and more as `WantPolicy` allows.
+    fn find_forbidden_want(&self, wants: &[[u8; 20]]) -> Result<Option<[u8; 20]>> {
+        let policy = WantPolicy::new(&self.config()?);
+        let tips = self.ref_tips()?;
+        let advertised: HashSet<[u8; 20]> = tips.advertised.iter().map(|(_, hash)| *hash).collect();
+
+        // walked at most once, for the first want which is not a tip
+        let mut reachable = None;
+        for want in wants {
+            if !self.has_object(want) {
+                return Ok(Some(*want));
+            }
+            if policy.any || advertised.contains(want) || (policy.tip && tips.hidden.contains(want))
+            {
+                continue;
+            }
+            if policy.reachable {
+                if reachable.is_none() {
+                    let roots: Vec<[u8; 20]> =
+                        advertised.iter().chain(&tips.hidden).copied().collect();
+                    let mut seen = HashSet::new();
+                    self.walk_objects(&roots, &mut seen, &mut Vec::new())?;
+                    reachable = Some(seen);
+                }
+                if reachable.as_ref().is_some_and(|seen| seen.contains(want)) {
+                    continue;
+                }
+            }
+            return Ok(Some(*want));
+        }
+
+        Ok(None)
+    }
+
+    /// Send the ref advertisement of protocol v0: HEAD and every ref not
+    /// hidden by `uploadpack.hideRefs` or `transfer.hideRefs`, with annotated
+    /// tags followed by the object they point to.
+    pub fn advertise_refs<W: Write>(&self, out: &mut W) -> Result<()> {
+        let refs = self.ref_tips()?.advertised;
+
+        let mut capabilities = format!(
+            "multi_ack_detailed side-band side-band-64k no-progress agent=mg/{}",
+            env!("CARGO_PKG_VERSION")
+        );
+        // clients only ask for unadvertised objects the server allows
+        let policy = WantPolicy::new(&self.config()?);
+        if policy.tip {
+            capabilities.push_str(" allow-tip-sha1-in-want");
+        }
+        if policy.reachable {
+            capabilities.push_str(" allow-reachable-sha1-in-want");
+        }
+        let head = fs::read_to_string(self.git_dir.join("HEAD"))?;
+        if let Some(branch) = head.trim().strip_prefix("ref: ") {
+            capabilities.push_str(&format!(" symref=HEAD:{}", branch));
+        }
+
+        if refs.is_empty() {
+            let line = format!(
+                "{} capabilities^{{}}\0{}\n",
+                hex::encode([0; 20]),
+                capabilities
+            );
+            out.write_all(&packet_line(&line))?;
+        }
+        for (i, (name, hash)) in refs.iter().enumerate() {
+            let line = if i == 0 {
+                format!("{} {}\0{}\n", hex::encode(hash), name, capabilities)
+            } else {
+                format!("{} {}\n", hex::encode(hash), name)
+            };
+            out.write_all(&packet_line(&line))?;
+        }
+        out.write_all(b"0000")?;
+        out.flush()?;
+
+        Ok(())
+    }
+
+    /// The objects to send to a client which wants `wants` and has `haves`,
+    /// from the reachability bitmap of a pack if one covers them. The
+    /// history walked is the stored one, whatever replaces its objects.
+    pub fn objects_to_pack(&self, wants: &[[u8; 20]], haves: &[[u8; 20]]) -> Result<Vec<[u8; 20]>> {
+        let repo = self.stored_objects();
+        if let Some((bitmap, reachable)) = repo.bitmap_reachable(wants, haves)? {
+            return Ok(bitmap.all_objects(&reachable));
+        }
+
+        let mut seen = HashSet::new();
+        repo.walk_objects(haves, &mut seen, &mut Vec::new())?;
+
+        let mut objects = Vec::new();
+        repo.walk_objects(wants, &mut seen, &mut objects)?;
+
+        Ok(objects)
+    }
+
+    /// Serve a fetch after the ref advertisement: read the wants and haves of
+    /// the client, acknowledge the common objects and send the pack. A
+    /// `stateless` request (smart HTTP) ends at the first flush of haves, the
+    /// client sending its wants and common objects again in the next one.
+    pub fn upload_pack<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        out: &mut W,
+        stateless: bool,
+    ) -> Result<()> {
+        let mut wants = Vec::new();
+        let mut capabilities = Vec::new();
+        while let Some(line) = read_pkt_line(input)? {
+            let line = String::from_utf8(line)?;
+            let Some(rest) = line.trim_end().strip_prefix("want ") else {
+                return Err(anyhow!("unexpected line: {}", line.trim_end()));
+            };
+            let mut words = rest.split(' ');
+            let hash = <[u8; 20]>::from_hex(words.next().unwrap_or_default())?;
+            if wants.is_empty() {
+                capabilities.extend(words.map(String::from));
+            }
+            wants.push(hash);
+        }
+
+        if let Some(hash) = self.find_forbidden_want(&wants)? {
+            let error = format!("ERR upload-pack: not our ref {}", hex::encode(hash));
+            out.write_all(&packet_line(&error))?;
+            out.flush()?;
+            return Err(anyhow!(
+                "client wants forbidden object {}",
+                hex::encode(hash)
+            ));
+        }
+
+        // a client with everything up to date only sends a flush
+        if wants.is_empty() {
+            return Ok(());
+        }
+
+        // with multi_ack_detailed every common object is acknowledged, else
+        // only the first one
+        let multi_ack = capabilities.iter().any(|c| c == "multi_ack_detailed");
+        let mut common: Vec<[u8; 20]> = Vec::new();
+        loop {
+            match read_pkt_line(input)? {
+                None => {
+                    if common.is_empty() || multi_ack {
+                        out.write_all(&packet_line("NAK\n"))?;
+                        out.flush()?;
+                    }
+                    if stateless {
+                        return Ok(());
+                    }
+                }
+                Some(line) => {
+                    let line = String::from_utf8(line)?;
+                    let line = line.trim_end();
+                    if line == "done" {
+                        match common.last() {
+                            Some(last) if multi_ack => out
+                                .write_all(&packet_line(&format!("ACK {}\n", hex::encode(last))))?,
+                            Some(_) => {}
+                            None => out.write_all(&packet_line("NAK\n"))?,
+                        }
+                        break;
+                    }
+                    let Some(hash) = line.strip_prefix("have ") else {
+                        return Err(anyhow!("unexpected line: {}", line));
+                    };
+                    let hash = <[u8; 20]>::from_hex(hash)?;
+                    if !self.has_object(&hash) {
+                        continue;
+                    }
+
+                    common.push(hash);
+                    let ack = if multi_ack {
+                        Some(format!("ACK {} common\n", hex::encode(hash)))
+                    } else if common.len() == 1 {
+                        Some(format!("ACK {}\n", hex::encode(hash)))
+                    } else {
+                        None
+                    };
+                    if let Some(ack) = ack {
+                        out.write_all(&packet_line(&ack))?;
+                        out.flush()?;
+                    }
+                }
+            }
+        }
+
+        let objects = self.objects_to_pack(&wants, &common)?;
+        let compression = self.compression(false)?;
+        let max_data = if capabilities.iter().any(|c| c == "side-band-64k") {
+            Some(65515)
+        } else if capabilities.iter().any(|c| c == "side-band") {
+            Some(995)
+        } else {
+            None
+        };
+
+        match max_data {
+            Some(max_data) => {
+                let mut band = SideBand {
+                    inner: &mut *out,
+                    band: 1,
+                    max_data,
+                };
+                self.write_pack(&objects, &mut band, compression)?;
+                out.write_all(b"0000")?;
+            }
+            None => {
+                self.write_pack(&objects, &mut *out, compression)?;
+            }
+        }
+        out.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_hidden() {
+        let rules = vec!["refs/pull".to_string(), "!refs/pull/1/".to_string()];
+        assert!(is_hidden(&rules, "refs/pull/2/head"));
+        assert!(!is_hidden(&rules, "refs/pull/1/head"));
+        assert!(!is_hidden(&rules, "refs/pulls"));
+        assert!(!is_hidden(&rules, "refs/heads/main"));
+    }
+}
@@ -0,0 +1,316 @@
+use anyhow::Result;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    cache_tree::CacheTree,
+    index::{Index, IndexEntry},
+    kind::Kind,
+    repository::Repository,
+};
+
+/// The modes an index entry can have.
+const VALID_MODES: [u32; 4] = [0o100644, 0o100755, 0o120000, 0o160000];
+
+/// Check the layout of the raw index `data`: the header, the entries'
+/// modes, names, padding and order, the extensions' sizes and the trailing
+/// checksum. Each problem is described with the offset it was found at.
+fn check_index_layout(data: &[u8]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if data.len() < 12 {
+        problems.push(format!(
+            "the index is {} bytes, too short for a header",
+            data.len()
+        ));
+        return problems;
+    }
+    if &data[..4] != b"DIRC" {
+        problems.push("offset 0: the signature is not DIRC".to_string());
+        return problems;
+    }
+    let version = u32::from_be_bytes(data[4..8].try_into().expect("4 bytes"));
+    if !(2..=3).contains(&version) {
+        problems.push(format!("offset 4: unsupported version {}", version));
+        return problems;
+    }
+    let count = u32::from_be_bytes(data[8..12].try_into().expect("4 bytes"));
+
+    let mut offset = 12;
+    let mut previous: Option<(Vec<u8>, u16)> = None;
+    for number in 0..count {
+        let start = offset;
+        if data.len() < start + 62 {
+            problems.push(format!(
+                "offset {}: entry {} of {} is truncated",
+                start, number, count
+            ));
+            return problems;
+        }
+
+        let mode = u32::from_be_bytes(data[start + 24..start + 28].try_into().expect("4 bytes"));
+        let flags = u16::from_be_bytes([data[start + 60], data[start + 61]]);
+        let header_len = if version >= 3 && flags & 0x4000 != 0 {
+            64
+        } else {
+            62
+        };
+        let name_start = start + header_len;
+
+        // names of 0xFFF bytes or more end at the first NUL
+        let name_len = (flags & 0xFFF) as usize;
+        let name_end = if name_len < 0xFFF {
+            name_start + name_len
+        } else {
+            match data[name_start.min(data.len())..]
+                .iter()
+                .position(|&b| b == 0)
+            {
+                Some(nul) => name_start + nul,
+                None => data.len(),
+            }
+        };
+        if name_end >= data.len() {
+            problems.push(format!(
+                "offset {}: the name of entry {} runs past the end of the index",
+                start, number
+            ));
+            return problems;
+        }
+
+        let name = &data[name_start..name_end];
+        let shown = String::from_utf8_lossy(name);
+        if name.contains(&0) {
+            problems.push(format!(
+                "offset {}: the name of entry {} ('{}') is shorter than its length {}",
+                name_start, number, shown, name_len
+            ));
+        }
+        if !VALID_MODES.contains(&mode) {
+            problems.push(format!(
+                "offset {}: entry {} ('{}') has invalid mode {:o}",
+                start + 24,
+                number,
+                shown,
+                mode
+            ));
+        }
+
+        // the name is followed by 1 to 8 NUL bytes, up to a multiple of 8
+        let end = start + (name_end - start + 8) / 8 * 8;
+        if end > data.len() {
+            problems.push(format!(
+                "offset {}: the padding of entry {} ('{}') runs past the end of the index",
+                name_end, number, shown
+            ));
+            return problems;
+        }
+        if let Some(bad) = data[name_end..end].iter().position(|&b| b != 0) {
+            problems.push(format!(
+                "offset {}: the padding of entry {} ('{}') is not NUL",
+                name_end + bad,
+                number,
+                shown
+            ));
+        }
+
+        let stage = (flags & 0x3000) >> 12;
+        if let Some((previous_name, previous_stage)) = &previous {
+            match (previous_name.as_slice(), *previous_stage).cmp(&(name, stage)) {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => problems.push(format!(
+                    "offset {}: entry {} ('{}', stage {}) is a duplicate",
+                    start, number, shown, stage
+                )),
+                std::cmp::Ordering::Greater => problems.push(format!(
+                    "offset {}: entry {} ('{}') is sorted after '{}'",
+                    start,
+                    number,
+                    shown,
+                    String::from_utf8_lossy(previous_name)
+                )),
+            }
+        }
+        previous = Some((name.to_vec(), stage));
+
+        offset = end;
+    }
+
+    // extensions, up to the 20 byte checksum
+    while data.len() - offset != 20
+        && data.len() >= offset + 8
+        && data[offset..offset + 4].iter().all(u8::is_ascii_alphabetic)
+    {
+        let size = u32::from_be_bytes(data[offset + 4..offset + 8].try_into().expect("4 bytes"));
+        let end = offset + 8 + size as usize;
+        if end > data.len() {
+            problems.push(format!(
+                "offset {}: the {} extension of {} bytes runs past the end of the index",
+                offset,
+                String::from_utf8_lossy(&data[offset..offset + 4]),
+                size
+            ));
+            return problems;
+        }
+        offset = end;
+    }
+
+    match data.len() - offset {
+        0 => problems.push(format!("offset {}: the index has no checksum", offset)),
+        20 => {
+            let expected: [u8; 20] = Sha1::digest(&data[..offset]).into();
+            if data[offset..] != expected {
+                problems.push(format!(
+                    "offset {}: the checksum is {} but the content hashes to {}",
+                    offset,
+                    hex::encode(&data[offset..]),
+                    hex::encode(expected)
+                ));
+            }
+        }
+        trailing => problems.push(format!(
+            "offset {}: {} bytes of unexpected data after the entries",
+            offset, trailing
+        )),
+    }
+
+    problems
+}
+
+impl Repository {
+    /// Check the index: its layout, that the objects of its entries exist
+    /// and that its cache tree agrees with its entries. Problems are
+    /// printed, and the result tells whether there were none.
+    pub fn verify_index(&self) -> Result<bool> {
+        let path = self.index_path();
+        let data = std::fs::read(&path)?;
+
+        let mut problems = check_index_layout(&data);
+
+        match Index::read_from_file(&path) {
+            Ok(index) => {
+                for entry in &index.entries {
+                    // submodule commits live in other repositories
+                    if !matches!(entry.kind(), Kind::Commit) && !self.has_object(&entry.sha1) {
+                        problems.push(format!(
+                            "'{}' refers to missing object {}",
+                            entry.file_path,
+                            hex::encode(entry.sha1)
+                        ));
+                    }
+                }
+
+                if let Some(cache_tree) = &index.cache_tree {
+                    self.check_cache_tree(cache_tree, &index.entries, "", &mut problems)?;
+                }
+            }
+            Err(e) => problems.push(format!("the index cannot be read: {}", e)),
+        }
+
+        for problem in &problems {
+            println!("error: {}", problem);
+        }
+
+        Ok(problems.is_empty())
+    }
+
+    /// Check that the valid directories of `cache_tree`, the one of
+    /// `prefix`, count the entries below them and have their tree.
+    fn check_cache_tree(
+        &self,
+        cache_tree: &CacheTree,
+        entries: &[IndexEntry],
+        prefix: &str,
+        problems: &mut Vec<String>,
+    ) -> Result<()> {
+        let below: Vec<&IndexEntry> = entries
+            .iter()
+            .filter(|e| e.file_path.starts_with(prefix))
+            .collect();
+        let name = if prefix.is_empty() { "/" } else { prefix };
+
+        if cache_tree.is_valid() {
+            if cache_tree.entry_count as usize != below.len() {
+                problems.push(format!(
+                    "cache tree '{}' counts {} entries, the index has {}",
+                    name,
+                    cache_tree.entry_count,
+                    below.len()
+                ));
+            } else if !self.has_object(&cache_tree.hash) {
+                problems.push(format!(
+                    "cache tree '{}' refers to missing tree {}",
+                    name,
+                    hex::encode(cache_tree.hash)
+                ));
+            } else {
+                let files = self.flatten_tree(&cache_tree.hash)?;
+                let differs = files.len() != below.len()
+                    || files.iter().zip(&below).any(|(file, entry)| {
+                        file.path != entry.file_path[prefix.len()..]
+                            || file.hash != entry.sha1
+                            || file.kind.to_mode() != entry.kind().to_mode()
+                    });
+                if differs {
+                    problems.push(format!(
+                        "cache tree '{}' has tree {} which does not match the index",
+                        name,
+                        hex::encode(cache_tree.hash)
+                    ));
+                }
+            }
+        }
+
+        for child in &cache_tree.children {
+            let child_prefix = format!("{}{}/", prefix, child.name);
+            self.check_cache_tree(child, entries, &child_prefix, problems)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An index with the given names, all with mode 100644.
+    fn index_with(names: &[&str]) -> Vec<u8> {
+        let mut data = b"DIRC".to_vec();
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(&(names.len() as u32).to_be_bytes());
+        for name in names {
+            let start = data.len();
+            data.extend_from_slice(&[0; 24]);
+            data.extend_from_slice(&0o100644u32.to_be_bytes());
+            data.extend_from_slice(&[0; 32]);
+            data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            data.extend_from_slice(name.as_bytes());
+            let len = data.len() - start;
+            data.resize(start + (len + 8) / 8 * 8, 0);
+        }
+        let checksum: [u8; 20] = Sha1::digest(&data).into();
+        data.extend_from_slice(&checksum);
+        data
+    }
+
+    #[test]
+    fn test_check_index_layout() {
+        assert!(check_index_layout(&index_with(&["a", "b/c"])).is_empty());
+
+        let problems = check_index_layout(&index_with(&["b", "a"]));
+        assert_eq!(
+            problems,
+            vec!["offset 76: entry 1 ('a') is sorted after 'b'"]
+        );
+
+        let mut data = index_with(&["a"]);
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert_eq!(check_index_layout(&data).len(), 1);
+
+        let mut data = index_with(&["a"]);
+        data[12 + 63] = 1;
+        let problems = check_index_layout(&data);
+        assert!(problems[0].starts_with("offset 75: the padding of entry 0"));
+    }
+}